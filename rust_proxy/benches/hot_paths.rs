@@ -0,0 +1,144 @@
+// Microbenchmarks for the per-request parsing and the copy loop.
+//
+//     cargo bench --bench hot_paths
+//
+// The copy benchmarks push a fixed payload through an in-memory duplex pipe,
+// so they measure the loop itself rather than the network. `bounded_copy`
+// runs the same loop without touching any counters, which makes it the
+// baseline for what the stats atomics cost.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_proxy::{
+    bounded_copy, bounded_copy_with_counters, bounded_copy_with_stats, find_request_end, parse_host_port,
+    scan_request_head, ByteCounters, CopyLimits, Direction, HeadScan, HostStats, ProxyStats,
+};
+use std::hint::black_box;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+
+const PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+const PIPE_CAPACITY: usize = 64 * 1024;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn request_head(extra_headers: usize) -> Vec<u8> {
+    let mut head = b"GET http://example.com/index.html HTTP/1.1\r\nHost: example.com\r\n".to_vec();
+    for i in 0..extra_headers {
+        head.extend_from_slice(format!("X-Header-{}: {}\r\n", i, "v".repeat(40)).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn bench_find_request_end(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_request_end");
+    for headers in [0, 20, 100] {
+        let head = request_head(headers);
+        group.throughput(Throughput::Bytes(head.len() as u64));
+        group.bench_with_input(BenchmarkId::new("complete", headers), &head, |b, head| {
+            b.iter(|| find_request_end(black_box(head)))
+        });
+        // No terminator: the whole buffer is scanned
+        let partial = &head[..head.len() - 2];
+        group.bench_with_input(BenchmarkId::new("incomplete", headers), partial, |b, partial| {
+            b.iter(|| find_request_end(black_box(partial)))
+        });
+    }
+    group.finish();
+}
+
+// What `handle_client` runs on each request head: the terminator search and
+// the header-line count in one pass, over the whole head and over a head
+// arriving in reads of `READ_SIZE` bytes
+fn bench_scan_request_head(c: &mut Criterion) {
+    const READ_SIZE: usize = 256;
+    let mut group = c.benchmark_group("scan_request_head");
+    for headers in [0, 20, 100] {
+        let head = request_head(headers);
+        group.throughput(Throughput::Bytes(head.len() as u64));
+        group.bench_with_input(BenchmarkId::new("complete", headers), &head, |b, head| {
+            b.iter(|| scan_request_head(black_box(head)))
+        });
+        group.bench_with_input(BenchmarkId::new("split_reads", headers), &head, |b, head| {
+            b.iter(|| {
+                let mut scan = HeadScan::default();
+                let mut received = 0;
+                while scan.advance(black_box(&head[..received])).is_none() {
+                    received = (received + READ_SIZE).min(head.len());
+                }
+                scan.header_lines()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_parse_host_port(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_host_port");
+    for target in ["example.com", "example.com:8443", "example.com:notaport"] {
+        group.bench_with_input(BenchmarkId::from_parameter(target), target, |b, target| {
+            b.iter(|| parse_host_port(black_box(target), 443))
+        });
+    }
+    group.finish();
+}
+
+// A pipe whose write end is fed `PAYLOAD_SIZE` bytes by a spawned task and
+// then closed
+fn filled_pipe() -> DuplexStream {
+    let (mut writer, reader) = tokio::io::duplex(PIPE_CAPACITY);
+    tokio::spawn(async move {
+        let chunk = vec![b'x'; PIPE_CAPACITY];
+        for _ in 0..PAYLOAD_SIZE / PIPE_CAPACITY {
+            writer.write_all(&chunk).await.unwrap();
+        }
+    });
+    reader
+}
+
+fn bench_copy(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("copy");
+    group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+
+    group.bench_function("no_stats", |b| {
+        b.to_async(&runtime).iter(|| async {
+            bounded_copy(filled_pipe(), tokio::io::sink(), u64::MAX, IDLE_TIMEOUT).await.unwrap();
+        })
+    });
+
+    group.bench_function("bounded_copy_with_stats", |b| {
+        let stats = Arc::new(ProxyStats::new());
+        b.to_async(&runtime).iter(|| {
+            let stats = stats.clone();
+            async move {
+                let outcome = bounded_copy_with_stats(filled_pipe(), tokio::io::sink(), u64::MAX, IDLE_TIMEOUT, None, None, "bench", Direction::ServerToClient, stats).await;
+                assert!(outcome.reason.is_normal());
+            }
+        })
+    });
+
+    // Per-host and per-connection counters on top of the global one, as
+    // forwarded traffic does
+    group.bench_function("bounded_copy_with_counters", |b| {
+        let stats = Arc::new(ProxyStats::new());
+        let host = HostStats::default();
+        let connection = AtomicU64::new(0);
+        let limits = CopyLimits { max_download_size: u64::MAX, idle_timeout: IDLE_TIMEOUT, write_timeout: IDLE_TIMEOUT, ..Default::default() };
+        b.to_async(&runtime).iter(|| {
+            let stats = stats.clone();
+            let counters = ByteCounters { host: Some(&host), connection: Some(&connection), tenant: None };
+            async move {
+                bounded_copy_with_counters(filled_pipe(), tokio::io::sink(), limits, "bench", Direction::ServerToClient, stats, counters)
+                    .await
+                    .unwrap();
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_find_request_end, bench_scan_request_head, bench_parse_host_port, bench_copy);
+criterion_main!(benches);
@@ -0,0 +1,229 @@
+// Admin HTTP listener for health checks and statistics.
+//
+// Served on its own address (`--admin-addr`) so orchestrators can probe the
+// proxy without going through the proxy path. Requests are tiny and always
+// answered with `Connection: close`.
+
+use crate::headers::RequestHead;
+use crate::{find_header_terminator, parse_host_port, HostStats, ProxyConfig, ProxyStats};
+use log::{debug, warn};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+pub const HEALTH_CHECK_CACHE_TTL: Duration = Duration::from_secs(5);
+const ADMIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const ADMIN_MAX_REQUEST: usize = 8192;
+
+// Readiness probe against a representative upstream. The result is cached
+// for `ttl` so frequent health checks don't turn into a connection storm.
+#[derive(Debug)]
+pub struct UpstreamCheck {
+    target: String,
+    ttl: Duration,
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl UpstreamCheck {
+    pub fn new(target: String, ttl: Duration) -> Self {
+        Self { target, ttl, last: Mutex::new(None) }
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    // Holding the lock across the probe means concurrent health checks wait
+    // for the one in flight instead of each opening their own connection
+    pub async fn is_reachable(&self, config: &ProxyConfig) -> bool {
+        let mut last = self.last.lock().await;
+        if let Some((checked, reachable)) = *last {
+            if checked.elapsed() < self.ttl {
+                return reachable;
+            }
+        }
+
+        let (host, port) = parse_host_port(&self.target, 80);
+        let reachable = matches!(timeout(HEALTH_CHECK_TIMEOUT, config.dialer.dial(host, port)).await, Ok(Ok(_)));
+        if !reachable {
+            warn!("Health check: upstream {} unreachable", self.target);
+        }
+        *last = Some((Instant::now(), reachable));
+        reachable
+    }
+}
+
+#[derive(Debug)]
+pub struct AdminState {
+    pub stats: Arc<ProxyStats>,
+    pub config: Arc<ProxyConfig>,
+    pub upstream_check: Option<UpstreamCheck>,
+}
+
+pub async fn serve_admin(listener: TcpListener, state: Arc<AdminState>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin(stream, &state).await {
+                debug!("Admin request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_admin(mut stream: TcpStream, state: &AdminState) -> io::Result<()> {
+    let mut buffer = vec![0; ADMIN_MAX_REQUEST];
+    let mut bytes_read = 0;
+    let deadline = tokio::time::Instant::now() + ADMIN_REQUEST_TIMEOUT;
+    while find_header_terminator(&buffer[..bytes_read]).is_none() && bytes_read < buffer.len() {
+        match tokio::time::timeout_at(deadline, stream.read(&mut buffer[bytes_read..])).await {
+            Ok(Ok(0)) | Err(_) => return Ok(()),
+            Ok(Ok(n)) => bytes_read += n,
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+
+    let response = match RequestHead::parse(&buffer[..bytes_read]) {
+        Some(head) if head.method == "GET" || head.method == "HEAD" => {
+            let mut response = route(&head.target, state).await;
+            if head.method == "HEAD" {
+                response.truncate(find_header_terminator(&response).unwrap_or(response.len()));
+            }
+            response
+        }
+        Some(_) => response("405 Method Not Allowed", "text/plain", "method not allowed\n"),
+        None => response("400 Bad Request", "text/plain", "bad request\n"),
+    };
+    stream.write_all(&response).await?;
+    stream.shutdown().await
+}
+
+async fn route(path: &str, state: &AdminState) -> Vec<u8> {
+    let path = path.split('?').next().unwrap_or(path);
+    match path {
+        "/healthz" => healthz(state).await,
+        "/stats.json" => stats_json(state),
+        "/metrics" => metrics(state),
+        _ => response("404 Not Found", "text/plain", "not found\n"),
+    }
+}
+
+async fn healthz(state: &AdminState) -> Vec<u8> {
+    if state.config.draining.load(Ordering::Relaxed) {
+        return response("503 Service Unavailable", "text/plain", "draining\n");
+    }
+    match &state.upstream_check {
+        Some(check) if !check.is_reachable(&state.config).await => {
+            response("503 Service Unavailable", "text/plain", "upstream unreachable\n")
+        }
+        _ => response("200 OK", "text/plain", "ok\n"),
+    }
+}
+
+// One snapshot feeds every value, so the document is internally consistent
+fn stats_json(state: &AdminState) -> Vec<u8> {
+    response("200 OK", "application/json", &format!("{}\n", stats_document(&state.stats)))
+}
+
+// The statistics JSON served by /stats.json (and the control socket)
+pub fn stats_document(stats: &ProxyStats) -> serde_json::Value {
+    let snapshot = stats.snapshot();
+    let mut document = serde_json::to_value(snapshot).unwrap_or_default();
+    document["megabytes_transferred"] = snapshot.megabytes_transferred().into();
+    let upstream_proxies: serde_json::Map<String, serde_json::Value> = stats
+        .upstream_proxies
+        .entries()
+        .into_iter()
+        .map(|(proxy, counts)| {
+            let counts = serde_json::json!({
+                "successes": counts.successes.load(Ordering::Relaxed),
+                "failures": counts.failures.load(Ordering::Relaxed),
+            });
+            (proxy, counts)
+        })
+        .collect();
+    document["upstream_proxies"] = upstream_proxies.into();
+    let tenants: serde_json::Map<String, serde_json::Value> = stats
+        .tenants
+        .entries()
+        .into_iter()
+        .map(|(tenant, counts)| {
+            let counts = serde_json::json!({
+                "connections": counts.connections.load(Ordering::Relaxed),
+                "bytes": counts.bytes.load(Ordering::Relaxed),
+                "errors": counts.errors.load(Ordering::Relaxed),
+            });
+            (tenant, counts)
+        })
+        .collect();
+    document["tenants"] = tenants.into();
+    document
+}
+
+// Picks one of a tenant's counters for a metric family
+type TenantCounter = fn(&HostStats) -> &AtomicU64;
+
+// Prometheus text exposition of the histograms, response classes and
+// per-tenant counters
+fn metrics(state: &AdminState) -> Vec<u8> {
+    let mut body = state
+        .stats
+        .connection_bytes
+        .snapshot()
+        .to_prometheus("proxy_connection_bytes", "Bytes relayed per client connection, recorded when it closes");
+    let snapshot = state.stats.snapshot();
+    body.push_str("# HELP proxy_http_responses_total Upstream HTTP responses by status class\n");
+    body.push_str("# TYPE proxy_http_responses_total counter\n");
+    let classes = [
+        ("1xx", snapshot.resp_1xx),
+        ("2xx", snapshot.resp_2xx),
+        ("3xx", snapshot.resp_3xx),
+        ("4xx", snapshot.resp_4xx),
+        ("5xx", snapshot.resp_5xx),
+        ("invalid", snapshot.resp_invalid),
+    ];
+    for (class, count) in classes {
+        body.push_str(&format!("proxy_http_responses_total{{class=\"{}\"}} {}\n", class, count));
+    }
+
+    let mut tenants = state.stats.tenants.entries();
+    if !tenants.is_empty() {
+        tenants.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let families: [(&str, &str, TenantCounter); 3] = [
+            ("proxy_tenant_requests_total", "Requests tagged with each tenant", |counts| &counts.connections),
+            ("proxy_tenant_bytes_total", "Bytes relayed for each tenant", |counts| &counts.bytes),
+            ("proxy_tenant_errors_total", "Failed upstream connections for each tenant", |counts| &counts.errors),
+        ];
+        for (name, help, counter) in families {
+            body.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+            for (tenant, counts) in &tenants {
+                let counter = counter(counts);
+                body.push_str(&format!("{}{{tenant=\"{}\"}} {}\n", name, label_value(tenant), counter.load(Ordering::Relaxed)));
+            }
+        }
+    }
+    response("200 OK", "text/plain; version=0.0.4", &body)
+}
+
+// Escape a Prometheus label value; tenant names come from clients
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
@@ -0,0 +1,62 @@
+// CONNECT tunnel policy on the ALPN protocols a TLS client offers
+// (`--allow-alpn`, `--block-alpn`).
+//
+// The protocols come from the ClientHello peeked at the start of the tunnel
+// (see sni.rs). A tunnel is refused when the client offers any blocked
+// protocol, or, with an allowlist, any protocol outside it; a ClientHello
+// without ALPN, and tunnels that don't start with TLS, are let through.
+// Protocol IDs are compared exactly, as RFC 7301 defines them as bytes.
+// List entries that can't be protocol IDs (empty, or over 255 bytes) are
+// dropped, or refuse startup under `--fail-closed`.
+//
+// A tunnel that starts a TLS handshake but whose ClientHello can't be read
+// in full (cut short, malformed, or with a malformed ALPN list) is refused,
+// since its protocols can't be checked. With `--fail-closed`, so is a
+// tunnel that doesn't start with TLS at all.
+//
+// By the time the ClientHello arrives the client already has its `200`, so a
+// refusal can't be an HTTP `403`. The client gets the TLS alert a server
+// sends when no offered protocol is acceptable (`no_application_protocol`)
+// and the tunnel is closed; the ClientHello never reaches the upstream.
+
+use std::collections::HashSet;
+
+const MAX_PROTOCOL_ID_LEN: usize = 255;
+
+// Fatal `no_application_protocol` (120) alert record
+pub const NO_APPLICATION_PROTOCOL_ALERT: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x78];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AlpnPolicy {
+    // Empty when every protocol not blocked is allowed
+    allow: HashSet<String>,
+    block: HashSet<String>,
+}
+
+impl AlpnPolicy {
+    // `None` when both lists are empty, so there is nothing to enforce
+    pub fn new(allow: &[String], block: &[String]) -> Option<Self> {
+        let list = |protocols: &[String]| -> HashSet<String> {
+            protocols.iter().map(|p| p.trim()).filter(|p| is_protocol_id(p)).map(str::to_string).collect()
+        };
+        let policy = Self { allow: list(allow), block: list(block) };
+        (!policy.allow.is_empty() || !policy.block.is_empty()).then_some(policy)
+    }
+
+    // List entries that aren't valid protocol IDs
+    pub fn malformed(protocols: &[String]) -> Vec<&str> {
+        protocols.iter().map(|p| p.trim()).filter(|p| !is_protocol_id(p)).collect()
+    }
+
+    // The first offered protocol that gets the tunnel refused, if any
+    pub fn refused<'a>(&self, offered: &'a [String]) -> Option<&'a str> {
+        offered
+            .iter()
+            .find(|p| self.block.contains(*p) || (!self.allow.is_empty() && !self.allow.contains(*p)))
+            .map(String::as_str)
+    }
+}
+
+fn is_protocol_id(protocol: &str) -> bool {
+    (1..=MAX_PROTOCOL_ID_LEN).contains(&protocol.len())
+}
@@ -0,0 +1,34 @@
+// Basic proxy authentication (RFC 7617 credentials in `Proxy-Authorization`)
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::headers::RequestHead;
+
+pub const PROXY_AUTH_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"rust_proxy\"\r\nContent-Length: 0\r\n\r\n";
+
+// Expected `Proxy-Authorization` value for a `user:pass` pair
+pub fn basic_credentials(user_pass: &str) -> String {
+    format!("Basic {}", STANDARD.encode(user_pass))
+}
+
+// Compare without short-circuiting so timing doesn't leak the credential
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Check the request's `Proxy-Authorization` header against the expected value
+pub fn is_authorized(head: &RequestHead, expected: &str) -> bool {
+    match head.get("Proxy-Authorization") {
+        Some(value) => {
+            // The scheme name is case-insensitive
+            let (scheme, token) = value.split_once(' ').unwrap_or((value, ""));
+            let normalized = format!("Basic {}", token.trim());
+            scheme.eq_ignore_ascii_case("basic") && constant_time_eq(normalized.as_bytes(), expected.as_bytes())
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,127 @@
+// Machine-readable lifecycle lines for supervisors.
+//
+// With `--banner-format json` the proxy prints one JSON object per line to
+// stdout when it starts listening and when a graceful shutdown completes.
+// A crash never reaches the `stopped` line, so its absence tells the
+// supervisor the exit was not clean.
+//
+// Before that, `EffectiveConfig` logs every option in force, as resolved by
+// clap from the command line, the environment and the defaults, so a
+// deployment can see which settings actually won. It is read back from the
+// parsed matches rather than `Args`, which keeps the listing complete as
+// options are added and tells where each value came from. Credentials are
+// redacted.
+
+use crate::StatsSnapshot;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use log::{debug, info};
+use serde_json::{json, Map, Value};
+
+// Options whose values are secrets
+const REDACTED_ARGS: &[&str] = &["auth", "upstream_socks5_auth"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum BannerFormat {
+    /// Human-readable log lines only
+    #[default]
+    Text,
+    /// Additionally print JSON lifecycle events on stdout
+    Json,
+}
+
+pub fn started_line(listen: &str) -> String {
+    json!({ "event": "started", "listen": listen, "pid": std::process::id() }).to_string()
+}
+
+pub fn stopped_line(snapshot: &StatsSnapshot) -> String {
+    json!({
+        "event": "stopped",
+        "uptime_secs": snapshot.uptime.as_secs(),
+        "total_connections": snapshot.total_connections,
+    })
+    .to_string()
+}
+
+// One option as resolved at startup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub flag: String,
+    pub values: Vec<String>,
+    pub source: &'static str,
+    // Takes a list (repeatable or comma-separated) rather than one value
+    pub list: bool,
+    // An on/off switch that is on
+    pub enabled_switch: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EffectiveConfig {
+    // Explicitly set options first, then defaults; unset options are left out
+    pub settings: Vec<Setting>,
+}
+
+impl EffectiveConfig {
+    pub fn from_matches(command: &Command, matches: &ArgMatches) -> Self {
+        let mut settings: Vec<Setting> = command
+            .get_arguments()
+            .filter(|arg| !matches!(arg.get_id().as_str(), "help" | "version"))
+            .filter_map(|arg| {
+                let id = arg.get_id().as_str();
+                let values: Vec<String> = matches.get_raw(id)?.map(|v| v.to_string_lossy().into_owned()).collect();
+                let source = match matches.value_source(id)? {
+                    ValueSource::CommandLine => "command line",
+                    ValueSource::EnvVariable => "environment",
+                    _ => "default",
+                };
+                let switch = matches!(arg.get_action(), ArgAction::SetTrue);
+                let enabled_switch = switch && values.iter().any(|v| v == "true");
+                let values = if REDACTED_ARGS.contains(&id) { vec!["<redacted>".to_string()] } else { values };
+                Some(Setting {
+                    flag: arg.get_long().map_or_else(|| id.to_string(), |long| format!("--{}", long)),
+                    values,
+                    source,
+                    list: matches!(arg.get_action(), ArgAction::Append),
+                    enabled_switch,
+                })
+            })
+            .collect();
+        settings.sort_by_key(|setting| setting.source == "default");
+        Self { settings }
+    }
+
+    // The banner text: one line per option, then the switches turned on
+    pub fn render(&self) -> String {
+        let mut text = String::from("Effective configuration:");
+        for setting in &self.settings {
+            text.push_str(&format!("\n   {} {} [{}]", setting.flag, setting.values.join(","), setting.source));
+        }
+        let enabled: Vec<&str> = self.settings.iter().filter(|s| s.enabled_switch).map(|s| s.flag.as_str()).collect();
+        let enabled = if enabled.is_empty() { "none".to_string() } else { enabled.join(" ") };
+        text.push_str(&format!("\n   Enabled features: {}", enabled));
+        text
+    }
+
+    // `{"--port": {"value": "3128", "source": "default"}, ...}`, with list
+    // options as arrays
+    pub fn to_json(&self) -> Value {
+        let settings: Map<String, Value> = self
+            .settings
+            .iter()
+            .map(|setting| {
+                let value = match (setting.list, setting.values.as_slice()) {
+                    (false, [value]) => json!(value),
+                    (_, values) => json!(values),
+                };
+                (setting.flag.clone(), json!({ "value": value, "source": setting.source }))
+            })
+            .collect();
+        Value::Object(settings)
+    }
+
+    // The banner at info, and the same as JSON at debug
+    pub fn log_effective(&self) {
+        info!("{}", self.render());
+        debug!("Effective configuration JSON: {}", self.to_json());
+    }
+}
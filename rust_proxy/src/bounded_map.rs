@@ -0,0 +1,105 @@
+// Concurrent string-keyed map with a size cap and least-recently-used
+// eviction. Used for per-destination state so that a client cycling through
+// random hostnames (e.g. domain fronting) can't grow memory without bound.
+
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: Arc<V>,
+    last_used: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct BoundedMap<V> {
+    entries: DashMap<String, Entry<V>>,
+    capacity: usize,
+    clock: AtomicU64,
+}
+
+impl<V> BoundedMap<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            capacity: capacity.max(1),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    // Logical access clock; strictly increasing so eviction order is exact
+    fn now(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Look up an entry, creating it (and evicting the least recently used
+    // entry if the map is full) when absent
+    pub fn get_or_insert_with(&self, key: &str, make: impl FnOnce() -> V) -> Arc<V> {
+        let now = self.now();
+        if let Some(entry) = self.entries.get(key) {
+            entry.last_used.store(now, Ordering::Relaxed);
+            return entry.value.clone();
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.evict_oldest();
+        }
+
+        self.entries
+            .entry(key.to_string())
+            .or_insert_with(|| Entry {
+                value: Arc::new(make()),
+                last_used: AtomicU64::new(now),
+            })
+            .value
+            .clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<V>> {
+        self.entries.get(key).map(|entry| {
+            entry.last_used.store(self.now(), Ordering::Relaxed);
+            entry.value.clone()
+        })
+    }
+
+    fn evict_oldest(&self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|entry| entry.last_used.load(Ordering::Relaxed))
+            .map(|entry| entry.key().clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Arc<V>> {
+        self.entries.remove(key).map(|(_, entry)| entry.value)
+    }
+
+    // Drop entries for which `keep` returns false
+    pub fn retain(&self, mut keep: impl FnMut(&str, &V) -> bool) {
+        self.entries.retain(|key, entry| keep(key, &entry.value));
+    }
+
+    // Point-in-time copy of all entries
+    pub fn entries(&self) -> Vec<(String, Arc<V>)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().value.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
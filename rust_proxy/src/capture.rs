@@ -0,0 +1,174 @@
+// Raw traffic capture for chosen destinations (`--capture`), for debugging
+// one misbehaving target.
+//
+// Once a connection to a captured `host:port` is established, the upstream
+// stream is wrapped so that everything written to it (the client's side of
+// the exchange) is teed into `<dir>/<millis>-<conn id>-<host>_<port>.client`
+// and everything read from it into the matching `.server` file. For a
+// CONNECT tunnel that is the encrypted TLS stream; for plain HTTP it is the
+// request and response as forwarded, headers and all.
+//
+// Each file stops growing at the size limit, so a large download can't fill
+// the disk. Captures hold whatever the client sent, credentials included,
+// which is why the mode is opt-in and announced with a warning at startup.
+
+use crate::dialer::{AsyncReadWrite, BoxedStream};
+use log::{info, warn};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub const DEFAULT_CAPTURE_DIR: &str = "captures";
+pub const DEFAULT_CAPTURE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+// `host:port`, with IPv6 hosts in brackets
+pub fn parse_capture_target(value: &str) -> Result<String, String> {
+    let (host, port) = value.rsplit_once(':').ok_or("expected host:port")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("expected host:port".to_string());
+    }
+    let port = port.parse::<u16>().map_err(|_| format!("invalid port in {}", value))?;
+    Ok(target_key(host, port))
+}
+
+fn target_key(host: &str, port: u16) -> String {
+    format!("{}:{}", host.to_ascii_lowercase(), port)
+}
+
+#[derive(Debug, Clone)]
+pub struct Capture {
+    targets: Vec<String>,
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl Capture {
+    // Capture connections to `targets` (as `parse_capture_target` returns
+    // them) into `dir`, at most `max_bytes` per direction per connection
+    pub fn new(targets: Vec<String>, dir: PathBuf, max_bytes: u64) -> Self {
+        Self { targets, dir, max_bytes }
+    }
+
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let key = target_key(host.trim_start_matches('[').trim_end_matches(']'), port);
+        self.targets.contains(&key)
+    }
+
+    // `stream` teeing into new capture files for this connection, or
+    // `stream` itself if they can't be created
+    pub fn wrap(&self, conn_id: u64, host: &str, port: u16, stream: BoxedStream) -> BoxedStream {
+        match self.open(conn_id, host, port) {
+            Ok((client, server, base)) => {
+                info!("[#{}] Capturing traffic to {}:{} in {}.{{client,server}}", conn_id, host, port, base.display());
+                Box::new(Captured {
+                    inner: stream,
+                    client: CaptureFile::new(client, self.max_bytes),
+                    server: CaptureFile::new(server, self.max_bytes),
+                })
+            }
+            Err(e) => {
+                warn!("[#{}] Not capturing traffic to {}:{}: {}", conn_id, host, port, e);
+                stream
+            }
+        }
+    }
+
+    fn open(&self, conn_id: u64, host: &str, port: u16) -> io::Result<(File, File, PathBuf)> {
+        fs::create_dir_all(&self.dir)?;
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let host: String = host.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect();
+        let name = format!("{}-{}-{}_{}", millis, conn_id, host, port);
+        let client = File::create(self.dir.join(format!("{}.client", name)))?;
+        let server = File::create(self.dir.join(format!("{}.server", name)))?;
+        Ok((client, server, self.dir.join(name)))
+    }
+}
+
+struct CaptureFile {
+    file: Option<File>,
+    remaining: u64,
+}
+
+impl CaptureFile {
+    fn new(file: File, max_bytes: u64) -> Self {
+        Self { file: Some(file), remaining: max_bytes }
+    }
+
+    // Append what fits under the limit, unbuffered so the files can be read
+    // while the connection is still open. A write error ends this capture
+    // rather than the connection.
+    fn record(&mut self, data: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let n = data.len().min(self.remaining as usize);
+        if let Err(e) = file.write_all(&data[..n]) {
+            warn!("Stopped capturing: {}", e);
+            self.file = None;
+            return;
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            self.file = None;
+        }
+    }
+}
+
+struct Captured {
+    inner: BoxedStream,
+    client: CaptureFile,
+    server: CaptureFile,
+}
+
+impl AsyncRead for Captured {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.server.record(&buf.filled()[before..]);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Captured {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.client.record(&buf[..n]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncReadWrite for Captured {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        self.inner.set_keepalive(idle)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
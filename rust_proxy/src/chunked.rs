@@ -0,0 +1,165 @@
+// Incremental `Transfer-Encoding: chunked` framing parser.
+//
+// Used to meter relayed responses by decoded body size rather than raw
+// bytes, so the download cap means the same thing for chunked and
+// length-delimited bodies. Bytes are never modified: the decoder only
+// reports how much of each slice may be forwarded and how many of those
+// were body bytes.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Size { value: u64, digits: usize },
+    Extension { value: u64 },
+    SizeLf { value: u64 },
+    Data { remaining: u64 },
+    DataCr,
+    DataLf,
+    TrailerStart,
+    TrailerLine,
+    TrailerLf { last: bool },
+    Done,
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkedDecoder {
+    state: State,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self { state: State::Size { value: 0, digits: 0 } }
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The terminating chunk and trailers have been seen
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    // The framing was not valid chunked encoding
+    pub fn is_invalid(&self) -> bool {
+        self.state == State::Invalid
+    }
+
+    // Consume `data`, allowing at most `budget` body bytes. Returns how many
+    // bytes of `data` were consumed and how many of those were body bytes.
+    // Fewer than `data.len()` consumed means the body would exceed `budget`.
+    // Once done or invalid, everything is consumed and nothing is counted.
+    pub fn feed(&mut self, data: &[u8], budget: u64) -> (usize, u64) {
+        self.feed_until(data, budget, false)
+    }
+
+    // Like `feed`, but stops right after the terminating chunk and trailers,
+    // leaving the bytes of whatever message follows unconsumed
+    pub fn feed_message(&mut self, data: &[u8], budget: u64) -> (usize, u64) {
+        self.feed_until(data, budget, true)
+    }
+
+    // Like `feed_message`, without a budget, handing each run of body bytes
+    // to `body`. Returns how many bytes of `data` were consumed.
+    pub fn feed_message_body(&mut self, data: &[u8], mut body: impl FnMut(&[u8])) -> usize {
+        let mut consumed = 0;
+        loop {
+            // With no budget this stops at the first body byte, if any
+            let (framing, _) = self.feed_message(&data[consumed..], 0);
+            consumed += framing;
+            let State::Data { remaining } = self.state else {
+                return consumed;
+            };
+            if consumed == data.len() {
+                return consumed;
+            }
+            let run = remaining.min((data.len() - consumed) as u64);
+            let (n, _) = self.feed_message(&data[consumed..consumed + run as usize], run);
+            body(&data[consumed..consumed + n]);
+            consumed += n;
+        }
+    }
+
+    fn feed_until(&mut self, data: &[u8], budget: u64, stop_when_done: bool) -> (usize, u64) {
+        let mut consumed = 0;
+        let mut decoded = 0u64;
+
+        while consumed < data.len() {
+            let byte = data[consumed];
+            self.state = match self.state {
+                State::Data { remaining } => {
+                    let available = (data.len() - consumed) as u64;
+                    let take = remaining.min(available).min(budget - decoded);
+                    if take == 0 {
+                        // Body bytes are waiting but the budget is spent
+                        return (consumed, decoded);
+                    }
+                    consumed += take as usize;
+                    decoded += take;
+                    self.state = if remaining == take { State::DataCr } else { State::Data { remaining: remaining - take } };
+                    continue;
+                }
+                State::Done if stop_when_done => return (consumed, decoded),
+                State::Done | State::Invalid => return (data.len(), decoded),
+                State::Size { value, digits } => match (byte as char).to_digit(16) {
+                    Some(d) => match value.checked_mul(16).and_then(|v| v.checked_add(d as u64)) {
+                        Some(value) => State::Size { value, digits: digits + 1 },
+                        None => State::Invalid,
+                    },
+                    None if digits == 0 => State::Invalid,
+                    None => match byte {
+                        b';' | b' ' | b'\t' => State::Extension { value },
+                        b'\r' => State::SizeLf { value },
+                        b'\n' => Self::after_size(value),
+                        _ => State::Invalid,
+                    },
+                },
+                State::Extension { value } => match byte {
+                    b'\r' => State::SizeLf { value },
+                    b'\n' => Self::after_size(value),
+                    _ => State::Extension { value },
+                },
+                State::SizeLf { value } => match byte {
+                    b'\n' => Self::after_size(value),
+                    _ => State::Invalid,
+                },
+                State::DataCr => match byte {
+                    b'\r' => State::DataLf,
+                    b'\n' => State::Size { value: 0, digits: 0 },
+                    _ => State::Invalid,
+                },
+                State::DataLf => match byte {
+                    b'\n' => State::Size { value: 0, digits: 0 },
+                    _ => State::Invalid,
+                },
+                State::TrailerStart => match byte {
+                    b'\r' => State::TrailerLf { last: true },
+                    b'\n' => State::Done,
+                    _ => State::TrailerLine,
+                },
+                State::TrailerLine => match byte {
+                    b'\r' => State::TrailerLf { last: false },
+                    b'\n' => State::TrailerStart,
+                    _ => State::TrailerLine,
+                },
+                State::TrailerLf { last } => match byte {
+                    b'\n' if last => State::Done,
+                    b'\n' => State::TrailerStart,
+                    _ => State::Invalid,
+                },
+            };
+            consumed += 1;
+        }
+        (consumed, decoded)
+    }
+
+    fn after_size(value: u64) -> State {
+        if value == 0 {
+            State::TrailerStart
+        } else {
+            State::Data { remaining: value }
+        }
+    }
+}
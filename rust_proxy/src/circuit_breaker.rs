@@ -0,0 +1,89 @@
+// Per-upstream circuit breaker.
+//
+// After `threshold` consecutive connect failures to the same `host:port`
+// (within `window` of the first one), the breaker opens and connections to
+// that upstream are refused outright for `cooldown`. After the cooldown a
+// single trial attempt is let through while everyone else is still refused:
+// success closes the breaker, failure reopens it immediately. A trial that
+// never reports back (its request was shed or timed out before connecting)
+// is given up on after another cooldown, and the next caller becomes the
+// trial.
+
+use crate::bounded_map::BoundedMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const CIRCUIT_BREAKER_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    first_failure: Option<Instant>,
+    open_until: Option<Instant>,
+    /// When the half-open trial was let through, while it is in flight
+    trial_started: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    hosts: BoundedMap<Mutex<BreakerState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration, capacity: usize) -> Self {
+        Self { threshold: threshold.max(1), window, cooldown, hosts: BoundedMap::new(capacity) }
+    }
+
+    // Whether a connection attempt to `key` may proceed right now
+    pub fn allow(&self, key: &str) -> bool {
+        let Some(state) = self.hosts.get(key) else {
+            return true;
+        };
+        let mut state = state.lock().unwrap();
+        let now = Instant::now();
+        match (state.open_until, state.trial_started) {
+            (None, _) => true,
+            (Some(until), _) if now < until => false,
+            (Some(_), Some(started)) if now.duration_since(started) < self.cooldown => false,
+            (Some(_), _) => {
+                state.trial_started = Some(now);
+                true
+            }
+        }
+    }
+
+    pub fn record(&self, key: &str, connected: bool) {
+        if connected {
+            // Nothing to reset for hosts that have never failed
+            if let Some(state) = self.hosts.get(key) {
+                *state.lock().unwrap() = BreakerState::default();
+            }
+            return;
+        }
+
+        let state = self.hosts.get_or_insert_with(key, || Mutex::new(BreakerState::default()));
+        let mut state = state.lock().unwrap();
+        let now = Instant::now();
+
+        if state.open_until.is_some() {
+            // Trial attempt after the cooldown failed
+            state.open_until = Some(now + self.cooldown);
+            state.trial_started = None;
+            return;
+        }
+
+        match state.first_failure {
+            Some(first) if now.duration_since(first) <= self.window => state.consecutive_failures += 1,
+            _ => {
+                state.first_failure = Some(now);
+                state.consecutive_failures = 1;
+            }
+        }
+        if state.consecutive_failures >= self.threshold {
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+// Coalescing of identical in-flight GET requests (`--coalesce-gets`).
+//
+// When many clients ask for the same URL at once (a cache stampede), the
+// first request becomes the leader and fetches it; the rest wait for the
+// leader's response and are answered from a shared copy instead of each
+// opening their own upstream connection. Nothing is kept once the leader
+// finishes, so this is not a cache: a request arriving afterwards fetches
+// again.
+//
+// Only responses that fit in `MAX_SHARED_RESPONSE` and aren't marked
+// private are shared. Whenever sharing isn't possible (too large, upstream
+// failure, wait timeout) waiters fall back to fetching on their own.
+//
+// The buffered relay is shared with `--serve-stale-on-error` (see `stale`),
+// which needs the whole response in hand for the same reasons.
+
+use crate::buffer_pool::BUFFER_POOL;
+use crate::error::{map_write_error, ProxyErrorKind};
+use crate::headers::{RequestHead, ResponseHead};
+use crate::stale::{serve_stale, StaleSlot};
+use crate::{bounded_copy_with_counters, find_header_terminator, write_all_with_progress};
+use crate::{ByteCounters, CopyLimits, Direction, ProxyError, ProxyStats};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+pub const COALESCE_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MAX_SHARED_RESPONSE: usize = 1024 * 1024;
+
+type SharedResponse = Option<Arc<[u8]>>;
+
+#[derive(Debug)]
+pub struct Coalescer {
+    wait: Duration,
+    inflight: Mutex<HashMap<String, watch::Receiver<SharedResponse>>>,
+}
+
+pub enum Role {
+    Leader(Leader),
+    Follower(Follower),
+}
+
+// Fetches on behalf of everyone waiting on the same key. Dropping it without
+// publishing releases the waiters to fetch for themselves.
+pub struct Leader {
+    coalescer: Arc<Coalescer>,
+    key: String,
+    sender: watch::Sender<SharedResponse>,
+}
+
+pub struct Follower {
+    wait: Duration,
+    receiver: watch::Receiver<SharedResponse>,
+}
+
+impl Coalescer {
+    pub fn new(wait: Duration) -> Self {
+        Self { wait, inflight: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn join(self: &Arc<Self>, key: &str) -> Role {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(receiver) = inflight.get(key) {
+            return Role::Follower(Follower { wait: self.wait, receiver: receiver.clone() });
+        }
+        let (sender, receiver) = watch::channel(None);
+        inflight.insert(key.to_string(), receiver);
+        Role::Leader(Leader { coalescer: self.clone(), key: key.to_string(), sender })
+    }
+
+    // Requests currently being fetched by a leader
+    pub fn in_flight(&self) -> usize {
+        self.inflight.lock().unwrap().len()
+    }
+}
+
+impl Leader {
+    fn publish(&self, response: Arc<[u8]>) {
+        let _ = self.sender.send(Some(response));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.coalescer.inflight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Follower {
+    // The leader's response, or `None` if it couldn't be shared in time
+    pub async fn wait(mut self) -> Option<Arc<[u8]>> {
+        match timeout(self.wait, self.receiver.wait_for(|response| response.is_some())).await {
+            Ok(Ok(response)) => response.clone(),
+            _ => None,
+        }
+    }
+}
+
+// Plain GETs only: anything carrying credentials, cookies, a body or a
+// range, or asking to bypass caches, must reach the upstream itself
+pub fn is_coalescable(head: &RequestHead) -> bool {
+    const PERSONAL: &[&str] = &["Authorization", "Cookie", "Range", "Content-Length", "Transfer-Encoding", "Upgrade"];
+    head.method == "GET"
+        && PERSONAL.iter().all(|name| head.get(name).is_none())
+        && !head
+            .get_all("Cache-Control")
+            .chain(head.get_all("Pragma"))
+            .any(|v| v.contains("no-cache") || v.contains("no-store"))
+}
+
+// Requests are interchangeable if they target the same URL and accept the
+// same encodings (so a gzip response never reaches a client that can't use it)
+pub fn request_key(head: &RequestHead) -> String {
+    format!("{} {}", head.target, head.get("Accept-Encoding").unwrap_or(""))
+}
+
+fn is_shareable(head: &ResponseHead) -> bool {
+    let cache_control = head.get("Cache-Control").unwrap_or("");
+    head.get("Set-Cookie").is_none() && !cache_control.contains("private") && !cache_control.contains("no-store")
+}
+
+// Relay an upstream response to the client while keeping a copy, for the
+// leader's waiters and/or the `--serve-stale-on-error` store. The request
+// was sent with `Connection: close`, so upstream EOF marks the end of the
+// response. A failed fetch (read error or 5xx) is answered from the stale
+// store instead when it has a copy.
+#[allow(clippy::too_many_arguments)]
+pub async fn relay_buffered<S, D>(
+    conn_id: u64,
+    mut client: S,
+    mut upstream: D,
+    leader: Option<Leader>,
+    stale: Option<StaleSlot<'_>>,
+    stats: Arc<ProxyStats>,
+    counters: ByteCounters<'_>,
+    limits: CopyLimits,
+) -> Result<(), ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    // Past the download cap nothing more is sent, so there's no point
+    // buffering further
+    let buffer_limit = limits.max_download_size.min(MAX_SHARED_RESPONSE as u64);
+    let mut response = Vec::new();
+    let mut buffer = BUFFER_POOL.get();
+    let read: Result<bool, ProxyError> = loop {
+        match timeout(limits.idle_timeout, upstream.read(&mut buffer)).await {
+            Ok(Ok(0)) => break Ok(true),
+            Ok(Ok(n)) => {
+                response.extend_from_slice(&buffer[..n]);
+                if response.len() as u64 > buffer_limit {
+                    break Ok(false);
+                }
+            }
+            Ok(Err(e)) => break Err(e.into()),
+            Err(_) => break Err(ProxyErrorKind::IdleTimeout.into()),
+        }
+    };
+    drop(buffer);
+    // Nothing has reached the client yet, so a failure can still be covered
+    let complete = match read {
+        Ok(complete) => complete,
+        Err(e) if serve_stale(conn_id, &mut client, stale, &stats, counters).await? => {
+            debug!("[#{}] Upstream read failed: {}", conn_id, e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let head = find_header_terminator(&response).and_then(|end| ResponseHead::parse(&response[..end]));
+    stats.record_response_status(head.as_ref().map(|head| head.status));
+    if let Some(head) = head.as_ref().filter(|_| complete) {
+        if head.status >= 500 && serve_stale(conn_id, &mut client, stale, &stats, counters).await? {
+            return Ok(());
+        }
+        if is_shareable(head) {
+            if let Some(leader) = &leader {
+                debug!("[#{}] Sharing {} byte response with coalesced requests", conn_id, response.len());
+                leader.publish(response.as_slice().into());
+            }
+            if let Some(slot) = stale.filter(|_| head.status == 200) {
+                slot.record(&response);
+            }
+        }
+    }
+    // Waiters fall back to their own fetch from here if nothing was published
+    drop(leader);
+
+    // The download cap may fall inside what was buffered
+    let allowed = response.len().min(usize::try_from(limits.max_download_size).unwrap_or(usize::MAX));
+    write_all_with_progress(&mut client, &response[..allowed], limits.write_timeout).await.map_err(|e| map_write_error(&e))?;
+    stats.record_bytes(Direction::ServerToClient, allowed as u64);
+    counters.add(allowed as u64);
+    if allowed < response.len() {
+        warn!("[#{}] Download size limit exceeded: more than {} bytes", conn_id, limits.max_download_size);
+        return Err(ProxyErrorKind::SizeLimitExceeded.into());
+    }
+
+    if !complete {
+        let label = format!("[#{}] server->client", conn_id);
+        let remaining = CopyLimits { max_download_size: limits.max_download_size.saturating_sub(response.len() as u64), ..limits };
+        bounded_copy_with_counters(&mut upstream, &mut client, remaining, &label, Direction::ServerToClient, stats, counters).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+// Error responses for requests whose target could not be reached, whether
+// a CONNECT tunnel or a forwarded plain-HTTP request.
+//
+// A failed connect used to get a bare `502 Bad Gateway` whatever went wrong,
+// leaving clients unable to tell a mistyped hostname from a firewall. The
+// status now says what kind of failure it was (502 when the target could
+// not be reached, 504 when it did not answer in time, 403 when policy
+// refused it) and a one-line text body names the cause.
+//
+// Causes come from the error kind where the OS or dialer sets one, and
+// otherwise from the message, as `SslErrorKind` does for TLS failures.
+
+use crate::ssl_errors::SslErrorKind;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectFailure {
+    /// Refused by the proxy's own policy, for the given reason
+    Blocked(&'static str),
+    /// The target's name did not resolve
+    Unresolved,
+    Refused,
+    TimedOut,
+    Tls(SslErrorKind),
+    /// Anything the proxy has no specific cause for
+    Failed,
+}
+
+impl ConnectFailure {
+    // The cause of a failed connect to the target
+    pub fn classify(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => return Self::Refused,
+            io::ErrorKind::TimedOut => return Self::TimedOut,
+            io::ErrorKind::NotFound => return Self::Unresolved,
+            _ => {}
+        }
+        let message = error.to_string().to_lowercase();
+        if message.contains("lookup address") || message.contains("did not resolve") {
+            return Self::Unresolved;
+        }
+        match SslErrorKind::classify(&message) {
+            SslErrorKind::NotSslRelated => Self::Failed,
+            kind => Self::Tls(kind),
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        match self {
+            Self::Blocked(_) => "403 Forbidden",
+            Self::TimedOut => "504 Gateway Timeout",
+            _ => "502 Bad Gateway",
+        }
+    }
+
+    // The complete response, closing the connection
+    pub fn response(&self) -> Vec<u8> {
+        let body = format!("{}\n", self);
+        format!(
+            "HTTP/1.1 {}\r\nConnection: close\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            self.status(),
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+}
+
+impl fmt::Display for ConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blocked(reason) => write!(f, "Blocked by proxy policy: {}", reason),
+            Self::Unresolved => f.write_str("Target host name could not be resolved"),
+            Self::Refused => f.write_str("Target refused the connection"),
+            Self::TimedOut => f.write_str("Timed out connecting to the target"),
+            Self::Tls(kind) => write!(f, "TLS error: {}", kind.cause().unwrap_or("unknown")),
+            Self::Failed => f.write_str("Could not connect to the target"),
+        }
+    }
+}
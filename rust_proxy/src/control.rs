@@ -0,0 +1,100 @@
+// Runtime control socket (`--control-socket`).
+//
+// A Unix socket accepting one text command per line, each answered with a
+// single line: `ok ...`, `error ...` or, for `stats`, the same JSON document
+// /stats.json serves.
+//
+//     set-log-level <off|error|warn|info|debug|trace>
+//     stats
+//     reset-stats
+//     maintenance [on|off]
+//
+// The log level is changed through `log::set_max_level`. When the control
+// socket is enabled, main installs the logger without a level filter so the
+// max level alone decides which records are emitted (module filters from
+// RUST_LOG still apply on top).
+//
+// `maintenance on` makes every listener answer 503 until `maintenance off`;
+// without an argument the current state is reported.
+
+use crate::admin::stats_document;
+use crate::ProxyStats;
+use log::{debug, info, LevelFilter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+
+// Longest command line read, newline included; no command comes close
+pub const MAX_COMMAND_LENGTH: u64 = 1024;
+
+fn maintenance_state(on: bool) -> String {
+    format!("ok maintenance {}", if on { "on" } else { "off" })
+}
+
+pub fn execute(command: &str, stats: &ProxyStats, maintenance: &AtomicBool) -> String {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("set-log-level"), Some(level), None) => match level.parse::<LevelFilter>() {
+            Ok(level) => {
+                log::set_max_level(level);
+                info!("Log level changed to {} via control socket", level);
+                format!("ok log level {}", level.as_str().to_ascii_lowercase())
+            }
+            Err(_) => format!("error unknown log level {}", level),
+        },
+        (Some("stats"), None, None) => stats_document(stats).to_string(),
+        (Some("reset-stats"), None, None) => {
+            stats.reset();
+            info!("Statistics reset via control socket");
+            "ok stats reset".to_string()
+        }
+        (Some("maintenance"), None, None) => maintenance_state(maintenance.load(Ordering::Relaxed)),
+        (Some("maintenance"), Some(state), None) => {
+            let on = match state {
+                "on" => true,
+                "off" => false,
+                _ => return format!("error expected maintenance on|off, got {}", state),
+            };
+            maintenance.store(on, Ordering::Relaxed);
+            info!("Maintenance mode turned {} via control socket", state);
+            maintenance_state(on)
+        }
+        (None, _, _) => "error empty command".to_string(),
+        _ => format!("error unknown command {}", command.trim()),
+    }
+}
+
+// Serve control clients on a Unix socket (see `bind_unix_listener`)
+pub async fn serve_control_socket(listener: UnixListener, stats: Arc<ProxyStats>, maintenance: Arc<AtomicBool>) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let stats = stats.clone();
+        let maintenance = maintenance.clone();
+        debug!("Control client connected");
+
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match (&mut reader).take(MAX_COMMAND_LENGTH + 1).read_line(&mut line).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                // Without a newline in reach the rest of the line could be
+                // any length, so the client is answered and dropped
+                if line.len() as u64 > MAX_COMMAND_LENGTH && !line.ends_with('\n') {
+                    let _ = writer.write_all(b"error command too long\n").await;
+                    break;
+                }
+                let reply = execute(line.trim_end_matches(['\r', '\n']), &stats, &maintenance);
+                if writer.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            debug!("Control client disconnected");
+        });
+    }
+}
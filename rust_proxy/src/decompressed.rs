@@ -0,0 +1,297 @@
+// Decompressed-size accounting for compressed responses
+// (`--account-decompressed`), for comparing what clients consume with what
+// crosses the wire.
+//
+// The upstream stream of a plain-HTTP exchange is wrapped so that every byte
+// read from it is also fed to a watcher. The watcher finds the response head,
+// skipping interim `1xx` responses, and when the final response carries
+// `Content-Encoding: gzip` or `deflate` it follows the body framing
+// (Content-Length, chunked or until close) and inflates a copy of the body
+// into a sink that only counts. The forwarded bytes are never touched.
+//
+// Inflating costs CPU, so the mode is opt-in, and each body stops being
+// inflated once it reaches the size limit (which also defuses decompression
+// bombs); the counts then cover what was inflated up to that point. A body
+// that fails to inflate is counted up to the error.
+
+use crate::chunked::ChunkedDecoder;
+use crate::dialer::{AsyncReadWrite, BoxedStream};
+use crate::find_header_terminator;
+use crate::headers::ResponseHead;
+use crate::keep_alive::{response_body_length, BodyLength};
+use crate::ProxyStats;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use log::debug;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub const DEFAULT_DECOMPRESS_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+// Response heads larger than this are not inspected
+const MAX_WATCHED_HEAD: usize = 64 * 1024;
+
+// Compressed bodies seen on one client connection
+#[derive(Debug, Default)]
+pub struct BodySizes {
+    pub compressed: AtomicU64,
+    pub decompressed: AtomicU64,
+}
+
+impl BodySizes {
+    fn add(&self, compressed: u64, decompressed: u64) {
+        self.compressed.fetch_add(compressed, Ordering::Relaxed);
+        self.decompressed.fetch_add(decompressed, Ordering::Relaxed);
+    }
+}
+
+// `stream` with the response to a `method` request accounted into `sizes`
+// and the global statistics
+pub fn wrap(
+    conn_id: u64,
+    method: &str,
+    stream: BoxedStream,
+    sizes: Arc<BodySizes>,
+    stats: Arc<ProxyStats>,
+    max_bytes: u64,
+) -> BoxedStream {
+    let watcher = Watcher { conn_id, method: method.to_string(), phase: Phase::Head(Vec::new()), sizes, stats, max_bytes };
+    Box::new(Accounted { inner: stream, watcher })
+}
+
+// Counts what the decoder produces, refusing output past the limit
+struct CountingSink {
+    written: u64,
+    limit: u64,
+}
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() as u64 > self.limit {
+            self.written = self.limit;
+            return Err(io::Error::other("decompressed size limit reached"));
+        }
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Inflater {
+    Gzip(GzDecoder<CountingSink>),
+    Deflate(ZlibDecoder<CountingSink>),
+}
+
+impl Inflater {
+    // The decoder for a `Content-Encoding`, if it is one we can inflate
+    fn for_encoding(encoding: &str, limit: u64) -> Option<Self> {
+        let sink = CountingSink { written: 0, limit };
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip(GzDecoder::new(sink))),
+            "deflate" => Some(Self::Deflate(ZlibDecoder::new(sink))),
+            _ => None,
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Gzip(decoder) => decoder.write_all(data),
+            Self::Deflate(decoder) => decoder.write_all(data),
+        }
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(decoder) => decoder.try_finish(),
+            Self::Deflate(decoder) => decoder.try_finish(),
+        }
+    }
+
+    fn decompressed(&self) -> u64 {
+        match self {
+            Self::Gzip(decoder) => decoder.get_ref().written,
+            Self::Deflate(decoder) => decoder.get_ref().written,
+        }
+    }
+}
+
+enum Framing {
+    Fixed(u64),
+    Chunked(ChunkedDecoder),
+    UntilClose,
+}
+
+struct Body {
+    framing: Framing,
+    inflater: Inflater,
+    compressed: u64,
+    // Stopped inflating (limit or corrupt data); still following the framing
+    stopped: bool,
+}
+
+enum Phase {
+    Head(Vec<u8>),
+    Body(Box<Body>),
+    Done,
+}
+
+struct Watcher {
+    conn_id: u64,
+    method: String,
+    phase: Phase,
+    sizes: Arc<BodySizes>,
+    stats: Arc<ProxyStats>,
+    max_bytes: u64,
+}
+
+impl Watcher {
+    fn observe(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            match &mut self.phase {
+                Phase::Head(head) => {
+                    let before = head.len();
+                    head.extend_from_slice(data);
+                    let Some(end) = find_header_terminator(head) else {
+                        if head.len() > MAX_WATCHED_HEAD {
+                            self.phase = Phase::Done;
+                        }
+                        return;
+                    };
+                    data = &data[end - before..];
+                    self.phase = match ResponseHead::parse(&head[..end]) {
+                        Some(response) if response.is_interim() => Phase::Head(Vec::new()),
+                        Some(response) => self.body_phase(&response),
+                        None => Phase::Done,
+                    };
+                }
+                Phase::Body(body) => {
+                    let used = body.feed(data);
+                    data = &data[used..];
+                    if matches!(body.framing, Framing::Fixed(0)) || matches!(&body.framing, Framing::Chunked(c) if c.is_done() || c.is_invalid()) {
+                        self.finish();
+                    }
+                }
+                Phase::Done => return,
+            }
+        }
+    }
+
+    fn body_phase(&self, response: &ResponseHead) -> Phase {
+        let Some(inflater) = response.get("Content-Encoding").and_then(|encoding| Inflater::for_encoding(encoding, self.max_bytes)) else {
+            return Phase::Done;
+        };
+        let framing = match response_body_length(&self.method, response) {
+            BodyLength::Empty | BodyLength::Fixed(0) => return Phase::Done,
+            BodyLength::Fixed(length) => Framing::Fixed(length),
+            BodyLength::Chunked => Framing::Chunked(ChunkedDecoder::new()),
+            BodyLength::UntilClose => Framing::UntilClose,
+        };
+        Phase::Body(Box::new(Body { framing, inflater, compressed: 0, stopped: false }))
+    }
+
+    // The body is complete (or the stream ended): record its sizes
+    fn finish(&mut self) {
+        let Phase::Body(mut body) = std::mem::replace(&mut self.phase, Phase::Done) else {
+            return;
+        };
+        if !body.stopped {
+            if let Err(e) = body.inflater.try_finish() {
+                debug!("[#{}] Stopped inflating response body: {}", self.conn_id, e);
+            }
+        }
+        let decompressed = body.inflater.decompressed();
+        debug!("[#{}] Compressed response body: {} bytes, {} decompressed", self.conn_id, body.compressed, decompressed);
+        self.sizes.add(body.compressed, decompressed);
+        self.stats.compressed_body_bytes.fetch_add(body.compressed, Ordering::Relaxed);
+        self.stats.decompressed_body_bytes.fetch_add(decompressed, Ordering::Relaxed);
+    }
+}
+
+impl Body {
+    // Feed what `data` holds of this body, returning how much that was
+    fn feed(&mut self, data: &[u8]) -> usize {
+        let Body { framing, inflater, compressed, stopped } = self;
+        let mut inflate = |run: &[u8]| {
+            *compressed += run.len() as u64;
+            if !*stopped && inflater.write_all(run).is_err() {
+                *stopped = true;
+            }
+        };
+        match framing {
+            Framing::Fixed(remaining) => {
+                let n = (*remaining).min(data.len() as u64) as usize;
+                *remaining -= n as u64;
+                inflate(&data[..n]);
+                n
+            }
+            Framing::Chunked(decoder) => decoder.feed_message_body(data, inflate),
+            Framing::UntilClose => {
+                inflate(data);
+                data.len()
+            }
+        }
+    }
+}
+
+struct Accounted {
+    inner: BoxedStream,
+    watcher: Watcher,
+}
+
+impl AsyncRead for Accounted {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[before..];
+            if read.is_empty() && buf.remaining() > 0 {
+                // End of stream ends a body delimited by close
+                self.watcher.finish();
+            } else {
+                let read = read.to_vec();
+                self.watcher.observe(&read);
+            }
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Accounted {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncReadWrite for Accounted {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        self.inner.set_keepalive(idle)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
@@ -0,0 +1,59 @@
+// Per-destination concurrency cap (`--max-per-destination`).
+//
+// Each `host:port` gets a semaphore with `limit` permits. A tunnel or
+// request takes one before connecting and holds it until it finishes; with
+// none free it waits up to `wait` for one and is otherwise shed, so one busy
+// destination sees backpressure rather than an unbounded pile of sockets.
+//
+// A semaphore with every permit free is indistinguishable from a new one, so
+// `purge_idle` drops those. The map is also capped like the other
+// per-destination state, which in the worst case forgets a destination that
+// is still in use and lets it briefly go over its limit.
+
+use crate::bounded_map::BoundedMap;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEST_LIMIT_WAIT: Duration = Duration::from_millis(500);
+pub const DEST_LIMIT_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct DestinationLimiter {
+    limit: usize,
+    wait: Duration,
+    destinations: BoundedMap<Semaphore>,
+}
+
+impl DestinationLimiter {
+    pub fn new(limit: usize, wait: Duration, capacity: usize) -> Self {
+        Self { limit: limit.max(1), wait, destinations: BoundedMap::new(capacity) }
+    }
+
+    // A slot for a connection to `host_port`, held until the permit drops;
+    // `None` means none came free within the wait
+    pub async fn acquire(&self, host_port: &str) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.destinations.get_or_insert_with(host_port, || Semaphore::new(self.limit));
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        match tokio::time::timeout(self.wait, semaphore.acquire_owned()).await {
+            Ok(Ok(permit)) => Some(permit),
+            _ => None,
+        }
+    }
+
+    // Drop destinations with no connections in flight, returning how many remain
+    pub fn purge_idle(&self) -> usize {
+        self.destinations.retain(|_, semaphore| semaphore.available_permits() < self.limit);
+        self.destinations.len()
+    }
+
+    pub fn tracked_destinations(&self) -> usize {
+        self.destinations.len()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
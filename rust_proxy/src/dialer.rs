@@ -0,0 +1,242 @@
+// Upstream connection abstraction.
+//
+// `handle_client` obtains upstream streams through an `UpstreamDialer`
+// rather than calling `TcpStream::connect` directly, so custom transports
+// (or in-memory streams in tests) can be plugged in without forking.
+
+use async_trait::async_trait;
+use std::future::Future;
+use std::io;
+use socket2::{SockRef, TcpKeepalive};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+// Head start each connection attempt gets before the next address is tried
+// alongside it (RFC 8305 suggests 250ms)
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+// A bidirectional byte stream the proxy can tunnel through. The socket
+// tuning hooks default to no-ops for transports where they don't apply.
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Enable TCP keepalive, probing after `idle` without traffic
+    fn set_keepalive(&self, _idle: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "stream has no peer address"))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "stream has no local address"))
+    }
+
+    // The destination a client connection was addressed to before a NAT
+    // redirect sent it to us (`--transparent`); `None` when not redirected
+    fn original_dst(&self) -> io::Result<Option<SocketAddr>> {
+        Ok(None)
+    }
+}
+
+impl AsyncReadWrite for TcpStream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        SockRef::from(self).set_tcp_keepalive(&keepalive_params(idle))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn original_dst(&self) -> io::Result<Option<SocketAddr>> {
+        crate::transparent::original_dst(self)
+    }
+}
+
+// Probe after `idle` and then every `idle` between unanswered probes; the
+// probe interval isn't settable everywhere, so other platforms keep theirs
+fn keepalive_params(idle: Duration) -> TcpKeepalive {
+    let params = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "windows"))]
+    let params = params.with_interval(idle);
+    params
+}
+
+impl AsyncReadWrite for DuplexStream {}
+
+#[cfg(unix)]
+impl AsyncReadWrite for tokio::net::UnixStream {}
+
+pub type BoxedStream = Box<dyn AsyncReadWrite>;
+
+#[async_trait]
+pub trait UpstreamDialer: Send + Sync + std::fmt::Debug {
+    async fn dial(&self, host: &str, port: u16) -> io::Result<BoxedStream>;
+}
+
+// Order addresses for happy eyeballs: alternate between families, starting
+// with the family of the resolver's first answer
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let prefer_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// Connect to the first of `addrs` that answers, happy-eyeballs style
+// (RFC 8305): attempts start one at a time in interleaved family order, the
+// next one as soon as the previous fails or after `delay` if it is still
+// pending, and the first to succeed wins while the rest are cancelled. An
+// unreachable address family, e.g. broken IPv6, then costs `delay` rather
+// than a whole connect timeout.
+pub async fn connect_happy_eyeballs<F, Fut, T>(addrs: Vec<SocketAddr>, delay: Duration, connect: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut pending = interleave_families(addrs).into_iter();
+    // Dropping the set aborts whatever attempts are still running
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.spawn(connect(addr)),
+                None => break,
+            };
+        }
+        tokio::select! {
+            Some(attempt) = attempts.join_next() => {
+                match attempt {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(e)) => last_error = Some(e),
+                    Err(e) => last_error = Some(io::Error::other(e)),
+                }
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(connect(addr));
+                }
+            }
+            _ = tokio::time::sleep(delay), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(connect(addr));
+                }
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")))
+}
+
+// Default dialer: plain TCP using the system resolver, racing the resolved
+// addresses with happy eyeballs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpDialer;
+
+#[async_trait]
+impl UpstreamDialer for TcpDialer {
+    async fn dial(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        let addrs = tokio::net::lookup_host((host, port)).await?.collect();
+        let stream = connect_happy_eyeballs(addrs, HAPPY_EYEBALLS_DELAY, TcpStream::connect).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+// Plain TCP from fixed local source addresses (`--bind-outbound`), for
+// multi-homed hosts where the upstream route or policy depends on the source
+// IP. Each resolved target is dialed from the source of its own address
+// family; targets of a family without a configured source are skipped
+// rather than dialed from an arbitrary address.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BoundDialer {
+    pub v4: Option<IpAddr>,
+    pub v6: Option<IpAddr>,
+}
+
+impl BoundDialer {
+    // Sort source addresses by family (the last one given for a family wins)
+    pub fn new(sources: &[IpAddr]) -> Self {
+        let mut dialer = Self::default();
+        for &source in sources {
+            match source {
+                IpAddr::V4(_) => dialer.v4 = Some(source),
+                IpAddr::V6(_) => dialer.v6 = Some(source),
+            }
+        }
+        dialer
+    }
+
+    fn source_for(&self, target: SocketAddr) -> Option<IpAddr> {
+        if target.is_ipv4() { self.v4 } else { self.v6 }
+    }
+
+    async fn connect_from(source: IpAddr, target: SocketAddr) -> io::Result<TcpStream> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::for_address(target), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::new(source, 0).into())?;
+        tokio::net::TcpSocket::from_std_stream(socket.into()).connect(target).await
+    }
+}
+
+#[async_trait]
+impl UpstreamDialer for BoundDialer {
+    async fn dial(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        let targets: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await?
+            .filter(|&target| self.source_for(target).is_some())
+            .collect();
+        if targets.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no outbound source address for the address family of {}", host),
+            ));
+        }
+        let dialer = *self;
+        let connect = move |target| async move {
+            match dialer.source_for(target) {
+                Some(source) => Self::connect_from(source, target).await,
+                None => Err(io::ErrorKind::AddrNotAvailable.into()),
+            }
+        };
+        let stream = connect_happy_eyeballs(targets, HAPPY_EYEBALLS_DELAY, connect).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+// Connect to a local Unix domain socket (`CONNECT unix:/path` targets)
+#[cfg(unix)]
+pub async fn connect_unix(path: &str) -> io::Result<BoxedStream> {
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+pub async fn connect_unix(_path: &str) -> io::Result<BoxedStream> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform"))
+}
@@ -0,0 +1,133 @@
+// Typed failure reasons for the proxy's own errors.
+//
+// `ProxyError` stays a boxed error so I/O and library errors still flow
+// through `?`, but failures the proxy decides on itself are boxed
+// `ProxyErrorKind`s that callers can recover with `ProxyErrorKind::of`.
+//
+// `CloseReason` is the other side of the same coin: why a relay stopped,
+// including the normal case, so tunnels can report it in logs and in the
+// `closed` connection event rather than as an error.
+
+use crate::ProxyError;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyErrorKind {
+    /// A write made no progress within the write timeout
+    WriteTimeout,
+    /// The peer went away mid-write
+    WriteFailed,
+    /// Reading from the peer failed
+    ReadFailed,
+    /// No data was received within the idle timeout
+    IdleTimeout,
+    /// Data arrived slower than the minimum throughput
+    SlowTransfer,
+    /// The transfer exceeded the per-connection size limit
+    SizeLimitExceeded,
+    /// The upstream refused or failed the connection
+    ConnectFailed,
+    /// The upstream did not accept the connection in time
+    ConnectTimeout,
+    /// The request could not be parsed or was missing required parts
+    MalformedRequest,
+    /// The request was refused by policy
+    Blocked,
+}
+
+impl ProxyErrorKind {
+    // The kind behind a `ProxyError`, if the proxy raised it itself
+    pub fn of(error: &ProxyError) -> Option<Self> {
+        error.downcast_ref::<Self>().copied()
+    }
+}
+
+impl fmt::Display for ProxyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::WriteTimeout => "Write timeout",
+            Self::WriteFailed => "Write error",
+            Self::ReadFailed => "Read error",
+            Self::IdleTimeout => "Idle timeout",
+            Self::SlowTransfer => "Transfer below minimum throughput",
+            Self::SizeLimitExceeded => "Download size limit exceeded",
+            Self::ConnectFailed => "Connect failed",
+            Self::ConnectTimeout => "Connect timeout",
+            Self::MalformedRequest => "Malformed request",
+            Self::Blocked => "Blocked",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for ProxyErrorKind {}
+
+// The kind for a failed `write_all_with_progress`: it reports a stalled
+// write as `TimedOut`, and anything else means the peer went away
+pub fn map_write_error(error: &std::io::Error) -> ProxyErrorKind {
+    if error.kind() == std::io::ErrorKind::TimedOut {
+        ProxyErrorKind::WriteTimeout
+    } else {
+        ProxyErrorKind::WriteFailed
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Both sides finished normally
+    Eof,
+    IdleTimeout,
+    WriteTimeout,
+    WriteError,
+    ReadError,
+    SizeLimit,
+    SlowTransfer,
+    /// A tunnel was cut at `--max-tunnel-duration`
+    MaxDuration,
+    /// An HTTP exchange ran past `--request-timeout`
+    RequestTimeout,
+    /// Anything the proxy has no specific reason for
+    Error,
+}
+
+impl CloseReason {
+    // Why a relay that finished with `result` stopped
+    pub fn of<T>(result: &Result<T, ProxyError>) -> Self {
+        let Err(error) = result else {
+            return Self::Eof;
+        };
+        match ProxyErrorKind::of(error) {
+            Some(ProxyErrorKind::IdleTimeout) => Self::IdleTimeout,
+            Some(ProxyErrorKind::WriteTimeout) => Self::WriteTimeout,
+            Some(ProxyErrorKind::WriteFailed) => Self::WriteError,
+            Some(ProxyErrorKind::ReadFailed) => Self::ReadError,
+            Some(ProxyErrorKind::SizeLimitExceeded) => Self::SizeLimit,
+            Some(ProxyErrorKind::SlowTransfer) => Self::SlowTransfer,
+            _ => Self::Error,
+        }
+    }
+
+    pub fn is_normal(&self) -> bool {
+        *self == Self::Eof
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::Eof => "end of stream",
+            Self::IdleTimeout => "idle timeout",
+            Self::WriteTimeout => "write timeout",
+            Self::WriteError => "write error",
+            Self::ReadError => "read error",
+            Self::SizeLimit => "size limit exceeded",
+            Self::SlowTransfer => "below minimum throughput",
+            Self::MaxDuration => "maximum duration reached",
+            Self::RequestTimeout => "request timeout",
+            Self::Error => "error",
+        };
+        f.write_str(message)
+    }
+}
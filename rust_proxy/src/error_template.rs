@@ -0,0 +1,137 @@
+// Branded error pages (`--error-template`).
+//
+// One template file restyles every error the proxy answers with itself
+// (403, 407, 502, 504). The response keeps its status line and headers,
+// such as `Proxy-Authenticate` on a 407; only the body is replaced by the
+// template with these placeholders filled in:
+//
+//   {status}      the status code, e.g. 502
+//   {reason}      why the request failed, or the status text when the proxy
+//                 has nothing more specific
+//   {host}        the requested target, when known
+//   {request_id}  the connection number shown as `[#N]` in the logs
+//
+// A placeholder with no value for this response (`{host}` before the target
+// is known) and anything else in braces are left as written. Templates
+// ending in `.html` or `.htm` are served as HTML, with substituted values
+// escaped; anything else as plain text.
+
+use std::io;
+use std::path::Path;
+
+// Templates are held in memory and sent whole, so keep them page-sized
+pub const MAX_TEMPLATE_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ErrorTemplate {
+    template: String,
+    html: bool,
+}
+
+// Values for one rendering; `None` leaves the placeholder as written
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorDetails<'a> {
+    pub reason: Option<&'a str>,
+    pub host: Option<&'a str>,
+    pub request_id: Option<u64>,
+}
+
+impl ErrorTemplate {
+    pub fn new(template: String, html: bool) -> Self {
+        Self { template, html }
+    }
+
+    // Read and check the template at startup, so a bad file stops the proxy
+    // rather than the first error page
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message));
+        let size = std::fs::metadata(path)?.len();
+        if size > MAX_TEMPLATE_SIZE {
+            return Err(invalid(format!("template is {} bytes, more than the {} allowed", size, MAX_TEMPLATE_SIZE)));
+        }
+        let template = String::from_utf8(std::fs::read(path)?).map_err(|_| invalid("template is not valid UTF-8".to_string()))?;
+        if template.trim().is_empty() {
+            return Err(invalid("template is empty".to_string()));
+        }
+        let html = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("html") || extension.eq_ignore_ascii_case("htm"));
+        Ok(Self::new(template, html))
+    }
+
+    // The template with every placeholder that has a value filled in
+    pub fn render(&self, status: u16, details: ErrorDetails<'_>) -> String {
+        let mut rendered = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(open) = rest.find('{') {
+            rendered.push_str(&rest[..open]);
+            rest = &rest[open..];
+            let Some(close) = rest.find('}') else {
+                break;
+            };
+            // `{` opening something that isn't a placeholder, e.g. CSS
+            if rest[1..close].contains('{') {
+                rendered.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+            let value = match &rest[1..close] {
+                "status" => Some(status.to_string()),
+                "reason" => details.reason.map(str::to_string),
+                "host" => details.host.map(str::to_string),
+                "request_id" => details.request_id.map(|id| id.to_string()),
+                _ => None,
+            };
+            match value {
+                Some(value) if self.html => rendered.push_str(&escape_html(&value)),
+                Some(value) => rendered.push_str(&value),
+                None => rendered.push_str(&rest[..=close]),
+            }
+            rest = &rest[close + 1..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    // `response` (a complete bodiless or plain-text error response) with its
+    // body replaced by the rendered template. Without a reason of its own,
+    // `{reason}` is the response's status text.
+    pub fn apply(&self, response: &[u8], details: ErrorDetails<'_>) -> Vec<u8> {
+        let response = String::from_utf8_lossy(response);
+        let head = response.split("\r\n\r\n").next().unwrap_or_default();
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ').skip(1);
+        let status = parts.next().and_then(|code| code.parse().ok()).unwrap_or(500);
+        let status_text = parts.next().unwrap_or_default();
+        let details = ErrorDetails { reason: details.reason.or(Some(status_text)), ..details };
+        let body = self.render(status, details);
+
+        let mut page = format!("{}\r\n", status_line);
+        for line in lines {
+            let name = line.split(':').next().unwrap_or_default();
+            if !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("content-type") {
+                page.push_str(line);
+                page.push_str("\r\n");
+            }
+        }
+        let content_type = if self.html { "text/html; charset=utf-8" } else { "text/plain; charset=utf-8" };
+        page.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}", content_type, body.len(), body));
+        page.into_bytes()
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,182 @@
+// Live connection events, published as newline-delimited JSON.
+//
+// `handle_client` publishes into a bounded broadcast channel. Subscribers
+// that fall behind lose events (the channel reports them as lagged) instead
+// of slowing down the proxy.
+
+use crate::decompressed::BodySizes;
+use crate::error::CloseReason;
+use log::{debug, warn};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProxyEvent {
+    Opened {
+        client: String,
+    },
+    Established {
+        client: String,
+        method: String,
+        target: String,
+    },
+    Closed {
+        client: String,
+        target: Option<String>,
+        bytes: u64,
+        duration_ms: u64,
+        /// Why the relay ended, for connections that got as far as one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<CloseReason>,
+        /// Gzip/deflate response body bytes and what they inflated to, under
+        /// `--account-decompressed`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compressed_bytes: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        decompressed_bytes: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<str>>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: &ProxyEvent) {
+        // Skip serialization entirely when nobody is listening
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        match serde_json::to_string(event) {
+            Ok(line) => {
+                let _ = self.sender.send(line.into());
+            }
+            Err(e) => warn!("Failed to serialize event: {}", e),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<str>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(EVENT_CHANNEL_CAPACITY)
+    }
+}
+
+// Per-connection event state. Publishes `opened` on creation and `closed`
+// when dropped, so every exit path out of the handler is reported.
+pub struct ConnectionEvents {
+    bus: Option<EventBus>,
+    client: String,
+    target: Option<String>,
+    reason: Option<CloseReason>,
+    /// Shared so the size histogram can read it after the handler is done
+    pub bytes: Arc<AtomicU64>,
+    /// Compressed response bodies, filled in by `--account-decompressed`
+    pub body_sizes: Arc<BodySizes>,
+    started: Instant,
+}
+
+impl ConnectionEvents {
+    pub fn new(bus: Option<EventBus>, client: String) -> Self {
+        if let Some(bus) = &bus {
+            bus.publish(&ProxyEvent::Opened { client: client.clone() });
+        }
+        Self {
+            bus,
+            client,
+            target: None,
+            reason: None,
+            bytes: Arc::new(AtomicU64::new(0)),
+            body_sizes: Arc::new(BodySizes::default()),
+            started: Instant::now(),
+        }
+    }
+
+    // Report later events as coming from `client`, e.g. the address a
+    // trusted proxy header named instead of the peer
+    pub fn set_client(&mut self, client: String) {
+        self.client = client;
+    }
+
+    pub fn established(&mut self, method: &str, target: String) {
+        if let Some(bus) = &self.bus {
+            bus.publish(&ProxyEvent::Established {
+                client: self.client.clone(),
+                method: method.to_string(),
+                target: target.clone(),
+            });
+        }
+        self.target = Some(target);
+    }
+
+    // Record why the relay ended, reported with `closed`. On a persistent
+    // connection the last exchange's reason wins.
+    pub fn relay_closed(&mut self, reason: CloseReason) {
+        self.reason = Some(reason);
+    }
+}
+
+impl Drop for ConnectionEvents {
+    fn drop(&mut self) {
+        if let Some(bus) = &self.bus {
+            let compressed = self.body_sizes.compressed.load(Ordering::Relaxed);
+            let decompressed = self.body_sizes.decompressed.load(Ordering::Relaxed);
+            let has_bodies = compressed > 0;
+            bus.publish(&ProxyEvent::Closed {
+                client: self.client.clone(),
+                target: self.target.take(),
+                bytes: self.bytes.load(Ordering::Relaxed),
+                duration_ms: self.started.elapsed().as_millis() as u64,
+                reason: self.reason.take(),
+                compressed_bytes: has_bodies.then_some(compressed),
+                decompressed_bytes: has_bodies.then_some(decompressed),
+            });
+        }
+    }
+}
+
+// Accept subscribers on a Unix socket (see `bind_unix_listener`) and stream
+// events to each of them
+#[cfg(unix)]
+pub async fn serve_event_socket(listener: tokio::net::UnixListener, bus: EventBus) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut receiver = bus.subscribe();
+        debug!("Event subscriber connected");
+
+        tokio::spawn(async move {
+            loop {
+                let line = match receiver.recv().await {
+                    Ok(line) => line,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("Event subscriber lagged, dropped {} events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if stream.write_all(line.as_bytes()).await.is_err() || stream.write_all(b"\n").await.is_err() {
+                    break;
+                }
+            }
+            debug!("Event subscriber disconnected");
+        });
+    }
+}
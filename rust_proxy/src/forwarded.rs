@@ -0,0 +1,199 @@
+// RFC 7239 `Forwarded` header generation and parsing, plus the legacy
+// `X-Forwarded-For` form used to recover the original client behind
+// trusted proxies.
+//
+// `--trust-proxy-headers` is the blunt alternative to `--trusted-proxy`: it
+// takes one `X-Forwarded-For` entry from every client, whoever the peer is.
+// The header is whatever the client sent, so it is only safe when every
+// connection comes through a proxy that sets or overwrites it; otherwise a
+// client can claim any address, dodging per-IP limits and forging logs.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::headers::RequestHead;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    pub for_node: Option<String>,
+    pub by: Option<String>,
+    pub proto: Option<String>,
+    pub host: Option<String>,
+}
+
+// Format an address as a Forwarded node; IPv6 must be bracketed and quoted
+pub fn format_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+    }
+}
+
+// Build a single `for=...;proto=...;by=...` element
+pub fn forwarded_element(client: IpAddr, proto: &str, by: Option<IpAddr>) -> String {
+    let mut element = format!("for={};proto={}", format_node(client), proto);
+    if let Some(by) = by {
+        element.push_str(";by=");
+        element.push_str(&format_node(by));
+    }
+    element
+}
+
+// Pseudonym this proxy records itself as in `Via`
+pub const VIA_PSEUDONYM: &str = "rust_proxy";
+
+// The `Via` element appended to forwarded requests
+pub fn via_element() -> String {
+    format!("1.1 {}", VIA_PSEUDONYM)
+}
+
+// Whether this proxy already appears in the request's `Via` chain, i.e. the
+// request has been through it before. Elements are `protocol received-by
+// [comment]`; only received-by is compared.
+pub fn via_names_self(head: &RequestHead) -> bool {
+    head.get_all("Via").flat_map(|value| split_unquoted(value, ',')).any(|element| {
+        element.split_whitespace().nth(1).is_some_and(|received_by| received_by.eq_ignore_ascii_case(VIA_PSEUDONYM))
+    })
+}
+
+// Split on `sep`, ignoring separators inside double-quoted strings
+fn split_unquoted(value: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && in_quotes {
+            escaped = true;
+        } else if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            parts.push(&value[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut out = String::with_capacity(value.len());
+        let mut escaped = false;
+        for c in value[1..value.len() - 1].chars() {
+            if escaped || c != '\\' {
+                out.push(c);
+                escaped = false;
+            } else {
+                escaped = true;
+            }
+        }
+        out
+    } else {
+        value.to_string()
+    }
+}
+
+// Parse a Forwarded header value into its comma-separated elements
+pub fn parse_forwarded(value: &str) -> Vec<ForwardedElement> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .filter(|element| !element.trim().is_empty())
+        .map(|element| {
+            let mut parsed = ForwardedElement::default();
+            for pair in split_unquoted(element, ';') {
+                let Some((key, val)) = pair.split_once('=') else {
+                    continue;
+                };
+                let val = unquote(val);
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => parsed.for_node = Some(val),
+                    "by" => parsed.by = Some(val),
+                    "proto" => parsed.proto = Some(val),
+                    "host" => parsed.host = Some(val),
+                    _ => {}
+                }
+            }
+            parsed
+        })
+        .collect()
+}
+
+// Extract the IP from a node such as `192.0.2.1:80` or `[2001:db8::1]:4711`.
+// Returns None for `unknown` and obfuscated identifiers.
+pub fn node_ip(node: &str) -> Option<IpAddr> {
+    let node = unquote(node);
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+}
+
+// Hops recorded by upstream proxies, oldest first. The standardized
+// Forwarded header wins over X-Forwarded-For when both are present.
+fn forwarded_chain(head: &RequestHead) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<&str> = head.get_all("Forwarded").collect();
+    if !forwarded.is_empty() {
+        return parse_forwarded(&forwarded.join(", "))
+            .into_iter()
+            .map(|element| element.for_node.as_deref().and_then(node_ip))
+            .collect();
+    }
+
+    head.get_all("X-Forwarded-For")
+        .flat_map(|value| value.split(','))
+        .map(node_ip)
+        .collect()
+}
+
+// Determine the original client address. Proxy headers are only honored
+// when the immediate peer is a trusted proxy; the chain is then walked from
+// the nearest hop outwards, skipping further trusted proxies.
+pub fn original_client(head: &RequestHead, peer: IpAddr, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+
+    let mut client = peer;
+    for hop in forwarded_chain(head).into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip;
+                if !trusted.contains(&ip) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    client
+}
+
+// Which `X-Forwarded-For` entry `--trust-proxy-headers` takes as the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum XffPosition {
+    /// The last entry, added by the proxy right in front of this one
+    #[default]
+    Rightmost,
+    /// The first entry, the client as seen by the outermost proxy
+    Leftmost,
+}
+
+// The client address named by the `position` entry of the request's
+// `X-Forwarded-For` headers (several combine in order), if it is an IP
+pub fn xff_client(head: &RequestHead, position: XffPosition) -> Option<IpAddr> {
+    let mut entries = head.get_all("X-Forwarded-For").flat_map(|value| value.split(','));
+    let entry = match position {
+        XffPosition::Rightmost => entries.last(),
+        XffPosition::Leftmost => entries.next(),
+    }?;
+    node_ip(entry.trim()).map(|ip| ip.to_canonical())
+}
@@ -0,0 +1,285 @@
+// Minimal HTTP/1.x request and response head parsing, and request rewriting.
+//
+// The proxy normally forwards request bytes verbatim. Features that need to
+// inspect or rewrite headers parse the head into a `RequestHead`, mutate it,
+// and reserialize it only if something actually changed.
+
+// Headers whose values must never be written to logs
+pub const SENSITIVE_HEADERS: &[&str] = &["Authorization", "Proxy-Authorization"];
+
+pub fn is_sensitive(name: &str) -> bool {
+    SENSITIVE_HEADERS.iter().any(|sensitive| sensitive.eq_ignore_ascii_case(name))
+}
+
+// Split a header block into its start line and (name, value) pairs
+fn parse_block(data: &[u8]) -> Option<(String, Vec<(String, String)>)> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.split("\r\n").flat_map(|l| l.split('\n'));
+
+    let start_line = lines.next()?.to_string();
+    let mut headers = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Some((start_line, headers))
+}
+
+// Whether any `name` header lists `token` in its comma-separated value
+fn has_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+        .flat_map(|(_, v)| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+// Whether `chunked` is the final transfer coding
+fn is_chunked(headers: &[(String, String)]) -> bool {
+    headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+// `Content-Length`, if present and valid. Repeated values must agree.
+fn content_length(headers: &[(String, String)]) -> Option<u64> {
+    let mut lengths = headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("Content-Length"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|v| v.trim())
+        // Digits only: `parse` would also accept a leading `+`
+        .map(|v| if v.bytes().all(|b| b.is_ascii_digit()) { v.parse::<u64>().ok() } else { None });
+    let first = lengths.next()??;
+    lengths.all(|length| length == Some(first)).then_some(first)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    modified: bool,
+}
+
+impl RequestHead {
+    // Parse a header block (request line + headers, terminator optional)
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (start_line, headers) = parse_block(data)?;
+        let mut parts = start_line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?.to_string();
+        let version = parts.next()?.to_string();
+
+        Some(Self { method, target, version, headers, modified: false })
+    }
+
+    // First value of a header (case-insensitive name match)
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // All values of a header in order of appearance
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Remove every occurrence of a header, returning how many were removed
+    pub fn remove(&mut self, name: &str) -> usize {
+        let before = self.headers.len();
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        let removed = before - self.headers.len();
+        if removed > 0 {
+            self.modified = true;
+        }
+        removed
+    }
+
+    // Replace all occurrences of a header with a single value, keeping the
+    // position of the first occurrence (or appending if absent)
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.headers.iter().position(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(index) => {
+                self.headers[index].1 = value.to_string();
+                let mut seen = 0;
+                self.headers.retain(|(n, _)| {
+                    if n.eq_ignore_ascii_case(name) {
+                        seen += 1;
+                        seen == 1
+                    } else {
+                        true
+                    }
+                });
+            }
+            None => self.headers.push((name.to_string(), value.to_string())),
+        }
+        self.modified = true;
+    }
+
+    // Append to a list-valued header, joining with ", " when already present
+    pub fn append(&mut self, name: &str, value: &str) {
+        let existing = self.get_all(name).collect::<Vec<_>>().join(", ");
+        let combined = if existing.is_empty() {
+            value.to_string()
+        } else {
+            format!("{}, {}", existing, value)
+        };
+        self.set(name, &combined);
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    // Header block as readable text for logging, with credentials masked
+    pub fn to_redacted_string(&self) -> String {
+        let mut out = format!("{} {} {}", self.method, self.target, self.version);
+        for (name, value) in &self.headers {
+            let value = if is_sensitive(name) { "[REDACTED]" } else { value.as_str() };
+            out.push_str(&format!("\n{}: {}", name, value));
+        }
+        out
+    }
+
+    // The client asked to switch this connection to WebSocket
+    pub fn is_websocket_upgrade(&self) -> bool {
+        has_token(&self.headers, "Connection", "upgrade") && has_token(&self.headers, "Upgrade", "websocket")
+    }
+
+    // The request body uses chunked framing
+    pub fn is_chunked(&self) -> bool {
+        is_chunked(&self.headers)
+    }
+
+    // The client holds its body back until told to go ahead with an interim
+    // `100 Continue` (or until it gives up waiting)
+    pub fn expects_continue(&self) -> bool {
+        self.get("Expect").is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        content_length(&self.headers)
+    }
+
+    // Why the body framing is ambiguous, if it is. Intermediaries that pick
+    // different framings for the same bytes are how requests get smuggled
+    // past the proxy to its upstream (RFC 9112 section 6.3), so such
+    // requests are refused rather than forwarded.
+    pub fn framing_conflict(&self) -> Option<&'static str> {
+        let lengths: Vec<&str> = self.get_all("Content-Length").collect();
+        let transfer_encoding = self.get("Transfer-Encoding").is_some();
+        if !lengths.is_empty() && transfer_encoding {
+            Some("both Content-Length and Transfer-Encoding")
+        } else if lengths.len() > 1 || lengths.iter().any(|v| v.contains(',')) {
+            Some("duplicate Content-Length")
+        } else if !lengths.is_empty() && self.content_length().is_none() {
+            Some("invalid Content-Length")
+        } else if transfer_encoding && !self.is_chunked() {
+            Some("Transfer-Encoding not ending in chunked")
+        } else {
+            None
+        }
+    }
+
+    // The client doesn't want to send further requests on this connection:
+    // it said so, or it's HTTP/1.0 without keep-alive
+    pub fn closes_connection(&self) -> bool {
+        has_token(&self.headers, "Connection", "close")
+            || (self.version == "HTTP/1.0" && !has_token(&self.headers, "Connection", "keep-alive"))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        for (name, value) in &self.headers {
+            out.push_str(name);
+            out.push_str(": ");
+            out.push_str(value);
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+}
+
+// Upstream response head (status line + headers), inspected read-only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (status_line, headers) = parse_block(data)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next()?.to_string();
+        if !version.starts_with("HTTP/") {
+            return None;
+        }
+        let status = parts.next()?.parse().ok()?;
+        let reason = parts.next().unwrap_or("").to_string();
+
+        Some(Self { version, status, reason, headers })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Whether any `Connection` header lists `token` (case-insensitive)
+    pub fn has_connection_token(&self, token: &str) -> bool {
+        has_token(&self.headers, "Connection", token)
+    }
+
+    // The server accepted a WebSocket upgrade; the connection is now a
+    // raw bidirectional tunnel
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.status == 101 && has_token(&self.headers, "Upgrade", "websocket")
+    }
+
+    // The body uses chunked framing (it must be the final transfer coding)
+    pub fn is_chunked(&self) -> bool {
+        is_chunked(&self.headers)
+    }
+
+    // An informational `1xx` response that precedes the final one, such as
+    // `100 Continue` or `103 Early Hints`. `101` is excluded: after it the
+    // connection no longer speaks HTTP.
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        content_length(&self.headers)
+    }
+
+    // The server will close the connection after this response: either it
+    // said so, or it's HTTP/1.0 without keep-alive. Interim 1xx responses
+    // never end the exchange.
+    pub fn closes_connection(&self) -> bool {
+        if (100..200).contains(&self.status) {
+            return false;
+        }
+        self.has_connection_token("close")
+            || (self.version == "HTTP/1.0" && !self.has_connection_token("keep-alive"))
+    }
+}
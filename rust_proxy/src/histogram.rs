@@ -0,0 +1,94 @@
+// Fixed-bucket histogram of byte sizes, in the shape Prometheus expects.
+//
+// Buckets are powers of two from 1 KiB to 1 GiB, each with its own atomic
+// counter, so recording a value is a couple of relaxed atomic adds with no
+// lock. Counts are kept per bucket and only made cumulative (`le`
+// semantics) when read.
+
+use crate::add_saturating;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SMALLEST_BUCKET_SHIFT: u32 = 10; // 1 KiB
+const LARGEST_BUCKET_SHIFT: u32 = 30; // 1 GiB
+pub const BUCKET_COUNT: usize = (LARGEST_BUCKET_SHIFT - SMALLEST_BUCKET_SHIFT + 1) as usize;
+
+// Upper bound (inclusive) of each bucket, smallest first
+pub fn bucket_bounds() -> impl Iterator<Item = u64> {
+    (SMALLEST_BUCKET_SHIFT..=LARGEST_BUCKET_SHIFT).map(|shift| 1u64 << shift)
+}
+
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+    // `buckets[i]` counts values in (bound[i - 1], bound[i]]; larger values
+    // only appear in `count` (the `+Inf` bucket)
+    buckets: [AtomicU64; BUCKET_COUNT],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+// Cumulative counts at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// `(upper bound, values <= bound)` for each finite bucket
+    pub buckets: Vec<(u64, u64)>,
+    /// Saturates at `u64::MAX` rather than wrapping
+    pub sum: u64,
+    pub count: u64,
+}
+
+impl SizeHistogram {
+    pub fn record(&self, value: u64) {
+        if let Some(bucket) = bucket_index(value) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        add_saturating(&self.sum, value);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = bucket_bounds()
+            .zip(&self.buckets)
+            .map(|(bound, bucket)| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot { buckets, sum: self.sum.load(Ordering::Relaxed), count: self.count.load(Ordering::Relaxed) }
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.sum.store(0, Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
+    }
+}
+
+// The bucket `value` falls in, or `None` past the largest bound
+fn bucket_index(value: u64) -> Option<usize> {
+    if value <= 1 << SMALLEST_BUCKET_SHIFT {
+        return Some(0);
+    }
+    // Smallest `shift` with `value <= 1 << shift`
+    let shift = u64::BITS - (value - 1).leading_zeros();
+    (shift <= LARGEST_BUCKET_SHIFT).then(|| (shift - SMALLEST_BUCKET_SHIFT) as usize)
+}
+
+impl HistogramSnapshot {
+    // Prometheus text exposition of the histogram as metric `name`. The
+    // `+Inf` bucket may briefly trail a finite one while values are being
+    // recorded, since each counter is read separately.
+    pub fn to_prometheus(&self, name: &str, help: &str) -> String {
+        let mut text = format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name);
+        for (bound, count) in &self.buckets {
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(text, "{}_sum {}", name, self.sum);
+        let _ = writeln!(text, "{}_count {}", name, self.count);
+        text
+    }
+}
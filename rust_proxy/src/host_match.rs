@@ -0,0 +1,53 @@
+// Hostname lists for per-destination policy (`--quiet-hosts`).
+//
+// A pattern is either an exact hostname (`example.com`) or a `*.` wildcard
+// matching any subdomain (`*.example.com` matches `api.example.com` and
+// `a.b.example.com`, but not `example.com` itself; list both for that).
+// Matching ignores case and a trailing dot, so `Example.COM.` is
+// `example.com`. IP literals are matched as exact strings.
+
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostMatcher {
+    exact: HashSet<String>,
+    // Stored with the leading dot: `*.example.com` becomes `.example.com`
+    suffixes: Vec<String>,
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl HostMatcher {
+    pub fn new<I, S>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut matcher = Self::default();
+        for pattern in patterns {
+            let pattern = normalize(pattern.as_ref().trim());
+            match pattern.strip_prefix('*') {
+                Some(suffix) if suffix.starts_with('.') => matcher.suffixes.push(suffix.to_string()),
+                _ if pattern.is_empty() => {}
+                _ => {
+                    matcher.exact.insert(pattern);
+                }
+            }
+        }
+        matcher
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.suffixes.is_empty()
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let host = normalize(host.trim_start_matches('[').trim_end_matches(']'));
+        self.exact.contains(&host) || self.suffixes.iter().any(|suffix| host.ends_with(suffix.as_str()))
+    }
+}
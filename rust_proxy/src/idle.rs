@@ -0,0 +1,62 @@
+// Draining an idle proxy for autoscaled fleets (`--drain-on-idle`,
+// `--drain-marker`, `--exit-on-idle`).
+//
+// Once no client connection has been open for the whole idle period, the
+// proxy marks itself draining: `/healthz` answers `503 draining`, and the
+// marker file, if configured, is created, either of which tells an
+// autoscaler the instance can be removed without cutting anyone off. With
+// `--exit-on-idle` the proxy then shuts down through the usual graceful path.
+//
+// Draining is one-way. Clients that still arrive are served, but the
+// instance stays marked, so a removal already under way isn't raced by the
+// health check flipping back.
+//
+// Idleness is sampled every `IDLE_POLL_INTERVAL`. A connection that opens and
+// closes between two samples still counts, as it moves the accepted-
+// connections counter.
+
+use crate::ProxyStats;
+use log::{error, info};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Resolves once `stats` has shown no client connections for `period`
+pub async fn wait_until_idle(stats: &ProxyStats, period: Duration, poll: Duration) {
+    let mut ticker = tokio::time::interval(poll);
+    let mut idle_since: Option<(Instant, u64)> = None;
+    loop {
+        ticker.tick().await;
+        let accepted = stats.tcp_connections_accepted.load(Ordering::Relaxed);
+        if stats.active_connections.load(Ordering::Relaxed) > 0 {
+            idle_since = None;
+            continue;
+        }
+        match idle_since {
+            Some((since, seen)) if seen == accepted => {
+                if since.elapsed() >= period {
+                    return;
+                }
+            }
+            _ => idle_since = Some((Instant::now(), accepted)),
+        }
+    }
+}
+
+// Wait out the idle period, then mark the proxy draining and create the
+// marker file. A marker that can't be written is logged; the health check
+// still reports draining.
+pub async fn drain_on_idle(stats: &ProxyStats, period: Duration, poll: Duration, draining: &AtomicBool, marker: Option<&Path>) {
+    wait_until_idle(stats, period, poll).await;
+    draining.store(true, Ordering::Relaxed);
+    info!("No connections for {:?}, draining", period);
+    if let Some(marker) = marker {
+        match std::fs::File::create(marker) {
+            Ok(_) => info!("Wrote drain marker {}", marker.display()),
+            Err(e) => error!("Failed to write drain marker {}: {}", marker.display(), e),
+        }
+    }
+}
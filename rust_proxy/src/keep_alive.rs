@@ -0,0 +1,365 @@
+// Persistent client connections for plain-HTTP forwarding.
+//
+// A forwarded request used to turn the client connection into a tunnel to
+// its upstream, so a second request on the same connection could only ever
+// reach the first request's server. Instead, when both messages of an
+// exchange have a known length, the request body and the response are
+// relayed message by message and the client connection is handed back to
+// `handle_client` for its next request, which may go anywhere. Pipelined
+// bytes that arrive early are carried over rather than dropped.
+//
+// Upstream connections are not reused: each exchange dials its own. An
+// exchange whose framing can't be followed (a response delimited by close,
+// a `101` protocol switch, an unparseable head) is relayed to the end and
+// closes the client connection, as does a request or response that asks to
+// close. Interim `1xx` responses are relayed ahead of the final one.
+//
+// A request with `Expect: 100-continue` has its body held back by the
+// client, so the proxy waits for either the upstream's `100 Continue` (or
+// any other answer) or the client to send the body anyway, and only then
+// copies the body. An upstream that answers without asking for the body
+// ends the client connection, since the client may still send it.
+//
+// Size caps and `--min-throughput` apply to the client connection as a
+// whole, as they do to a tunnel, so splitting a transfer over several
+// requests doesn't get around them. `handle_client` keeps a `ConnectionMeter`
+// for that across exchanges.
+
+use crate::buffer_pool::BUFFER_POOL;
+use crate::chunked::ChunkedDecoder;
+use crate::error::{map_write_error, ProxyErrorKind};
+use crate::headers::{RequestHead, ResponseHead};
+use crate::throughput::ThroughputMonitor;
+use crate::{continue_response_head, find_header_terminator, relay_interim_head, write_all_with_progress};
+use crate::{ByteCounters, CopyLimits, Direction, ProxyError, ProxyStats};
+use log::{debug, warn};
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::time::timeout;
+
+// How the end of a message body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+// Framing of a request body, or `None` when it can't be delimited (a
+// transfer coding other than chunked, or a conflicting Content-Length)
+pub fn request_body_length(head: &RequestHead) -> Option<BodyLength> {
+    if head.get("Transfer-Encoding").is_some() {
+        return head.is_chunked().then_some(BodyLength::Chunked);
+    }
+    match head.get("Content-Length") {
+        None => Some(BodyLength::Empty),
+        Some(_) => head.content_length().map(BodyLength::Fixed),
+    }
+}
+
+// Framing of a response body to a `method` request (RFC 9112 section 6.3)
+pub fn response_body_length(method: &str, response: &ResponseHead) -> BodyLength {
+    if method.eq_ignore_ascii_case("HEAD") || matches!(response.status, 100..=199 | 204 | 304) {
+        return BodyLength::Empty;
+    }
+    if response.get("Transfer-Encoding").is_some() {
+        return if response.is_chunked() { BodyLength::Chunked } else { BodyLength::UntilClose };
+    }
+    match response.get("Content-Length").map(|_| response.content_length()) {
+        Some(Some(length)) => BodyLength::Fixed(length),
+        _ => BodyLength::UntilClose,
+    }
+}
+
+// Body bytes relayed in one direction of a client connection so far
+#[derive(Debug)]
+struct Metered {
+    direction: Direction,
+    label: String,
+    transferred: u64,
+    throughput: Option<ThroughputMonitor>,
+}
+
+impl Metered {
+    fn new(conn_id: u64, direction: Direction, limits: CopyLimits) -> Self {
+        let label = match direction {
+            Direction::ClientToServer => format!("[#{}] client->server", conn_id),
+            Direction::ServerToClient => format!("[#{}] server->client", conn_id),
+        };
+        Self { direction, label, transferred: 0, throughput: limits.min_throughput.map(ThroughputMonitor::new) }
+    }
+
+    // Note a read of `n` bytes, failing once the rate has fallen too low
+    fn record_read(&mut self, n: usize, stats: &ProxyStats) -> Result<(), ProxyError> {
+        if self.throughput.as_mut().is_some_and(|monitor| monitor.record(n as u64)) {
+            stats.slow_transfer_aborted.fetch_add(1, Ordering::Relaxed);
+            warn!("Transfer below minimum throughput in {}", self.label);
+            return Err(ProxyErrorKind::SlowTransfer.into());
+        }
+        Ok(())
+    }
+
+    // How many bytes of `data` belong to the body, counting them against
+    // the size cap
+    fn take(&mut self, framing: &mut Framing, data: &[u8], limits: CopyLimits) -> Result<usize, ProxyError> {
+        match framing.take(data, limits.size_cap(self.direction) - self.transferred) {
+            Ok((consumed, body)) => {
+                self.transferred += body;
+                Ok(consumed)
+            }
+            Err(ProxyErrorKind::SizeLimitExceeded) => {
+                let kind = match self.direction {
+                    Direction::ClientToServer => "Upload",
+                    Direction::ServerToClient => "Download",
+                };
+                warn!("{} size limit exceeded in {}: {} bytes", kind, self.label, self.transferred.saturating_add(data.len() as u64));
+                Err(ProxyErrorKind::SizeLimitExceeded.into())
+            }
+            Err(kind) => Err(kind.into()),
+        }
+    }
+}
+
+// Everything a client connection has relayed, carried from one exchange to
+// the next
+#[derive(Debug)]
+pub struct ConnectionMeter {
+    upload: Metered,
+    download: Metered,
+}
+
+impl ConnectionMeter {
+    pub fn new(conn_id: u64, limits: CopyLimits) -> Self {
+        Self {
+            upload: Metered::new(conn_id, Direction::ClientToServer, limits),
+            download: Metered::new(conn_id, Direction::ServerToClient, limits),
+        }
+    }
+
+    // `limits` with the size caps reduced by what earlier exchanges used,
+    // for an exchange relayed some other way
+    pub fn remaining(&self, limits: CopyLimits) -> CopyLimits {
+        CopyLimits {
+            max_download_size: limits.max_download_size.saturating_sub(self.download.transferred),
+            max_upload_size: limits.max_upload_size.saturating_sub(self.upload.transferred),
+            ..limits
+        }
+    }
+}
+
+// Whether the exchange can be relayed message by message. Anything else is
+// left to `tunnel_http`, which ends the client connection afterwards.
+pub fn is_relayable(request: &RequestHead) -> bool {
+    !request.is_websocket_upgrade()
+        // Other expectations are left for the upstream to refuse
+        && (request.get("Expect").is_none() || request.expects_continue())
+        && request_body_length(request).is_some()
+}
+
+// Relay one exchange whose request head has already been written to
+// `upstream`. `pending` holds the client bytes read past that head. Returns
+// the client bytes read past the request body (the start of the next
+// request) if the connection may carry another request, or `None` once the
+// response has been relayed and the client connection should close.
+#[allow(clippy::too_many_arguments)]
+pub async fn relay_exchange<C, U>(
+    conn_id: u64,
+    client: &mut C,
+    upstream: &mut U,
+    request: &RequestHead,
+    pending: &[u8],
+    stats: &ProxyStats,
+    counters: ByteCounters<'_>,
+    limits: CopyLimits,
+    meter: &mut ConnectionMeter,
+) -> Result<Option<Vec<u8>>, ProxyError>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let request_length = request_body_length(request).ok_or(ProxyErrorKind::MalformedRequest)?;
+    // `None` while the body is held back waiting for `100 Continue`
+    let mut next_request = None;
+    if !request.expects_continue() || request_length == BodyLength::Empty || !pending.is_empty() {
+        next_request = Some(copy_body(client, upstream, &mut meter.upload, pending, request_length, limits, stats, counters).await?);
+    }
+
+    let mut buffer = BUFFER_POOL.get();
+    let mut bytes_read = 0;
+    let mut client_open = true;
+    let (buffer, bytes_read, response) = loop {
+        if next_request.is_none() && bytes_read == 0 && client_open {
+            let mut early = BUFFER_POOL.get();
+            let first = timeout(limits.idle_timeout, async {
+                tokio::select! {
+                    read = upstream.read(&mut buffer) => (read, true),
+                    read = client.read(&mut early) => (read, false),
+                }
+            });
+            match first.await {
+                Ok((Ok(0), true)) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok((Ok(n), true)) => bytes_read = n,
+                // A client that shut down its side has no body coming; wait
+                // for the upstream alone
+                Ok((Ok(0), false)) => client_open = false,
+                Ok((Ok(n), false)) => {
+                    debug!("[#{}] Client sent its body without waiting for 100 Continue", conn_id);
+                    next_request = Some(copy_body(client, upstream, &mut meter.upload, &early[..n], request_length, limits, stats, counters).await?);
+                }
+                Ok((Err(e), _)) => return Err(e.into()),
+                Err(_) => return Err(ProxyErrorKind::IdleTimeout.into()),
+            }
+            continue;
+        }
+
+        let (read_buffer, read, response) = continue_response_head(upstream, buffer, bytes_read, limits.idle_timeout).await?;
+        (buffer, bytes_read) = (read_buffer, read);
+        stats.record_response_status(response.as_ref().map(|r| r.status));
+        let head_end = find_header_terminator(&buffer[..bytes_read]);
+        let Some((status, head_end)) = response.as_ref().filter(|r| r.is_interim()).map(|r| r.status).zip(head_end) else {
+            break (buffer, bytes_read, response);
+        };
+        bytes_read = relay_interim_head(client, &mut buffer, bytes_read, head_end, limits, stats, counters).await?;
+        if status == 100 && next_request.is_none() {
+            debug!("[#{}] Upstream sent 100 Continue, relaying the request body", conn_id);
+            next_request = Some(copy_body(client, upstream, &mut meter.upload, &[], request_length, limits, stats, counters).await?);
+        }
+    };
+
+    let head_end = find_header_terminator(&buffer[..bytes_read]);
+    let (Some(response), Some(head_end)) = (response, head_end) else {
+        debug!("[#{}] Unparseable response head, relaying until close", conn_id);
+        write_counted(client, Direction::ServerToClient, &buffer[..bytes_read], limits, stats, counters).await?;
+        copy_body(upstream, client, &mut meter.download, &[], BodyLength::UntilClose, limits, stats, counters).await?;
+        return Ok(None);
+    };
+
+    let mut response_length = response_body_length(&request.method, &response);
+    if response.status == 101 {
+        // Switched protocols; follow the rest blindly
+        response_length = BodyLength::UntilClose;
+    }
+    write_counted(client, Direction::ServerToClient, &buffer[..head_end], limits, stats, counters).await?;
+    copy_body(upstream, client, &mut meter.download, &buffer[head_end..bytes_read], response_length, limits, stats, counters).await?;
+
+    let Some(next_request) = next_request else {
+        debug!("[#{}] Upstream answered without reading the request body, closing", conn_id);
+        return Ok(None);
+    };
+    if request.closes_connection() || response_length == BodyLength::UntilClose || response.closes_connection() {
+        debug!("[#{}] Exchange ends the client connection", conn_id);
+        return Ok(None);
+    }
+    Ok(Some(next_request))
+}
+
+// Progress through one message body
+enum Framing {
+    Remaining(u64),
+    Chunked(ChunkedDecoder),
+    UntilClose,
+}
+
+impl Framing {
+    fn new(length: BodyLength) -> Self {
+        match length {
+            BodyLength::Empty => Self::Remaining(0),
+            BodyLength::Fixed(length) => Self::Remaining(length),
+            BodyLength::Chunked => Self::Chunked(ChunkedDecoder::new()),
+            BodyLength::UntilClose => Self::UntilClose,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self {
+            Self::Remaining(remaining) => *remaining == 0,
+            Self::Chunked(decoder) => decoder.is_done(),
+            Self::UntilClose => false,
+        }
+    }
+
+    // How many bytes of `data` belong to the body, and how many body bytes
+    // those are, with at most `allowed` body bytes left under the cap
+    fn take(&mut self, data: &[u8], allowed: u64) -> Result<(usize, u64), ProxyErrorKind> {
+        match self {
+            // A declared length that can't fit is refused before any of it
+            // is relayed
+            Self::Remaining(remaining) if *remaining > allowed => Err(ProxyErrorKind::SizeLimitExceeded),
+            Self::Remaining(remaining) => {
+                let n = (*remaining).min(data.len() as u64);
+                *remaining -= n;
+                Ok((n as usize, n))
+            }
+            Self::Chunked(decoder) => {
+                let (consumed, body) = decoder.feed_message(data, allowed);
+                if decoder.is_invalid() {
+                    Err(ProxyErrorKind::MalformedRequest)
+                } else if consumed < data.len() && !decoder.is_done() {
+                    Err(ProxyErrorKind::SizeLimitExceeded)
+                } else {
+                    Ok((consumed, body))
+                }
+            }
+            Self::UntilClose if data.len() as u64 > allowed => Err(ProxyErrorKind::SizeLimitExceeded),
+            Self::UntilClose => Ok((data.len(), data.len() as u64)),
+        }
+    }
+}
+
+// Copy one body from `reader` to `writer`, starting with the `pending`
+// bytes already read, and return whatever was read past its end
+#[allow(clippy::too_many_arguments)]
+async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    meter: &mut Metered,
+    pending: &[u8],
+    length: BodyLength,
+    limits: CopyLimits,
+    stats: &ProxyStats,
+    counters: ByteCounters<'_>,
+) -> Result<Vec<u8>, ProxyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let direction = meter.direction;
+    let mut framing = Framing::new(length);
+    let n = meter.take(&mut framing, pending, limits)?;
+    write_counted(writer, direction, &pending[..n], limits, stats, counters).await?;
+    if framing.is_done() {
+        return Ok(pending[n..].to_vec());
+    }
+
+    let mut buffer = BUFFER_POOL.get();
+    loop {
+        let read = match timeout(limits.idle_timeout, reader.read(&mut buffer)).await {
+            Ok(Ok(0)) if matches!(framing, Framing::UntilClose) => return Ok(Vec::new()),
+            Ok(Ok(0)) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            Ok(Ok(read)) => read,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(ProxyErrorKind::IdleTimeout.into()),
+        };
+        meter.record_read(read, stats)?;
+        let n = meter.take(&mut framing, &buffer[..read], limits)?;
+        write_counted(writer, direction, &buffer[..n], limits, stats, counters).await?;
+        if framing.is_done() {
+            return Ok(buffer[n..read].to_vec());
+        }
+    }
+}
+
+async fn write_counted<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    direction: Direction,
+    data: &[u8],
+    limits: CopyLimits,
+    stats: &ProxyStats,
+    counters: ByteCounters<'_>,
+) -> Result<(), ProxyError> {
+    write_all_with_progress(writer, data, limits.write_timeout).await.map_err(|e| map_write_error(&e))?;
+    stats.record_bytes(direction, data.len() as u64);
+    counters.add(data.len() as u64);
+    Ok(())
+}
@@ -1,4 +1,4 @@
-pub use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+pub use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 pub use std::sync::Arc;
 pub use std::time::{Duration, Instant};
 pub use clap::Parser;
@@ -12,61 +12,736 @@ pub use url::Url;
 #[cfg(windows)]
 pub mod windows;
 
+pub mod admin;
+pub mod alpn;
+pub mod auth;
+pub mod banner;
+pub mod buffer_pool;
+pub mod bounded_map;
+pub mod capture;
+pub mod chunked;
+pub mod circuit_breaker;
+pub mod coalesce;
+pub mod connect_error;
+#[cfg(unix)]
+pub mod control;
+pub mod dest_limit;
+pub mod decompressed;
+pub mod dialer;
+pub mod error;
+pub mod error_template;
+pub mod events;
+pub mod forwarded;
+pub mod headers;
+pub mod histogram;
+pub mod host_match;
+pub mod idle;
+pub mod keep_alive;
+pub mod log_file;
+pub mod profiles;
+pub mod rate_limit;
+pub mod reload;
+pub mod resolver;
+pub mod routes;
+pub mod sni;
+pub mod socks5;
+pub mod ssl_errors;
+pub mod ssrf;
+pub mod stale;
+pub mod syslog;
+pub mod tarpit;
+pub mod throughput;
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+pub mod upstream_proxy;
+pub mod version;
+
+use alpn::AlpnPolicy;
+use bounded_map::BoundedMap;
+use buffer_pool::{PooledBuffer, BUFFER_POOL};
+use capture::Capture;
+use chunked::ChunkedDecoder;
+use circuit_breaker::{CircuitBreaker, CIRCUIT_BREAKER_WINDOW};
+use coalesce::{Coalescer, Role, COALESCE_WAIT_TIMEOUT};
+use connect_error::ConnectFailure;
+use dest_limit::{DestinationLimiter, DEST_LIMIT_WAIT};
+use dialer::{AsyncReadWrite, BoundDialer, TcpDialer, UpstreamDialer};
+use error::{map_write_error, CloseReason, ProxyErrorKind};
+use error_template::{ErrorDetails, ErrorTemplate};
+use events::{ConnectionEvents, EventBus};
+
+use headers::RequestHead;
+use histogram::SizeHistogram;
+use host_match::HostMatcher;
+use rate_limit::{RateLimiter, MAX_TRACKED_CLIENTS};
+use resolver::{Resolver, ResolvingDialer};
+use routes::{Route, RouteMap};
+use socks5::Socks5Dialer;
+use ssl_errors::{analyze_ssl_error, SslErrorCounts, SslErrorStats};
+use stale::{serve_stale, StaleSlot, StaleStore, MAX_STALE_ENTRIES};
+use tarpit::Tarpit;
+use throughput::{MinThroughput, ThroughputMonitor, MIN_THROUGHPUT_WINDOW};
+use upstream_proxy::{UpstreamProxies, UpstreamProxy, UpstreamProxyStats};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use tokio::io::{AsyncRead, AsyncWrite};
+
 pub type ProxyError = Box<dyn std::error::Error + Send + Sync>;
 
 pub const BUFFER_SIZE: usize = 65536; // Larger buffer for better throughput
 pub const MAX_CONNECTIONS: usize = 10000; // Connection limit
 pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MIN_ADAPTIVE_CONNECT_TIMEOUT: Duration = Duration::from_secs(1); // Floor for --adaptive-connect-timeout
+pub const ADAPTIVE_CONNECT_MULTIPLIER: u32 = 4; // Headroom over the average connect time
+const CONNECT_EWMA_WEIGHT: f64 = 0.2; // Share of each new sample in the average
 pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300); // 5 minutes idle timeout
 pub const MAX_DOWNLOAD_SIZE: u64 = 1024 * 1024 * 1024; // 1GB max download
+pub const MAX_TRACKED_HOSTS: usize = 1024; // Cap on per-destination stats entries
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30); // In-flight drain limit on shutdown
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024; // Same as tokio's TcpListener::bind
+pub const DEFAULT_TOP_HOSTS: usize = 10;
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+
+// Process-wide connection IDs, prefixed to log lines as `[#<id>]` so output
+// from concurrent connections can be grouped
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+const BAD_REQUEST_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+// Body for origin-form requests (`GET /path`), which reach the proxy when a
+// client is pointed at it as if it were the web server
+const NOT_A_PROXY_REQUEST_BODY: &str = "This is a forward proxy. Requests must use an absolute URI \
+(GET http://example.com/ HTTP/1.1) or CONNECT host:port. Configure this address as your HTTP proxy \
+instead of requesting it directly.\n";
+const BAD_GATEWAY_RESPONSE: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+// Seconds clients are told to wait while the proxy is in maintenance mode
+const MAINTENANCE_RETRY_AFTER: u64 = 120;
+const MAINTENANCE_BODY: &str = "The proxy is down for maintenance. Please try again later.\n";
+const SERVICE_UNAVAILABLE_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const METHOD_NOT_ALLOWED_RESPONSE: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const GATEWAY_TIMEOUT_RESPONSE: &[u8] = b"HTTP/1.1 504 Gateway Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const REQUEST_TIMEOUT_RESPONSE: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const HEADER_FIELDS_TOO_LARGE_RESPONSE: &[u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const TOO_MANY_REQUESTS_RESPONSE: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
 
 // Statistics tracking
 #[derive(Debug)]
 pub struct ProxyStats {
-    pub total_connections: AtomicU64,
+    /// Client connections taken on (after any TLS handshake). A persistent
+    /// connection counts once however many requests it carries; those are
+    /// counted in `http_requests` and `https_requests`.
+    pub tcp_connections_accepted: AtomicU64,
     pub active_connections: AtomicUsize,
+    /// Highest `active_connections` has been this period
+    pub peak_active_connections: AtomicUsize,
+    /// Saturates at `u64::MAX` rather than wrapping (see `add_saturating`)
     pub bytes_transferred: AtomicU64,
+    /// The two directions of `bytes_transferred` (see `record_bytes`)
+    pub bytes_client_to_server: AtomicU64,
+    pub bytes_server_to_client: AtomicU64,
     pub http_requests: AtomicU64,
     pub https_requests: AtomicU64,
     pub connection_errors: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub header_timeouts: AtomicU64,
+    pub tls_handshake_errors: AtomicU64,
+    pub circuit_open_rejections: AtomicU64,
+    pub blocked_ssrf: AtomicU64,
+    pub websocket_connections: AtomicU64,
+    pub coalesced_requests: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub stale_responses: AtomicU64,
+    pub listener_limit_rejections: AtomicU64,
+    pub smuggling_blocked: AtomicU64,
+    /// Requests refused because `Via` showed they had already passed through
+    pub loop_detected: AtomicU64,
+    /// Requests answered 503 while in maintenance mode
+    pub maintenance_rejections: AtomicU64,
+    pub header_limit_exceeded: AtomicU64,
+    pub method_class_disabled: AtomicU64,
+    pub tunnel_duration_exceeded: AtomicU64,
+    /// Transfers aborted for falling below `--min-throughput`
+    pub slow_transfer_aborted: AtomicU64,
+    /// HTTP exchanges cut at `--request-timeout`
+    pub request_timeout: AtomicU64,
+    /// Tunnels and requests shed at `--max-per-destination`
+    pub dest_overload: AtomicU64,
+    /// Policy blocks answered only after the `--block-tarpit` delay
+    pub tarpitted: AtomicU64,
+    /// Bytes of gzip/deflate response bodies and what they inflated to
+    /// (`--account-decompressed`)
+    pub compressed_body_bytes: AtomicU64,
+    pub decompressed_body_bytes: AtomicU64,
+    /// CONNECT tunnels whose TLS ClientHello carried an SNI (`--inspect-sni`),
+    /// and those where it named a different host than the CONNECT target
+    pub sni_seen: AtomicU64,
+    pub sni_mismatches: AtomicU64,
+    /// CONNECT tunnels refused for the ALPN protocols they offered
+    /// (`--allow-alpn`, `--block-alpn`)
+    pub alpn_blocked: AtomicU64,
+    /// Requests denied because a policy check couldn't be evaluated
+    /// (`--fail-closed`)
+    pub fail_closed_denied: AtomicU64,
+    /// Redirected connections tunneled to their original destination
+    /// (`--transparent`)
+    pub transparent_connections: AtomicU64,
+    /// Upstream HTTP responses by status class; `resp_invalid` counts those
+    /// without a parseable status line
+    pub resp_1xx: AtomicU64,
+    pub resp_2xx: AtomicU64,
+    pub resp_3xx: AtomicU64,
+    pub resp_4xx: AtomicU64,
+    pub resp_5xx: AtomicU64,
+    pub resp_invalid: AtomicU64,
+    /// Upstream connect failures that looked TLS-related, by kind
+    pub ssl_errors: SslErrorStats,
     pub start_time: Instant,
+    /// When the counters started counting: `start_time` until the first
+    /// `reset()`, then the time of the most recent one
+    pub period_start: std::sync::Mutex<Instant>,
+    pub hosts: BoundedMap<HostStats>,
+    /// Bytes relayed per client connection, recorded when it closes
+    pub connection_bytes: SizeHistogram,
+    /// Tunnel outcomes per `--upstream-proxy`, keyed by its `host:port`
+    pub upstream_proxies: BoundedMap<UpstreamProxyStats>,
+    /// Per-tenant totals, keyed by the `--tenant-header` value
+    pub tenants: BoundedMap<HostStats>,
+    pub top_hosts: usize,
+    /// Level the periodic and shutdown statistics are logged at
+    pub stats_log_level: log::Level,
+}
+
+// Per-destination statistics, keyed by `host:port`; the same counters are
+// kept per tenant
+#[derive(Debug, Default)]
+pub struct HostStats {
+    pub connections: AtomicU64,
+    pub bytes: AtomicU64,
+    pub errors: AtomicU64,
+    /// Exponentially weighted moving average of successful connect times,
+    /// in microseconds; 0 until the first one
+    pub connect_ewma_micros: AtomicU64,
+}
+
+impl HostStats {
+    // Fold a successful connect time into the moving average
+    pub fn record_connect_time(&self, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        let _ = self.connect_ewma_micros.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+            Some(match average {
+                0 => sample,
+                average => ((1.0 - CONNECT_EWMA_WEIGHT) * average as f64 + CONNECT_EWMA_WEIGHT * sample as f64).max(1.0) as u64,
+            })
+        });
+    }
+
+    // A connect timeout of `ADAPTIVE_CONNECT_MULTIPLIER` times the average
+    // connect time, kept within `[min, max]`; `max` until there is an average
+    pub fn adaptive_connect_timeout(&self, min: Duration, max: Duration) -> Duration {
+        match self.connect_ewma_micros.load(Ordering::Relaxed) {
+            0 => max,
+            average => (Duration::from_micros(average) * ADAPTIVE_CONNECT_MULTIPLIER).clamp(min, max),
+        }
+    }
 }
 
 impl ProxyStats {
     pub fn new() -> Self {
+        let start_time = Instant::now();
         Self {
-            total_connections: AtomicU64::new(0),
+            tcp_connections_accepted: AtomicU64::new(0),
             active_connections: AtomicUsize::new(0),
+            peak_active_connections: AtomicUsize::new(0),
             bytes_transferred: AtomicU64::new(0),
+            bytes_client_to_server: AtomicU64::new(0),
+            bytes_server_to_client: AtomicU64::new(0),
             http_requests: AtomicU64::new(0),
             https_requests: AtomicU64::new(0),
             connection_errors: AtomicU64::new(0),
-            start_time: Instant::now(),
+            auth_failures: AtomicU64::new(0),
+            header_timeouts: AtomicU64::new(0),
+            tls_handshake_errors: AtomicU64::new(0),
+            circuit_open_rejections: AtomicU64::new(0),
+            blocked_ssrf: AtomicU64::new(0),
+            websocket_connections: AtomicU64::new(0),
+            coalesced_requests: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            stale_responses: AtomicU64::new(0),
+            listener_limit_rejections: AtomicU64::new(0),
+            smuggling_blocked: AtomicU64::new(0),
+            loop_detected: AtomicU64::new(0),
+            maintenance_rejections: AtomicU64::new(0),
+            header_limit_exceeded: AtomicU64::new(0),
+            method_class_disabled: AtomicU64::new(0),
+            tunnel_duration_exceeded: AtomicU64::new(0),
+            slow_transfer_aborted: AtomicU64::new(0),
+            request_timeout: AtomicU64::new(0),
+            dest_overload: AtomicU64::new(0),
+            tarpitted: AtomicU64::new(0),
+            compressed_body_bytes: AtomicU64::new(0),
+            decompressed_body_bytes: AtomicU64::new(0),
+            sni_seen: AtomicU64::new(0),
+            sni_mismatches: AtomicU64::new(0),
+            alpn_blocked: AtomicU64::new(0),
+            fail_closed_denied: AtomicU64::new(0),
+            transparent_connections: AtomicU64::new(0),
+            resp_1xx: AtomicU64::new(0),
+            resp_2xx: AtomicU64::new(0),
+            resp_3xx: AtomicU64::new(0),
+            resp_4xx: AtomicU64::new(0),
+            resp_5xx: AtomicU64::new(0),
+            resp_invalid: AtomicU64::new(0),
+            ssl_errors: SslErrorStats::default(),
+            start_time,
+            period_start: std::sync::Mutex::new(start_time),
+            hosts: BoundedMap::new(MAX_TRACKED_HOSTS),
+            connection_bytes: SizeHistogram::default(),
+            upstream_proxies: BoundedMap::new(MAX_TRACKED_HOSTS),
+            tenants: BoundedMap::new(MAX_TRACKED_HOSTS),
+            top_hosts: DEFAULT_TOP_HOSTS,
+            stats_log_level: log::Level::Info,
+        }
+    }
+
+    // Count a newly opened connection as active and return how many now
+    // are, raising the peak if this is a new high
+    pub fn connection_opened(&self) -> usize {
+        let active = self.active_connections.fetch_add(1, Ordering::Relaxed) + 1;
+        // Only ever raise the peak: a racing opener may have stored a higher
+        // one since it was loaded, and the exchange fails until we see it
+        let mut peak = self.peak_active_connections.load(Ordering::Relaxed);
+        while active > peak {
+            match self.peak_active_connections.compare_exchange_weak(peak, active, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => peak = current,
+            }
+        }
+        active
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Count an upstream HTTP response by the class of its status, or as
+    // invalid when it had no status line that parsed (`None`)
+    pub fn record_response_status(&self, status: Option<u16>) {
+        let counter = match status {
+            Some(100..=199) => &self.resp_1xx,
+            Some(200..=299) => &self.resp_2xx,
+            Some(300..=399) => &self.resp_3xx,
+            Some(400..=499) => &self.resp_4xx,
+            Some(500..=599) => &self.resp_5xx,
+            _ => &self.resp_invalid,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Stats entry for a destination, created on first use
+    pub fn host(&self, host_port: &str) -> Arc<HostStats> {
+        self.hosts.get_or_insert_with(host_port, HostStats::default)
+    }
+
+    // Stats entry for a tenant, created on first use
+    pub fn tenant(&self, tenant: &str) -> Arc<HostStats> {
+        self.tenants.get_or_insert_with(tenant, HostStats::default)
+    }
+
+    // Stats entry for an upstream proxy, created on first use
+    pub fn upstream_proxy(&self, host_port: &str) -> Arc<UpstreamProxyStats> {
+        self.upstream_proxies.get_or_insert_with(host_port, UpstreamProxyStats::default)
+    }
+
+    // The `n` destinations with the most bytes transferred, largest first
+    pub fn top_destinations(&self, n: usize) -> Vec<(String, Arc<HostStats>)> {
+        top_by_bytes(&self.hosts, n)
+    }
+
+    // The `n` tenants with the most bytes transferred, largest first
+    pub fn top_tenants(&self, n: usize) -> Vec<(String, Arc<HostStats>)> {
+        top_by_bytes(&self.tenants, n)
+    }
+
+    // Read every counter back-to-back into a plain struct.
+    //
+    // Counters are updated independently with relaxed atomics, so a snapshot
+    // taken under load is only eventually consistent: e.g. a request may be
+    // counted before the connection that carries it, or the other way round.
+    // Each individual value is always exact.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.read_counters(|counter| counter.load(Ordering::Relaxed), false)
+    }
+
+    // Zero the counters and forget per-destination stats, starting a new
+    // period. Active connections are a live gauge and the uptime is the
+    // process's, so both are kept.
+    pub fn reset(&self) {
+        self.snapshot_and_reset();
+        self.forget_destinations();
+    }
+
+    // Like `snapshot()` followed by `reset()` of the counters, except that
+    // each counter is swapped to zero atomically, so an increment lands in
+    // exactly one period and consecutive snapshots add up to the totals.
+    // Per-destination stats are left to the caller.
+    pub fn snapshot_and_reset(&self) -> StatsSnapshot {
+        self.read_counters(|counter| counter.swap(0, Ordering::Relaxed), true)
+    }
+
+    fn read_counters(&self, read: impl Fn(&AtomicU64) -> u64, new_period: bool) -> StatsSnapshot {
+        let now = Instant::now();
+        let period = {
+            let mut period_start = self.period_start.lock().unwrap_or_else(|e| e.into_inner());
+            let period = now.duration_since(*period_start);
+            if new_period {
+                *period_start = now;
+            }
+            period
+        };
+        // Read once: a reset swaps the counter to zero
+        let tcp_connections_accepted = read(&self.tcp_connections_accepted);
+        StatsSnapshot {
+            total_connections: tcp_connections_accepted,
+            tcp_connections_accepted,
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            // A new period's peak starts from the connections still open
+            peak_active_connections: if new_period {
+                self.peak_active_connections.swap(self.active_connections.load(Ordering::Relaxed), Ordering::Relaxed)
+            } else {
+                self.peak_active_connections.load(Ordering::Relaxed)
+            },
+            bytes_transferred: read(&self.bytes_transferred),
+            bytes_client_to_server: read(&self.bytes_client_to_server),
+            bytes_server_to_client: read(&self.bytes_server_to_client),
+            http_requests: read(&self.http_requests),
+            https_requests: read(&self.https_requests),
+            connection_errors: read(&self.connection_errors),
+            auth_failures: read(&self.auth_failures),
+            header_timeouts: read(&self.header_timeouts),
+            tls_handshake_errors: read(&self.tls_handshake_errors),
+            circuit_open_rejections: read(&self.circuit_open_rejections),
+            blocked_ssrf: read(&self.blocked_ssrf),
+            websocket_connections: read(&self.websocket_connections),
+            coalesced_requests: read(&self.coalesced_requests),
+            rate_limited: read(&self.rate_limited),
+            stale_responses: read(&self.stale_responses),
+            listener_limit_rejections: read(&self.listener_limit_rejections),
+            smuggling_blocked: read(&self.smuggling_blocked),
+            loop_detected: read(&self.loop_detected),
+            maintenance_rejections: read(&self.maintenance_rejections),
+            header_limit_exceeded: read(&self.header_limit_exceeded),
+            method_class_disabled: read(&self.method_class_disabled),
+            tunnel_duration_exceeded: read(&self.tunnel_duration_exceeded),
+            slow_transfer_aborted: read(&self.slow_transfer_aborted),
+            request_timeout: read(&self.request_timeout),
+            dest_overload: read(&self.dest_overload),
+            tarpitted: read(&self.tarpitted),
+            compressed_body_bytes: read(&self.compressed_body_bytes),
+            decompressed_body_bytes: read(&self.decompressed_body_bytes),
+            sni_seen: read(&self.sni_seen),
+            sni_mismatches: read(&self.sni_mismatches),
+            alpn_blocked: read(&self.alpn_blocked),
+            fail_closed_denied: read(&self.fail_closed_denied),
+            transparent_connections: read(&self.transparent_connections),
+            resp_1xx: read(&self.resp_1xx),
+            resp_2xx: read(&self.resp_2xx),
+            resp_3xx: read(&self.resp_3xx),
+            resp_4xx: read(&self.resp_4xx),
+            resp_5xx: read(&self.resp_5xx),
+            resp_invalid: read(&self.resp_invalid),
+            ssl_errors: self.ssl_errors.read_with(&read),
+            uptime: now.duration_since(self.start_time),
+            period,
         }
     }
 
     pub fn log_stats(&self) {
-        let uptime = self.start_time.elapsed();
-        let total_conn = self.total_connections.load(Ordering::Relaxed);
-        let active_conn = self.active_connections.load(Ordering::Relaxed);
-        let bytes = self.bytes_transferred.load(Ordering::Relaxed);
-        let http = self.http_requests.load(Ordering::Relaxed);
-        let https = self.https_requests.load(Ordering::Relaxed);
-        let errors = self.connection_errors.load(Ordering::Relaxed);
+        self.log_snapshot(&self.snapshot(), None);
+    }
+
+    // For the periodic log: also report the throughput since `previous`,
+    // the snapshot the last tick returned, and return this tick's
+    pub fn log_stats_since(&self, previous: &StatsSnapshot) -> StatsSnapshot {
+        let snapshot = self.snapshot();
+        self.log_snapshot(&snapshot, Some(snapshot.throughput_since(previous)));
+        snapshot
+    }
+
+    // For `--stats-reset-interval`: log what was counted since the previous
+    // reset, then start counting from zero again
+    pub fn log_stats_and_reset(&self) {
+        // The period is the interval, so its average is the current rate
+        let snapshot = self.snapshot_and_reset();
+        self.log_snapshot(&snapshot, Some(snapshot.bytes_per_second()));
+        self.forget_destinations();
+    }
+
+    // The per-destination half of a reset: per-host, per-upstream-proxy and
+    // per-tenant stats, and the per-connection byte distribution
+    fn forget_destinations(&self) {
+        self.hosts.retain(|_, _| false);
+        self.upstream_proxies.retain(|_, _| false);
+        self.tenants.retain(|_, _| false);
+        self.connection_bytes.reset();
+    }
+
+    fn log_snapshot(&self, snapshot: &StatsSnapshot, current_throughput: Option<f64>) {
+        let level = self.stats_log_level;
+
+        log::log!(level, "📊 Proxy Statistics:");
+        log::log!(level, "   Uptime: {:?}", snapshot.uptime);
+        if snapshot.period < snapshot.uptime {
+            log::log!(level, "   Counted Since Last Reset: {:?}", snapshot.period);
+        }
+        log::log!(level, "   TCP Connections Accepted: {}", snapshot.tcp_connections_accepted);
+        log::log!(level, "   Active Connections: {}", snapshot.active_connections);
+        log::log!(level, "   Peak Active Connections: {} (limit {})", snapshot.peak_active_connections, MAX_CONNECTIONS);
+        log::log!(level, "   Bytes Transferred: {} ({:.2} MB)", snapshot.bytes_transferred, snapshot.megabytes_transferred());
+        log::log!(level, "   Bytes Client->Server: {}", snapshot.bytes_client_to_server);
+        log::log!(level, "   Bytes Server->Client: {}", snapshot.bytes_server_to_client);
+        log::log!(level, "   Download/Upload Ratio: {:.2}", snapshot.download_upload_ratio());
+        log::log!(level, "   Average Throughput: {:.2} KB/s", snapshot.bytes_per_second() / 1024.0);
+        if let Some(current) = current_throughput {
+            log::log!(level, "   Current Throughput: {:.2} MB/s", current / 1_048_576.0);
+        }
+        log::log!(level, "   Average Bytes/Connection: {:.0}", snapshot.avg_bytes_per_connection());
+        log::log!(level, "   HTTP Requests: {}", snapshot.http_requests);
+        log::log!(level, "   HTTPS Requests (CONNECT): {}", snapshot.https_requests);
+        log::log!(level, "   Requests/Connection: {:.2}", snapshot.requests_per_connection());
+        log::log!(level, "   Connection Errors: {}", snapshot.connection_errors);
+        log::log!(level, "   Auth Failures: {}", snapshot.auth_failures);
+        log::log!(level, "   Header Read Timeouts: {}", snapshot.header_timeouts);
+        log::log!(level, "   TLS Handshake Errors: {}", snapshot.tls_handshake_errors);
+        log::log!(level, "   Circuit Breaker Rejections: {}", snapshot.circuit_open_rejections);
+        log::log!(level, "   SSRF Blocks: {}", snapshot.blocked_ssrf);
+        log::log!(level, "   WebSocket Connections: {}", snapshot.websocket_connections);
+        log::log!(level, "   Coalesced Requests: {}", snapshot.coalesced_requests);
+        log::log!(level, "   Rate Limited Requests: {}", snapshot.rate_limited);
+        log::log!(level, "   Stale Responses Served: {}", snapshot.stale_responses);
+        log::log!(level, "   Listener Limit Rejections: {}", snapshot.listener_limit_rejections);
+        log::log!(level, "   Smuggling Attempts Blocked: {}", snapshot.smuggling_blocked);
+        log::log!(level, "   Forwarding Loops Detected: {}", snapshot.loop_detected);
+        log::log!(level, "   Maintenance Rejections: {}", snapshot.maintenance_rejections);
+        log::log!(level, "   Header Limit Rejections: {}", snapshot.header_limit_exceeded);
+        log::log!(level, "   Disabled Method Class Rejections: {}", snapshot.method_class_disabled);
+        log::log!(level, "   Tunnels Cut at Max Duration: {}", snapshot.tunnel_duration_exceeded);
+        log::log!(level, "   Slow Transfers Aborted: {}", snapshot.slow_transfer_aborted);
+        log::log!(level, "   Requests Cut at Request Timeout: {}", snapshot.request_timeout);
+        log::log!(level, "   Destination Overload Rejections: {}", snapshot.dest_overload);
+        log::log!(level, "   Tarpitted Blocks: {}", snapshot.tarpitted);
+        log::log!(
+            level,
+            "   Compressed Response Bodies: {} bytes ({} decompressed)",
+            snapshot.compressed_body_bytes,
+            snapshot.decompressed_body_bytes
+        );
+        log::log!(level, "   TLS SNI Seen: {} ({} mismatched)", snapshot.sni_seen, snapshot.sni_mismatches);
+        log::log!(level, "   TLS ALPN Blocks: {}", snapshot.alpn_blocked);
+        log::log!(level, "   Fail-Closed Denials: {}", snapshot.fail_closed_denied);
+        log::log!(level, "   Transparent Connections: {}", snapshot.transparent_connections);
+        log::log!(
+            level,
+            "   HTTP Responses: 1xx {}, 2xx {}, 3xx {}, 4xx {}, 5xx {}, invalid {}",
+            snapshot.resp_1xx,
+            snapshot.resp_2xx,
+            snapshot.resp_3xx,
+            snapshot.resp_4xx,
+            snapshot.resp_5xx,
+            snapshot.resp_invalid
+        );
+        let ssl = snapshot.ssl_errors;
+        log::log!(
+            level,
+            "   SSL/TLS Errors: {} (expired {}, self-signed {}, handshake {}, verify {}, revoked {}, unknown {})",
+            ssl.total(),
+            ssl.expired,
+            ssl.self_signed,
+            ssl.handshake_failed,
+            ssl.verify_failed,
+            ssl.revoked,
+            ssl.unknown
+        );
+
+        let top = self.top_destinations(self.top_hosts);
+        if !top.is_empty() {
+            log::log!(level, "   Top Destinations (by bytes):");
+            log_host_stats(level, top);
+        }
+
+        let tenants = self.top_tenants(self.top_hosts);
+        if !tenants.is_empty() {
+            log::log!(level, "   Top Tenants (by bytes):");
+            log_host_stats(level, tenants);
+        }
+
+        let mut upstream_proxies = self.upstream_proxies.entries();
+        if !upstream_proxies.is_empty() {
+            upstream_proxies.sort_by(|(a, _), (b, _)| a.cmp(b));
+            log::log!(level, "   Upstream Proxies:");
+            for (proxy, proxy_stats) in upstream_proxies {
+                log::log!(
+                    level,
+                    "     {} - {} tunnels, {} failures",
+                    proxy,
+                    proxy_stats.successes.load(Ordering::Relaxed),
+                    proxy_stats.failures.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+}
+
+fn top_by_bytes(map: &BoundedMap<HostStats>, n: usize) -> Vec<(String, Arc<HostStats>)> {
+    let mut entries = map.entries();
+    entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.bytes.load(Ordering::Relaxed)));
+    entries.truncate(n);
+    entries
+}
+
+fn log_host_stats(level: log::Level, entries: Vec<(String, Arc<HostStats>)>) {
+    for (key, entry) in entries {
+        log::log!(
+            level,
+            "     {} - {} bytes, {} connections, {} errors",
+            key,
+            entry.bytes.load(Ordering::Relaxed),
+            entry.connections.load(Ordering::Relaxed),
+            entry.errors.load(Ordering::Relaxed)
+        );
+    }
+}
+
+// Plain-data copy of the counters at one point in time
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct StatsSnapshot {
+    /// Same as `tcp_connections_accepted`, under the name existing users,
+    /// /stats.json and the stopped banner know it by
+    pub total_connections: u64,
+    pub tcp_connections_accepted: u64,
+    pub active_connections: usize,
+    pub peak_active_connections: usize,
+    pub bytes_transferred: u64,
+    pub bytes_client_to_server: u64,
+    pub bytes_server_to_client: u64,
+    pub http_requests: u64,
+    pub https_requests: u64,
+    pub connection_errors: u64,
+    pub auth_failures: u64,
+    pub header_timeouts: u64,
+    pub tls_handshake_errors: u64,
+    pub circuit_open_rejections: u64,
+    pub blocked_ssrf: u64,
+    pub websocket_connections: u64,
+    pub coalesced_requests: u64,
+    pub rate_limited: u64,
+    pub stale_responses: u64,
+    pub listener_limit_rejections: u64,
+    pub smuggling_blocked: u64,
+    pub loop_detected: u64,
+    pub maintenance_rejections: u64,
+    pub header_limit_exceeded: u64,
+    pub method_class_disabled: u64,
+    pub tunnel_duration_exceeded: u64,
+    pub slow_transfer_aborted: u64,
+    pub request_timeout: u64,
+    pub dest_overload: u64,
+    pub tarpitted: u64,
+    pub compressed_body_bytes: u64,
+    pub decompressed_body_bytes: u64,
+    pub sni_seen: u64,
+    pub sni_mismatches: u64,
+    pub alpn_blocked: u64,
+    pub fail_closed_denied: u64,
+    pub transparent_connections: u64,
+    pub resp_1xx: u64,
+    pub resp_2xx: u64,
+    pub resp_3xx: u64,
+    pub resp_4xx: u64,
+    pub resp_5xx: u64,
+    pub resp_invalid: u64,
+    pub ssl_errors: SslErrorCounts,
+    #[serde(rename = "uptime_secs", serialize_with = "serialize_secs")]
+    pub uptime: Duration,
+    /// Time the counters cover; equal to `uptime` until stats are reset
+    #[serde(rename = "period_secs", serialize_with = "serialize_secs")]
+    pub period: Duration,
+}
+
+fn serialize_secs<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+impl StatsSnapshot {
+    pub fn megabytes_transferred(&self) -> f64 {
+        self.bytes_transferred as f64 / 1_048_576.0
+    }
+
+    // Derived rates are 0.0 (never NaN/inf) when their denominator is zero
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.period.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_transferred as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    // Server->client bytes per client->server byte: above 1.0 the traffic
+    // is download-heavy, below it upload-heavy
+    pub fn download_upload_ratio(&self) -> f64 {
+        if self.bytes_client_to_server > 0 {
+            self.bytes_server_to_client as f64 / self.bytes_client_to_server as f64
+        } else {
+            0.0
+        }
+    }
+
+    // Bytes per second transferred between `previous` and this snapshot,
+    // both taken from the same (unreset) counters
+    pub fn throughput_since(&self, previous: &StatsSnapshot) -> f64 {
+        let bytes = self.bytes_transferred.saturating_sub(previous.bytes_transferred);
+        let secs = self.uptime.saturating_sub(previous.uptime).as_secs_f64();
+        if secs > 0.0 {
+            bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn avg_bytes_per_connection(&self) -> f64 {
+        if self.total_connections > 0 {
+            self.bytes_transferred as f64 / self.total_connections as f64
+        } else {
+            0.0
+        }
+    }
+
+    // Above 1 when persistent connections carry several requests
+    pub fn requests_per_connection(&self) -> f64 {
+        if self.tcp_connections_accepted > 0 {
+            (self.http_requests + self.https_requests) as f64 / self.tcp_connections_accepted as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.total_connections > 0 {
+            self.connection_errors as f64 / self.total_connections as f64
+        } else {
+            0.0
+        }
+    }
+}
 
-        info!("📊 Proxy Statistics:");
-        info!("   Uptime: {:?}", uptime);
-        info!("   Total Connections: {}", total_conn);
-        info!("   Active Connections: {}", active_conn);
-        info!("   Bytes Transferred: {} ({:.2} MB)", bytes, bytes as f64 / 1_048_576.0);
-        info!("   HTTP Requests: {}", http);
-        info!("   HTTPS Requests: {}", https);
-        info!("   Connection Errors: {}", errors);
+impl Default for ProxyStats {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version = version::LONG_VERSION, about, long_about = None)]
 pub struct Args {
     /// Host to listen on (default: 0.0.0.0)
     #[arg(long, default_value = "0.0.0.0")]
@@ -76,279 +751,2250 @@ pub struct Args {
     #[arg(short, long, default_value = "3129")]
     pub port: u16,
 
+    /// Tokio worker threads running connections (default: number of CPUs)
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+
+    /// Accept queue length for the listening socket (capped by the OS, e.g. net.core.somaxconn on Linux)
+    #[arg(long, default_value_t = DEFAULT_LISTEN_BACKLOG, value_parser = clap::value_parser!(u32).range(1..=i32::MAX as i64))]
+    pub listen_backlog: u32,
+
     /// Log level: debug, info, warn, error (default: info)
     #[arg(short, long, default_value = "info")]
     pub log_level: String,
+
+    /// Write logs to this file instead of stderr, rotating it by size
+    #[arg(long)]
+    pub log_file: Option<std::path::PathBuf>,
+
+    /// Rotate the log file once it would grow past this many bytes
+    #[arg(long, default_value_t = log_file::DEFAULT_LOG_MAX_SIZE, value_parser = clap::value_parser!(u64).range(1..), requires = "log_file")]
+    pub log_max_size: u64,
+
+    /// Number of rotated log files to keep (`<file>.1` is the newest)
+    #[arg(long, default_value_t = log_file::DEFAULT_LOG_KEEP, requires = "log_file")]
+    pub log_keep: usize,
+
+    /// Lifecycle banner format; json also prints started/stopped events on stdout
+    #[arg(long, value_enum, default_value_t = banner::BannerFormat::Text)]
+    pub banner_format: banner::BannerFormat,
+
+    /// Add an RFC 7239 `Forwarded` header to forwarded HTTP requests
+    #[arg(long)]
+    pub add_forwarded_headers: bool,
+
+    /// Add or append the client IP to X-Forwarded-For on forwarded HTTP requests
+    #[arg(long)]
+    pub add_xff: bool,
+
+    /// Append `Via: 1.1 rust_proxy` to forwarded HTTP requests and reject requests already carrying it (loop detection)
+    #[arg(long)]
+    pub append_via_header: bool,
+
+    /// Add `X-Proxy-Version` with this build's version and commit to forwarded HTTP requests
+    #[arg(long)]
+    pub version_header: bool,
+
+    /// Proxy IP whose Forwarded/X-Forwarded-For headers are trusted (repeatable)
+    #[arg(long = "trusted-proxy")]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Take every client's address from X-Forwarded-For for logs and per-IP limits (spoofable unless a proxy in front sets it)
+    #[arg(long)]
+    pub trust_proxy_headers: bool,
+
+    /// Which X-Forwarded-For entry --trust-proxy-headers uses
+    #[arg(long, value_enum, default_value_t = forwarded::XffPosition::Rightmost, requires = "trust_proxy_headers")]
+    pub xff_position: forwarded::XffPosition,
+
+    /// Require Basic proxy authentication with these credentials (user:pass)
+    #[arg(long, env = "PROXY_AUTH", hide_env_values = true)]
+    pub auth: Option<String>,
+
+    /// Number of top destinations (by bytes) to include in statistics output
+    #[arg(long, default_value_t = DEFAULT_TOP_HOSTS)]
+    pub top_hosts: usize,
+
+    /// Request header naming the tenant, e.g. X-Proxy-Tenant; keeps per-tenant statistics and is stripped before forwarding
+    #[arg(long)]
+    pub tenant_header: Option<String>,
+
+    /// Start in maintenance mode, answering every request with 503 until turned off via the control socket
+    #[arg(long)]
+    pub maintenance: bool,
+
+    /// Level statistics are logged at (error, warn, info, debug), independent of other logging
+    #[arg(long, default_value_t = log::Level::Info)]
+    pub stats_log_level: log::Level,
+
+    /// Log statistics every N seconds and then reset them, so each block shows that interval's deltas
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub stats_reset_interval: Option<u64>,
+
+    /// Total seconds allowed to receive a complete request header block
+    #[arg(long, default_value_t = CONNECT_TIMEOUT.as_secs())]
+    pub header_read_timeout: u64,
+
+    /// Most header lines accepted in a request; more get 431 Request Header Fields Too Large
+    #[arg(long, default_value_t = DEFAULT_MAX_HEADER_COUNT)]
+    pub max_header_count: usize,
+
+    /// Seconds a relayed connection may go without receiving data
+    #[arg(long, default_value_t = IDLE_TIMEOUT.as_secs())]
+    pub idle_timeout_secs: u64,
+
+    /// Close CONNECT tunnels that have been open this many seconds, even if active
+    #[arg(long)]
+    pub max_tunnel_duration: Option<u64>,
+
+    /// Seconds a plain-HTTP request may take from connecting upstream to the end of the response, however busy; 504 if nothing was sent yet
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub request_timeout: Option<u64>,
+
+    /// Most bytes relayed from upstreams to a client per connection (responses, downloads); 0 for unlimited
+    #[arg(long, default_value_t = MAX_DOWNLOAD_SIZE)]
+    pub max_download_size: u64,
+
+    /// Most bytes relayed from a client to upstreams per connection (request bodies, uploads); 0 for unlimited
+    #[arg(long, default_value_t = MAX_DOWNLOAD_SIZE)]
+    pub max_upload_size: u64,
+
+    /// Seconds a write to a slow peer may go without making progress
+    #[arg(long, default_value_t = IDLE_TIMEOUT.as_secs())]
+    pub write_timeout_secs: u64,
+
+    /// Abort a relayed transfer whose data arrives slower than this many bytes/sec over the window
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_throughput: Option<u64>,
+
+    /// Seconds of activity `--min-throughput` is measured over
+    #[arg(long, default_value_t = MIN_THROUGHPUT_WINDOW.as_secs(), value_parser = clap::value_parser!(u64).range(1..), requires = "min_throughput")]
+    pub min_throughput_window: u64,
+
+    /// Enable TCP keepalive on client and upstream sockets, probing after this many idle seconds (OS default when unset)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Leave Nagle's algorithm on for client and upstream sockets (fewer packets for bulk transfers, at some latency)
+    #[arg(long)]
+    pub nagle: bool,
+
+    /// Time out upstream connects at 4x each destination's average connect time (1s to 10s) instead of a flat 10s
+    #[arg(long)]
+    pub adaptive_connect_timeout: bool,
+
+    /// Open a per-upstream circuit breaker after this many consecutive connect failures
+    #[arg(long)]
+    pub cb_threshold: Option<u32>,
+
+    /// Seconds an open circuit breaker rejects connections before retrying
+    #[arg(long, default_value_t = 30)]
+    pub cb_cooldown: u64,
+
+    /// Log complete request header blocks at debug level (credentials redacted)
+    #[arg(long)]
+    pub log_headers: bool,
+
+    /// Read the SNI from each CONNECT tunnel's TLS ClientHello and warn when it differs from the target
+    #[arg(long)]
+    pub inspect_sni: bool,
+
+    /// Only allow CONNECT tunnels whose TLS ClientHello offers just these ALPN protocols (comma-separated, e.g. `http/1.1`)
+    #[arg(long, value_delimiter = ',')]
+    pub allow_alpn: Vec<String>,
+
+    /// Refuse CONNECT tunnels whose TLS ClientHello offers any of these ALPN protocols (comma-separated, e.g. `h2`)
+    #[arg(long, value_delimiter = ',')]
+    pub block_alpn: Vec<String>,
+
+    /// Deny instead of allow when a policy check can't be evaluated: malformed ALPN lists, DNS errors in the admin-listener check, ALPN tunnels without a ClientHello, unreadable transparent destinations
+    #[arg(long)]
+    pub fail_closed: bool,
+
+    /// Refuse targets resolving to loopback, private, link-local or ULA addresses
+    #[arg(long)]
+    pub deny_private_ranges: bool,
+
+    /// Share one upstream fetch between identical concurrent GET requests
+    #[arg(long)]
+    pub coalesce_gets: bool,
+
+    /// Local IP upstream connections originate from; give one IPv4 and/or one IPv6 address (repeatable)
+    #[arg(long)]
+    pub bind_outbound: Vec<IpAddr>,
+
+    /// DNS server (`ip[:port]`) used for all upstream name resolution instead of the system resolver
+    #[arg(long, value_parser = resolver::parse_resolver)]
+    pub resolver: Option<std::net::SocketAddr>,
+
+    /// Answer failed GETs with the last good response, marked stale, instead of an error
+    #[arg(long)]
+    pub serve_stale_on_error: bool,
+
+    /// Write the raw bytes of connections to this host:port into --capture-dir (repeatable; captures may hold credentials)
+    #[arg(long = "capture", value_parser = capture::parse_capture_target)]
+    pub capture: Vec<String>,
+
+    /// Directory for --capture files
+    #[arg(long, default_value = capture::DEFAULT_CAPTURE_DIR)]
+    pub capture_dir: std::path::PathBuf,
+
+    /// Most bytes captured per direction of each connection
+    #[arg(long, default_value_t = capture::DEFAULT_CAPTURE_MAX_BYTES)]
+    pub capture_max_bytes: u64,
+
+    /// Inflate a copy of gzip/deflate response bodies to count their decompressed size (forwarded bytes are unchanged)
+    #[arg(long)]
+    pub account_decompressed: bool,
+
+    /// Most bytes inflated per response body under --account-decompressed
+    #[arg(long, default_value_t = decompressed::DEFAULT_DECOMPRESS_MAX_BYTES, value_parser = clap::value_parser!(u64).range(1..), requires = "account_decompressed")]
+    pub account_decompressed_max: u64,
+
+    /// Body for the proxy's own 403/407/502/504 responses; {status}, {reason}, {host} and {request_id} are filled in
+    #[arg(long)]
+    pub error_template: Option<std::path::PathBuf>,
+
+    /// Reach every destination through this proxy's CONNECT tunnels; repeat for round-robin failover
+    #[arg(long = "upstream-proxy", value_parser = upstream_proxy::parse_upstream_proxy)]
+    pub upstream_proxies: Vec<UpstreamProxy>,
+
+    /// Reach every destination through this SOCKS5 proxy (host:port)
+    #[arg(long, value_parser = socks5::parse_socks5_proxy, conflicts_with = "upstream_proxies")]
+    pub upstream_socks5: Option<UpstreamProxy>,
+
+    /// Username and password for --upstream-socks5 (user:pass)
+    #[arg(long, value_parser = socks5::parse_socks5_auth, requires = "upstream_socks5")]
+    pub upstream_socks5_auth: Option<socks5::Socks5Credentials>,
+
+    /// Limit each client IP to this many requests per second (fractions allowed)
+    #[arg(long)]
+    pub rate_per_ip: Option<f64>,
+
+    /// Accept at most this many new connections per second across all listeners; the rest wait in the OS backlog
+    #[arg(long)]
+    pub accept_rate: Option<f64>,
+
+    /// Log requests to these hosts at debug instead of info (comma-separated, `*.` wildcards)
+    #[arg(long, value_delimiter = ',')]
+    pub quiet_hosts: Vec<String>,
+
+    /// Dial a destination at another address, as host:port=target:port (repeatable)
+    #[arg(long = "route", value_parser = routes::parse_route)]
+    pub routes: Vec<Route>,
+
+    /// JSON file of additional listeners, each with its own policy profile (one for the main address sets its policy); reloaded on SIGHUP
+    #[arg(long)]
+    pub listener_config: Option<std::path::PathBuf>,
+
+    /// Cap concurrent connections on one listener, as listen-address=count (repeatable)
+    #[arg(long, value_parser = parse_listener_limit)]
+    pub listen_max_connections: Vec<(std::net::SocketAddr, usize)>,
+
+    /// Shut down gracefully after running this long, e.g. 90s, 30m, 2h or 1h30m (default: run until signalled)
+    #[arg(long, value_parser = parse_duration)]
+    pub run_for: Option<Duration>,
+
+    /// Serve a single client connection to completion, then shut down (for scripted tests)
+    #[arg(long)]
+    pub one_shot: bool,
+
+    /// After no client connections for this long (e.g. 300 or 5m), mark the proxy draining: /healthz answers 503
+    #[arg(long, value_parser = parse_duration)]
+    pub drain_on_idle: Option<Duration>,
+
+    /// File to create when --drain-on-idle marks the proxy draining
+    #[arg(long, requires = "drain_on_idle")]
+    pub drain_marker: Option<std::path::PathBuf>,
+
+    /// Shut down gracefully once --drain-on-idle marks the proxy draining
+    #[arg(long, requires = "drain_on_idle")]
+    pub exit_on_idle: bool,
+
+    /// Cap concurrent tunnels and requests to any one host:port; extras wait briefly, then get 503
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_per_destination: Option<usize>,
+
+    /// Lowest port CONNECT may tunnel to
+    #[arg(long, default_value_t = 1)]
+    pub connect_port_min: u16,
+
+    /// Highest port CONNECT may tunnel to
+    #[arg(long, default_value_t = u16::MAX)]
+    pub connect_port_max: u16,
+
+    /// Port for CONNECT targets that don't name one, e.g. `CONNECT 10.0.0.5 HTTP/1.1`
+    #[arg(long, default_value_t = 443, value_parser = clap::value_parser!(u16).range(1..))]
+    pub default_connect_port: u16,
+
+    /// Seconds to wait before answering a request blocked by policy with 403, to slow down scans
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub block_tarpit: Option<u64>,
+
+    /// Most connections held in the --block-tarpit delay at once; further blocks are answered immediately
+    #[arg(long, default_value_t = tarpit::DEFAULT_TARPIT_MAX, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub block_tarpit_max: usize,
+
+    /// Refuse CONNECT tunnels with 405, serving plain HTTP only
+    #[arg(long)]
+    pub disable_https: bool,
+
+    /// Refuse plain-HTTP requests with 405, serving CONNECT tunnels only
+    #[arg(long)]
+    pub disable_http: bool,
+
+    /// Allow `CONNECT unix:/path` tunnels to local Unix domain sockets
+    #[cfg(unix)]
+    #[arg(long)]
+    pub allow_unix_sockets: bool,
+
+    /// Tunnel connections redirected here by iptables (REDIRECT/DNAT) to their original destination, read with SO_ORIGINAL_DST
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    pub transparent: bool,
+
+    /// PEM certificate chain; serve the proxy endpoint itself over TLS
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<std::path::PathBuf>,
+
+    /// Address for the admin listener serving /healthz (e.g. 127.0.0.1:9090)
+    #[arg(long)]
+    pub admin_addr: Option<std::net::SocketAddr>,
+
+    /// Make /healthz also require a TCP connect to this upstream (host:port)
+    #[arg(long, requires = "admin_addr")]
+    pub health_check_upstream: Option<String>,
+
+    /// Before accepting clients, connect to this host:port (through any upstream proxy) and exit if that fails
+    #[arg(long)]
+    pub probe_upstream: Option<String>,
+
+    /// Only warn when the --probe-upstream check fails
+    #[arg(long, requires = "probe_upstream")]
+    pub probe_warn_only: bool,
+
+    /// Unix socket path streaming newline-delimited JSON connection events
+    #[cfg(unix)]
+    #[arg(long)]
+    pub event_socket: Option<std::path::PathBuf>,
+
+    /// Send access logs to this syslog collector: host:port (UDP), udp://host:port or tcp://host:port
+    #[arg(long)]
+    pub syslog: Option<syslog::SyslogTarget>,
+
+    /// Syslog facility for access log messages
+    #[arg(long, value_enum, default_value_t = syslog::Facility::Local0, requires = "syslog")]
+    pub syslog_facility: syslog::Facility,
+
+    /// Unix socket path accepting runtime commands (set-log-level, stats, reset-stats, maintenance)
+    #[cfg(unix)]
+    #[arg(long)]
+    pub control_socket: Option<std::path::PathBuf>,
 }
 
-// Optimized function to find end of HTTP headers
-pub fn find_request_end(data: &[u8]) -> usize {
-    let mut i = 0;
-    while i + 3 < data.len() {
-        if data[i] == b'\r' && data[i + 1] == b'\n' &&
-           data[i + 2] == b'\r' && data[i + 3] == b'\n' {
-            return i + 4;
+// Runtime configuration shared by all connections
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub add_forwarded_headers: bool,
+    pub add_xff: bool,
+    pub append_via_header: bool,
+    pub version_header: bool,
+    pub trusted_proxies: Vec<IpAddr>,
+    /// X-Forwarded-For entry taken as the client address, when trusted
+    pub trust_proxy_headers: Option<forwarded::XffPosition>,
+    /// Expected `Proxy-Authorization` value when authentication is enforced
+    pub proxy_auth: Option<String>,
+    /// Request methods accepted, all when `None`
+    pub allowed_methods: Option<Vec<String>>,
+    /// Connection event publisher, when an event socket is configured
+    pub events: Option<EventBus>,
+    /// Deadline for the whole header block, not per read (slowloris guard)
+    pub header_read_timeout: Duration,
+    /// Header lines allowed per request (header-bomb guard)
+    pub max_header_count: usize,
+    /// Opens upstream connections, directly or through --upstream-socks5
+    pub dialer: Arc<dyn UpstreamDialer>,
+    /// Proxies to tunnel through instead of connecting directly, when set
+    pub upstream_proxies: Option<Arc<UpstreamProxies>>,
+    /// Permit `CONNECT unix:/path` targets (off by default: exposes local sockets)
+    pub allow_unix_sockets: bool,
+    /// Tunnel NAT-redirected connections to their original destination
+    /// (`--transparent`, Linux only)
+    pub transparent: bool,
+    /// Debug-log each parsed request header block
+    pub log_headers: bool,
+    /// Header whose value tags a request with a tenant for per-tenant stats
+    pub tenant_header: Option<String>,
+    /// While set, every request is answered 503 without connecting upstream.
+    /// Shared by all listeners and toggled by the control socket.
+    pub maintenance: Arc<AtomicBool>,
+    /// Set once `--drain-on-idle` finds the proxy idle; /healthz then
+    /// answers 503 so an autoscaler can remove the instance
+    pub draining: Arc<AtomicBool>,
+    /// Peek at the TLS ClientHello opening each CONNECT tunnel for its SNI
+    pub inspect_sni: bool,
+    /// ALPN protocols CONNECT tunnels may offer, when restricted; implies
+    /// peeking at the ClientHello
+    pub alpn_policy: Option<AlpnPolicy>,
+    /// Deny, rather than allow, when a policy check can't be evaluated
+    pub fail_closed: bool,
+    /// Read inactivity limit for relayed connections
+    pub idle_timeout: Duration,
+    /// Absolute lifetime of a CONNECT tunnel, when capped
+    pub max_tunnel_duration: Option<Duration>,
+    /// Budget for a whole plain-HTTP exchange (connect, request, response)
+    pub request_timeout: Option<Duration>,
+    /// Cap on server->client bytes, whatever the request method per connection (`u64::MAX` when unlimited)
+    pub max_download_size: u64,
+    /// Cap on client->server bytes per connection (`u64::MAX` when unlimited)
+    pub max_upload_size: u64,
+    /// Per-write progress limit, separate so slow consumers can be told
+    /// apart from idle ones
+    pub write_timeout: Duration,
+    /// Slowest rate a relayed transfer may sustain (slowloris guard)
+    pub min_throughput: Option<MinThroughput>,
+    /// TCP keepalive idle time for client and upstream sockets
+    pub tcp_keepalive: Option<Duration>,
+    /// Keep Nagle's algorithm rather than setting TCP_NODELAY
+    pub nagle: bool,
+    /// Scale each destination's connect timeout to its usual connect time
+    pub adaptive_connect_timeout: bool,
+    /// Fails fast for upstreams that keep refusing connections
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Refuse upstreams that resolve to internal addresses (SSRF guard)
+    pub deny_private_ranges: bool,
+    /// Name resolution for the SSRF and admin listener checks; `from_args`
+    /// also routes `dialer` through it
+    pub resolver: Resolver,
+    /// Ports CONNECT targets must fall within
+    pub connect_ports: RangeInclusive<u16>,
+    /// Port used for a CONNECT target without one
+    pub default_connect_port: u16,
+    /// Refuse CONNECT tunnels (`--disable-https`)
+    pub disable_https: bool,
+    /// Refuse plain-HTTP requests (`--disable-http`)
+    pub disable_http: bool,
+    /// Deduplicates identical in-flight GETs, when enabled
+    pub coalescer: Option<Arc<Coalescer>>,
+    /// Destinations whose per-request log lines drop to debug
+    pub quiet_hosts: HostMatcher,
+    /// Destinations dialed at a different address (`--route`)
+    pub routes: RouteMap,
+    /// The admin listener's address, which clients may never tunnel to
+    pub admin_addr: Option<std::net::SocketAddr>,
+    /// Per-client-IP request rate limit, when enabled
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Last good responses to fall back on, when serving stale is enabled
+    pub stale_store: Option<Arc<StaleStore>>,
+    /// Destinations whose traffic is written to disk (`--capture`)
+    pub capture: Option<Arc<Capture>>,
+    /// Per-body inflate limit, when counting decompressed sizes
+    /// (`--account-decompressed`)
+    pub account_decompressed: Option<u64>,
+    /// Body for the proxy's own error responses (`--error-template`)
+    pub error_template: Option<Arc<ErrorTemplate>>,
+    /// This listener's own concurrent connection cap, under the global one
+    pub connection_limit: Option<Arc<Semaphore>>,
+    /// Concurrent connections allowed to each destination, when capped
+    pub destination_limiter: Option<Arc<DestinationLimiter>>,
+    /// Delay for policy-blocked requests, when tarpitting (`--block-tarpit`)
+    pub block_tarpit: Option<Arc<Tarpit>>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            add_forwarded_headers: false,
+            add_xff: false,
+            append_via_header: false,
+            version_header: false,
+            trusted_proxies: Vec::new(),
+            trust_proxy_headers: None,
+            proxy_auth: None,
+            allowed_methods: None,
+            events: None,
+            header_read_timeout: CONNECT_TIMEOUT,
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            dialer: Arc::new(TcpDialer),
+            upstream_proxies: None,
+            allow_unix_sockets: false,
+            transparent: false,
+            log_headers: false,
+            tenant_header: None,
+            maintenance: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            inspect_sni: false,
+            alpn_policy: None,
+            fail_closed: false,
+            idle_timeout: IDLE_TIMEOUT,
+            max_tunnel_duration: None,
+            request_timeout: None,
+            max_download_size: MAX_DOWNLOAD_SIZE,
+            max_upload_size: MAX_DOWNLOAD_SIZE,
+            write_timeout: IDLE_TIMEOUT,
+            min_throughput: None,
+            tcp_keepalive: None,
+            nagle: false,
+            adaptive_connect_timeout: false,
+            circuit_breaker: None,
+            deny_private_ranges: false,
+            resolver: Resolver::System,
+            connect_ports: 1..=u16::MAX,
+            default_connect_port: 443,
+            disable_https: false,
+            disable_http: false,
+            coalescer: None,
+            quiet_hosts: HostMatcher::default(),
+            routes: RouteMap::default(),
+            admin_addr: None,
+            rate_limiter: None,
+            stale_store: None,
+            capture: None,
+            account_decompressed: None,
+            error_template: None,
+            connection_limit: None,
+            destination_limiter: None,
+            block_tarpit: None,
         }
-        i += 1;
     }
-    data.len()
 }
 
-// Optimized host:port parsing
-pub fn parse_host_port(url: &str, default_port: u16) -> (&str, u16) {
-    match url.split_once(':') {
-        Some((host, port_str)) => {
-            let port = port_str.parse::<u16>().unwrap_or(default_port);
-            (host, port)
+impl ProxyConfig {
+    pub fn from_args(args: &Args) -> Self {
+        // One resolver, so dialing and the SSRF check share its cache
+        let resolver = args.resolver.map(Resolver::dns).unwrap_or_default();
+        Self {
+            add_forwarded_headers: args.add_forwarded_headers,
+            add_xff: args.add_xff,
+            append_via_header: args.append_via_header,
+            version_header: args.version_header,
+            trusted_proxies: args.trusted_proxies.clone(),
+            trust_proxy_headers: args.trust_proxy_headers.then_some(args.xff_position),
+            proxy_auth: args.auth.as_deref().map(auth::basic_credentials),
+            allowed_methods: None,
+            events: None,
+            header_read_timeout: Duration::from_secs(args.header_read_timeout),
+            max_header_count: args.max_header_count,
+            dialer: {
+                let direct: Arc<dyn UpstreamDialer> = if args.bind_outbound.is_empty() {
+                    Arc::new(TcpDialer)
+                } else {
+                    Arc::new(BoundDialer::new(&args.bind_outbound))
+                };
+                let direct: Arc<dyn UpstreamDialer> = match resolver {
+                    Resolver::System => direct,
+                    Resolver::Dns { .. } => Arc::new(ResolvingDialer::new(resolver.clone(), direct)),
+                };
+                match &args.upstream_socks5 {
+                    Some(proxy) => Arc::new(Socks5Dialer::new(proxy.clone(), args.upstream_socks5_auth.clone(), direct)),
+                    None => direct,
+                }
+            },
+            upstream_proxies: (!args.upstream_proxies.is_empty())
+                .then(|| Arc::new(UpstreamProxies::new(args.upstream_proxies.clone()))),
+            #[cfg(unix)]
+            allow_unix_sockets: args.allow_unix_sockets,
+            #[cfg(not(unix))]
+            allow_unix_sockets: false,
+            #[cfg(target_os = "linux")]
+            transparent: args.transparent,
+            #[cfg(not(target_os = "linux"))]
+            transparent: false,
+            log_headers: args.log_headers,
+            tenant_header: args.tenant_header.clone(),
+            maintenance: Arc::new(AtomicBool::new(args.maintenance)),
+            draining: Arc::new(AtomicBool::new(false)),
+            inspect_sni: args.inspect_sni,
+            alpn_policy: AlpnPolicy::new(&args.allow_alpn, &args.block_alpn),
+            fail_closed: args.fail_closed,
+            idle_timeout: Duration::from_secs(args.idle_timeout_secs),
+            max_tunnel_duration: args.max_tunnel_duration.map(Duration::from_secs),
+            request_timeout: args.request_timeout.map(Duration::from_secs),
+            max_download_size: size_limit(args.max_download_size),
+            max_upload_size: size_limit(args.max_upload_size),
+            write_timeout: Duration::from_secs(args.write_timeout_secs),
+            min_throughput: args.min_throughput.map(|bytes_per_sec| MinThroughput {
+                bytes_per_sec,
+                window: Duration::from_secs(args.min_throughput_window),
+            }),
+            tcp_keepalive: args.tcp_keepalive.map(Duration::from_secs),
+            nagle: args.nagle,
+            adaptive_connect_timeout: args.adaptive_connect_timeout,
+            circuit_breaker: args.cb_threshold.map(|threshold| {
+                Arc::new(CircuitBreaker::new(
+                    threshold,
+                    CIRCUIT_BREAKER_WINDOW,
+                    Duration::from_secs(args.cb_cooldown),
+                    MAX_TRACKED_HOSTS,
+                ))
+            }),
+            deny_private_ranges: args.deny_private_ranges,
+            resolver,
+            connect_ports: args.connect_port_min..=args.connect_port_max,
+            default_connect_port: args.default_connect_port,
+            disable_https: args.disable_https,
+            disable_http: args.disable_http,
+            coalescer: args.coalesce_gets.then(|| Arc::new(Coalescer::new(COALESCE_WAIT_TIMEOUT))),
+            quiet_hosts: HostMatcher::new(&args.quiet_hosts),
+            routes: RouteMap::new(args.routes.iter().cloned()),
+            admin_addr: args.admin_addr,
+            rate_limiter: args.rate_per_ip.map(|rate| Arc::new(RateLimiter::new(rate, MAX_TRACKED_CLIENTS))),
+            stale_store: args.serve_stale_on_error.then(|| Arc::new(StaleStore::new(MAX_STALE_ENTRIES))),
+            capture: (!args.capture.is_empty())
+                .then(|| Arc::new(Capture::new(args.capture.clone(), args.capture_dir.clone(), args.capture_max_bytes))),
+            account_decompressed: args.account_decompressed.then_some(args.account_decompressed_max),
+            // Read from disk, so main loads it and reports a bad file
+            error_template: None,
+            // Belongs to a listener, so main assigns it per listener
+            connection_limit: None,
+            destination_limiter: args
+                .max_per_destination
+                .map(|limit| Arc::new(DestinationLimiter::new(limit, DEST_LIMIT_WAIT, MAX_TRACKED_HOSTS))),
+            block_tarpit: args
+                .block_tarpit
+                .map(|secs| Arc::new(Tarpit::new(Duration::from_secs(secs), args.block_tarpit_max))),
         }
-        None => (url, default_port)
     }
-}
 
-// Function to analyze connection errors for SSL/TLS certificate issues
-fn analyze_ssl_error(host: &str, port: u16, error: &std::io::Error) {
-    let error_str = error.to_string().to_lowercase();
-    let error_display = error.to_string();
-
-    // Common SSL/TLS certificate error patterns
-    let ssl_cert_indicators = [
-        "certificate",
-        "cert",
-        "tls",
-        "ssl",
-        "handshake",
-        "verification",
-        "expired",
-        "self-signed",
-        "untrusted",
-        "certificate chain",
-        "certificate verify",
-        "certificate has expired",
-        "certificate not yet valid",
-        "certificate revoked",
-        "certificate signature",
-        "certificate authority",
-        "ca",
-        "unknown ca",
-        "unable to get local issuer",
-        "issuer certificate",
-        "root certificate",
-    ];
-
-    let is_ssl_related = ssl_cert_indicators.iter().any(|indicator| error_str.contains(indicator));
-
-    if is_ssl_related {
-        warn!("🔒 SSL/TLS Certificate Issue Detected");
-        warn!("   Target: {}:{}", host, port);
-        warn!("   Error: {}", error_display);
-
-        // Provide specific guidance based on error type
-        if error_str.contains("expired") {
-            warn!("   Cause: Certificate has expired");
-            warn!("   Action: Update certificate on target server");
-        } else if error_str.contains("self-signed") || error_str.contains("untrusted") {
-            warn!("   Cause: Certificate is self-signed or untrusted");
-            warn!("   Action: Add certificate to trust store or use valid certificate");
-        } else if error_str.contains("handshake") {
-            warn!("   Cause: TLS handshake failed");
-            warn!("   Action: Check certificate compatibility and TLS version");
-        } else if error_str.contains("verify") {
-            warn!("   Cause: Certificate verification failed");
-            warn!("   Action: Check certificate chain and CA trust");
-        } else if error_str.contains("revoked") {
-            warn!("   Cause: Certificate has been revoked");
-            warn!("   Action: Renew certificate with new signing");
+    // How long to wait for a connection to the destination `host` tracks
+    pub fn connect_timeout_for(&self, host: &HostStats) -> Duration {
+        if self.adaptive_connect_timeout {
+            host.adaptive_connect_timeout(MIN_ADAPTIVE_CONNECT_TIMEOUT, CONNECT_TIMEOUT)
         } else {
-            warn!("   Cause: Unknown SSL/TLS certificate issue");
-            warn!("   Action: Investigate certificate validity and trust");
+            CONNECT_TIMEOUT
         }
+    }
 
-        // Additional context for VPN scenarios
-        if cfg!(windows) {
-            info!("   Note: VPN routing may affect certificate validation");
-            info!("   Consider: Certificate might be valid but blocked by VPN policy");
+    // Level for the per-request log line, lowered for quiet hosts
+    pub fn request_log_level(&self, host: &str) -> log::Level {
+        if self.quiet_hosts.matches(host) {
+            log::Level::Debug
+        } else {
+            log::Level::Info
         }
     }
-}
 
-pub async fn handle_client(mut client_socket: TcpStream, stats: Arc<ProxyStats>) -> Result<(), ProxyError> {
-    // Configure socket options for better performance
-    client_socket.set_nodelay(true)?;
-
-    let client_addr = client_socket.peer_addr()?;
-    stats.total_connections.fetch_add(1, Ordering::Relaxed);
-    stats.active_connections.fetch_add(1, Ordering::Relaxed);
-    debug!("Handling client connection from: {}", client_addr);
+    pub fn copy_limits(&self) -> CopyLimits {
+        CopyLimits {
+            max_download_size: self.max_download_size,
+            max_upload_size: self.max_upload_size,
+            idle_timeout: self.idle_timeout,
+            write_timeout: self.write_timeout,
+            max_duration: self.max_tunnel_duration,
+            min_throughput: self.min_throughput,
+        }
+    }
+}
 
-    let mut buffer = vec![0; BUFFER_SIZE];
-    let bytes_read = timeout(CONNECT_TIMEOUT, client_socket.read(&mut buffer)).await??;
+// A `--max-download-size`/`--max-upload-size` value, where 0 lifts the cap
+fn size_limit(bytes: u64) -> u64 {
+    if bytes == 0 { u64::MAX } else { bytes }
+}
 
-    if bytes_read == 0 {
-        return Ok(());
+// Parse a `--listen-max-connections` value: `127.0.0.1:3128=500`
+fn parse_listener_limit(value: &str) -> Result<(std::net::SocketAddr, usize), String> {
+    let (addr, limit) = value.rsplit_once('=').ok_or("expected <listen-address>=<count>")?;
+    let addr = addr.parse().map_err(|e| format!("invalid listen address {}: {}", addr, e))?;
+    match limit.parse::<usize>() {
+        Ok(limit) if limit > 0 => Ok((addr, limit)),
+        _ => Err(format!("invalid connection count {}", limit)),
     }
+}
 
-    // Find end of headers more efficiently
-    let request_end = find_request_end(&buffer[..bytes_read]);
-    if request_end == 0 {
-        return Ok(());
+// Parse a human-readable duration: one or more `<number><unit>` parts with
+// units d, h, m, s (`2h`, `1h30m`); a bare number is seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {}: expected e.g. 90s, 30m, 2h or 1h30m", value);
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()).ok_or_else(invalid);
     }
-
-    let request = String::from_utf8_lossy(&buffer[..request_end]);
-    let first_line = request.lines().next().ok_or("Empty request")?;
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-
-    if parts.len() < 3 {
-        return Ok(());
+    let mut total: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = match &rest[digits..digits + 1] {
+            "d" => 86_400,
+            "h" => 3_600,
+            "m" => 60,
+            "s" => 1,
+            _ => return Err(invalid()),
+        };
+        total = amount.checked_mul(unit).and_then(|secs| total.checked_add(secs)).ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
     }
+    Some(Duration::from_secs(total)).filter(|d| !d.is_zero()).ok_or_else(invalid)
+}
+
+// Optimized function to find end of HTTP headers
+pub fn find_request_end(data: &[u8]) -> usize {
+    find_header_terminator(data).unwrap_or(data.len())
+}
 
-    let method = parts[0];
-    let url = parts[1];
+// `find_request_end` together with the number of header lines before it,
+// in one pass. The request line and the empty line ending the block aren't
+// counted, nor is a trailing partial line when the block is incomplete.
+pub fn scan_request_head(data: &[u8]) -> (usize, usize) {
+    let mut scan = HeadScan::default();
+    (scan.advance(data).unwrap_or(data.len()), scan.header_lines())
+}
 
-    if method.eq_ignore_ascii_case("CONNECT") {
-        // HTTPS request
-        let (host, port) = parse_host_port(url, 443);
-        stats.https_requests.fetch_add(1, Ordering::Relaxed);
-        info!("HTTPS CONNECT request to {}:{}", host, port);
+// Incremental form of `scan_request_head` for a header block arriving over
+// several reads: each call scans only the bytes added since the last one,
+// jumping between line feeds with memchr, so every byte of the head is
+// looked at once however it is split.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeadScan {
+    scanned: usize,
+    line_ends: usize,
+    last_line_end: Option<usize>,
+    end: Option<usize>,
+}
 
-        match timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
-            Ok(Ok(remote)) => {
-                debug!("Connected to {}:{}", host, port);
-                client_socket.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
-                tunnel_fast(client_socket, remote, stats.clone()).await?;
-            }
-            Ok(Err(e)) => {
-                // Analyze for SSL certificate issues
-                analyze_ssl_error(host, port, &e);
-                stats.connection_errors.fetch_add(1, Ordering::Relaxed);
-                warn!("Failed to connect to {}:{} - {}", host, port, e);
-                client_socket.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
-            }
-            Err(_) => {
-                stats.connection_errors.fetch_add(1, Ordering::Relaxed);
-                warn!("Timeout connecting to {}:{}", host, port);
-                client_socket.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
-            }
+impl HeadScan {
+    // Scan `data`, which extends what was passed before, and return the end
+    // of the header block once its terminator has been seen
+    pub fn advance(&mut self, data: &[u8]) -> Option<usize> {
+        if self.end.is_some() {
+            return self.end;
         }
-    } else {
-        // HTTP request
-        let parsed_url = Url::parse(url)?;
-        let scheme = parsed_url.scheme();
-        let host = parsed_url.host_str().ok_or("No host found")?;
-        let port = parsed_url.port().unwrap_or(if scheme == "https" { 443 } else { 80 });
-        stats.http_requests.fetch_add(1, Ordering::Relaxed);
-        info!("HTTP {} request to {}://{}:{}", method, scheme, host, port);
-
-        match timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
-            Ok(Ok(mut remote)) => {
-                remote.set_nodelay(true)?;
-                debug!("Connected to {}://{}:{}", scheme, host, port);
-
-                // Send the original request
-                remote.write_all(&buffer[..bytes_read]).await?;
-                tunnel_fast(client_socket, remote, stats.clone()).await?;
+        for lf in memchr::memchr_iter(b'\n', &data[self.scanned..]).map(|i| i + self.scanned) {
+            if lf == 0 || data[lf - 1] != b'\r' {
+                continue;
             }
-            Ok(Err(e)) => {
-                // Analyze for SSL certificate issues for HTTPS URLs
-                if scheme == "https" {
-                    analyze_ssl_error(host, port, &e);
-                }
-                stats.connection_errors.fetch_add(1, Ordering::Relaxed);
-                warn!("Failed to connect to {}://{}:{} - {}", scheme, host, port, e);
-                client_socket.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
-            }
-            Err(_) => {
-                stats.connection_errors.fetch_add(1, Ordering::Relaxed);
-                warn!("Timeout connecting to {}://{}:{}", scheme, host, port);
-                client_socket.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            let line_end = lf - 1;
+            if self.last_line_end.is_some_and(|last| last + 2 == line_end) {
+                self.end = Some(lf + 1);
+                return self.end;
             }
+            self.line_ends += 1;
+            self.last_line_end = Some(line_end);
         }
+        self.scanned = data.len();
+        None
     }
 
-    // Cleanup: decrement active connections counter
-    stats.active_connections.fetch_sub(1, Ordering::Relaxed);
-    Ok(())
+    // Header lines seen so far, not counting the request line or a partial
+    // line still being received
+    pub fn header_lines(&self) -> usize {
+        self.line_ends.saturating_sub(1)
+    }
 }
 
-async fn tunnel_fast(mut src: TcpStream, mut dst: TcpStream, stats: Arc<ProxyStats>) -> Result<(), ProxyError> {
-    // Configure both sockets for better performance
-    src.set_nodelay(true)?;
-    dst.set_nodelay(true)?;
+// Bind the proxy listener with an explicit accept backlog. Built by hand
+// because `TcpListener::bind` doesn't expose the backlog; SO_REUSEADDR is set
+// on Unix to match it, so restarts don't trip over TIME_WAIT sockets.
+pub fn bind_listener(addr: std::net::SocketAddr, backlog: u32) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
 
-    // Get addresses for error reporting before splitting
-    let src_addr = src.peer_addr().map(|a| a.to_string()).ok();
-    let dst_addr = dst.peer_addr().map(|a| a.to_string()).ok();
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
 
-    let (mut src_reader, mut src_writer) = src.split();
-    let (mut dst_reader, mut dst_writer) = dst.split();
+// Bind a Unix socket for a local endpoint (`--event-socket`,
+// `--control-socket`). A socket left at `path` by a previous run would make
+// bind fail, so it is removed first; anything else there is an error rather
+// than something to delete, so a mistyped path can't remove a log file.
+#[cfg(unix)]
+pub fn bind_unix_listener(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
 
-    // Stream data with size limits and idle timeout
-    let stats_clone = stats.clone();
-    let client_to_server = bounded_copy_with_stats(
-        &mut src_reader, &mut dst_writer, MAX_DOWNLOAD_SIZE, IDLE_TIMEOUT,
-        src_addr.as_deref(), dst_addr.as_deref(), "client->server", stats_clone
-    );
-    let stats_clone = stats.clone();
-    let server_to_client = bounded_copy_with_stats(
-        &mut dst_reader, &mut src_writer, MAX_DOWNLOAD_SIZE, IDLE_TIMEOUT,
-        dst_addr.as_deref(), src_addr.as_deref(), "server->client", stats_clone
-    );
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            let message = format!("{} already exists and is not a socket", path.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, message));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    tokio::net::UnixListener::bind(path)
+}
 
-    tokio::try_join!(client_to_server, server_to_client)?;
-    Ok(())
+// Position just past the `\r\n\r\n` terminator, if the header block is
+// complete. memchr skips straight from one `\r` to the next, so only those
+// positions are checked for the full sequence.
+pub fn find_header_terminator(data: &[u8]) -> Option<usize> {
+    memchr::memchr_iter(b'\r', data)
+        .find(|&i| data[i..].starts_with(b"\r\n\r\n"))
+        .map(|i| i + 4)
 }
 
-// Copy with size limits and statistics tracking
-pub async fn bounded_copy_with_stats<R, W>(
-    mut reader: R,
-    mut writer: W,
-    max_size: u64,
-    idle_timeout: Duration,
-    _src_addr: Option<&str>,
-    _dst_addr: Option<&str>,
-    direction: &str,
-    stats: Arc<ProxyStats>,
-) -> Result<(), ProxyError>
-where
-    R: AsyncReadExt + Unpin,
-    W: AsyncWriteExt + Unpin,
-{
-    let mut transferred = 0u64;
-    let mut buffer = vec![0; BUFFER_SIZE];
+// A peer address in the form it is logged and keyed by. Dual-stack
+// listeners see IPv4 clients as `::ffff:a.b.c.d`; those become plain IPv4 so
+// one client looks the same on every listener. Other IPv6 addresses keep
+// their scope ID, shown as `[fe80::1%2]:port`, and drop the flow label,
+// which only varies per connection.
+pub fn normalize_peer_addr(addr: std::net::SocketAddr) -> std::net::SocketAddr {
+    match addr {
+        std::net::SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => std::net::SocketAddr::new(v4.into(), v6.port()),
+            None => std::net::SocketAddrV6::new(*v6.ip(), v6.port(), 0, v6.scope_id()).into(),
+        },
+        v4 => v4,
+    }
+}
 
-    loop {
-        let read_result = timeout(idle_timeout, reader.read(&mut buffer)).await;
+// Optimized host:port parsing
+pub fn parse_host_port(url: &str, default_port: u16) -> (&str, u16) {
+    match url.split_once(':') {
+        Some((host, port_str)) => {
+            let port = port_str.parse::<u16>().unwrap_or(default_port);
+            (host, port)
+        }
+        None => (url, default_port)
+    }
+}
 
-        match read_result {
-            Ok(Ok(0)) => break, // EOF
-            Ok(Ok(n)) => {
-                transferred += n as u64;
-                stats.bytes_transferred.fetch_add(n as u64, Ordering::Relaxed);
+pub async fn handle_client<S: AsyncReadWrite>(
+    mut client_socket: S,
+    stats: Arc<ProxyStats>,
+    config: Arc<ProxyConfig>,
+) -> Result<(), ProxyError> {
+    let client_addr = normalize_peer_addr(client_socket.peer_addr()?);
 
-                if transferred > max_size {
-                    warn!("Download size limit exceeded: {} bytes", transferred);
-                    return Err("Download size limit exceeded".into());
+    // Configure socket options for better performance. The connection works
+    // without them, so a failure here is not worth dropping it over.
+    if !config.nagle {
+        if let Err(e) = client_socket.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY for client {}: {}", client_addr, e);
+        }
+    }
+    if let Some(idle) = config.tcp_keepalive {
+        if let Err(e) = client_socket.set_keepalive(idle) {
+            warn!("Failed to enable TCP keepalive for client {}: {}", client_addr, e);
+        }
+    }
+    stats.tcp_connections_accepted.fetch_add(1, Ordering::Relaxed);
+    let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let _active = ActiveConnectionGuard::new(&stats, conn_id);
+    // Held for the life of the connection, like the global permit
+    let _listener_permit = match &config.connection_limit {
+        Some(limit) => match limit.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                stats.listener_limit_rejections.fetch_add(1, Ordering::Relaxed);
+                warn!("[#{}] Listener connection limit reached, rejecting {}", conn_id, client_addr);
+                client_socket.write_all(SERVICE_UNAVAILABLE_RESPONSE).await?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    debug!("[#{}] Handling client connection from: {}", conn_id, client_addr);
+    let mut conn_events = ConnectionEvents::new(config.events.clone(), client_addr.to_string());
+    let _size = ConnectionSizeRecorder { histogram: &stats.connection_bytes, bytes: conn_events.bytes.clone() };
+
+    if config.transparent {
+        match client_socket.original_dst() {
+            Ok(Some(target)) => {
+                return tunnel_transparent(conn_id, client_socket, client_addr, target, &config, &stats, &mut conn_events).await;
+            }
+            Ok(None) => {}
+            Err(e) if config.fail_closed => {
+                stats.fail_closed_denied.fetch_add(1, Ordering::Relaxed);
+                warn!("[#{}] Closed connection from {} (can't tell whether it was redirected, failing closed: {})", conn_id, client_addr, e);
+                return Ok(());
+            }
+            Err(e) => debug!("[#{}] No original destination for {} ({}), serving it as a proxy client", conn_id, client_addr, e),
+        }
+    }
+
+    let mut buffer = BUFFER_POOL.get();
+    let mut bytes_read = 0;
+    let mut meter = keep_alive::ConnectionMeter::new(conn_id, config.copy_limits());
+    // One iteration per request. Only plain-HTTP exchanges relayed by
+    // `keep_alive` come back around; every other path ends the connection.
+    for request_number in 1u64.. {
+        // Accumulate until the header block is complete, bounding the total time
+        // rather than each read so a trickling client can't hold the slot open.
+        // Pipelined bytes left over from the previous request count as read.
+        let header_deadline = tokio::time::Instant::now() + config.header_read_timeout;
+        let mut scan = HeadScan::default();
+        while bytes_read < buffer.len() && scan.advance(&buffer[..bytes_read]).is_none() {
+            match tokio::time::timeout_at(header_deadline, client_socket.read(&mut buffer[bytes_read..])).await {
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => bytes_read += n,
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) if request_number > 1 && bytes_read == 0 => {
+                    debug!("[#{}] Idle persistent connection from {} timed out", conn_id, client_addr);
+                    conn_events.relay_closed(CloseReason::IdleTimeout);
+                    return Ok(());
                 }
+                Err(_) => {
+                    stats.header_timeouts.fetch_add(1, Ordering::Relaxed);
+                    warn!("[#{}] Timed out reading request headers from {} ({} bytes received)", conn_id, client_addr, bytes_read);
+                    client_socket.write_all(REQUEST_TIMEOUT_RESPONSE).await?;
+                    return Ok(());
+                }
+            }
+        }
 
-                let write_result = timeout(idle_timeout, writer.write_all(&buffer[..n])).await;
-                match write_result {
-                    Ok(Ok(_)) => {}
+        if bytes_read == 0 {
+            // Connected (or finished the previous request) and closed without
+            // sending anything
+            if request_number > 1 {
+                relay_closed(conn_id, &mut conn_events, CloseReason::Eof);
+            }
+            return Ok(());
+        }
+        if request_number > 1 {
+            debug!("[#{}] Request {} on persistent connection from {}", conn_id, request_number, client_addr);
+        }
+
+        // Never 0 here: without a terminator it is `bytes_read`, and a block
+        // that starts with `\r\n\r\n` ends at 4 with an empty request line,
+        // which is answered with a 400 below rather than dropped
+        let request_end = scan.advance(&buffer[..bytes_read]).unwrap_or(bytes_read);
+        let header_lines = scan.header_lines();
+        if header_lines > config.max_header_count {
+            stats.header_limit_exceeded.fetch_add(1, Ordering::Relaxed);
+            warn!("[#{}] Rejected request from {} with {} header lines (limit {})", conn_id, client_addr, header_lines, config.max_header_count);
+            client_socket.write_all(HEADER_FIELDS_TOO_LARGE_RESPONSE).await?;
+            return Ok(());
+        }
+        let request = String::from_utf8_lossy(&buffer[..request_end]);
+        let first_line = request.lines().next().unwrap_or("");
+        let parts: Vec<&str> = first_line.split_whitespace().collect();
+
+        if parts.len() < 3 {
+            warn!("[#{}] Malformed request line from {} ({} bytes)", conn_id, client_addr, first_line.len());
+            client_socket.write_all(BAD_REQUEST_RESPONSE).await?;
+            return Ok(());
+        }
+
+        let method = parts[0];
+        let url = parts[1];
+        let mut head = RequestHead::parse(&buffer[..request_end]).ok_or(ProxyErrorKind::MalformedRequest)?;
+        // Who the request is logged and limited as: the peer, unless proxy
+        // headers are trusted and name someone else
+        let forwarded_for = config.trust_proxy_headers.and_then(|position| forwarded::xff_client(&head, position));
+        let client_ip = forwarded_for.unwrap_or(client_addr.ip());
+        let client = match forwarded_for {
+            Some(ip) => {
+                debug!("[#{}] Client {} forwarded by {}", conn_id, ip, client_addr);
+                conn_events.set_client(ip.to_string());
+                ip.to_string()
+            }
+            None => client_addr.to_string(),
+        };
+        if config.log_headers {
+            debug!("[#{}] Request headers from {}:\n{}", conn_id, client, head.to_redacted_string());
+        }
+
+        if config.maintenance.load(Ordering::Relaxed) {
+            stats.maintenance_rejections.fetch_add(1, Ordering::Relaxed);
+            info!("[#{}] Rejected {} {} from {} (maintenance mode)", conn_id, method, url, client);
+            let response = format!(
+                "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nRetry-After: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                MAINTENANCE_RETRY_AFTER,
+                MAINTENANCE_BODY.len(),
+                MAINTENANCE_BODY
+            );
+            client_socket.write_all(response.as_bytes()).await?;
+            return Ok(());
+        }
+
+        if let Some(limiter) = &config.rate_limiter {
+            if !limiter.check(client_ip) {
+                stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                warn!("[#{}] Rate limit exceeded for {}", conn_id, client_ip);
+                client_socket.write_all(TOO_MANY_REQUESTS_RESPONSE).await?;
+                return Ok(());
+            }
+        }
+
+        if let Some(expected) = &config.proxy_auth {
+            if !auth::is_authorized(&head, expected) {
+                stats.auth_failures.fetch_add(1, Ordering::Relaxed);
+                warn!("[#{}] Proxy authentication failed for {}", conn_id, client);
+                let details = ErrorDetails { request_id: Some(conn_id), ..Default::default() };
+                send_error(&mut client_socket, &config, auth::PROXY_AUTH_REQUIRED, details).await?;
+                return Ok(());
+            }
+            // The credential is for this hop only; never forward it upstream
+            head.remove("Proxy-Authorization");
+        }
+
+        // Like the credential, the tenant tag is meant for this proxy only
+        let tenant_stats = config.tenant_header.as_deref().and_then(|name| {
+            let tenant = head.get(name).map(str::trim).filter(|tenant| !tenant.is_empty()).map(|tenant| stats.tenant(tenant));
+            head.remove(name);
+            tenant
+        });
+        if let Some(tenant) = &tenant_stats {
+            tenant.connections.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(allowed) = &config.allowed_methods {
+            if !allowed.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+                warn!("[#{}] Rejected {} from {} (method not allowed on this listener)", conn_id, method, client);
+                client_socket.write_all(METHOD_NOT_ALLOWED_RESPONSE).await?;
+                return Ok(());
+            }
+        }
+        let is_connect = method.eq_ignore_ascii_case("CONNECT");
+        if (is_connect && config.disable_https) || (!is_connect && config.disable_http) {
+            stats.method_class_disabled.fetch_add(1, Ordering::Relaxed);
+            let class = if is_connect { "HTTPS" } else { "HTTP" };
+            warn!("[#{}] Rejected {} from {} ({} proxying is disabled)", conn_id, method, client, class);
+            client_socket.write_all(METHOD_NOT_ALLOWED_RESPONSE).await?;
+            return Ok(());
+        }
+
+        let unix_socket_path = url.strip_prefix("unix:").filter(|_| is_connect);
+
+        if let Some(path) = unix_socket_path {
+            // Tunnel to a local Unix domain socket
+            stats.https_requests.fetch_add(1, Ordering::Relaxed);
+            info!("[#{}] CONNECT request to Unix socket {}", conn_id, path);
+
+            if !config.allow_unix_sockets {
+                warn!("[#{}] Rejected CONNECT to Unix socket {} (not enabled)", conn_id, path);
+                let failure = ConnectFailure::Blocked("Unix socket tunnels are not enabled");
+                send_connect_failure(&mut client_socket, &config, &stats, conn_id, url, failure).await?;
+            } else {
+                let host_stats = stats.host(url);
+                host_stats.connections.fetch_add(1, Ordering::Relaxed);
+
+                match timeout(CONNECT_TIMEOUT, dialer::connect_unix(path)).await {
+                    Ok(Ok(remote)) => {
+                        debug!("[#{}] Connected to Unix socket {}", conn_id, path);
+                        conn_events.established(method, url.to_string());
+                        client_socket.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+                        let counters = ByteCounters { host: Some(&host_stats), connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() };
+                        let client_peer = client_addr.to_string();
+                        let outcome = tunnel_fast(conn_id, client_socket, remote, Some(&client_peer), Some(url), stats.clone(), counters, config.copy_limits()).await;
+                        relay_closed(conn_id, &mut conn_events, outcome.reason);
+                    }
                     Ok(Err(e)) => {
-                        debug!("Write error in {}: {}", direction, e);
-                        return Err("Write error".into());
+                        stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+                        count_upstream_error(&host_stats, tenant_stats.as_deref());
+                        warn!("[#{}] Failed to connect to Unix socket {} - {}", conn_id, path, e);
+                        send_connect_failure(&mut client_socket, &config, &stats, conn_id, url, ConnectFailure::classify(&e)).await?;
                     }
                     Err(_) => {
-                        warn!("Write timeout in {}", direction);
-                        return Err("Write timeout".into());
+                        stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+                        count_upstream_error(&host_stats, tenant_stats.as_deref());
+                        warn!("[#{}] Timeout connecting to Unix socket {}", conn_id, path);
+                        send_connect_failure(&mut client_socket, &config, &stats, conn_id, url, ConnectFailure::TimedOut).await?;
+                    }
+                }
+            }
+        } else if is_connect {
+            // HTTPS request
+            let (host, port) = parse_host_port(url, config.default_connect_port);
+            if url.split_once(':').and_then(|(_, port)| port.parse::<u16>().ok()).is_none() {
+                warn!("[#{}] CONNECT target {} from {} has no valid port, using default {}", conn_id, url, client, port);
+            }
+            stats.https_requests.fetch_add(1, Ordering::Relaxed);
+            log::log!(config.request_log_level(host), "[#{}] HTTPS CONNECT request to {}:{}", conn_id, host, port);
+            if !config.connect_ports.contains(&port) {
+                warn!("[#{}] Rejected CONNECT to {}:{} (port outside {:?})", conn_id, host, port, config.connect_ports);
+                let failure = ConnectFailure::Blocked("port not allowed for CONNECT");
+                send_connect_failure(&mut client_socket, &config, &stats, conn_id, host, failure).await?;
+                return Ok(());
+            }
+            let Some((dial_host, dial_port)) = resolve_dial_host(conn_id, &config, &stats, &mut client_socket, host, port).await? else {
+                return Ok(());
+            };
+            let upstream = format!("{}:{}", host, port);
+            if circuit_rejects(&config, &stats, &upstream) {
+                warn!("[#{}] Circuit open for {}, rejecting", conn_id, upstream);
+                client_socket.write_all(SERVICE_UNAVAILABLE_RESPONSE).await?;
+                return Ok(());
+            }
+            let Some(_dest_permit) = acquire_destination_slot(conn_id, &config, &stats, &upstream).await else {
+                client_socket.write_all(SERVICE_UNAVAILABLE_RESPONSE).await?;
+                return Ok(());
+            };
+            let host_stats = stats.host(&upstream);
+            host_stats.connections.fetch_add(1, Ordering::Relaxed);
+
+            let connected = connect_upstream(conn_id, &config, &stats, &host_stats, &dial_host, dial_port).await;
+            if let Some(breaker) = &config.circuit_breaker {
+                breaker.record(&upstream, matches!(connected, Ok(Ok(_))));
+            }
+            match connected {
+                Ok(Ok(mut remote)) => {
+                    if let Some(capture) = config.capture.as_ref().filter(|c| c.matches(host, port)) {
+                        remote = capture.wrap(conn_id, host, port, remote);
+                    }
+                    tune_upstream_socket(conn_id, remote.as_ref(), &config, &upstream);
+                    debug!("[#{}] Connected to {}:{}", conn_id, host, port);
+                    conn_events.established(method, upstream.clone());
+                    client_socket.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+                    let counters = ByteCounters { host: Some(&host_stats), connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() };
+                    if config.inspect_sni || config.alpn_policy.is_some() {
+                        let (hello, sni) = sni::peek_client_hello(&mut client_socket, sni::SNI_PEEK_TIMEOUT, BUFFER_SIZE).await?;
+                        if config.inspect_sni {
+                            if matches!(sni, sni::ClientHelloSni::Found(_)) {
+                                stats.sni_seen.fetch_add(1, Ordering::Relaxed);
+                            }
+                            if sni::log_sni(conn_id, host, &sni) {
+                                stats.sni_mismatches.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        if let Some(policy) = &config.alpn_policy {
+                            match sni::offered_alpn(&hello) {
+                                Some(offered) => {
+                                    if let Some(protocol) = policy.refused(&offered) {
+                                        warn!("[#{}] Refused CONNECT tunnel to {} (ALPN {} not allowed, offered {:?})", conn_id, upstream, protocol, offered);
+                                        stats.alpn_blocked.fetch_add(1, Ordering::Relaxed);
+                                        client_socket.write_all(alpn::NO_APPLICATION_PROTOCOL_ALERT).await?;
+                                        return Ok(());
+                                    }
+                                }
+                                // A TLS handshake whose ALPN can't be read may be hiding a blocked protocol
+                                None if !hello.is_empty() && sni != sni::ClientHelloSni::NotTls => {
+                                    warn!("[#{}] Refused CONNECT tunnel to {} (truncated or malformed TLS ClientHello, can't check ALPN)", conn_id, upstream);
+                                    stats.alpn_blocked.fetch_add(1, Ordering::Relaxed);
+                                    client_socket.write_all(alpn::NO_APPLICATION_PROTOCOL_ALERT).await?;
+                                    return Ok(());
+                                }
+                                None if config.fail_closed => {
+                                    warn!("[#{}] Refused CONNECT tunnel to {} (no TLS ClientHello to check ALPN, failing closed)", conn_id, upstream);
+                                    stats.fail_closed_denied.fetch_add(1, Ordering::Relaxed);
+                                    client_socket.write_all(alpn::NO_APPLICATION_PROTOCOL_ALERT).await?;
+                                    return Ok(());
+                                }
+                                None => {}
+                            }
+                        }
+                        remote.write_all(&hello).await?;
+                        stats.record_bytes(Direction::ClientToServer, hello.len() as u64);
+                        counters.add(hello.len() as u64);
+                    }
+                    let client_peer = client_addr.to_string();
+                    let remote_peer = remote.peer_addr().map(|a| normalize_peer_addr(a).to_string()).ok();
+                    let outcome = tunnel_fast(conn_id, client_socket, remote, Some(&client_peer), remote_peer.as_deref(), stats.clone(), counters, config.copy_limits()).await;
+                    relay_closed(conn_id, &mut conn_events, outcome.reason);
+                }
+                Ok(Err(e)) => {
+                    // Analyze for SSL certificate issues
+                    stats.ssl_errors.record(analyze_ssl_error(host, port, &e));
+                    stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+                    count_upstream_error(&host_stats, tenant_stats.as_deref());
+                    warn!("[#{}] Failed to connect to {}:{} - {}", conn_id, host, port, e);
+                    send_connect_failure(&mut client_socket, &config, &stats, conn_id, host, ConnectFailure::classify(&e)).await?;
+                }
+                Err(_) => {
+                    stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+                    count_upstream_error(&host_stats, tenant_stats.as_deref());
+                    warn!("[#{}] Timeout connecting to {}:{}", conn_id, host, port);
+                    send_connect_failure(&mut client_socket, &config, &stats, conn_id, host, ConnectFailure::TimedOut).await?;
+                }
+            }
+        } else {
+            // HTTP request
+            if url.starts_with('/') {
+                warn!("[#{}] Rejected origin-form request {} {} from {} (not a proxy request)", conn_id, method, url, client);
+                let response = format!(
+                    "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                    NOT_A_PROXY_REQUEST_BODY.len(),
+                    NOT_A_PROXY_REQUEST_BODY
+                );
+                client_socket.write_all(response.as_bytes()).await?;
+                return Ok(());
+            }
+            if let Some(conflict) = head.framing_conflict() {
+                stats.smuggling_blocked.fetch_add(1, Ordering::Relaxed);
+                warn!("[#{}] Rejected {} {} from {} (ambiguous body framing: {})", conn_id, method, url, client, conflict);
+                client_socket.write_all(BAD_REQUEST_RESPONSE).await?;
+                return Ok(());
+            }
+            if config.append_via_header && forwarded::via_names_self(&head) {
+                stats.loop_detected.fetch_add(1, Ordering::Relaxed);
+                warn!("[#{}] Rejected {} {} from {} (forwarding loop: already in Via chain)", conn_id, method, url, client);
+                let details = ErrorDetails { reason: Some("Forwarding loop detected"), host: None, request_id: Some(conn_id) };
+                send_error(&mut client_socket, &config, BAD_GATEWAY_RESPONSE, details).await?;
+                return Ok(());
+            }
+            let parsed_url = Url::parse(url).map_err(|_| ProxyErrorKind::MalformedRequest)?;
+            let scheme = parsed_url.scheme();
+            let host = parsed_url.host_str().ok_or(ProxyErrorKind::MalformedRequest)?;
+            let port = parsed_url.port().unwrap_or(if scheme == "https" { 443 } else { 80 });
+            stats.http_requests.fetch_add(1, Ordering::Relaxed);
+            log::log!(config.request_log_level(host), "[#{}] HTTP {} request to {}://{}:{}", conn_id, method, scheme, host, port);
+            let Some((dial_host, dial_port)) = resolve_dial_host(conn_id, &config, &stats, &mut client_socket, host, port).await? else {
+                return Ok(());
+            };
+            let upstream = format!("{}:{}", host, port);
+            let stale_key = config.stale_store.as_ref().filter(|_| coalesce::is_coalescable(&head)).map(|_| coalesce::request_key(&head));
+            let stale = config.stale_store.as_deref().zip(stale_key.as_deref()).map(|(store, key)| StaleSlot { store, key });
+            if circuit_rejects(&config, &stats, &upstream) {
+                warn!("[#{}] Circuit open for {}, rejecting", conn_id, upstream);
+                let counters = ByteCounters { host: None, connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() };
+                if !serve_stale(conn_id, &mut client_socket, stale, &stats, counters).await? {
+                    client_socket.write_all(SERVICE_UNAVAILABLE_RESPONSE).await?;
+                }
+                return Ok(());
+            }
+            // Held for this exchange only; a persistent connection takes a
+            // new slot for its next request
+            let Some(_dest_permit) = acquire_destination_slot(conn_id, &config, &stats, &upstream).await else {
+                let counters = ByteCounters { host: None, connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() };
+                if !serve_stale(conn_id, &mut client_socket, stale, &stats, counters).await? {
+                    client_socket.write_all(SERVICE_UNAVAILABLE_RESPONSE).await?;
+                }
+                return Ok(());
+            };
+            let host_stats = stats.host(&upstream);
+            host_stats.connections.fetch_add(1, Ordering::Relaxed);
+
+            let role = match &config.coalescer {
+                Some(coalescer) if coalesce::is_coalescable(&head) => Some(coalescer.join(&coalesce::request_key(&head))),
+                _ => None,
+            };
+            let leader = match role {
+                Some(Role::Leader(leader)) => Some(leader),
+                Some(Role::Follower(follower)) => {
+                    if let Some(response) = follower.wait().await {
+                        stats.coalesced_requests.fetch_add(1, Ordering::Relaxed);
+                        debug!("[#{}] Answered {} from a coalesced fetch", conn_id, url);
+                        client_socket.write_all(&response).await?;
+                        stats.record_bytes(Direction::ServerToClient, response.len() as u64);
+                        ByteCounters { host: Some(&host_stats), connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() }.add(response.len() as u64);
+                        return Ok(());
+                    }
+                    debug!("[#{}] Coalesced fetch of {} not shareable, fetching directly", conn_id, url);
+                    None
+                }
+                None => None,
+            };
+
+            // `--request-timeout` bounds everything from here to the end of
+            // the response; an upgraded connection is a tunnel, not a request
+            let deadline = config.request_timeout.filter(|_| !head.is_websocket_upgrade()).map(|limit| tokio::time::Instant::now() + limit);
+            let connect = connect_upstream(conn_id, &config, &stats, &host_stats, &dial_host, dial_port);
+            let Some(connected) = before_deadline(deadline, connect).await else {
+                return request_timed_out(conn_id, &config, &stats, &mut conn_events, &mut client_socket, host, false).await;
+            };
+            if let Some(breaker) = &config.circuit_breaker {
+                breaker.record(&upstream, matches!(connected, Ok(Ok(_))));
+            }
+            match connected {
+                Ok(Ok(mut remote)) => {
+                    if let Some(capture) = config.capture.as_ref().filter(|c| c.matches(host, port)) {
+                        remote = capture.wrap(conn_id, host, port, remote);
+                    }
+                    if let Some(max_bytes) = config.account_decompressed {
+                        remote = decompressed::wrap(conn_id, method, remote, conn_events.body_sizes.clone(), stats.clone(), max_bytes);
+                    }
+                    tune_upstream_socket(conn_id, remote.as_ref(), &config, &upstream);
+                    debug!("[#{}] Connected to {}://{}:{}", conn_id, scheme, host, port);
+                    conn_events.established(method, upstream.clone());
+
+                    // Send the request, rewriting the header block only if needed
+                    if config.add_forwarded_headers {
+                        let client_ip = client_addr.ip();
+                        let original = forwarded::original_client(&head, client_ip, &config.trusted_proxies);
+                        if original != client_ip {
+                            debug!("[#{}] Original client {} forwarded by {}", conn_id, original, client_ip);
+                        }
+                        let proxy_ip = client_socket.local_addr().ok().map(|a| a.ip());
+                        head.append("Forwarded", &forwarded::forwarded_element(client_ip, "http", proxy_ip));
+                    }
+                    if config.add_xff {
+                        head.append("X-Forwarded-For", &client_addr.ip().to_string());
+                    }
+                    if config.append_via_header {
+                        head.append("Via", &forwarded::via_element());
+                    }
+                    if config.version_header {
+                        head.set(version::VERSION_HEADER, version::LONG_VERSION);
+                    }
+                    if head.is_websocket_upgrade() {
+                        debug!("[#{}] WebSocket upgrade requested", conn_id);
+                    }
+                    if leader.is_some() || stale.is_some() {
+                        // The buffered copy ends at upstream EOF
+                        head.set("Connection", "close");
+                        head.remove("Keep-Alive");
+                    }
+
+                    let counters = ByteCounters { host: Some(&host_stats), connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() };
+                    let head_complete = find_header_terminator(&buffer[..bytes_read]).is_some();
+                    let response_started = AtomicBool::new(false);
+                    if head_complete && leader.is_none() && stale.is_none() && keep_alive::is_relayable(&head) {
+                        let exchange = async {
+                            if head.is_modified() {
+                                remote.write_all(&head.to_bytes()).await?;
+                            } else {
+                                remote.write_all(&buffer[..request_end]).await?;
+                            }
+                            let pending = &buffer[request_end..bytes_read];
+                            let mut client = ResponseWatch { inner: &mut client_socket, started: &response_started };
+                            keep_alive::relay_exchange(conn_id, &mut client, &mut remote, &head, pending, &stats, counters, config.copy_limits(), &mut meter).await
+                        };
+                        let Some(result) = before_deadline(deadline, exchange).await else {
+                            let responded = response_started.load(Ordering::Relaxed);
+                            return request_timed_out(conn_id, &config, &stats, &mut conn_events, &mut client_socket, host, responded).await;
+                        };
+                        if let Ok(Some(next_request)) = result {
+                            buffer[..next_request.len()].copy_from_slice(&next_request);
+                            bytes_read = next_request.len();
+                            continue;
+                        }
+                        relay_closed(conn_id, &mut conn_events, CloseReason::of(&result));
+                        return Ok(());
+                    }
+
+                    let exchange = async {
+                        if head.is_modified() {
+                            remote.write_all(&head.to_bytes()).await?;
+                            remote.write_all(&buffer[request_end..bytes_read]).await?;
+                        } else {
+                            remote.write_all(&buffer[..bytes_read]).await?;
+                        }
+                        let client = ResponseWatch { inner: &mut client_socket, started: &response_started };
+                        let limits = meter.remaining(config.copy_limits());
+                        if leader.is_some() || stale.is_some() {
+                            coalesce::relay_buffered(conn_id, client, remote, leader, stale, stats.clone(), counters, limits).await
+                        } else {
+                            tunnel_http(conn_id, client, remote, stats.clone(), counters, limits).await
+                        }
+                    };
+                    let Some(result) = before_deadline(deadline, exchange).await else {
+                        let responded = response_started.load(Ordering::Relaxed);
+                        return request_timed_out(conn_id, &config, &stats, &mut conn_events, &mut client_socket, host, responded).await;
+                    };
+                    relay_closed(conn_id, &mut conn_events, CloseReason::of(&result));
+                }
+                Ok(Err(e)) => {
+                    // Analyze for SSL certificate issues for HTTPS URLs
+                    if scheme == "https" {
+                        stats.ssl_errors.record(analyze_ssl_error(host, port, &e));
+                    }
+                    stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+                    count_upstream_error(&host_stats, tenant_stats.as_deref());
+                    warn!("[#{}] Failed to connect to {}://{}:{} - {}", conn_id, scheme, host, port, e);
+                    let counters = ByteCounters { host: Some(&host_stats), connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() };
+                    if !serve_stale(conn_id, &mut client_socket, stale, &stats, counters).await? {
+                        send_connect_failure(&mut client_socket, &config, &stats, conn_id, host, ConnectFailure::classify(&e)).await?;
+                    }
+                }
+                Err(_) => {
+                    stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+                    count_upstream_error(&host_stats, tenant_stats.as_deref());
+                    warn!("[#{}] Timeout connecting to {}://{}:{}", conn_id, scheme, host, port);
+                    let counters = ByteCounters { host: Some(&host_stats), connection: Some(&conn_events.bytes), tenant: tenant_stats.as_deref() };
+                    if !serve_stale(conn_id, &mut client_socket, stale, &stats, counters).await? {
+                        send_connect_failure(&mut client_socket, &config, &stats, conn_id, host, ConnectFailure::TimedOut).await?;
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    Ok(())
+}
+
+// The host and port to dial for `host:port`, once it has passed the admin
+// listener check. A `--route` for it wins; otherwise, whenever the target
+// had to be resolved for a check (the admin listener's port, or
+// --deny-private-ranges), this is the IP it resolved to, dialed directly so a
+// later DNS answer can't differ from the one we vetted. `None` means the
+// client has already been sent a rejection.
+async fn resolve_dial_host<W: AsyncWrite + Unpin>(
+    conn_id: u64,
+    config: &ProxyConfig,
+    stats: &ProxyStats,
+    client: &mut W,
+    host: &str,
+    port: u16,
+) -> Result<Option<(String, u16)>, ProxyError> {
+    let Some(checked) = check_own_listener(conn_id, config, stats, client, host, port).await? else {
+        return Ok(None);
+    };
+    if let Some((target, target_port)) = config.routes.lookup(host, port) {
+        debug!("[#{}] Routing {}:{} to {}:{}", conn_id, host, port, target, target_port);
+        return Ok(Some((target, target_port)));
+    }
+    if !config.deny_private_ranges {
+        return Ok(Some(match checked {
+            Some(addr) => (addr.ip().to_string(), port),
+            None => (host.to_string(), port),
+        }));
+    }
+    match ssrf::resolve_external(&config.resolver, host, port).await {
+        // This lookup may answer differently from the admin listener check's
+        Ok(addr) if config.admin_addr.is_some_and(|admin| ssrf::reaches_listener(addr, admin)) => {
+            reject_own_listener(conn_id, config, stats, client, host, port).await?;
+            Ok(None)
+        }
+        Ok(addr) => Ok(Some((addr.ip().to_string(), port))),
+        Err(e) if ProxyErrorKind::of(&e) == Some(ProxyErrorKind::Blocked) => {
+            stats.blocked_ssrf.fetch_add(1, Ordering::Relaxed);
+            warn!("[#{}] Blocked {}:{} (resolves to an internal address)", conn_id, host, port);
+            let failure = ConnectFailure::Blocked("target resolves to an internal address");
+            send_connect_failure(client, config, stats, conn_id, host, failure).await?;
+            Ok(None)
+        }
+        Err(e) => {
+            stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+            warn!("[#{}] Failed to resolve {}:{} - {}", conn_id, host, port, e);
+            send_connect_failure(client, config, stats, conn_id, host, ConnectFailure::Unresolved).await?;
+            Ok(None)
+        }
+    }
+}
+
+// Tunnel a connection that a NAT redirect sent us (`--transparent`) to the
+// destination it was addressed to, after the same checks a CONNECT gets. The
+// client has said nothing yet and expects to be talking to that
+// destination, so a refusal or a failed connect can only close the
+// connection.
+pub async fn tunnel_transparent<S: AsyncReadWrite>(
+    conn_id: u64,
+    client_socket: S,
+    client_addr: std::net::SocketAddr,
+    target: std::net::SocketAddr,
+    config: &ProxyConfig,
+    stats: &Arc<ProxyStats>,
+    conn_events: &mut ConnectionEvents,
+) -> Result<(), ProxyError> {
+    stats.transparent_connections.fetch_add(1, Ordering::Relaxed);
+    let (host, port) = (target.ip().to_string(), target.port());
+    log::log!(config.request_log_level(&host), "[#{}] Transparent connection from {} to {}", conn_id, client_addr, target);
+    if config.maintenance.load(Ordering::Relaxed) {
+        stats.maintenance_rejections.fetch_add(1, Ordering::Relaxed);
+        info!("[#{}] Rejected transparent connection to {} from {} (maintenance mode)", conn_id, target, client_addr);
+        return Ok(());
+    }
+    if let Some(limiter) = &config.rate_limiter {
+        if !limiter.check(client_addr.ip()) {
+            stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+            warn!("[#{}] Rate limit exceeded for {}", conn_id, client_addr.ip());
+            return Ok(());
+        }
+    }
+    if !config.connect_ports.contains(&port) {
+        warn!("[#{}] Rejected transparent connection to {} (port outside {:?})", conn_id, target, config.connect_ports);
+        return Ok(());
+    }
+    if config.deny_private_ranges && ssrf::is_internal(target.ip()) {
+        stats.blocked_ssrf.fetch_add(1, Ordering::Relaxed);
+        warn!("[#{}] Blocked transparent connection to {} (internal address)", conn_id, target);
+        return Ok(());
+    }
+    if config.admin_addr.is_some_and(|admin| ssrf::reaches_listener(target, admin)) {
+        warn!("[#{}] Rejected transparent connection to {} (proxy's own admin listener)", conn_id, target);
+        return Ok(());
+    }
+
+    let upstream = target.to_string();
+    if circuit_rejects(config, stats, &upstream) {
+        warn!("[#{}] Circuit open for {}, rejecting", conn_id, upstream);
+        return Ok(());
+    }
+    let Some(_dest_permit) = acquire_destination_slot(conn_id, config, stats, &upstream).await else {
+        return Ok(());
+    };
+    let host_stats = stats.host(&upstream);
+    host_stats.connections.fetch_add(1, Ordering::Relaxed);
+    let connected = connect_upstream(conn_id, config, stats, &host_stats, &host, port).await;
+    if let Some(breaker) = &config.circuit_breaker {
+        breaker.record(&upstream, matches!(connected, Ok(Ok(_))));
+    }
+    let mut remote = match connected {
+        Ok(Ok(remote)) => remote,
+        Ok(Err(e)) => {
+            stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+            count_upstream_error(&host_stats, None);
+            warn!("[#{}] Failed to connect to {} - {}", conn_id, target, e);
+            return Ok(());
+        }
+        Err(_) => {
+            stats.connection_errors.fetch_add(1, Ordering::Relaxed);
+            count_upstream_error(&host_stats, None);
+            warn!("[#{}] Timeout connecting to {}", conn_id, target);
+            return Ok(());
+        }
+    };
+    if let Some(capture) = config.capture.as_ref().filter(|c| c.matches(&host, port)) {
+        remote = capture.wrap(conn_id, &host, port, remote);
+    }
+    tune_upstream_socket(conn_id, remote.as_ref(), config, &upstream);
+    debug!("[#{}] Connected to {}", conn_id, target);
+    conn_events.established("TRANSPARENT", upstream.clone());
+    let counters = ByteCounters { host: Some(&host_stats), connection: Some(&conn_events.bytes), tenant: None };
+    let client_peer = client_addr.to_string();
+    let outcome = tunnel_fast(conn_id, client_socket, remote, Some(&client_peer), Some(&upstream), stats.clone(), counters, config.copy_limits()).await;
+    relay_closed(conn_id, conn_events, outcome.reason);
+    Ok(())
+}
+
+// Apply `--nagle` and `--tcp-keepalive` to a freshly connected upstream
+fn tune_upstream_socket(conn_id: u64, remote: &dyn dialer::AsyncReadWrite, config: &ProxyConfig, upstream: &str) {
+    if !config.nagle {
+        if let Err(e) = remote.set_nodelay(true) {
+            warn!("[#{}] Failed to set TCP_NODELAY for {}: {}", conn_id, upstream, e);
+        }
+    }
+    if let Some(idle) = config.tcp_keepalive {
+        if let Err(e) = remote.set_keepalive(idle) {
+            warn!("[#{}] Failed to enable TCP keepalive for {}: {}", conn_id, upstream, e);
+        }
+    }
+}
+
+// Log why the relay for a connection ended and keep it for the `closed`
+// event. An abnormal end is the relay's outcome, not a handler failure, so
+// it is reported here rather than returned as an error.
+fn relay_closed(conn_id: u64, conn_events: &mut ConnectionEvents, reason: CloseReason) {
+    if reason.is_normal() {
+        debug!("[#{}] Connection closed: {}", conn_id, reason);
+    } else {
+        info!("[#{}] Connection closed: {}", conn_id, reason);
+    }
+    conn_events.relay_closed(reason);
+}
+
+// Give up on an HTTP exchange that ran past `--request-timeout`. A client
+// that has none of the response yet gets a 504; otherwise the connection is
+// simply closed, since a half-sent response can't be replaced.
+async fn request_timed_out<W: AsyncWrite + Unpin>(
+    conn_id: u64,
+    config: &ProxyConfig,
+    stats: &ProxyStats,
+    conn_events: &mut ConnectionEvents,
+    client: &mut W,
+    host: &str,
+    responded: bool,
+) -> Result<(), ProxyError> {
+    stats.request_timeout.fetch_add(1, Ordering::Relaxed);
+    relay_closed(conn_id, conn_events, CloseReason::RequestTimeout);
+    if !responded {
+        let details = ErrorDetails { reason: Some("The request timed out"), host: Some(host), request_id: Some(conn_id) };
+        send_error(client, config, GATEWAY_TIMEOUT_RESPONSE, details).await?;
+    }
+    Ok(())
+}
+
+// Send an error response the proxy generated itself, with its body replaced
+// by the `--error-template` page when one is configured
+async fn send_error<W: AsyncWrite + Unpin>(
+    client: &mut W,
+    config: &ProxyConfig,
+    response: &[u8],
+    details: ErrorDetails<'_>,
+) -> Result<(), ProxyError> {
+    match &config.error_template {
+        Some(template) => client.write_all(&template.apply(response, details)).await?,
+        None => client.write_all(response).await?,
+    }
+    Ok(())
+}
+
+async fn send_connect_failure<W: AsyncWrite + Unpin>(
+    client: &mut W,
+    config: &ProxyConfig,
+    stats: &ProxyStats,
+    conn_id: u64,
+    host: &str,
+    failure: ConnectFailure,
+) -> Result<(), ProxyError> {
+    if let (ConnectFailure::Blocked(_), Some(tarpit)) = (failure, &config.block_tarpit) {
+        if tarpit.hold().await {
+            stats.tarpitted.fetch_add(1, Ordering::Relaxed);
+            debug!("[#{}] Answered block of {} after {:?} tarpit", conn_id, host, tarpit.delay());
+        }
+    }
+    let reason = failure.to_string();
+    let details = ErrorDetails { reason: Some(&reason), host: Some(host), request_id: Some(conn_id) };
+    send_error(client, config, &failure.response(), details).await
+}
+
+// Count a failed upstream connection against its destination and, when the
+// request was tagged, its tenant
+fn count_upstream_error(host: &HostStats, tenant: Option<&HostStats>) {
+    host.errors.fetch_add(1, Ordering::Relaxed);
+    if let Some(tenant) = tenant {
+        tenant.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Refuse targets that would reach the proxy's own admin listener. `None`
+// means the client has already been sent a 403, or with `--fail-closed` a
+// 502 when the target couldn't be resolved to check it; otherwise this is the
+// address the check resolved the target to, if it had to resolve it.
+async fn check_own_listener<W: AsyncWrite + Unpin>(
+    conn_id: u64,
+    config: &ProxyConfig,
+    stats: &ProxyStats,
+    client: &mut W,
+    host: &str,
+    port: u16,
+) -> Result<Option<Option<std::net::SocketAddr>>, ProxyError> {
+    let Some(admin) = config.admin_addr else {
+        return Ok(Some(None));
+    };
+    match ssrf::resolve_avoiding_listener(&config.resolver, host, port, admin).await {
+        Ok(checked) => Ok(Some(checked)),
+        Err(e) if ProxyErrorKind::of(&e) == Some(ProxyErrorKind::Blocked) => {
+            reject_own_listener(conn_id, config, stats, client, host, port).await?;
+            Ok(None)
+        }
+        // Otherwise the dial reports the failure as usual
+        Err(e) if config.fail_closed => {
+            stats.fail_closed_denied.fetch_add(1, Ordering::Relaxed);
+            warn!("[#{}] Rejected {}:{} (can't resolve it to check for the admin listener, failing closed: {})", conn_id, host, port, e);
+            send_connect_failure(client, config, stats, conn_id, host, ConnectFailure::Unresolved).await?;
+            Ok(None)
+        }
+        Err(_) => Ok(Some(None)),
+    }
+}
+
+async fn reject_own_listener<W: AsyncWrite + Unpin>(
+    conn_id: u64,
+    config: &ProxyConfig,
+    stats: &ProxyStats,
+    client: &mut W,
+    host: &str,
+    port: u16,
+) -> Result<(), ProxyError> {
+    warn!("[#{}] Rejected {}:{} (proxy's own admin listener)", conn_id, host, port);
+    let failure = ConnectFailure::Blocked("target is the proxy's own admin listener");
+    send_connect_failure(client, config, stats, conn_id, host, failure).await
+}
+
+// Connect to `host:port`, directly or through the upstream proxies. Each
+// upstream proxy attempt has its own connect timeout, so failing over isn't
+// cut short by a proxy that never answers. The timeout is the destination's
+// (see `ProxyConfig::connect_timeout_for`), and successful connects feed its
+// average connect time.
+async fn connect_upstream(
+    conn_id: u64,
+    config: &ProxyConfig,
+    stats: &ProxyStats,
+    host_stats: &HostStats,
+    host: &str,
+    port: u16,
+) -> Result<std::io::Result<dialer::BoxedStream>, tokio::time::error::Elapsed> {
+    let connect_timeout = config.connect_timeout_for(host_stats);
+    let started = Instant::now();
+    let connected = match &config.upstream_proxies {
+        Some(proxies) => Ok(proxies.dial(conn_id, config.dialer.as_ref(), stats, host, port, connect_timeout).await),
+        None => timeout(connect_timeout, config.dialer.dial(host, port)).await,
+    };
+    if let Ok(Ok(_)) = &connected {
+        host_stats.record_connect_time(started.elapsed());
+    }
+    connected
+}
+
+// Connect to `target` (host:port) the way a client request would, through
+// the same dialer, upstream proxies and timeout, and report how long it
+// took. Used at startup by `--probe-upstream`.
+pub async fn probe_upstream(config: &ProxyConfig, stats: &ProxyStats, target: &str) -> std::io::Result<Duration> {
+    let (host, port) = parse_host_port(target, 80);
+    let started = Instant::now();
+    match connect_upstream(0, config, stats, &HostStats::default(), host, port).await {
+        Ok(Ok(_)) => Ok(started.elapsed()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out connecting")),
+    }
+}
+
+// Whether the circuit breaker is open for `upstream`, counting the rejection
+fn circuit_rejects(config: &ProxyConfig, stats: &ProxyStats, upstream: &str) -> bool {
+    match &config.circuit_breaker {
+        Some(breaker) if !breaker.allow(upstream) => {
+            stats.circuit_open_rejections.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        _ => false,
+    }
+}
+
+// A `--max-per-destination` slot for `upstream`, held until the permit
+// drops. `None` means the destination stayed full and the caller should shed
+// the request; without a limit every request gets `Some`.
+async fn acquire_destination_slot(
+    conn_id: u64,
+    config: &ProxyConfig,
+    stats: &ProxyStats,
+    upstream: &str,
+) -> Option<Option<tokio::sync::OwnedSemaphorePermit>> {
+    let Some(limiter) = &config.destination_limiter else {
+        return Some(None);
+    };
+    match limiter.acquire(upstream).await {
+        Some(permit) => Some(Some(permit)),
+        None => {
+            stats.dest_overload.fetch_add(1, Ordering::Relaxed);
+            warn!("[#{}] {} already has {} connections in flight, rejecting", conn_id, upstream, limiter.limit());
+            None
+        }
+    }
+}
+
+// Run `future` until `deadline`, returning None if it passed first. Without
+// a deadline the future simply runs to completion.
+async fn before_deadline<F: std::future::Future>(deadline: Option<tokio::time::Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+// The client side of an HTTP exchange, noting whether any of the response
+// has been written yet: until then a timed-out request can still be
+// answered with a 504
+struct ResponseWatch<'a, S> {
+    inner: &'a mut S,
+    started: &'a AtomicBool,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ResponseWatch<'_, S> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut *self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ResponseWatch<'_, S> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let result = std::pin::Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if matches!(result, std::task::Poll::Ready(Ok(n)) if n > 0) {
+            self.started.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+// Counts a connection as active for as long as it is alive. Decrementing on
+// drop covers every early return and `?` out of `handle_client`.
+struct ActiveConnectionGuard<'a> {
+    stats: &'a ProxyStats,
+    conn_id: u64,
+}
+
+impl<'a> ActiveConnectionGuard<'a> {
+    fn new(stats: &'a ProxyStats, conn_id: u64) -> Self {
+        stats.connection_opened();
+        Self { stats, conn_id }
+    }
+}
+
+impl Drop for ActiveConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.connection_closed();
+        debug!("[#{}] Connection closed", self.conn_id);
+    }
+}
+
+// Records the connection's relayed bytes in the size histogram when the
+// connection ends, whichever way it ends
+struct ConnectionSizeRecorder<'a> {
+    histogram: &'a SizeHistogram,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Drop for ConnectionSizeRecorder<'_> {
+    fn drop(&mut self) {
+        self.histogram.record(self.bytes.load(Ordering::Relaxed));
+    }
+}
+
+// Relay bytes in both directions between two streams until either side
+// finishes, returning the bytes relayed each way and why the tunnel closed;
+// a tunnel cut at `max_duration` reports what it relayed until then. The
+// first direction to fail decides the reason. Socket tuning
+// (e.g. `set_nodelay`) is left to the caller, which knows the concrete
+// stream types; the addresses are only used for logging.
+#[allow(clippy::too_many_arguments)]
+pub async fn tunnel_fast<S, D>(
+    conn_id: u64,
+    src: S,
+    dst: D,
+    src_addr: Option<&str>,
+    dst_addr: Option<&str>,
+    stats: Arc<ProxyStats>,
+    counters: ByteCounters<'_>,
+    limits: CopyLimits,
+) -> TunnelOutcome
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut src_reader, mut src_writer) = tokio::io::split(src);
+    let (mut dst_reader, mut dst_writer) = tokio::io::split(dst);
+    let upstream_label = format!("[#{}] client->server", conn_id);
+    let downstream_label = format!("[#{}] server->client", conn_id);
+
+    // Stream data with size limits and idle timeout. The totals live out
+    // here so they survive the copies being dropped at the duration cap.
+    let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+    let client_to_server = bounded_copy_metered(
+        &mut src_reader, &mut dst_writer, limits,
+        &upstream_label, Direction::ClientToServer, stats.clone(), counters, None, &sent
+    );
+    let server_to_client = bounded_copy_metered(
+        &mut dst_reader, &mut src_writer, limits,
+        &downstream_label, Direction::ServerToClient, stats.clone(), counters, None, &received
+    );
+
+    let relay = async {
+        tokio::try_join!(client_to_server, server_to_client)?;
+        Ok::<(), ProxyError>(())
+    };
+    let reason = match limits.max_duration {
+        Some(max_duration) => match timeout(max_duration, relay).await {
+            Ok(result) => CloseReason::of(&result),
+            Err(_) => {
+                stats.tunnel_duration_exceeded.fetch_add(1, Ordering::Relaxed);
+                warn!("[#{}] Closing tunnel open longer than the {:?} maximum", conn_id, max_duration);
+                CloseReason::MaxDuration
+            }
+        },
+        None => CloseReason::of(&relay.await),
+    };
+    let (sent, received) = (sent.into_inner(), received.into_inner());
+    debug!(
+        "[#{}] Tunnel {} <-> {} closed ({}): {} bytes sent, {} bytes received",
+        conn_id,
+        src_addr.unwrap_or("client"),
+        dst_addr.unwrap_or("server"),
+        reason,
+        sent,
+        received
+    );
+    TunnelOutcome { sent, received, reason }
+}
+
+// HTTP-aware variant of `tunnel_fast` for forwarded plain-HTTP requests.
+// The upstream response head is parsed on its way back (bodies still stream
+// untouched) so the server's intent can be honored: once a response that
+// closes the connection has been relayed, both sides are torn down instead
+// of leaving the upstream open for further client bytes. A `101` WebSocket
+// upgrade is counted separately and then relayed both ways like CONNECT.
+pub async fn tunnel_http<S, D>(
+    conn_id: u64,
+    src: S,
+    dst: D,
+    stats: Arc<ProxyStats>,
+    counters: ByteCounters<'_>,
+    limits: CopyLimits,
+) -> Result<(), ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut src_reader, mut src_writer) = tokio::io::split(src);
+    let (mut dst_reader, mut dst_writer) = tokio::io::split(dst);
+    let upstream_label = format!("[#{}] client->server", conn_id);
+    let downstream_label = format!("[#{}] server->client", conn_id);
+
+    let client_to_server = bounded_copy_with_counters(
+        &mut src_reader, &mut dst_writer, limits, &upstream_label, Direction::ClientToServer, stats.clone(), counters
+    );
+    let server_to_client = async {
+        let (mut buffer, mut bytes_read, mut response) = read_response_head(&mut dst_reader, limits.idle_timeout).await?;
+        stats.record_response_status(response.as_ref().map(|r| r.status));
+        // Interim responses (e.g. `100 Continue` to a client holding back its
+        // body) are passed on as they come; the final one is handled below
+        while let Some(head_end) = response
+            .as_ref()
+            .filter(|r| r.is_interim())
+            .and_then(|_| find_header_terminator(&buffer[..bytes_read]))
+        {
+            let carried = relay_interim_head(&mut src_writer, &mut buffer, bytes_read, head_end, limits, &stats, counters).await?;
+            (buffer, bytes_read, response) = continue_response_head(&mut dst_reader, buffer, carried, limits.idle_timeout).await?;
+            stats.record_response_status(response.as_ref().map(|r| r.status));
+        }
+        // Chunked bodies are capped by decoded size, starting with whatever
+        // body bytes arrived along with the head
+        let mut chunked = response.as_ref().filter(|r| r.is_chunked()).map(|_| ChunkedDecoder::new());
+        let (head, body_limit) = match chunked.as_mut() {
+            Some(decoder) => {
+                let body_start = find_header_terminator(&buffer[..bytes_read]).unwrap_or(bytes_read);
+                let (consumed, decoded) = decoder.feed(&buffer[body_start..bytes_read], limits.max_download_size);
+                let forwarded = body_start + consumed;
+                (&buffer[..forwarded], (forwarded == bytes_read).then(|| limits.max_download_size - decoded))
+            }
+            None => (&buffer[..bytes_read], Some(limits.max_download_size.saturating_sub(bytes_read as u64))),
+        };
+        let closes = response.as_ref().is_some_and(|r| r.closes_connection());
+        if closes {
+            debug!("[#{}] Upstream requested Connection: close", conn_id);
+        }
+        if response.as_ref().is_some_and(|r| r.is_websocket_upgrade()) {
+            stats.websocket_connections.fetch_add(1, Ordering::Relaxed);
+            info!("[#{}] WebSocket upgrade accepted, tunneling", conn_id);
+        }
+        write_all_with_progress(&mut src_writer, head, limits.write_timeout).await.map_err(|e| map_write_error(&e))?;
+        stats.record_bytes(Direction::ServerToClient, head.len() as u64);
+        counters.add(head.len() as u64);
+        let Some(body_limit) = body_limit else {
+            warn!("Download size limit exceeded in {}", downstream_label);
+            return Err(ProxyErrorKind::SizeLimitExceeded.into());
+        };
+
+        bounded_copy_metered(
+            &mut dst_reader, &mut src_writer,
+            CopyLimits { max_download_size: body_limit, ..limits },
+            &downstream_label, Direction::ServerToClient, stats.clone(), counters, chunked, &AtomicU64::new(0)
+        ).await?;
+        Ok::<bool, ProxyError>(closes)
+    };
+
+    tokio::pin!(client_to_server, server_to_client);
+    let mut client_done = false;
+    loop {
+        tokio::select! {
+            result = &mut client_to_server, if !client_done => {
+                result?;
+                client_done = true;
+            }
+            result = &mut server_to_client => {
+                // The upstream has finished; unless it asked to close, keep
+                // relaying client bytes exactly as `tunnel_fast` would
+                if !result? && !client_done {
+                    client_to_server.await?;
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Read until the end of the response head (or the buffer fills), returning
+// the buffer, how much of it was read and the parsed head if it was complete
+pub(crate) async fn read_response_head<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    idle_timeout: Duration,
+) -> Result<(PooledBuffer<'static>, usize, Option<headers::ResponseHead>), ProxyError> {
+    continue_response_head(reader, BUFFER_POOL.get(), 0, idle_timeout).await
+}
+
+// `read_response_head` into a buffer that already holds the first
+// `bytes_read` bytes, e.g. what followed an interim response
+pub(crate) async fn continue_response_head<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    mut buffer: PooledBuffer<'static>,
+    mut bytes_read: usize,
+    idle_timeout: Duration,
+) -> Result<(PooledBuffer<'static>, usize, Option<headers::ResponseHead>), ProxyError> {
+    while bytes_read < buffer.len() && find_header_terminator(&buffer[..bytes_read]).is_none() {
+        match timeout(idle_timeout, reader.read(&mut buffer[bytes_read..])).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => bytes_read += n,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(ProxyErrorKind::IdleTimeout.into()),
+        }
+    }
+    let head = find_header_terminator(&buffer[..bytes_read]).and_then(|end| headers::ResponseHead::parse(&buffer[..end]));
+    Ok((buffer, bytes_read, head))
+}
+
+// Relay the interim response head ending at `head_end` to `writer` and drop
+// it from the front of `buffer`, returning how many bytes read past it remain
+pub(crate) async fn relay_interim_head<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buffer: &mut [u8],
+    bytes_read: usize,
+    head_end: usize,
+    limits: CopyLimits,
+    stats: &ProxyStats,
+    counters: ByteCounters<'_>,
+) -> Result<usize, ProxyError> {
+    write_all_with_progress(writer, &buffer[..head_end], limits.write_timeout).await.map_err(|e| map_write_error(&e))?;
+    stats.record_bytes(Direction::ServerToClient, head_end as u64);
+    counters.add(head_end as u64);
+    buffer.copy_within(head_end..bytes_read, 0);
+    Ok(bytes_read - head_end)
+}
+
+// Copy with size limits and statistics tracking, returning the bytes
+// transferred (also added to `stats.bytes_transferred` and the counter for
+// `direction`) and why the copy stopped. `label` names the copy in log
+// messages.
+#[allow(clippy::too_many_arguments)]
+pub async fn bounded_copy_with_stats<R, W>(
+    reader: R,
+    writer: W,
+    max_size: u64,
+    idle_timeout: Duration,
+    _src_addr: Option<&str>,
+    _dst_addr: Option<&str>,
+    label: &str,
+    direction: Direction,
+    stats: Arc<ProxyStats>,
+) -> CopyOutcome
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let limits = CopyLimits { max_download_size: max_size, max_upload_size: max_size, idle_timeout, write_timeout: idle_timeout, ..Default::default() };
+    let relayed = AtomicU64::new(0);
+    let result = bounded_copy_metered(reader, writer, limits, label, direction, stats, ByteCounters::default(), None, &relayed).await;
+    CopyOutcome { bytes: relayed.into_inner(), reason: CloseReason::of(&result) }
+}
+
+// What a finished copy relayed and why it stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyOutcome {
+    pub bytes: u64,
+    pub reason: CloseReason,
+}
+
+// What a finished tunnel relayed each way and why it closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelOutcome {
+    /// Client -> server bytes
+    pub sent: u64,
+    /// Server -> client bytes
+    pub received: u64,
+    pub reason: CloseReason,
+}
+
+// Add to a monotonic byte counter, clamping at `u64::MAX` instead of
+// wrapping. Atomics have no saturating add, so this is a plain `fetch_add`
+// (the hot path stays a single instruction) followed by a clamp when it
+// wrapped. Under concurrent adds right at the limit a wrapped value can be
+// visible briefly before the clamp lands; at petabyte-per-year rates the
+// limit is centuries away, so counters are documented as saturating rather
+// than exact past it.
+pub fn add_saturating(counter: &AtomicU64, n: u64) {
+    let previous = counter.fetch_add(n, Ordering::Relaxed);
+    if previous.checked_add(n).is_none() {
+        counter.store(u64::MAX, Ordering::Relaxed);
+    }
+}
+
+// Which way relayed bytes flow, for the per-direction byte counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl ProxyStats {
+    // Count `n` relayed bytes towards `bytes_transferred` and its direction
+    pub fn record_bytes(&self, direction: Direction, n: u64) {
+        add_saturating(&self.bytes_transferred, n);
+        let counter = match direction {
+            Direction::ClientToServer => &self.bytes_client_to_server,
+            Direction::ServerToClient => &self.bytes_server_to_client,
+        };
+        add_saturating(counter, n);
+    }
+}
+
+// Additional counters a copy attributes its transferred bytes to
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ByteCounters<'a> {
+    pub host: Option<&'a HostStats>,
+    pub connection: Option<&'a AtomicU64>,
+    pub tenant: Option<&'a HostStats>,
+}
+
+impl ByteCounters<'_> {
+    pub(crate) fn add(&self, n: u64) {
+        if let Some(host) = self.host {
+            add_saturating(&host.bytes, n);
+        }
+        if let Some(connection) = self.connection {
+            add_saturating(connection, n);
+        }
+        if let Some(tenant) = self.tenant {
+            add_saturating(&tenant.bytes, n);
+        }
+    }
+}
+
+// Size and time bounds for one direction of a relayed connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyLimits {
+    /// Cap on server->client bytes, whatever the request method
+    pub max_download_size: u64,
+    /// Cap on client->server bytes
+    pub max_upload_size: u64,
+    /// Longest wait for the next read
+    pub idle_timeout: Duration,
+    /// Longest a write may go without making any progress
+    pub write_timeout: Duration,
+    /// Absolute cap on the whole connection, however busy; only
+    /// `tunnel_fast` (CONNECT tunnels) enforces it
+    pub max_duration: Option<Duration>,
+    /// Slowest sustained rate before the copy is aborted
+    pub min_throughput: Option<MinThroughput>,
+}
+
+impl CopyLimits {
+    // The size cap for bytes flowing in `direction`
+    pub fn size_cap(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::ClientToServer => self.max_upload_size,
+            Direction::ServerToClient => self.max_download_size,
+        }
+    }
+}
+
+impl Default for CopyLimits {
+    fn default() -> Self {
+        Self {
+            max_download_size: MAX_DOWNLOAD_SIZE,
+            max_upload_size: MAX_DOWNLOAD_SIZE,
+            idle_timeout: IDLE_TIMEOUT,
+            write_timeout: IDLE_TIMEOUT,
+            max_duration: None,
+            min_throughput: None,
+        }
+    }
+}
+
+// `write_all` where the timeout applies to each partial write rather than
+// the whole buffer, so a slow reader that keeps draining isn't cut off
+pub(crate) async fn write_all_with_progress<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    write_timeout: Duration,
+) -> std::io::Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+        match timeout(write_timeout, writer.write(&data[written..])).await {
+            Ok(Ok(0)) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(Ok(n)) => written += n,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+    Ok(())
+}
+
+// Same as `bounded_copy_with_stats`, additionally updating `counters`
+pub async fn bounded_copy_with_counters<R, W>(
+    reader: R,
+    writer: W,
+    limits: CopyLimits,
+    label: &str,
+    direction: Direction,
+    stats: Arc<ProxyStats>,
+    counters: ByteCounters<'_>,
+) -> Result<u64, ProxyError>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let relayed = AtomicU64::new(0);
+    bounded_copy_metered(reader, writer, limits, label, direction, stats, counters, None, &relayed).await?;
+    Ok(relayed.into_inner())
+}
+
+// The copy loop behind `bounded_copy_with_counters`. With a chunked decoder
+// the size limit applies to decoded body bytes instead of raw bytes. Raw
+// counting takes over after the last chunk (later responses on the same
+// connection) or if the framing turns out to be invalid. Bytes forwarded
+// are also added to `relayed` as they go, so a caller that cancels the copy
+// still knows how far it got.
+#[allow(clippy::too_many_arguments)]
+async fn bounded_copy_metered<R, W>(
+    mut reader: R,
+    mut writer: W,
+    limits: CopyLimits,
+    label: &str,
+    direction: Direction,
+    stats: Arc<ProxyStats>,
+    counters: ByteCounters<'_>,
+    mut chunked: Option<ChunkedDecoder>,
+    relayed: &AtomicU64,
+) -> Result<(), ProxyError>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let CopyLimits { idle_timeout, write_timeout, .. } = limits;
+    let max_size = limits.size_cap(direction);
+    let mut transferred = 0u64;
+    let mut throughput = limits.min_throughput.map(ThroughputMonitor::new);
+    let mut buffer = BUFFER_POOL.get();
+
+    loop {
+        let read_result = timeout(idle_timeout, reader.read(&mut buffer)).await;
+
+        match read_result {
+            Ok(Ok(0)) => break, // EOF
+            Ok(Ok(n)) => {
+                if throughput.as_mut().is_some_and(|monitor| monitor.record(n as u64)) {
+                    stats.slow_transfer_aborted.fetch_add(1, Ordering::Relaxed);
+                    warn!("Transfer below minimum throughput in {}", label);
+                    return Err(ProxyErrorKind::SlowTransfer.into());
+                }
+
+                // Only forward (and count) what still fits under the limit
+                let allowed = match chunked.as_mut().filter(|decoder| !decoder.is_done() && !decoder.is_invalid()) {
+                    Some(decoder) => {
+                        let (consumed, decoded) = decoder.feed(&buffer[..n], max_size - transferred);
+                        transferred += decoded;
+                        consumed
+                    }
+                    None => {
+                        let allowed = (max_size - transferred).min(n as u64) as usize;
+                        transferred += allowed as u64;
+                        allowed
+                    }
+                };
+                stats.record_bytes(direction, allowed as u64);
+                counters.add(allowed as u64);
+                add_saturating(relayed, allowed as u64);
+
+                if allowed < n {
+                    let _ = write_all_with_progress(&mut writer, &buffer[..allowed], write_timeout).await;
+                    let kind = match direction {
+                        Direction::ClientToServer => "Upload",
+                        Direction::ServerToClient => "Download",
+                    };
+                    warn!("{} size limit exceeded: {} bytes", kind, transferred.saturating_add((n - allowed) as u64));
+                    return Err(ProxyErrorKind::SizeLimitExceeded.into());
+                }
+
+                if let Err(e) = write_all_with_progress(&mut writer, &buffer[..n], write_timeout).await {
+                    let kind = map_write_error(&e);
+                    match kind {
+                        ProxyErrorKind::WriteTimeout => warn!("Write timeout in {}", label),
+                        _ => debug!("Write error in {}: {}", label, e),
                     }
+                    return Err(kind.into());
                 }
             }
             Ok(Err(e)) => {
-                debug!("Read error in {}: {}", direction, e);
-                return Err(e.into());
+                debug!("Read error in {}: {}", label, e);
+                return Err(ProxyErrorKind::ReadFailed.into());
             }
             Err(_) => {
-                warn!("Connection idle timeout in {}", direction);
-                return Err("Idle timeout".into());
+                warn!("Connection idle timeout in {}", label);
+                return Err(ProxyErrorKind::IdleTimeout.into());
             }
         }
     }
@@ -371,7 +3017,7 @@ where
     W: AsyncWriteExt + Unpin,
 {
     let mut transferred = 0u64;
-    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut buffer = BUFFER_POOL.get();
 
     loop {
         let read_result = timeout(idle_timeout, reader.read(&mut buffer)).await;
@@ -379,10 +3025,10 @@ where
         match read_result {
             Ok(Ok(0)) => break, // EOF
             Ok(Ok(n)) => {
-                transferred += n as u64;
+                transferred = transferred.saturating_add(n as u64);
                 if transferred > max_size {
                     warn!("Download size limit exceeded: {} bytes", transferred);
-                    return Err("Download size limit exceeded".into());
+                    return Err(ProxyErrorKind::SizeLimitExceeded.into());
                 }
 
                 let write_result = timeout(idle_timeout, writer.write_all(&buffer[..n])).await;
@@ -390,11 +3036,11 @@ where
                     Ok(Ok(_)) => {}
                     Ok(Err(e)) => {
                         debug!("Write error in {}: {}", direction, e);
-                        return Err("Write error".into());
+                        return Err(ProxyErrorKind::WriteFailed.into());
                     }
                     Err(_) => {
                         warn!("Write timeout in {}", direction);
-                        return Err("Write timeout".into());
+                        return Err(ProxyErrorKind::WriteTimeout.into());
                     }
                 }
             }
@@ -421,7 +3067,7 @@ where
             }
             Err(_) => {
                 warn!("Connection idle timeout in {}", direction);
-                return Err("Idle timeout".into());
+                return Err(ProxyErrorKind::IdleTimeout.into());
             }
         }
     }
@@ -441,7 +3087,7 @@ where
     W: AsyncWriteExt + Unpin,
 {
     let mut transferred = 0u64;
-    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut buffer = BUFFER_POOL.get();
 
     loop {
         let read_result = timeout(idle_timeout, reader.read(&mut buffer)).await;
@@ -449,10 +3095,10 @@ where
         match read_result {
             Ok(Ok(0)) => break, // EOF
             Ok(Ok(n)) => {
-                transferred += n as u64;
+                transferred = transferred.saturating_add(n as u64);
                 if transferred > max_size {
                     warn!("Download size limit exceeded: {} bytes", transferred);
-                    return Err("Download size limit exceeded".into());
+                    return Err(ProxyErrorKind::SizeLimitExceeded.into());
                 }
 
                 let write_result = timeout(idle_timeout, writer.write_all(&buffer[..n])).await;
@@ -460,11 +3106,11 @@ where
                     Ok(Ok(_)) => {}
                     Ok(Err(e)) => {
                         debug!("Write error: {}", e);
-                        return Err("Write error".into());
+                        return Err(ProxyErrorKind::WriteFailed.into());
                     }
                     Err(_) => {
                         warn!("Write timeout");
-                        return Err("Write timeout".into());
+                        return Err(ProxyErrorKind::WriteTimeout.into());
                     }
                 }
             }
@@ -474,7 +3120,7 @@ where
             }
             Err(_) => {
                 warn!("Connection idle timeout");
-                return Err("Idle timeout".into());
+                return Err(ProxyErrorKind::IdleTimeout.into());
             }
         }
     }
@@ -0,0 +1,144 @@
+// Size-rotated log file (`--log-file`).
+//
+// The logger writes each record with one call, and rotation only happens
+// between calls, so a record never straddles two files. When a write would
+// take the file past the size limit, `<path>` becomes `<path>.1`, the older
+// `<path>.N` shift up by one, the oldest past the kept count is deleted, and
+// a fresh `<path>` is started. A single record larger than the limit still
+// goes out whole, into a file of its own.
+//
+// Writes never fail towards the logger: the log (and the statistics blocks
+// in it) may live on a network mount that briefly goes away, and that must
+// not take logging down for good or disturb the proxy. After a failed write
+// or rotation the file backs off, dropping records, for a jittered delay
+// that doubles with each consecutive failure, then reopens `<path>` on the
+// next record. Failures and the recovery are reported on stderr, since the
+// log itself is what is broken.
+
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_LOG_KEEP: usize = 5;
+
+// Backoff after a failed write, doubling per consecutive failure
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+    // Consecutive failed writes, and when to reopen after the last one
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl RotatingFile {
+    // Append to `path`, keeping up to `keep` rotated files of about
+    // `max_size` bytes each next to it
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_size, keep, file, size, failures: 0, retry_at: None })
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    // `<path>.<n>`, the n-th most recent rotated file
+    pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            // The oldest is overwritten by the rename into its slot
+            for n in (1..self.keep).rev() {
+                let from = Self::rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, Self::rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, Self::rotated_path(&self.path, 1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_record(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                return Ok(());
+            }
+            self.reopen()?;
+            self.retry_at = None;
+        }
+        if self.size > 0 && self.size.saturating_add(buf.len() as u64) > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        if self.failures > 0 {
+            eprintln!("Log file {} is writable again after {} failed attempt(s)", self.path.display(), self.failures);
+            self.failures = 0;
+        }
+        Ok(())
+    }
+
+    fn back_off(&mut self, error: io::Error) {
+        self.failures += 1;
+        let delay = jittered(RETRY_MIN.saturating_mul(1 << (self.failures - 1).min(6)).min(RETRY_MAX));
+        eprintln!(
+            "Log file {} write failed: {}; dropping log records and reopening in {:.1}s",
+            self.path.display(),
+            error,
+            delay.as_secs_f64()
+        );
+        self.retry_at = Some(Instant::now() + delay);
+    }
+}
+
+// Between half and all of `delay`, so proxies sharing a mount don't all
+// retry at the same moment
+fn jittered(delay: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    delay.mul_f64(0.5 + (random % 1000) as f64 / 2000.0)
+}
+
+impl Write for RotatingFile {
+    // Always reports the record as written; see the top of this file
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(e) = self.write_record(buf) {
+            self.back_off(e);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.retry_at.is_some() {
+            return Ok(());
+        }
+        if let Err(e) = self.file.flush() {
+            self.back_off(e);
+        }
+        Ok(())
+    }
+}
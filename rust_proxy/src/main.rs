@@ -1,11 +1,32 @@
+use clap::{CommandFactory, FromArgMatches};
+use rust_proxy::banner::{self, BannerFormat, EffectiveConfig};
+use rust_proxy::reload::{LiveConfig, ProfileListener};
+use rust_proxy::rate_limit::AcceptRate;
 use rust_proxy::*;
 
 #[cfg(windows)]
 use rust_proxy::windows;
 
-#[tokio::main]
-async fn main() -> Result<(), ProxyError> {
-    let args = Args::parse();
+// The runtime is built by hand rather than with `#[tokio::main]` so
+// `--worker-threads` can size it
+fn main() -> Result<(), ProxyError> {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let effective = EffectiveConfig::from_matches(&Args::command(), &matches);
+    if args.worker_threads == Some(0) {
+        return Err("--worker-threads must be at least 1".into());
+    }
+    let worker_threads = args
+        .worker_threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()?
+        .block_on(run(args, effective, worker_threads))
+}
+
+async fn run(args: Args, effective: EffectiveConfig, worker_threads: usize) -> Result<(), ProxyError> {
     
     // Initialize logger with configurable level
     let log_level = match args.log_level.as_str() {
@@ -19,9 +40,23 @@ async fn main() -> Result<(), ProxyError> {
         }
     };
     
-    env_logger::Builder::from_default_env()
-        .filter_level(log_level)
-        .init();
+    // With a control socket the level can change at runtime, so the logger
+    // passes everything and the global max level does the filtering
+    #[cfg(unix)]
+    let dynamic_level = args.control_socket.is_some();
+    #[cfg(not(unix))]
+    let dynamic_level = false;
+    let mut logger = env_logger::Builder::from_default_env();
+    logger.filter_level(if dynamic_level { log::LevelFilter::Trace } else { log_level });
+    if let Some(path) = &args.log_file {
+        let file = rust_proxy::log_file::RotatingFile::open(path, args.log_max_size, args.log_keep)
+            .map_err(|e| format!("Cannot open --log-file {}: {}", path.display(), e))?;
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    logger.init();
+    if dynamic_level {
+        log::set_max_level(log_level);
+    }
     
     #[cfg(windows)]
     {
@@ -31,43 +66,428 @@ async fn main() -> Result<(), ProxyError> {
         }
     }
     
+    if args.connect_port_min > args.connect_port_max {
+        return Err(format!(
+            "--connect-port-min ({}) is greater than --connect-port-max ({})",
+            args.connect_port_min, args.connect_port_max
+        )
+        .into());
+    }
+
+    if args.max_tunnel_duration == Some(0) {
+        return Err("--max-tunnel-duration must be at least 1 second".into());
+    }
+
+    if args.disable_http && args.disable_https {
+        return Err("--disable-http and --disable-https can't both be set; the proxy would refuse every request".into());
+    }
+
+    let outbound_v4 = args.bind_outbound.iter().filter(|ip| ip.is_ipv4()).count();
+    if outbound_v4 > 1 || args.bind_outbound.len() - outbound_v4 > 1 {
+        return Err("--bind-outbound accepts at most one IPv4 and one IPv6 address".into());
+    }
+
+    if let Some(rate) = args.rate_per_ip {
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err(format!("--rate-per-ip must be a positive number of requests per second, got {}", rate).into());
+        }
+    }
+    if let Some(rate) = args.accept_rate {
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err(format!("--accept-rate must be a positive number of connections per second, got {}", rate).into());
+        }
+    }
+
+    // Policy lists fail open (bad entries are skipped) unless --fail-closed
+    for (flag, list) in [("--allow-alpn", &args.allow_alpn), ("--block-alpn", &args.block_alpn)] {
+        let malformed = rust_proxy::alpn::AlpnPolicy::malformed(list);
+        if malformed.is_empty() {
+            continue;
+        }
+        let message = format!("{} has malformed ALPN protocol IDs (empty or over 255 bytes): {:?}", flag, malformed);
+        if args.fail_closed {
+            return Err(format!("{} (refusing to start with --fail-closed)", message).into());
+        }
+        warn!("{}; ignoring them", message);
+    }
+
     let addr = format!("{}:{}", args.host, args.port);
-    let listener = TcpListener::bind(&addr).await?;
+    let bind_addr = tokio::net::lookup_host(&addr).await?.next().ok_or_else(|| format!("{} did not resolve", addr))?;
+    let listener = bind_listener(bind_addr, args.listen_backlog)?;
     
     // Use semaphore to limit concurrent connections
     let semaphore = Arc::new(Semaphore::new(MAX_CONNECTIONS));
     
-    // Initialize statistics
-    let stats = Arc::new(ProxyStats::new());
+    // Initialize statistics and shared configuration
+    let stats = Arc::new(ProxyStats {
+        top_hosts: args.top_hosts,
+        stats_log_level: args.stats_log_level,
+        ..ProxyStats::new()
+    });
+    let mut config = ProxyConfig::from_args(&args);
+
+    #[cfg(unix)]
+    if let Some(path) = args.event_socket.clone() {
+        let bus = config.events.get_or_insert_with(rust_proxy::events::EventBus::default).clone();
+        let listener = rust_proxy::bind_unix_listener(&path)?;
+        info!("Streaming connection events on {}", path.display());
+        tokio::spawn(async move {
+            if let Err(e) = rust_proxy::events::serve_event_socket(listener, bus).await {
+                error!("Event socket failed: {}", e);
+            }
+        });
+    }
+
+    // Access logs are built from the same connection events
+    if let Some(target) = args.syslog.clone() {
+        let events = config.events.get_or_insert_with(rust_proxy::events::EventBus::default).subscribe();
+        info!("Sending access logs to syslog {:?} (facility {:?})", target, args.syslog_facility);
+        let facility = args.syslog_facility;
+        tokio::spawn(async move {
+            if let Err(e) = rust_proxy::syslog::forward_access_log(target, facility, events).await {
+                error!("Syslog forwarding failed: {}", e);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = args.control_socket.clone() {
+        let control_stats = stats.clone();
+        let maintenance = config.maintenance.clone();
+        let listener = rust_proxy::bind_unix_listener(&path)?;
+        info!("Accepting control commands on {}", path.display());
+        tokio::spawn(async move {
+            if let Err(e) = rust_proxy::control::serve_control_socket(listener, control_stats, maintenance).await {
+                error!("Control socket failed: {}", e);
+            }
+        });
+    }
+
+    let tls_acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(rust_proxy::tls::load_acceptor(cert, key)?),
+        _ => None,
+    };
+
+    if let Some(path) = &args.error_template {
+        config.error_template = Some(Arc::new(rust_proxy::error_template::ErrorTemplate::load(path)?));
+        info!("Serving proxy error responses from template {}", path.display());
+    }
+
+    let profiles = match &args.listener_config {
+        Some(path) => rust_proxy::profiles::load_profiles(path)?,
+        None => Vec::new(),
+    };
+    let listener_limits: std::collections::HashMap<_, _> = args.listen_max_connections.iter().copied().collect();
+    for addr in listener_limits.keys() {
+        if *addr != bind_addr && !profiles.iter().any(|profile| profile.listen == *addr) {
+            return Err(format!("--listen-max-connections names {}, which is not a listener", addr).into());
+        }
+    }
+    let connection_limit = |addr| {
+        listener_limits.get(&addr).map(|&limit| {
+            info!("Listener {} limited to {} concurrent connections", addr, limit);
+            Arc::new(Semaphore::new(limit))
+        })
+    };
+
+    config.connection_limit = connection_limit(bind_addr);
+    let config = Arc::new(config);
+
+    // The main listener uses the command-line config unless a profile names
+    // its address; each other profile gets a listener of its own
+    let main_config = match profiles.iter().find(|profile| profile.listen == bind_addr) {
+        Some(profile) => {
+            info!("Listener {} using its profile (auth: {}, methods: {:?})", bind_addr, profile.auth.is_some(), profile.methods);
+            Arc::new(ProxyConfig { connection_limit: config.connection_limit.clone(), ..profile.apply_to_main(&config) })
+        }
+        None => config.clone(),
+    };
+    let main_live = Arc::new(LiveConfig::new(main_config));
+    let mut listeners = vec![(listener, main_live.clone())];
+    let mut profile_listeners = vec![ProfileListener { listen: bind_addr, config: main_live, main: true }];
+    for profile in profiles.into_iter().filter(|profile| profile.listen != bind_addr) {
+        let profile_listener = bind_listener(profile.listen, args.listen_backlog)?;
+        info!("Listener {} using its own profile (auth: {}, methods: {:?})",
+            profile.listen, profile.auth.is_some(), profile.methods);
+        let profile_config = ProxyConfig { connection_limit: connection_limit(profile.listen), ..profile.apply(&config) };
+        let live = Arc::new(LiveConfig::new(Arc::new(profile_config)));
+        profile_listeners.push(ProfileListener { listen: profile.listen, config: live.clone(), main: false });
+        listeners.push((profile_listener, live));
+    }
+
+    let rate_listeners = profile_listeners.clone();
+
+    // Installed even without a file to reload, since SIGHUP's default
+    // action would otherwise kill the proxy
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup())?;
+        let path = args.listener_config.clone();
+        let base = config.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                let Some(path) = &path else {
+                    info!("Received SIGHUP; nothing to reload without --listener-config");
+                    continue;
+                };
+                if let Err(e) = rust_proxy::reload::reload_profiles(path, &base, &profile_listeners) {
+                    error!("Reload of {} failed, keeping current settings: {}", path.display(), e);
+                }
+            }
+        });
+    }
+
+    if let Some(admin_addr) = args.admin_addr {
+        let admin_listener = TcpListener::bind(admin_addr).await?;
+        let upstream_check = args.health_check_upstream.clone().map(|target| {
+            rust_proxy::admin::UpstreamCheck::new(target, rust_proxy::admin::HEALTH_CHECK_CACHE_TTL)
+        });
+        let state = Arc::new(rust_proxy::admin::AdminState {
+            stats: stats.clone(),
+            config: config.clone(),
+            upstream_check,
+        });
+        info!("Admin endpoint listening on http://{}/healthz", admin_addr);
+        tokio::spawn(async move {
+            if let Err(e) = rust_proxy::admin::serve_admin(admin_listener, state).await {
+                error!("Admin listener failed: {}", e);
+            }
+        });
+    }
+
+    if let Some(proxy) = &args.upstream_socks5 {
+        info!("Connecting to destinations through SOCKS5 proxy {}", proxy);
+    }
+
+    if let Some(rate) = args.rate_per_ip {
+        info!("Rate limiting each client IP to {} requests/sec", rate);
+    }
+    // Profiles can bring their own limiters, and a reload can swap them, so
+    // purge whichever ones the listeners hold at the time
+    tokio::spawn(async move {
+        let mut interval = interval(rust_proxy::rate_limit::RATE_LIMIT_PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut limiters: Vec<Arc<rust_proxy::rate_limit::RateLimiter>> = Vec::new();
+            for listener in &rate_listeners {
+                if let Some(limiter) = listener.config.current().rate_limiter.clone() {
+                    if !limiters.iter().any(|seen| Arc::ptr_eq(seen, &limiter)) {
+                        limiters.push(limiter);
+                    }
+                }
+            }
+            for limiter in limiters {
+                let remaining = limiter.purge_idle();
+                debug!("Rate limiter tracking {} clients after purge", remaining);
+            }
+        }
+    });
+
+    if let Some(limiter) = config.destination_limiter.clone() {
+        info!("Limiting each destination to {} concurrent connections", limiter.limit());
+        tokio::spawn(async move {
+            let mut interval = interval(rust_proxy::dest_limit::DEST_LIMIT_PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let remaining = limiter.purge_idle();
+                debug!("Destination limiter tracking {} destinations after purge", remaining);
+            }
+        });
+    }
+
+    if let Some(tarpit) = &config.block_tarpit {
+        info!("Delaying policy blocks by {:?} (at most {} connections held)", tarpit.delay(), args.block_tarpit_max);
+    }
+
     let stats_logger = stats.clone();
+    let stats_reset_interval = args.stats_reset_interval.map(Duration::from_secs);
     
     // Start periodic statistics logging task
     tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(180)); // Log every 3 minutes
+        // Log every 3 minutes, unless resetting on an interval of its own
+        let mut interval = interval(stats_reset_interval.unwrap_or(Duration::from_secs(180)));
         interval.tick().await; // Skip first immediate tick
+        // Kept between ticks for the current-throughput figure
+        let mut previous = stats_logger.snapshot();
         
         loop {
             interval.tick().await;
-            stats_logger.log_stats();
+            if stats_reset_interval.is_some() {
+                stats_logger.log_stats_and_reset();
+            } else {
+                previous = stats_logger.log_stats_since(&previous);
+            }
         }
     });
     
+    // Checked before the started banner, so a bad upstream fails the start
+    if let Some(target) = &args.probe_upstream {
+        match rust_proxy::probe_upstream(&config, &stats, target).await {
+            Ok(rtt) => info!("Upstream probe: connected to {} in {:?}", target, rtt),
+            Err(e) if args.probe_warn_only => warn!("Upstream probe: cannot reach {}: {}", target, e),
+            Err(e) => return Err(format!("upstream probe failed: cannot reach {}: {}", target, e).into()),
+        }
+    }
+
     info!("Proxy server starting on {} (max connections: {})", addr, MAX_CONNECTIONS);
-    info!("Log level set to: {}", args.log_level);
-    info!("Host configured: {}", args.host);
-    info!("Port configured: {}", args.port);
-    info!("Statistics logging enabled (every 3 minutes in INFO mode)");
+    effective.log_effective();
+    info!("Runtime worker threads: {}", worker_threads);
+    match args.stats_reset_interval {
+        Some(secs) => info!(
+            "Statistics logging enabled (every {}s at {} level, reset after each log)",
+            secs, args.stats_log_level
+        ),
+        None => info!("Statistics logging enabled (every 3 minutes at {} level)", args.stats_log_level),
+    }
+    if tls_acceptor.is_some() {
+        info!("TLS termination enabled for inbound connections");
+    }
+    if let Some(duration) = args.run_for {
+        info!("Shutting down after {:?}", duration);
+    }
+    if let Some(period) = args.drain_on_idle {
+        info!("Draining after {:?} without connections{}", period, if args.exit_on_idle { ", then shutting down" } else { "" });
+    }
+    if args.maintenance {
+        warn!("Starting in maintenance mode: every request is answered 503");
+    }
+    if !args.capture.is_empty() {
+        warn!("⚠️  TRAFFIC CAPTURE ENABLED: raw bytes to {} are written to {}", args.capture.join(", "), args.capture_dir.display());
+        warn!("   Captures can contain credentials and other secrets; delete them once done");
+    }
+    // Registered before the started banner so a supervisor can signal as
+    // soon as it sees it
+    let shutdown = shutdown_signal()?;
+    tokio::pin!(shutdown);
+    // `--run-for` ends the run through the same graceful path as a signal
+    let run_for = async {
+        match args.run_for {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(run_for);
+    // `--drain-on-idle` marks the proxy draining; it only ends the run with
+    // `--exit-on-idle`
+    let drain = async {
+        match args.drain_on_idle {
+            Some(period) => {
+                let marker = args.drain_marker.as_deref();
+                rust_proxy::idle::drain_on_idle(&stats, period, rust_proxy::idle::IDLE_POLL_INTERVAL, &config.draining, marker).await;
+                if !args.exit_on_idle {
+                    std::future::pending::<()>().await;
+                }
+            }
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(drain);
+    if args.banner_format == BannerFormat::Json {
+        println!("{}", banner::started_line(&addr));
+    }
 
+    if let Some(rate) = args.accept_rate {
+        info!("Accepting at most {} new connections/sec", rate);
+    }
+    let accept_rate = args.accept_rate.map(|rate| Arc::new(AcceptRate::new(rate)));
+    let mut accept_loops = tokio::task::JoinSet::new();
+    for (listener, config) in listeners {
+        accept_loops.spawn(accept_loop(
+            listener,
+            config,
+            stats.clone(),
+            semaphore.clone(),
+            accept_rate.clone(),
+            tls_acceptor.clone(),
+            args.one_shot,
+        ));
+    }
+    tokio::select! {
+        _ = &mut shutdown => {}
+        _ = &mut run_for => info!("Run time of {:?} elapsed", args.run_for.unwrap_or_default()),
+        _ = &mut drain => info!("Idle, shutting down"),
+        Some(result) = accept_loops.join_next() => result??,
+    }
+
+    // Stop accepting, then wait for in-flight connections to release their
+    // permits. Reaching the end of main is what makes the exit graceful.
+    accept_loops.abort_all();
+    let active = stats.active_connections.load(Ordering::Relaxed);
+    info!("Shutting down, waiting up to {:?} for {} active connections", SHUTDOWN_GRACE_PERIOD, active);
+    if timeout(SHUTDOWN_GRACE_PERIOD, semaphore.acquire_many(MAX_CONNECTIONS as u32)).await.is_err() {
+        warn!("Grace period expired with {} connections still active", stats.active_connections.load(Ordering::Relaxed));
+    }
+
+    stats.log_stats();
+    if args.banner_format == BannerFormat::Json {
+        println!("{}", banner::stopped_line(&stats.snapshot()));
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
+
+// Serve one listener, handing each connection that listener's current config.
+// With `one_shot`, returns once the first connection has been served, which
+// ends the run through the usual shutdown path.
+async fn accept_loop(
+    listener: TcpListener,
+    config: Arc<LiveConfig>,
+    stats: Arc<ProxyStats>,
+    semaphore: Arc<Semaphore>,
+    accept_rate: Option<Arc<AcceptRate>>,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    one_shot: bool,
+) -> Result<(), ProxyError> {
     loop {
+        // Waiting before `accept` leaves connections over the rate queued
+        // in the OS backlog rather than refused
+        if let Some(accept_rate) = &accept_rate {
+            accept_rate.acquire().await;
+        }
         let (client_socket, _) = listener.accept().await?;
         let permit = semaphore.clone().acquire_owned().await?;
         let stats_clone = stats.clone();
-        
-        tokio::spawn(async move {
+        let config_clone = config.current();
+        let tls_acceptor = tls_acceptor.clone();
+
+        let connection = tokio::spawn(async move {
             let _permit = permit; // Hold permit until task completes
-            if let Err(e) = handle_client(client_socket, stats_clone).await {
+            let result = match tls_acceptor {
+                Some(acceptor) => rust_proxy::tls::handle_tls_client(client_socket, acceptor, stats_clone, config_clone).await,
+                None => handle_client(client_socket, stats_clone, config_clone).await,
+            };
+            if let Err(e) = result {
                 error!("Error handling client: {}", e);
             }
         });
+        if one_shot {
+            connection.await?;
+            info!("One-shot connection served");
+            return Ok(());
+        }
     }
-}
\ No newline at end of file
+}
+
+// Resolves on Ctrl+C, or SIGTERM where available (what supervisors send).
+// Handlers are installed eagerly; the returned future only waits.
+#[cfg(unix)]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    let mut ctrl_c = tokio::signal::windows::ctrl_c()?;
+    Ok(async move {
+        ctrl_c.recv().await;
+    })
+}
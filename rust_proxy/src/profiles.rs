@@ -0,0 +1,138 @@
+// Per-listener policy profiles (`--listener-config`).
+//
+// Each profile opens an extra listener whose connections get their own
+// `ProxyConfig`, derived from the command-line one. `handle_client` needs no
+// listener lookup: every accept loop hands its connections the config of
+// the listener that accepted them.
+//
+//     {
+//       "listeners": [
+//         { "listen": "10.0.0.1:3129" },
+//         { "listen": "0.0.0.0:8443", "auth": "user:pass", "methods": ["CONNECT"] },
+//         { "listen": "0.0.0.0:8080", "idle_timeout": 60, "rate_per_ip": 5 }
+//       ]
+//     }
+//
+// `auth` is per listener (absent means none); the other fields override the
+// command-line value when present. Timeouts are in seconds, and a listener
+// with its own `rate_per_ip` gets a rate limiter of its own, started afresh
+// each time the profile is applied.
+//
+// A profile whose `listen` is the main `--host`/`--port` address configures
+// that listener rather than opening another one, so its policy can be
+// reloaded too. There an absent `auth` keeps the command-line `--auth`.
+
+use crate::rate_limit::{RateLimiter, MAX_TRACKED_CLIENTS};
+use crate::{auth, ProxyConfig};
+use serde::Deserialize;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerProfile {
+    pub listen: SocketAddr,
+    /// Basic proxy credentials (`user:pass`) required on this listener
+    #[serde(default)]
+    pub auth: Option<String>,
+    /// Request methods accepted on this listener; all when absent
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny_private_ranges: Option<bool>,
+    #[serde(default)]
+    pub connect_port_min: Option<u16>,
+    #[serde(default)]
+    pub connect_port_max: Option<u16>,
+    #[serde(default)]
+    pub header_read_timeout: Option<u64>,
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    #[serde(default)]
+    pub write_timeout: Option<u64>,
+    #[serde(default)]
+    pub request_timeout: Option<u64>,
+    #[serde(default)]
+    pub rate_per_ip: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    listeners: Vec<ListenerProfile>,
+}
+
+// Parse and check the profiles. Values are held to the same rules as the
+// command-line flags they override, so a bad file is refused as a whole
+// rather than locking clients out of one listener.
+pub fn parse_profiles(json: &str) -> Result<Vec<ListenerProfile>, serde_json::Error> {
+    let listeners = serde_json::from_str::<ProfileFile>(json)?.listeners;
+    for profile in &listeners {
+        profile
+            .validate()
+            .map_err(|e| <serde_json::Error as serde::de::Error>::custom(format!("listener {}: {}", profile.listen, e)))?;
+    }
+    Ok(listeners)
+}
+
+pub fn load_profiles(path: &Path) -> io::Result<Vec<ListenerProfile>> {
+    let json = std::fs::read_to_string(path)?;
+    parse_profiles(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+impl ListenerProfile {
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.connect_port_min, self.connect_port_max) {
+            if min > max {
+                return Err(format!("connect_port_min ({}) is greater than connect_port_max ({})", min, max));
+            }
+        }
+        let timeouts = [
+            ("header_read_timeout", self.header_read_timeout),
+            ("idle_timeout", self.idle_timeout),
+            ("write_timeout", self.write_timeout),
+            ("request_timeout", self.request_timeout),
+        ];
+        if let Some((name, _)) = timeouts.iter().find(|(_, secs)| *secs == Some(0)) {
+            return Err(format!("{} must be at least 1 second", name));
+        }
+        match self.rate_per_ip {
+            Some(rate) if !(rate.is_finite() && rate > 0.0) => {
+                Err(format!("rate_per_ip must be a positive number of requests per second, got {}", rate))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The config for connections accepted on this listener
+    pub fn apply(&self, base: &ProxyConfig) -> ProxyConfig {
+        let ports = base.connect_ports.clone();
+        ProxyConfig {
+            proxy_auth: self.auth.as_deref().map(auth::basic_credentials),
+            allowed_methods: self.methods.clone().or_else(|| base.allowed_methods.clone()),
+            deny_private_ranges: self.deny_private_ranges.unwrap_or(base.deny_private_ranges),
+            connect_ports: self.connect_port_min.unwrap_or(*ports.start())..=self.connect_port_max.unwrap_or(*ports.end()),
+            header_read_timeout: self.header_read_timeout.map_or(base.header_read_timeout, Duration::from_secs),
+            idle_timeout: self.idle_timeout.map_or(base.idle_timeout, Duration::from_secs),
+            write_timeout: self.write_timeout.map_or(base.write_timeout, Duration::from_secs),
+            request_timeout: self.request_timeout.map(Duration::from_secs).or(base.request_timeout),
+            rate_limiter: match self.rate_per_ip {
+                Some(rate) => Some(Arc::new(RateLimiter::new(rate, MAX_TRACKED_CLIENTS))),
+                None => base.rate_limiter.clone(),
+            },
+            // Connection caps are per listener, never inherited
+            connection_limit: None,
+            ..base.clone()
+        }
+    }
+
+    // The config for the main listener, which keeps `--auth` unless the
+    // profile names its own
+    pub fn apply_to_main(&self, base: &ProxyConfig) -> ProxyConfig {
+        let config = self.apply(base);
+        ProxyConfig { proxy_auth: config.proxy_auth.or_else(|| base.proxy_auth.clone()), ..config }
+    }
+}
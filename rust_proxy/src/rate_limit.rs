@@ -0,0 +1,112 @@
+// Per-client-IP request rate limiting (`--rate-per-ip`).
+//
+// Each source IP gets a token bucket holding up to `burst` tokens, refilled
+// continuously at `rate` tokens per second from the time elapsed since its
+// last request, so there is no refill tick to align bursts against. A
+// request takes one token; with none left it is refused.
+//
+// A bucket that has refilled to capacity is indistinguishable from a new
+// one, so `purge_idle` drops those; the map is also capped, evicting the
+// least recently seen client, so a flood of source addresses can't grow it
+// without bound between purges.
+//
+// `AcceptRate` (`--accept-rate`) is a single bucket of the same shape for
+// the accept loops. Rather than refusing, it makes the caller wait for its
+// token before accepting, so a connection storm queues in the OS backlog
+// and is let in at the configured pace.
+
+use crate::bounded_map::BoundedMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const MAX_TRACKED_CLIENTS: usize = 16384;
+pub const RATE_LIMIT_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    clients: BoundedMap<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    // `rate` requests per second, allowing bursts of up to one second's worth
+    // (and always at least one request)
+    pub fn new(rate: f64, capacity: usize) -> Self {
+        Self { rate, burst: rate.max(1.0), clients: BoundedMap::new(capacity) }
+    }
+
+    // Take a token for a request from `ip`; `false` means it is over the limit
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let bucket = self
+            .clients
+            .get_or_insert_with(&ip.to_canonical().to_string(), || Mutex::new(Bucket { tokens: self.burst, updated: now }));
+        let mut bucket = bucket.lock().unwrap();
+        bucket.tokens = self.refilled(&bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    // Drop buckets that have refilled completely, returning how many remain
+    pub fn purge_idle(&self) -> usize {
+        let now = Instant::now();
+        self.clients.retain(|_, bucket| self.refilled(&bucket.lock().unwrap(), now) < self.burst);
+        self.clients.len()
+    }
+
+    pub fn tracked_clients(&self) -> usize {
+        self.clients.len()
+    }
+}
+
+#[derive(Debug)]
+pub struct AcceptRate {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<(f64, tokio::time::Instant)>,
+}
+
+impl AcceptRate {
+    // `rate` connections per second, with the same one-second burst as
+    // `RateLimiter`
+    pub fn new(rate: f64) -> Self {
+        let burst = rate.max(1.0);
+        Self { rate, burst, bucket: Mutex::new((burst, tokio::time::Instant::now())) }
+    }
+
+    // Take a token, waiting until one is due. The token is claimed before
+    // the wait, so loops sharing the limiter queue up behind each other
+    // instead of racing for the next refill.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, updated) = *bucket;
+            let now = tokio::time::Instant::now();
+            let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+            let tokens = (tokens + elapsed * self.rate).min(self.burst) - 1.0;
+            *bucket = (tokens, now);
+            Duration::from_secs_f64((-tokens).max(0.0) / self.rate)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
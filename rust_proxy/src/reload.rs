@@ -0,0 +1,85 @@
+// Live reload of `--listener-config` on SIGHUP.
+//
+// Every listener's config sits in a `LiveConfig`. Accept loops take the
+// current one for each connection they accept, so a reload applies to new
+// connections while those already running finish under the config they
+// started with; nothing is dropped or rebound. Only what a profile can set
+// is reloaded. The command-line config is fixed for the life of the
+// process, and listeners stay bound to the addresses they started with, so
+// profiles that are added, removed or moved to another address are logged
+// and otherwise ignored until a restart. The main listener is the
+// exception: a profile for its address is optional, and without one it
+// goes back to the command-line config.
+
+use crate::profiles::{load_profiles, ListenerProfile};
+use crate::ProxyConfig;
+use log::{info, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug)]
+pub struct LiveConfig {
+    current: RwLock<Arc<ProxyConfig>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Arc<ProxyConfig>) -> Self {
+        Self { current: RwLock::new(config) }
+    }
+
+    // The config new connections get
+    pub fn current(&self) -> Arc<ProxyConfig> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn replace(&self, config: Arc<ProxyConfig>) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+}
+
+// A listener opened for a profile, and the config its connections get
+#[derive(Debug, Clone)]
+pub struct ProfileListener {
+    pub listen: SocketAddr,
+    pub config: Arc<LiveConfig>,
+    /// The `--host`/`--port` listener, which needs no profile
+    pub main: bool,
+}
+
+// Swap in the configs `profiles` describe, derived from `base` as at
+// startup. Returns how many listeners were updated.
+pub fn apply_profiles(base: &ProxyConfig, listeners: &[ProfileListener], profiles: &[ListenerProfile]) -> usize {
+    let mut updated = 0;
+    for listener in listeners {
+        let config = match profiles.iter().find(|profile| profile.listen == listener.listen) {
+            Some(profile) if listener.main => profile.apply_to_main(base),
+            Some(profile) => profile.apply(base),
+            None if listener.main => base.clone(),
+            None => {
+                warn!("Reload: no profile for listener {} any more, keeping its current settings (it stays open until restart)", listener.listen);
+                continue;
+            }
+        };
+        // The connection cap comes from the command line, not the profile
+        let connection_limit = listener.config.current().connection_limit.clone();
+        listener.config.replace(Arc::new(ProxyConfig { connection_limit, ..config }));
+        updated += 1;
+    }
+    for profile in profiles {
+        if !listeners.iter().any(|listener| listener.listen == profile.listen) {
+            warn!("Reload: ignoring profile for {} (new listeners need a restart)", profile.listen);
+        }
+    }
+    updated
+}
+
+// Re-read the listener config file and apply it. A file that can't be read
+// or parsed changes nothing.
+pub fn reload_profiles(path: &Path, base: &ProxyConfig, listeners: &[ProfileListener]) -> io::Result<usize> {
+    let profiles = load_profiles(path)?;
+    let updated = apply_profiles(base, listeners, &profiles);
+    info!("Reloaded {}: {} of {} listeners updated", path.display(), updated, listeners.len());
+    Ok(updated)
+}
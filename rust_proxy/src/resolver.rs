@@ -0,0 +1,114 @@
+// Upstream name resolution (`--resolver`).
+//
+// By default names are resolved by the OS, through `getaddrinfo`. With
+// `--resolver` every upstream lookup (dialing, the SSRF guard, the admin
+// listener check) goes to that DNS server instead, through hickory's
+// resolver and its answer cache, so a test or a split-horizon setup sees
+// the same answers the proxy acts on. The hosts file is not consulted in
+// that mode.
+//
+// `ResolvingDialer` applies the resolver to outbound connections: it
+// resolves the target itself and races the addresses (happy eyeballs),
+// handing the wrapped dialer IP literals, which plain `connect` and
+// `--bind-outbound` pass through without a second lookup.
+
+use crate::dialer::{connect_happy_eyeballs, BoxedStream, UpstreamDialer, HAPPY_EYEBALLS_DELAY};
+use async_trait::async_trait;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+pub const DNS_PORT: u16 = 53;
+
+#[derive(Clone, Default)]
+pub enum Resolver {
+    /// The OS resolver
+    #[default]
+    System,
+    /// A specific DNS server
+    Dns { server: SocketAddr, resolver: Arc<TokioAsyncResolver> },
+}
+
+impl Resolver {
+    // Query `server` (over UDP, falling back to TCP for truncated answers)
+    pub fn dns(server: SocketAddr) -> Self {
+        let servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+        let mut options = ResolverOpts::default();
+        options.use_hosts_file = false;
+        let resolver = TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], servers), options);
+        Self::Dns { server, resolver: Arc::new(resolver) }
+    }
+
+    // Every address `host:port` resolves to. IP literals (bracketed or not)
+    // are returned as they are.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        match self {
+            Self::System => Ok(tokio::net::lookup_host((host, port)).await?.collect()),
+            Self::Dns { resolver, .. } => {
+                let answer = resolver
+                    .lookup_ip(host)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("{}: {}", host, e)))?;
+                Ok(answer.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::System => f.write_str("System"),
+            Self::Dns { server, .. } => f.debug_struct("Dns").field("server", server).finish(),
+        }
+    }
+}
+
+// `ip:port`, or a bare IP for port 53
+pub fn parse_resolver(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .map_err(|_| format!("expected a DNS server address like 8.8.8.8:53, got {}", value))
+}
+
+#[derive(Debug)]
+pub struct ResolvingDialer {
+    resolver: Resolver,
+    inner: Arc<dyn UpstreamDialer>,
+}
+
+impl ResolvingDialer {
+    // Resolve targets with `resolver`, then dial the addresses with `inner`
+    pub fn new(resolver: Resolver, inner: Arc<dyn UpstreamDialer>) -> Self {
+        Self { resolver, inner }
+    }
+}
+
+#[async_trait]
+impl UpstreamDialer for ResolvingDialer {
+    async fn dial(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        let addrs = self.resolver.lookup(host, port).await?;
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", host)));
+        }
+        let inner = self.inner.clone();
+        let connect = move |addr: SocketAddr| {
+            let inner = inner.clone();
+            async move { inner.dial(&addr.ip().to_string(), addr.port()).await }
+        };
+        connect_happy_eyeballs(addrs, HAPPY_EYEBALLS_DELAY, connect).await
+    }
+}
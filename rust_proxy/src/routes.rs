@@ -0,0 +1,63 @@
+// Static destination rewrites (`--route host:port=target:port`).
+//
+// A routed destination is dialed at its mapped target instead, while the
+// client's request, including the Host header and absolute URI, is relayed
+// unchanged, so the client still believes it reached the original host.
+// Routes are operator configuration, so the mapped target is dialed as
+// given without the `--deny-private-ranges` check (mapping to internal
+// addresses is the point). Port and method policy still apply to the
+// destination the client asked for.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub from: (String, u16),
+    pub to: (String, u16),
+}
+
+// `host:port`, with IPv6 hosts in brackets (`[::1]:443`)
+fn parse_host_port(value: &str) -> Result<(String, u16), String> {
+    let (host, port) = value.rsplit_once(':').ok_or_else(|| format!("expected host:port, got {}", value))?;
+    let port = port.parse::<u16>().map_err(|_| format!("invalid port in {}", value))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(format!("missing host in {}", value));
+    }
+    Ok((normalize(host), port))
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub fn parse_route(value: &str) -> Result<Route, String> {
+    let (from, to) = value.split_once('=').ok_or("expected host:port=target:port")?;
+    Ok(Route { from: parse_host_port(from)?, to: parse_host_port(to)? })
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteMap {
+    routes: HashMap<(String, u16), (String, u16)>,
+}
+
+impl RouteMap {
+    // Later routes for the same destination replace earlier ones
+    pub fn new(routes: impl IntoIterator<Item = Route>) -> Self {
+        Self { routes: routes.into_iter().map(|route| (route.from, route.to)).collect() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    // Where to dial for a client's `host:port`, if it is routed. Hosts
+    // match case-insensitively, ignoring a trailing dot and IPv6 brackets.
+    pub fn lookup(&self, host: &str, port: u16) -> Option<(String, u16)> {
+        if self.routes.is_empty() {
+            return None;
+        }
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        self.routes.get(&(normalize(host), port)).cloned()
+    }
+}
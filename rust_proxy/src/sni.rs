@@ -0,0 +1,250 @@
+// Server name indication from a CONNECT tunnel's TLS ClientHello
+// (`--inspect-sni`).
+//
+// The tunnel stays end to end: the proxy only reads the client's first
+// bytes, pulls the `server_name` out of the ClientHello if that is what they
+// are, and forwards them unchanged. An SNI that differs from the CONNECT
+// authority is the signature of domain fronting, so it is logged and
+// counted. A ClientHello split over several TLS records is put back
+// together before it is read.
+//
+// The same peek yields the offered ALPN protocols for `--allow-alpn` and
+// `--block-alpn` (see alpn.rs). A ClientHello whose ALPN list can't be read
+// reports that rather than "no ALPN", so the policy can refuse it.
+
+use log::{debug, warn};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+// How long to wait for the client's first bytes. TLS clients send the
+// ClientHello straight away; protocols where the server speaks first lose
+// only this much before their tunnel starts.
+pub const SNI_PEEK_TIMEOUT: Duration = Duration::from_millis(500);
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+const EXTENSION_ALPN: u16 = 0x0010;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientHelloSni {
+    // A ClientHello naming this server
+    Found(String),
+    // A ClientHello without a (parseable) host name
+    Absent,
+    // The start of a TLS record; more bytes are needed
+    Incomplete,
+    // Not a TLS handshake
+    NotTls,
+}
+
+// Look for the SNI host name in the first bytes a client sent
+pub fn parse_client_hello(data: &[u8]) -> ClientHelloSni {
+    let message = match first_handshake(data) {
+        Handshake::Message(message) => message,
+        Handshake::Incomplete => return ClientHelloSni::Incomplete,
+        Handshake::NotTls => return ClientHelloSni::NotTls,
+    };
+    match server_name(&message) {
+        Some(Some(name)) => ClientHelloSni::Found(name),
+        Some(None) | None if message.first() == Some(&HANDSHAKE_CLIENT_HELLO) => ClientHelloSni::Absent,
+        _ => ClientHelloSni::NotTls,
+    }
+}
+
+// The ALPN protocols offered by the ClientHello in the first bytes a client
+// sent, in its order of preference; empty when it has no ALPN extension.
+// `None` when there is no whole, well-formed ClientHello or its ALPN list
+// is malformed, so what it offers is unknown.
+pub fn offered_alpn(data: &[u8]) -> Option<Vec<String>> {
+    let Handshake::Message(message) = first_handshake(data) else {
+        return None;
+    };
+    let Some(data) = extension(&message, EXTENSION_ALPN)? else {
+        return Some(Vec::new());
+    };
+    let mut list = Reader(Reader(data).vec16()?);
+    let mut protocols = Vec::new();
+    while !list.0.is_empty() {
+        protocols.push(String::from_utf8_lossy(list.vec8()?).into_owned());
+    }
+    Some(protocols)
+}
+
+enum Handshake {
+    Message(Vec<u8>),
+    Incomplete,
+    NotTls,
+}
+
+// The first handshake message (type, length and body) in the first bytes a
+// client sent, put back together from the handshake records it spans
+fn first_handshake(data: &[u8]) -> Handshake {
+    if data.is_empty() {
+        return Handshake::Incomplete;
+    }
+    if data[0] != CONTENT_TYPE_HANDSHAKE || (data.len() > 1 && data[1] != 0x03) {
+        return Handshake::NotTls;
+    }
+    let mut message = Vec::new();
+    let mut records = Reader(data);
+    loop {
+        if let Some(body_len) = Reader(message.get(1..).unwrap_or_default()).u24() {
+            if message.len() >= 4 + body_len {
+                message.truncate(4 + body_len);
+                return Handshake::Message(message);
+            }
+        }
+        let Some(header) = records.take(RECORD_HEADER_LEN) else {
+            return Handshake::Incomplete;
+        };
+        // Anything but another handshake record leaves the message cut
+        // short, which then fails to parse
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Handshake::Message(message);
+        }
+        let Some(record) = records.take(u16::from_be_bytes([header[3], header[4]]) as usize) else {
+            return Handshake::Incomplete;
+        };
+        message.extend_from_slice(record);
+    }
+}
+
+// The data of the first extension of `wanted` type in a ClientHello
+// message. `None` when the message isn't a well-formed ClientHello;
+// `Some(None)` when it is one without that extension.
+fn extension(message: &[u8], wanted: u16) -> Option<Option<&[u8]>> {
+    let mut reader = Reader(message);
+    if reader.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let body_len = reader.u24()?;
+    let mut body = Reader(reader.take(body_len)?);
+    body.take(2 + 32)?; // legacy_version, random
+    body.vec8()?; // legacy_session_id
+    body.vec16()?; // cipher_suites
+    body.vec8()?; // legacy_compression_methods
+    if body.0.is_empty() {
+        return Some(None);
+    }
+    let mut extensions = Reader(body.vec16().unwrap_or(body.0));
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if extension_type == wanted {
+            return Some(Some(data));
+        }
+    }
+    Some(None)
+}
+
+// `None` when the record isn't a well-formed ClientHello; `Some(None)` when
+// it is one without a host name
+fn server_name(message: &[u8]) -> Option<Option<String>> {
+    let Some(data) = extension(message, EXTENSION_SERVER_NAME)? else {
+        return Some(None);
+    };
+    let mut names = Reader(Reader(data).vec16()?);
+    while !names.0.is_empty() {
+        let name_type = names.u8()?;
+        let name = names.vec16()?;
+        if name_type == NAME_TYPE_HOST_NAME {
+            return Some(std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase()));
+        }
+    }
+    Some(None)
+}
+
+// Big-endian cursor over TLS wire data
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+        Some(((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize)
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+// Read the client's first bytes, up to the whole ClientHello, and report the
+// SNI they carry. The bytes are returned for the caller to forward; they may
+// be empty if the client sent nothing in time.
+pub async fn peek_client_hello<R: AsyncRead + Unpin>(
+    client: &mut R,
+    wait: Duration,
+    max_len: usize,
+) -> std::io::Result<(Vec<u8>, ClientHelloSni)> {
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let sni = parse_client_hello(&data);
+        if sni != ClientHelloSni::Incomplete || data.len() >= max_len {
+            return Ok((data, sni));
+        }
+        match timeout(wait, client.read(&mut buffer)).await {
+            Ok(Ok(0)) | Err(_) => return Ok((data, sni)),
+            Ok(Ok(n)) => data.extend_from_slice(&buffer[..n]),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+}
+
+// Whether `sni` names a different server than the CONNECT target `host`.
+// IP-address targets carry no name to compare against.
+pub fn is_mismatch(host: &str, sni: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    host.parse::<std::net::IpAddr>().is_err() && !host.eq_ignore_ascii_case(sni.trim_end_matches('.'))
+}
+
+// Log what was found for a tunnel to `host`, returning whether the SNI was
+// a mismatch
+pub fn log_sni(conn_id: u64, host: &str, sni: &ClientHelloSni) -> bool {
+    match sni {
+        ClientHelloSni::Found(name) if is_mismatch(host, name) => {
+            warn!("[#{}] TLS SNI {} differs from CONNECT target {} (possible domain fronting)", conn_id, name, host);
+            true
+        }
+        ClientHelloSni::Found(name) => {
+            debug!("[#{}] TLS SNI {}", conn_id, name);
+            false
+        }
+        ClientHelloSni::Absent => {
+            debug!("[#{}] TLS ClientHello without SNI for {}", conn_id, host);
+            false
+        }
+        ClientHelloSni::Incomplete | ClientHelloSni::NotTls => {
+            debug!("[#{}] No TLS ClientHello at the start of the tunnel to {}", conn_id, host);
+            false
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Outbound connections through a SOCKS5 proxy (`--upstream-socks5`).
+//
+// `Socks5Dialer` wraps the dialer that would otherwise reach destinations
+// directly: it dials the SOCKS5 proxy with it instead, then asks the proxy
+// to CONNECT to the real target (RFC 1928). Hostnames are passed through
+// unresolved, so the proxy does the DNS lookup, unless --deny-private-ranges
+// or a `--route` already turned the target into an address. Authentication
+// is either none or username/password (RFC 1929, `--upstream-socks5-auth`).
+
+use crate::dialer::{BoxedStream, UpstreamDialer};
+use crate::upstream_proxy::{parse_upstream_proxy, UpstreamProxy};
+use async_trait::async_trait;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const COMMAND_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// `host:port`, optionally written as a `socks5://` URL
+pub fn parse_socks5_proxy(value: &str) -> Result<UpstreamProxy, String> {
+    parse_upstream_proxy(value.strip_prefix("socks5://").unwrap_or(value))
+}
+
+// Username and password for RFC 1929 authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+// `user:pass`; each part must fit the protocol's one-byte length
+pub fn parse_socks5_auth(value: &str) -> Result<Socks5Credentials, String> {
+    let (username, password) = value.split_once(':').ok_or("expected user:pass")?;
+    if username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255 {
+        return Err("username and password must each be 1 to 255 bytes".to_string());
+    }
+    Ok(Socks5Credentials { username: username.to_string(), password: password.to_string() })
+}
+
+#[derive(Debug)]
+pub struct Socks5Dialer {
+    proxy: UpstreamProxy,
+    credentials: Option<Socks5Credentials>,
+    inner: Arc<dyn UpstreamDialer>,
+}
+
+impl Socks5Dialer {
+    // Reach destinations through `proxy`, which is itself dialed with `inner`
+    pub fn new(proxy: UpstreamProxy, credentials: Option<Socks5Credentials>, inner: Arc<dyn UpstreamDialer>) -> Self {
+        Self { proxy, credentials, inner }
+    }
+}
+
+#[async_trait]
+impl UpstreamDialer for Socks5Dialer {
+    async fn dial(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        let mut stream = self.inner.dial(&self.proxy.host, self.proxy.port).await?;
+        connect(&mut stream, self.credentials.as_ref(), host, port)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("SOCKS5 proxy {}: {}", self.proxy, e)))?;
+        Ok(stream)
+    }
+}
+
+// Authenticate with the SOCKS5 proxy at the other end of `stream` and have
+// it connect to `host:port`. Once this returns, the stream is the tunnel.
+pub async fn connect<S>(stream: &mut S, credentials: Option<&Socks5Credentials>, host: &str, port: u16) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+        None => &[VERSION, 1, METHOD_NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(invalid_data("not a SOCKS5 server"));
+    }
+    match (choice[1], credentials) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_USERNAME_PASSWORD, Some(credentials)) => authenticate(stream, credentials).await?,
+        (METHOD_NONE_ACCEPTABLE, _) => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "no acceptable authentication method"));
+        }
+        (method, _) => return Err(invalid_data(&format!("server chose unoffered method {:#04x}", method))),
+    }
+
+    let mut request = vec![VERSION, COMMAND_CONNECT, 0];
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name = u8::try_from(host.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "hostname too long for SOCKS5"))?;
+            request.push(ATYP_DOMAIN);
+            request.push(name);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // VER REP RSV ATYP, then the bound address, which is read and discarded
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid_data("malformed CONNECT reply"));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("CONNECT refused: {}", reply_message(reply[1]))));
+    }
+    let address_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => return Err(invalid_data(&format!("unknown address type {:#04x} in CONNECT reply", atyp))),
+    };
+    let mut bound = vec![0; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn authenticate<S>(stream: &mut S, credentials: &Socks5Credentials) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Lengths are checked when the credentials are parsed
+    let mut request = vec![1, credentials.username.len() as u8];
+    request.extend_from_slice(credentials.username.as_bytes());
+    request.push(credentials.password.len() as u8);
+    request.extend_from_slice(credentials.password.as_bytes());
+    stream.write_all(&request).await?;
+
+    let mut status = [0; 2];
+    stream.read_exact(&mut status).await?;
+    if status[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "username/password rejected"));
+    }
+    Ok(())
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,167 @@
+// Classification of upstream connection errors that look TLS-related.
+//
+// Upstream TLS happens end to end between client and server, so the proxy
+// only ever sees these as failed connects whose message mentions
+// certificates or handshakes. `classify` turns such a message into an
+// `SslErrorKind` that can be counted (`ProxyStats::ssl_errors`) as well as
+// logged with guidance by `analyze_ssl_error`.
+
+use log::{info, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Common SSL/TLS certificate error patterns
+pub const SSL_ERROR_INDICATORS: &[&str] = &[
+    "certificate",
+    "cert",
+    "tls",
+    "ssl",
+    "handshake",
+    "verification",
+    "expired",
+    "self-signed",
+    "untrusted",
+    "certificate chain",
+    "certificate verify",
+    "certificate has expired",
+    "certificate not yet valid",
+    "certificate revoked",
+    "certificate signature",
+    "certificate authority",
+    "ca",
+    "unknown ca",
+    "unable to get local issuer",
+    "issuer certificate",
+    "root certificate",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SslErrorKind {
+    Expired,
+    SelfSigned,
+    HandshakeFailed,
+    VerifyFailed,
+    Revoked,
+    Unknown,
+    NotSslRelated,
+}
+
+impl SslErrorKind {
+    // Most specific cause first, as the guidance has always been ordered
+    pub fn classify(message: &str) -> Self {
+        let message = message.to_lowercase();
+        if !SSL_ERROR_INDICATORS.iter().any(|indicator| message.contains(indicator)) {
+            Self::NotSslRelated
+        } else if message.contains("expired") {
+            Self::Expired
+        } else if message.contains("self-signed") || message.contains("untrusted") {
+            Self::SelfSigned
+        } else if message.contains("handshake") {
+            Self::HandshakeFailed
+        } else if message.contains("verify") {
+            Self::VerifyFailed
+        } else if message.contains("revoked") {
+            Self::Revoked
+        } else {
+            Self::Unknown
+        }
+    }
+
+    // What went wrong, in a few words; `None` for errors that aren't TLS-related
+    pub fn cause(self) -> Option<&'static str> {
+        self.guidance().map(|(cause, _)| cause)
+    }
+
+    // What went wrong and what to do about it, for the log
+    fn guidance(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Self::Expired => Some(("Certificate has expired", "Update certificate on target server")),
+            Self::SelfSigned => Some((
+                "Certificate is self-signed or untrusted",
+                "Add certificate to trust store or use valid certificate",
+            )),
+            Self::HandshakeFailed => Some(("TLS handshake failed", "Check certificate compatibility and TLS version")),
+            Self::VerifyFailed => Some(("Certificate verification failed", "Check certificate chain and CA trust")),
+            Self::Revoked => Some(("Certificate has been revoked", "Renew certificate with new signing")),
+            Self::Unknown => Some(("Unknown SSL/TLS certificate issue", "Investigate certificate validity and trust")),
+            Self::NotSslRelated => None,
+        }
+    }
+}
+
+// Classify a failed upstream connect and, if it looks TLS-related, log the
+// likely cause and remedy
+pub fn analyze_ssl_error(host: &str, port: u16, error: &std::io::Error) -> SslErrorKind {
+    let kind = SslErrorKind::classify(&error.to_string());
+    let Some((cause, action)) = kind.guidance() else {
+        return kind;
+    };
+
+    warn!("🔒 SSL/TLS Certificate Issue Detected");
+    warn!("   Target: {}:{}", host, port);
+    warn!("   Error: {}", error);
+    warn!("   Cause: {}", cause);
+    warn!("   Action: {}", action);
+
+    // Additional context for VPN scenarios
+    if cfg!(windows) {
+        info!("   Note: VPN routing may affect certificate validation");
+        info!("   Consider: Certificate might be valid but blocked by VPN policy");
+    }
+    kind
+}
+
+// TLS-related upstream connect failures by kind, kept in `ProxyStats`
+#[derive(Debug, Default)]
+pub struct SslErrorStats {
+    pub expired: AtomicU64,
+    pub self_signed: AtomicU64,
+    pub handshake_failed: AtomicU64,
+    pub verify_failed: AtomicU64,
+    pub revoked: AtomicU64,
+    pub unknown: AtomicU64,
+}
+
+impl SslErrorStats {
+    pub fn record(&self, kind: SslErrorKind) {
+        let counter = match kind {
+            SslErrorKind::Expired => &self.expired,
+            SslErrorKind::SelfSigned => &self.self_signed,
+            SslErrorKind::HandshakeFailed => &self.handshake_failed,
+            SslErrorKind::VerifyFailed => &self.verify_failed,
+            SslErrorKind::Revoked => &self.revoked,
+            SslErrorKind::Unknown => &self.unknown,
+            SslErrorKind::NotSslRelated => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Values of every counter, each passed through `read` (a load, or a
+    // swap when resetting)
+    pub(crate) fn read_with(&self, read: impl Fn(&AtomicU64) -> u64) -> SslErrorCounts {
+        SslErrorCounts {
+            expired: read(&self.expired),
+            self_signed: read(&self.self_signed),
+            handshake_failed: read(&self.handshake_failed),
+            verify_failed: read(&self.verify_failed),
+            revoked: read(&self.revoked),
+            unknown: read(&self.unknown),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub struct SslErrorCounts {
+    pub expired: u64,
+    pub self_signed: u64,
+    pub handshake_failed: u64,
+    pub verify_failed: u64,
+    pub revoked: u64,
+    pub unknown: u64,
+}
+
+impl SslErrorCounts {
+    pub fn total(&self) -> u64 {
+        self.expired + self.self_signed + self.handshake_failed + self.verify_failed + self.revoked + self.unknown
+    }
+}
@@ -0,0 +1,86 @@
+// SSRF guard for `--deny-private-ranges`.
+//
+// The proxy will connect anywhere it is asked to, which makes it a handy
+// pivot into internal networks. With the guard enabled the target is
+// resolved once here, every resolved address is checked, and the caller
+// dials the checked IP itself so a second DNS answer (rebinding) can't
+// swap in an internal address between check and connect.
+//
+// Independently of that flag, targets that reach the proxy's own admin
+// listener are always refused, so proxied clients can't read /stats.json
+// or /healthz through a tunnel. That check pins the address it vetted as the
+// dial target too.
+
+use crate::error::ProxyErrorKind;
+use crate::resolver::Resolver;
+use crate::ProxyError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Loopback, RFC 1918, link-local, unspecified and IPv6 ULA addresses
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_internal_v4(mapped),
+            None => is_internal_v6(ip),
+        },
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || (first & 0xfe00) == 0xfc00 // fc00::/7 unique local
+        || (first & 0xffc0) == 0xfe80 // fe80::/10 link-local
+}
+
+// Resolve `host` and return the address to dial, or a `Blocked` error if any
+// resolved address is internal. Rejecting on any match (not just the first)
+// stops a name that mixes public and internal records from slipping through.
+pub async fn resolve_external(resolver: &Resolver, host: &str, port: u16) -> Result<SocketAddr, ProxyError> {
+    let addrs = resolver.lookup(host, port).await?;
+    if addrs.iter().any(|addr| is_internal(addr.ip())) {
+        return Err(ProxyErrorKind::Blocked.into());
+    }
+    addrs.into_iter().next().ok_or_else(|| format!("{} did not resolve to any address", host).into())
+}
+
+// Whether a connection to `target` would land on a listener bound to `own`.
+// Loopback and unspecified targets reach any local listener; a listener on
+// the unspecified address is also reachable through every local interface
+// address, which is detected by whether that address can be bound to.
+pub fn reaches_listener(target: SocketAddr, own: SocketAddr) -> bool {
+    let ip = target.ip().to_canonical();
+    target.port() == own.port()
+        && (ip.is_loopback()
+            || ip.is_unspecified()
+            || ip == own.ip().to_canonical()
+            || (own.ip().is_unspecified() && std::net::UdpSocket::bind((ip, 0)).is_ok()))
+}
+
+// Resolve `host` and check its addresses against `own`, returning the one to
+// dial, or a `Blocked` error if any of them reaches the listener. The caller
+// dials the returned IP rather than the name, so a rebinding answer can't
+// point the connection at the listener after the check. Targets on other
+// ports can't reach it and aren't resolved (`None`). The caller decides what
+// a resolution failure means (see `--fail-closed`).
+pub async fn resolve_avoiding_listener(
+    resolver: &Resolver,
+    host: &str,
+    port: u16,
+    own: SocketAddr,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    if port != own.port() {
+        return Ok(None);
+    }
+    let addrs = resolver.lookup(host, port).await?;
+    if addrs.iter().any(|addr| reaches_listener(*addr, own)) {
+        return Err(ProxyErrorKind::Blocked.into());
+    }
+    addrs.into_iter().next().map(Some).ok_or_else(|| format!("{} did not resolve to any address", host).into())
+}
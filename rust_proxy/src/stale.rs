@@ -0,0 +1,100 @@
+// Last-known-good responses for `--serve-stale-on-error`.
+//
+// The proxy has no response cache; this keeps only what is needed to ride
+// out an upstream outage. The most recent complete `200` response to each
+// plain GET (the same requests `--coalesce-gets` would share, subject to the
+// same size and privacy limits) is remembered, and when a later fetch of it
+// fails (connect error, timeout, open circuit or a 5xx) the remembered copy
+// is served with `Warning: 110` instead of an error. Entries never expire:
+// being stale is the point, and they are replaced by every fresh response.
+
+use crate::bounded_map::BoundedMap;
+use crate::{find_header_terminator, ByteCounters, Direction, ProxyError, ProxyStats};
+use log::warn;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+pub const MAX_STALE_ENTRIES: usize = 1024;
+pub const STALE_WARNING: &str = "Warning: 110 - \"Response is Stale\"\r\n";
+
+#[derive(Debug)]
+pub struct StaleStore {
+    responses: BoundedMap<Vec<u8>>,
+}
+
+impl StaleStore {
+    pub fn new(capacity: usize) -> Self {
+        Self { responses: BoundedMap::new(capacity) }
+    }
+
+    pub fn record(&self, key: &str, response: &[u8]) {
+        self.responses.remove(key);
+        self.responses.get_or_insert_with(key, || response.to_vec());
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
+        self.responses.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+}
+
+// The store entry a request may fall back to or refresh
+#[derive(Debug, Clone, Copy)]
+pub struct StaleSlot<'a> {
+    pub store: &'a StaleStore,
+    pub key: &'a str,
+}
+
+impl StaleSlot<'_> {
+    // The remembered response, marked stale, if there is one
+    pub fn fallback(&self) -> Option<Vec<u8>> {
+        self.store.get(self.key).map(|response| with_stale_warning(&response))
+    }
+
+    pub fn record(&self, response: &[u8]) {
+        self.store.record(self.key, response);
+    }
+}
+
+// Insert the stale `Warning` header after the status line
+pub fn with_stale_warning(response: &[u8]) -> Vec<u8> {
+    let Some(status_end) = response.windows(2).position(|w| w == b"\r\n").map(|i| i + 2) else {
+        return response.to_vec();
+    };
+    if find_header_terminator(response).is_none() {
+        return response.to_vec();
+    }
+    let mut marked = Vec::with_capacity(response.len() + STALE_WARNING.len());
+    marked.extend_from_slice(&response[..status_end]);
+    marked.extend_from_slice(STALE_WARNING.as_bytes());
+    marked.extend_from_slice(&response[status_end..]);
+    marked
+}
+
+// Answer with the remembered response if there is one. `false` means there
+// was nothing to serve and the caller should report the failure as usual.
+pub async fn serve_stale<W: AsyncWrite + Unpin>(
+    conn_id: u64,
+    client: &mut W,
+    slot: Option<StaleSlot<'_>>,
+    stats: &ProxyStats,
+    counters: ByteCounters<'_>,
+) -> Result<bool, ProxyError> {
+    let Some(response) = slot.and_then(|slot| slot.fallback()) else {
+        return Ok(false);
+    };
+    stats.stale_responses.fetch_add(1, Ordering::Relaxed);
+    warn!("[#{}] Upstream failed, serving stale response for {}", conn_id, slot.map_or("", |slot| slot.key));
+    client.write_all(&response).await?;
+    stats.record_bytes(Direction::ServerToClient, response.len() as u64);
+    counters.add(response.len() as u64);
+    Ok(true)
+}
@@ -0,0 +1,191 @@
+// Access logs to a syslog collector (`--syslog`).
+//
+// Every finished connection (the `closed` connection event) is sent as one
+// RFC 5424 message whose MSG is the event's JSON, over UDP (the default) or
+// TCP with RFC 6587 octet-counting framing. Delivery is best effort: a
+// message that can't be sent is dropped with a warning, and a collector
+// that falls behind loses events the same way event socket subscribers do.
+
+use log::{debug, warn};
+use std::io;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast;
+
+pub const APP_NAME: &str = "rust_proxy";
+pub const SEVERITY_INFO: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Facility {
+    User,
+    Daemon,
+    Auth,
+    Authpriv,
+    #[default]
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    pub fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Auth => 4,
+            Self::Authpriv => 10,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTarget {
+    Udp(String),
+    Tcp(String),
+}
+
+impl std::str::FromStr for SyslogTarget {
+    type Err = String;
+
+    // `host:port` or `udp://host:port` for UDP, `tcp://host:port` for TCP
+    fn from_str(value: &str) -> Result<Self, String> {
+        let target = match value.split_once("://") {
+            None => Self::Udp(value.to_string()),
+            Some(("udp", addr)) => Self::Udp(addr.to_string()),
+            Some(("tcp", addr)) => Self::Tcp(addr.to_string()),
+            Some((scheme, _)) => return Err(format!("unsupported syslog transport {}", scheme)),
+        };
+        match &target {
+            Self::Udp(addr) | Self::Tcp(addr) if addr.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) => Ok(target),
+            _ => Err(format!("expected host:port, got {}", value)),
+        }
+    }
+}
+
+// One RFC 5424 message: `<PRI>1 TIMESTAMP HOSTNAME APP PROCID MSGID - MSG`.
+// The hostname is left to the collector (NILVALUE), which knows the sender.
+pub fn format_message(facility: Facility, severity: u8, timestamp: SystemTime, msgid: &str, msg: &str) -> String {
+    format!(
+        "<{}>1 {} - {} {} {} - {}",
+        facility.code() as u16 * 8 + severity as u16,
+        rfc3339_utc(timestamp),
+        APP_NAME,
+        std::process::id(),
+        msgid,
+        msg
+    )
+}
+
+// `2024-02-29T12:34:56.789Z`, without pulling in a date crate
+fn rfc3339_utc(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, day_secs) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_secs / 3_600,
+        day_secs / 60 % 60,
+        day_secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(Option<TcpStream>, String),
+}
+
+impl Connection {
+    async fn open(target: &SyslogTarget) -> io::Result<Self> {
+        match target {
+            SyslogTarget::Udp(addr) => {
+                let remote = tokio::net::lookup_host(addr.as_str())
+                    .await?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", addr)))?;
+                let local = if remote.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(remote).await?;
+                Ok(Self::Udp(socket))
+            }
+            SyslogTarget::Tcp(addr) => Ok(Self::Tcp(None, addr.clone())),
+        }
+    }
+
+    async fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+            Self::Tcp(stream, addr) => {
+                // (Re)connect lazily so a collector restart only costs the
+                // messages sent while it was down
+                if stream.is_none() {
+                    *stream = Some(TcpStream::connect(addr.as_str()).await?);
+                }
+                let framed = format!("{} {}", message.len(), message);
+                let result = stream.as_mut().unwrap().write_all(framed.as_bytes()).await;
+                if result.is_err() {
+                    *stream = None;
+                }
+                result
+            }
+        }
+    }
+}
+
+// Forward `closed` connection events from `events` to the collector until
+// the event bus shuts down
+pub async fn forward_access_log(
+    target: SyslogTarget,
+    facility: Facility,
+    mut events: broadcast::Receiver<Arc<str>>,
+) -> io::Result<()> {
+    let mut connection = Connection::open(&target).await?;
+    loop {
+        let line = match events.recv().await {
+            Ok(line) => line,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!("Syslog forwarder lagged, dropped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        let is_closed = serde_json::from_str::<serde_json::Value>(&line).is_ok_and(|event| event["event"] == "closed");
+        if !is_closed {
+            continue;
+        }
+        let message = format_message(facility, SEVERITY_INFO, SystemTime::now(), "access", &line);
+        if let Err(e) = connection.send(&message).await {
+            warn!("Failed to send access log to syslog {:?}: {}", target, e);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Delay before answering policy-blocked requests (`--block-tarpit`).
+//
+// A client probing which destinations the proxy will reach gets each `403`
+// only after the delay, which makes scanning the policy slow and expensive.
+//
+// A tarpitted connection keeps its slot in the global connection limit for
+// the whole delay: it is a real open socket, and giving the slot back would
+// let an abuser hold any number of them. Instead, at most `max` connections
+// are held at once (`--block-tarpit-max`); blocks beyond that are answered
+// straight away, so the tarpit can never take more than that share of
+// `MAX_CONNECTIONS` from legitimate clients.
+
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+pub const DEFAULT_TARPIT_MAX: usize = 256;
+
+#[derive(Debug)]
+pub struct Tarpit {
+    delay: Duration,
+    slots: Semaphore,
+}
+
+impl Tarpit {
+    pub fn new(delay: Duration, max: usize) -> Self {
+        Self { delay, slots: Semaphore::new(max) }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    // Wait out the delay, unless `max` connections are already waiting.
+    // `false` means the block should be answered now.
+    pub async fn hold(&self) -> bool {
+        match self.slots.try_acquire() {
+            Ok(_slot) => {
+                tokio::time::sleep(self.delay).await;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
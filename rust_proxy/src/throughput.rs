@@ -0,0 +1,64 @@
+// Minimum-throughput enforcement for relayed data (`--min-throughput`).
+//
+// The idle timeout only bounds the gap between two reads, so a peer that
+// trickles a byte every few seconds (slowloris-style) can hold a connection
+// open indefinitely. `ThroughputMonitor` keeps the reads of the last
+// `window` and flags the transfer once they add up to less than the
+// minimum rate.
+//
+// A direction that goes quiet is idle rather than slow, which is the idle
+// timeout's business: when a read follows a gap of a whole window or more
+// (including the first read, e.g. after server think time), measurement
+// starts over from that read, and nothing is judged until a full window of
+// activity has been seen. The window should therefore be well below the
+// idle timeout.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const MIN_THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinThroughput {
+    pub bytes_per_sec: u64,
+    pub window: Duration,
+}
+
+#[derive(Debug)]
+pub struct ThroughputMonitor {
+    minimum: MinThroughput,
+    samples: VecDeque<(Instant, u64)>,
+    windowed: u64,
+    since: Instant,
+}
+
+impl ThroughputMonitor {
+    pub fn new(minimum: MinThroughput) -> Self {
+        Self { minimum, samples: VecDeque::new(), windowed: 0, since: Instant::now() }
+    }
+
+    // Record a read of `n` bytes, returning true if the transfer has fallen
+    // below the minimum rate over the last window
+    pub fn record(&mut self, n: u64) -> bool {
+        let now = Instant::now();
+        let window = self.minimum.window;
+        if self.samples.back().is_none_or(|&(last, _)| now.duration_since(last) >= window) {
+            self.samples.clear();
+            self.windowed = 0;
+            self.since = now;
+        }
+        self.samples.push_back((now, n));
+        self.windowed += n;
+        while let Some(&(at, bytes)) = self.samples.front() {
+            if now.duration_since(at) <= window {
+                break;
+            }
+            self.samples.pop_front();
+            self.windowed -= bytes;
+        }
+
+        now.duration_since(self.since) >= window
+            && (self.windowed as f64) < self.minimum.bytes_per_sec as f64 * window.as_secs_f64()
+    }
+}
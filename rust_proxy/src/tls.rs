@@ -0,0 +1,91 @@
+// TLS termination for inbound proxy connections.
+//
+// With `--tls-cert`/`--tls-key` the proxy itself is reached over TLS (an
+// HTTPS proxy endpoint). Accepted sockets are wrapped here and then handed
+// to `handle_client` like any other client stream.
+
+use crate::dialer::AsyncReadWrite;
+use crate::{handle_client, normalize_peer_addr, ProxyConfig, ProxyError, ProxyStats};
+use log::debug;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+fn invalid_data(message: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Build an acceptor from a PEM certificate chain and PEM private key
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data(format!("{}: {}", cert_path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("{}: no certificates found", cert_path.display())));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| invalid_data(format!("{}: {}", key_path.display(), e)))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+impl AsyncReadWrite for TlsStream<TcpStream> {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.get_ref().0.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        AsyncReadWrite::set_keepalive(self.get_ref().0, idle)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+}
+
+// Complete the TLS handshake, then serve the connection as usual. The
+// handshake shares the header read budget so a stalled client can't hold a
+// connection slot indefinitely; failures are counted and the socket closed.
+pub async fn handle_tls_client(
+    socket: TcpStream,
+    acceptor: TlsAcceptor,
+    stats: Arc<ProxyStats>,
+    config: Arc<ProxyConfig>,
+) -> Result<(), ProxyError> {
+    let peer = normalize_peer_addr(socket.peer_addr()?);
+    let stream = match timeout(config.header_read_timeout, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            stats.tls_handshake_errors.fetch_add(1, Ordering::Relaxed);
+            debug!("TLS handshake with {} failed: {}", peer, e);
+            return Ok(());
+        }
+        Err(_) => {
+            stats.tls_handshake_errors.fetch_add(1, Ordering::Relaxed);
+            debug!("TLS handshake with {} timed out", peer);
+            return Ok(());
+        }
+    };
+    handle_client(stream, stats, config).await
+}
@@ -0,0 +1,55 @@
+// Transparent proxying (`--transparent`, Linux only).
+//
+// With an iptables `REDIRECT` (or `DNAT`) rule in front of the proxy, clients
+// connect to their real destination and the kernel hands the connection to
+// us instead. There is no CONNECT line or absolute URI to read the target
+// from; netfilter keeps the pre-NAT destination, which the `SO_ORIGINAL_DST`
+// socket option (`IP6T_SO_ORIGINAL_DST` for IPv6) returns. The connection is
+// then tunneled to that address untouched, CONNECT style but without the
+// `200`. It goes through the checks a CONNECT would (maintenance mode,
+// `--rate-per-ip`, the CONNECT port range, internal addresses, the circuit
+// breaker, `--max-per-destination`); with nothing to answer a refusal on,
+// the connection is just closed.
+//
+// A connection made straight to the proxy has no translated destination
+// (the option fails, or returns the proxy's own address), so it is served as
+// an ordinary proxy request; one listener can do both. With `--fail-closed`,
+// a connection whose destination can't be read at all is closed instead,
+// since it may have been redirected.
+//
+// Trying it needs root and a rule such as
+//
+//     iptables -t nat -A OUTPUT -p tcp --dport 80 -m owner ! --uid-owner proxy \
+//         -j REDIRECT --to-ports 3128
+//
+// (the owner match keeps the proxy's own upstream connections from being
+// redirected back to it), so the tests cover the option parsing, the
+// not-redirected case, and `tunnel_transparent` fed a target directly.
+
+use socket2::{SockAddr, SockRef};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+// Where a connection accepted on `stream` was originally addressed, when it
+// was redirected to us
+pub fn original_dst(stream: &TcpStream) -> io::Result<Option<SocketAddr>> {
+    let local = stream.local_addr()?;
+    let socket = SockRef::from(stream);
+    let original = if local.is_ipv6() { socket.original_dst_v6() } else { socket.original_dst_v4() };
+    match original {
+        Ok(original) => Ok(redirected_target(&original, local)),
+        // No conntrack entry: nothing translated this connection
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// The target an `SO_ORIGINAL_DST` answer names for a connection to our
+// `local` address. `None` when it is our own address, i.e. the connection
+// was not redirected, or not an IP address at all.
+pub fn redirected_target(original: &SockAddr, local: SocketAddr) -> Option<SocketAddr> {
+    let original = original.as_socket()?;
+    let original = SocketAddr::new(original.ip().to_canonical(), original.port());
+    (original != SocketAddr::new(local.ip().to_canonical(), local.port())).then_some(original)
+}
@@ -0,0 +1,231 @@
+// Chaining through upstream proxies (`--upstream-proxy`).
+//
+// With upstream proxies configured, every destination is reached through a
+// CONNECT tunnel that one of them opens, for plain-HTTP requests as well as
+// CONNECT ones, so the upstream must allow CONNECT to those ports. The
+// tunnel goes to the address the proxy would otherwise have dialed, so
+// `--route` and `--deny-private-ranges` pinning apply unchanged.
+//
+// Proxies take turns round-robin. When the chosen one can't be reached or
+// refuses the tunnel, the next is tried, and a request only fails once every
+// proxy has failed it.
+//
+// The CONNECT answer is read until its head is complete, however many reads
+// that takes (bounded by the attempt timeout). Anything the upstream sent
+// after the head already belongs to the tunnel, e.g. a server-first banner,
+// and is handed to the client ahead of the rest.
+
+use crate::dialer::{AsyncReadWrite, BoxedStream, UpstreamDialer};
+use crate::{find_header_terminator, ProxyStats};
+use crate::headers::ResponseHead;
+use log::{debug, warn};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+// Longest CONNECT response head accepted from an upstream proxy
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamProxy {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for UpstreamProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", authority(&self.host, self.port))
+    }
+}
+
+// `host:port`, optionally written as an `http://` URL
+pub fn parse_upstream_proxy(value: &str) -> Result<UpstreamProxy, String> {
+    let address = value.strip_prefix("http://").unwrap_or(value).trim_end_matches('/');
+    let (host, port) = address.rsplit_once(':').ok_or_else(|| format!("expected host:port, got {}", value))?;
+    let port = port.parse::<u16>().map_err(|_| format!("invalid port in {}", value))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(format!("missing host in {}", value));
+    }
+    Ok(UpstreamProxy { host: host.to_string(), port })
+}
+
+// Connection outcomes for one upstream proxy
+#[derive(Debug, Default)]
+pub struct UpstreamProxyStats {
+    pub successes: AtomicU64,
+    pub failures: AtomicU64,
+}
+
+// The configured upstream proxies and whose turn it is
+#[derive(Debug)]
+pub struct UpstreamProxies {
+    proxies: Vec<UpstreamProxy>,
+    next: AtomicUsize,
+}
+
+impl UpstreamProxies {
+    pub fn new(proxies: Vec<UpstreamProxy>) -> Self {
+        Self { proxies, next: AtomicUsize::new(0) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.proxies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
+    }
+
+    // Every proxy once, starting with the one whose turn it is
+    pub fn rotation(&self) -> impl Iterator<Item = &UpstreamProxy> {
+        let start = if self.proxies.is_empty() { 0 } else { self.next.fetch_add(1, Ordering::Relaxed) % self.proxies.len() };
+        self.proxies.iter().cycle().skip(start).take(self.proxies.len())
+    }
+
+    // Open a tunnel to `host:port` through the first proxy in the rotation
+    // that accepts it, recording each attempt in `stats`. Each attempt gets
+    // `attempt_timeout` for both the connect and the CONNECT exchange.
+    pub async fn dial(
+        &self,
+        conn_id: u64,
+        dialer: &dyn UpstreamDialer,
+        stats: &ProxyStats,
+        host: &str,
+        port: u16,
+        attempt_timeout: Duration,
+    ) -> io::Result<BoxedStream> {
+        let mut last_error = None;
+        for proxy in self.rotation() {
+            let attempt = async {
+                let mut stream = dialer.dial(&proxy.host, proxy.port).await?;
+                let early = open_tunnel(&mut stream, host, port).await?;
+                if early.is_empty() {
+                    return Ok::<_, io::Error>(stream);
+                }
+                debug!("[#{}] {} tunnel bytes arrived with the CONNECT response from {}", conn_id, early.len(), proxy);
+                Ok(Box::new(Prefixed { prefix: early, offset: 0, inner: stream }) as BoxedStream)
+            };
+            let result = match timeout(attempt_timeout, attempt).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            };
+            let proxy_stats = stats.upstream_proxy(&proxy.to_string());
+            match result {
+                Ok(stream) => {
+                    proxy_stats.successes.fetch_add(1, Ordering::Relaxed);
+                    debug!("[#{}] Tunneled to {}:{} through upstream proxy {}", conn_id, host, port, proxy);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    proxy_stats.failures.fetch_add(1, Ordering::Relaxed);
+                    warn!("[#{}] Upstream proxy {} failed for {}:{} - {}", conn_id, proxy, host, port, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no upstream proxies configured")))
+    }
+}
+
+// Ask the proxy at the other end of `stream` for a tunnel to `host:port` and
+// wait for its answer. Any 2xx opens the tunnel; the bytes read past the
+// response head are returned, as they are the start of the tunneled stream.
+pub async fn open_tunnel<S>(stream: &mut S, host: &str, port: u16) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let target = authority(host, port);
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).as_bytes()).await?;
+
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    let end = loop {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed before answering CONNECT"));
+        }
+        head.extend_from_slice(&buffer[..n]);
+        if let Some(end) = find_header_terminator(&head) {
+            break end;
+        }
+        if head.len() > MAX_RESPONSE_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "CONNECT response head too large"));
+        }
+    };
+    let response = ResponseHead::parse(&head[..end])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed CONNECT response"))?;
+    if !(200..300).contains(&response.status) {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("CONNECT refused with {} {}", response.status, response.reason),
+        ));
+    }
+    Ok(head.split_off(end))
+}
+
+// A tunnel whose first bytes were read along with the CONNECT response:
+// reads return those before anything more from the stream
+struct Prefixed {
+    prefix: Vec<u8>,
+    offset: usize,
+    inner: BoxedStream,
+}
+
+impl AsyncRead for Prefixed {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.offset < self.prefix.len() {
+            let n = (self.prefix.len() - self.offset).min(buf.remaining());
+            buf.put_slice(&self.prefix[self.offset..self.offset + n]);
+            self.offset += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Prefixed {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncReadWrite for Prefixed {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        self.inner.set_keepalive(idle)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
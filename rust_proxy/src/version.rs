@@ -0,0 +1,13 @@
+// Which build this is, for `--version` and `--version-header`. The commit
+// and date come from build.rs.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("RUST_PROXY_GIT_COMMIT");
+pub const BUILD_DATE: &str = env!("RUST_PROXY_BUILD_DATE");
+
+// e.g. `0.1.0 (3f2a9c1b7d4e, built 2026-10-16)`
+pub const LONG_VERSION: &str =
+    concat!(env!("CARGO_PKG_VERSION"), " (", env!("RUST_PROXY_GIT_COMMIT"), ", built ", env!("RUST_PROXY_BUILD_DATE"), ")");
+
+// Header naming the build on forwarded requests
+pub const VERSION_HEADER: &str = "X-Proxy-Version";
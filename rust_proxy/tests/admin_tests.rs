@@ -0,0 +1,355 @@
+mod common;
+
+use async_trait::async_trait;
+use rust_proxy::admin::{serve_admin, AdminState, UpstreamCheck};
+use rust_proxy::dialer::{BoxedStream, UpstreamDialer};
+use rust_proxy::idle::{drain_on_idle, wait_until_idle};
+use rust_proxy::resolver::{Resolver, ResolvingDialer};
+use rust_proxy::{ProxyConfig, ProxyStats};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+async fn start_admin(stats: Arc<ProxyStats>, upstream_check: Option<UpstreamCheck>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(AdminState {
+        stats,
+        config: Arc::new(ProxyConfig::default()),
+        upstream_check,
+    });
+    tokio::spawn(serve_admin(listener, state));
+    addr
+}
+
+#[tokio::test]
+async fn test_healthz_ok() {
+    let admin = start_admin(Arc::new(ProxyStats::new()), None).await;
+    let response = common::send_request(admin, b"GET /healthz HTTP/1.1\r\nHost: admin\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("\r\n\r\nok\n"));
+
+    let response = common::send_request(admin, b"GET /nope HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn test_healthz_tracks_upstream_reachability() {
+    // Reserve a port, then free it so the upstream starts out down
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    drop(upstream);
+
+    let ttl = Duration::from_millis(300);
+    let check = UpstreamCheck::new(upstream_addr.to_string(), ttl);
+    let admin = start_admin(Arc::new(ProxyStats::new()), Some(check)).await;
+    let response = common::send_request(admin, b"GET /healthz HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "{}", response);
+
+    // Bring the upstream up; the cached failure holds until the TTL expires
+    let _upstream = TcpListener::bind(upstream_addr).await.unwrap();
+    let response = common::send_request(admin, b"GET /healthz HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503"));
+
+    tokio::time::sleep(ttl + Duration::from_millis(50)).await;
+    let response = common::send_request(admin, b"GET /healthz HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_metrics_exposes_connection_size_histogram() {
+    let stats = Arc::new(ProxyStats::new());
+    stats.connection_bytes.record(700);
+    stats.connection_bytes.record(2000);
+    let admin = start_admin(stats, None).await;
+
+    let response = common::send_request(admin, b"GET /metrics HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    assert!(body.contains("# TYPE proxy_connection_bytes histogram\n"));
+    assert!(body.contains("proxy_connection_bytes_bucket{le=\"1024\"} 1\n"));
+    assert!(body.contains("proxy_connection_bytes_bucket{le=\"2048\"} 2\n"));
+    assert!(body.contains("proxy_connection_bytes_bucket{le=\"+Inf\"} 2\n"));
+    assert!(body.contains("proxy_connection_bytes_sum 2700\n"));
+    assert!(body.contains("proxy_connection_bytes_count 2\n"));
+    assert!(body.contains("# TYPE proxy_http_responses_total counter\n"));
+    assert!(body.contains("proxy_http_responses_total{class=\"2xx\"} 0\n"));
+}
+
+#[tokio::test]
+async fn test_metrics_counts_responses_by_class() {
+    let stats = Arc::new(ProxyStats::new());
+    stats.record_response_status(Some(200));
+    stats.record_response_status(Some(404));
+    stats.record_response_status(Some(404));
+    stats.record_response_status(None);
+    let admin = start_admin(stats, None).await;
+
+    let response = common::send_request(admin, b"GET /metrics HTTP/1.1\r\n\r\n").await;
+    assert!(response.contains("proxy_http_responses_total{class=\"2xx\"} 1\n"));
+    assert!(response.contains("proxy_http_responses_total{class=\"4xx\"} 2\n"));
+    assert!(response.contains("proxy_http_responses_total{class=\"5xx\"} 0\n"));
+    assert!(response.contains("proxy_http_responses_total{class=\"invalid\"} 1\n"));
+}
+
+#[tokio::test]
+async fn test_stats_json_reports_counters() {
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+    let (origin, _requests) = common::start_recording_origin(RESPONSE).await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+    let admin = start_admin(stats, None).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    common::send_request(proxy, request.as_bytes()).await;
+    common::send_request(proxy, b"CONNECT 127.0.0.1:1 HTTP/1.1\r\n\r\n").await;
+
+    let response = common::send_request(admin, b"GET /stats.json HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    let document: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(document["total_connections"], 2);
+    assert_eq!(document["active_connections"], 0);
+    assert_eq!(document["http_requests"], 1);
+    assert_eq!(document["https_requests"], 1);
+    assert_eq!(document["connection_errors"], 1);
+    assert_eq!(document["bytes_transferred"], RESPONSE.len() as u64);
+    assert!(document["uptime_secs"].as_f64().unwrap() > 0.0);
+    assert!(document["megabytes_transferred"].as_f64().unwrap() > 0.0);
+}
+
+#[tokio::test]
+async fn test_connect_to_own_admin_listener_rejected() {
+    let admin = start_admin(Arc::new(ProxyStats::new()), None).await;
+    let (proxy, _stats) = common::start_proxy(ProxyConfig { admin_addr: Some(admin), ..Default::default() }).await;
+
+    for target in [format!("127.0.0.1:{}", admin.port()), format!("localhost:{}", admin.port())] {
+        let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}: {}", target, response);
+    }
+
+    // Forwarding to it is refused too
+    let request = format!("GET http://127.0.0.1:{}/stats.json HTTP/1.1\r\n\r\n", admin.port());
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+    // Other local ports are unaffected
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+// A DNS server that answers every query with SERVFAIL
+async fn start_failing_dns() -> SocketAddr {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut query = [0; 512];
+        while let Ok((n, peer)) = socket.recv_from(&mut query).await {
+            // Same ID and question, response flag and rcode 2, no records
+            let mut response = query[..n].to_vec();
+            response[2..4].copy_from_slice(&[0x81, 0x82]);
+            response[6..12].fill(0);
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_admin_listener_check_fails_closed_on_dns_error() {
+    use clap::Parser;
+    use rust_proxy::Args;
+
+    let dns = start_failing_dns().await.to_string();
+    for fail_closed in [false, true] {
+        let mut args = vec!["rust_proxy", "--resolver", &dns, "--admin-addr", "127.0.0.1:9"];
+        if fail_closed {
+            args.push("--fail-closed");
+        }
+        let (proxy, stats) = common::start_proxy(ProxyConfig::from_args(&Args::parse_from(args))).await;
+
+        // The target is unreachable either way; failing closed refuses it
+        // before the dial is tried
+        let response = common::send_request(proxy, b"CONNECT nowhere.test:9 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+        assert_eq!(stats.fail_closed_denied.load(Ordering::Relaxed), u64::from(fail_closed));
+    }
+}
+
+// A DNS server that answers its first A query with a public address and
+// every later one with 127.0.0.1, uncached (TTL 0), like a rebinding name
+async fn start_rebinding_dns() -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let answered = queries.clone();
+    tokio::spawn(async move {
+        let mut query = [0; 512];
+        while let Ok((_, peer)) = socket.recv_from(&mut query).await {
+            let mut end = 12;
+            while query[end] != 0 {
+                end += 1 + query[end] as usize;
+            }
+            let is_a = query[end + 1..end + 3] == [0, 1];
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+            response.extend_from_slice(&query[12..end + 5]);
+            if is_a {
+                let ip = if answered.fetch_add(1, Ordering::Relaxed) == 0 { [203, 0, 113, 7] } else { [127, 0, 0, 1] };
+                response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4]);
+                response.extend_from_slice(&ip);
+            }
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+    (addr, queries)
+}
+
+// Dialer recording the hosts it's handed, refusing them all
+#[derive(Debug, Default)]
+struct RecordingDialer(Mutex<Vec<String>>);
+
+#[async_trait]
+impl UpstreamDialer for RecordingDialer {
+    async fn dial(&self, host: &str, _port: u16) -> io::Result<BoxedStream> {
+        self.0.lock().unwrap().push(host.to_string());
+        Err(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"))
+    }
+}
+
+#[tokio::test]
+async fn test_admin_listener_check_pins_the_dialed_address() {
+    let admin = start_admin(Arc::new(ProxyStats::new()), None).await;
+    let (dns, queries) = start_rebinding_dns().await;
+    let resolver = Resolver::dns(dns);
+    let recorder = Arc::new(RecordingDialer::default());
+    let config = ProxyConfig {
+        admin_addr: Some(admin),
+        dialer: Arc::new(ResolvingDialer::new(resolver.clone(), recorder.clone())),
+        resolver,
+        ..Default::default()
+    };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    // The check sees the public answer; the dial must use that address
+    // rather than looking the name up again and getting loopback
+    let request = format!("CONNECT rebind.test:{} HTTP/1.1\r\n\r\n", admin.port());
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+    assert_eq!(*recorder.0.lock().unwrap(), ["203.0.113.7"]);
+    assert_eq!(queries.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_reaches_listener() {
+    use rust_proxy::ssrf::reaches_listener;
+
+    let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+    let loopback_admin = addr("127.0.0.1:9090");
+    assert!(reaches_listener(addr("127.0.0.1:9090"), loopback_admin));
+    assert!(reaches_listener(addr("[::1]:9090"), loopback_admin));
+    assert!(reaches_listener(addr("0.0.0.0:9090"), loopback_admin));
+    assert!(reaches_listener(addr("[::ffff:127.0.0.1]:9090"), loopback_admin));
+    assert!(!reaches_listener(addr("127.0.0.1:9091"), loopback_admin));
+    assert!(!reaches_listener(addr("93.184.215.14:9090"), loopback_admin));
+
+    // Admin on a specific external address only matches that address
+    assert!(reaches_listener(addr("203.0.113.7:9090"), addr("203.0.113.7:9090")));
+    assert!(!reaches_listener(addr("203.0.113.8:9090"), addr("203.0.113.7:9090")));
+    // Non-local addresses can't reach a wildcard listener
+    assert!(!reaches_listener(addr("203.0.113.8:9090"), addr("0.0.0.0:9090")));
+}
+
+#[tokio::test]
+async fn test_tenant_header_keeps_separate_stats() {
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+    let (origin, mut requests) = common::start_recording_origin(RESPONSE).await;
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let config = ProxyConfig { tenant_header: Some("X-Proxy-Tenant".to_string()), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+    let request = |target: SocketAddr, tenant: &str| {
+        format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nx-proxy-tenant: {}\r\n\r\n", target, target, tenant)
+    };
+
+    for tenant in ["acme", "acme", "globex"] {
+        let response = common::send_request(proxy, request(origin, tenant).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        // The tag never reaches the upstream
+        assert!(!requests.recv().await.unwrap().to_ascii_lowercase().contains("x-proxy-tenant"));
+    }
+    let response = common::send_request(proxy, request(closed, "globex").as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+
+    let acme = stats.tenant("acme");
+    let globex = stats.tenant("globex");
+    assert_eq!(acme.connections.load(Ordering::Relaxed), 2);
+    assert_eq!(acme.errors.load(Ordering::Relaxed), 0);
+    assert_eq!(globex.connections.load(Ordering::Relaxed), 2);
+    assert_eq!(globex.errors.load(Ordering::Relaxed), 1);
+    assert_eq!(acme.bytes.load(Ordering::Relaxed), 2 * globex.bytes.load(Ordering::Relaxed));
+
+    let admin = start_admin(stats, None).await;
+    let response = common::send_request(admin, b"GET /metrics HTTP/1.1\r\n\r\n").await;
+    assert!(response.contains("# TYPE proxy_tenant_requests_total counter\n"));
+    assert!(response.contains("proxy_tenant_requests_total{tenant=\"acme\"} 2\n"));
+    assert!(response.contains("proxy_tenant_errors_total{tenant=\"globex\"} 1\n"));
+    let response = common::send_request(admin, b"GET /stats.json HTTP/1.1\r\n\r\n").await;
+    let document: serde_json::Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    assert_eq!(document["tenants"]["globex"]["connections"], 2);
+}
+
+#[tokio::test]
+async fn test_healthz_reports_draining_after_idle_period() {
+    let stats = Arc::new(ProxyStats::new());
+    let config = Arc::new(ProxyConfig::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin = listener.local_addr().unwrap();
+    tokio::spawn(serve_admin(listener, Arc::new(AdminState { stats: stats.clone(), config: config.clone(), upstream_check: None })));
+    let marker = std::env::temp_dir().join(format!("rust_proxy_drain_{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+
+    // A connection held open keeps the proxy from counting as idle
+    stats.active_connections.store(1, Ordering::Relaxed);
+    let drain = {
+        let (stats, config, marker) = (stats.clone(), config.clone(), marker.clone());
+        tokio::spawn(async move {
+            drain_on_idle(&stats, Duration::from_millis(200), Duration::from_millis(20), &config.draining, Some(&marker)).await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let response = common::send_request(admin, b"GET /healthz HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(!marker.exists());
+
+    stats.active_connections.store(0, Ordering::Relaxed);
+    tokio::time::timeout(Duration::from_secs(5), drain).await.unwrap().unwrap();
+    let response = common::send_request(admin, b"GET /healthz HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable") && response.ends_with("draining\n"), "{}", response);
+    assert!(marker.exists());
+    std::fs::remove_file(&marker).unwrap();
+}
+
+#[tokio::test]
+async fn test_short_connections_reset_idle_period() {
+    let stats = Arc::new(ProxyStats::new());
+    let waiter = {
+        let stats = stats.clone();
+        tokio::spawn(async move { wait_until_idle(&stats, Duration::from_millis(300), Duration::from_millis(20)).await })
+    };
+    // Connections come and go between samples; none is ever seen active
+    for _ in 0..5 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stats.tcp_connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+    assert!(!waiter.is_finished());
+    tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use rust_proxy::buffer_pool::{BufferPool, BUFFER_POOL};
+use rust_proxy::{bounded_copy, ProxyConfig, BUFFER_SIZE};
+use std::time::Duration;
+
+#[test]
+fn test_buffers_return_to_pool_on_drop() {
+    let pool = BufferPool::new(16, 2);
+    {
+        let mut first = pool.get();
+        let _second = pool.get();
+        first[0] = 7;
+        assert_eq!(first.len(), 16);
+    }
+    assert_eq!(pool.idle(), 2);
+
+    // Reuse hands back the same allocations; nothing new is allocated
+    let _again = (pool.get(), pool.get());
+    assert_eq!(pool.allocations(), 2);
+    assert_eq!(pool.reuses(), 2);
+}
+
+#[test]
+fn test_pool_caps_idle_buffers() {
+    let pool = BufferPool::new(16, 1);
+    drop((pool.get(), pool.get(), pool.get()));
+    assert_eq!(pool.idle(), 1);
+}
+
+// Both checks use the process-wide pool, so they share one test to avoid
+// racing with each other
+#[tokio::test]
+async fn test_pool_reuse_under_sequential_requests() {
+    const REQUESTS: u64 = 200;
+
+    // Error paths still give their buffers back
+    let (reader, mut writer) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::io::AsyncWriteExt::write_all(&mut writer, &[1; 100]).await.unwrap();
+    let result = bounded_copy(reader, tokio::io::sink(), 10, Duration::from_secs(1)).await;
+    assert!(result.is_err());
+    assert!(BUFFER_POOL.idle() >= 1);
+
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+
+    let allocations_before = BUFFER_POOL.allocations();
+    let reuses_before = BUFFER_POOL.reuses();
+    for _ in 0..REQUESTS {
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.ends_with("ok"));
+        // Let the proxy task finish dropping its buffers
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let allocations = BUFFER_POOL.allocations() - allocations_before;
+    let reuses = BUFFER_POOL.reuses() - reuses_before;
+
+    // Two buffers per request (request head and response head; both bodies
+    // fit in them), nearly all of them reused
+    assert_eq!(allocations + reuses, REQUESTS * 2, "{} allocations, {} reuses", allocations, reuses);
+    assert!(allocations <= 16, "{} allocations", allocations);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use rust_proxy::capture::{parse_capture_target, Capture};
+use rust_proxy::ProxyConfig;
+use std::path::Path;
+use std::sync::Arc;
+
+const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+
+// The capture files in `dir` with this suffix, oldest first
+fn captures(dir: &Path, suffix: &str) -> Vec<String> {
+    let mut names: Vec<_> = std::fs::read_dir(dir)
+        .map(|entries| entries.map(|e| e.unwrap().path()).filter(|p| p.to_string_lossy().ends_with(suffix)).collect())
+        .unwrap_or_default();
+    names.sort();
+    names.iter().map(|p| String::from_utf8_lossy(&std::fs::read(p).unwrap()).into_owned()).collect()
+}
+
+#[test]
+fn test_parse_capture_target() {
+    assert_eq!(parse_capture_target("Example.com:443").unwrap(), "example.com:443");
+    assert_eq!(parse_capture_target("[::1]:8080").unwrap(), "::1:8080");
+    assert!(parse_capture_target("example.com").is_err());
+    assert!(parse_capture_target("example.com:https").is_err());
+    assert!(parse_capture_target(":443").is_err());
+
+    let capture = Capture::new(vec![parse_capture_target("example.com:443").unwrap()], "unused".into(), 1);
+    assert!(capture.matches("EXAMPLE.com", 443));
+    assert!(!capture.matches("example.com", 80));
+}
+
+#[tokio::test]
+async fn test_http_exchange_captured_to_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let (origin, _requests) = common::start_recording_origin(RESPONSE).await;
+    let (other, _) = common::start_recording_origin(RESPONSE).await;
+    let target = parse_capture_target(&origin.to_string()).unwrap();
+    let capture = Capture::new(vec![target], dir.path().to_path_buf(), 1024);
+    let config = ProxyConfig { capture: Some(Arc::new(capture)), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/captured HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.ends_with("hello"), "{}", response);
+
+    // Other destinations are left alone
+    let response = common::send_request(proxy, format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", other, other).as_bytes()).await;
+    assert!(response.ends_with("hello"), "{}", response);
+
+    let sent = captures(dir.path(), ".client");
+    let received = captures(dir.path(), ".server");
+    assert_eq!(sent.len(), 1, "{:?}", sent);
+    assert!(sent[0].starts_with("GET http://"), "{}", sent[0]);
+    assert!(sent[0].contains("/captured HTTP/1.1\r\n"), "{}", sent[0]);
+    assert!(sent[0].ends_with("\r\n\r\n"), "{}", sent[0]);
+    assert_eq!(received, vec![String::from_utf8_lossy(RESPONSE).into_owned()]);
+}
+
+#[tokio::test]
+async fn test_capture_stops_at_max_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (origin, _requests) = common::start_recording_origin(RESPONSE).await;
+    let capture = Capture::new(vec![parse_capture_target(&origin.to_string()).unwrap()], dir.path().to_path_buf(), 8);
+    let config = ProxyConfig { capture: Some(Arc::new(capture)), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.ends_with("hello"), "the limit only affects the capture: {}", response);
+    assert_eq!(captures(dir.path(), ".client"), vec!["GET http".to_string()]);
+    assert_eq!(captures(dir.path(), ".server"), vec!["HTTP/1.1".to_string()]);
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use async_trait::async_trait;
+use rust_proxy::circuit_breaker::CircuitBreaker;
+use rust_proxy::dialer::{BoxedStream, UpstreamDialer};
+use rust_proxy::ProxyConfig;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Dialer whose upstream can be switched between refusing and accepting
+#[derive(Debug, Default)]
+struct FlakyDialer {
+    down: AtomicBool,
+    attempts: AtomicUsize,
+}
+
+#[async_trait]
+impl UpstreamDialer for FlakyDialer {
+    async fn dial(&self, _host: &str, _port: u16) -> io::Result<BoxedStream> {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        if self.down.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "upstream down"));
+        }
+        let (proxy_side, _origin_side) = tokio::io::duplex(64);
+        Ok(Box::new(proxy_side))
+    }
+}
+
+#[test]
+fn test_circuit_breaker_state_transitions() {
+    let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_millis(50), 16);
+    assert!(breaker.allow("a:443"));
+
+    breaker.record("a:443", false);
+    assert!(breaker.allow("a:443"));
+    breaker.record("a:443", false);
+    assert!(!breaker.allow("a:443"));
+    assert!(breaker.allow("b:443"));
+
+    // After the cooldown only one trial goes through while it is in flight
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allow("a:443"));
+    assert!(!breaker.allow("a:443"));
+    assert!(!breaker.allow("a:443"));
+
+    // A failed trial reopens straight away
+    breaker.record("a:443", false);
+    assert!(!breaker.allow("a:443"));
+
+    // A trial that never reports back is replaced after another cooldown
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allow("a:443"));
+    assert!(!breaker.allow("a:443"));
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allow("a:443"));
+
+    // A successful trial closes it and resets the failure count
+    breaker.record("a:443", true);
+    assert!(breaker.allow("a:443"));
+    assert!(breaker.allow("a:443"));
+    breaker.record("a:443", false);
+    assert!(breaker.allow("a:443"));
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_fails_fast_then_recovers() {
+    let dialer = Arc::new(FlakyDialer::default());
+    dialer.down.store(true, Ordering::Relaxed);
+    let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_millis(300), 16);
+    let config = ProxyConfig {
+        dialer: dialer.clone(),
+        circuit_breaker: Some(Arc::new(breaker)),
+        ..Default::default()
+    };
+    let (proxy, stats) = common::start_proxy(config).await;
+    let request = b"CONNECT flaky.invalid:443 HTTP/1.1\r\n\r\n";
+
+    // Failures up to the threshold are real connect attempts
+    for _ in 0..2 {
+        let response = common::send_request(proxy, request).await;
+        assert!(response.starts_with("HTTP/1.1 502"));
+    }
+    assert_eq!(dialer.attempts.load(Ordering::Relaxed), 2);
+
+    // Then the breaker answers without dialing
+    let response = common::send_request(proxy, request).await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+    assert_eq!(dialer.attempts.load(Ordering::Relaxed), 2);
+    assert_eq!(stats.circuit_open_rejections.load(Ordering::Relaxed), 1);
+
+    // After the cooldown a successful connect closes the breaker
+    dialer.down.store(false, Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    for _ in 0..2 {
+        let response = common::send_request(proxy, request).await;
+        assert!(response.starts_with("HTTP/1.1 200 Connection Established"));
+    }
+    assert_eq!(dialer.attempts.load(Ordering::Relaxed), 4);
+    assert_eq!(stats.circuit_open_rejections.load(Ordering::Relaxed), 1);
+}
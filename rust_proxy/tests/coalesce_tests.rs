@@ -0,0 +1,130 @@
+mod common;
+
+use rust_proxy::coalesce::{is_coalescable, request_key, Coalescer, Role};
+use rust_proxy::headers::RequestHead;
+use rust_proxy::ProxyConfig;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Origin that answers slowly (so requests overlap) and counts fetches
+async fn start_slow_origin(response: &'static [u8]) -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let counter = fetches.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut buffer = [0; 4096];
+                let _ = socket.read(&mut buffer).await;
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                let _ = socket.write_all(response).await;
+            });
+        }
+    });
+    (addr, fetches)
+}
+
+fn coalescing_config() -> ProxyConfig {
+    ProxyConfig { coalescer: Some(Arc::new(Coalescer::new(Duration::from_secs(5)))), ..Default::default() }
+}
+
+#[test]
+fn test_coalescable_requests() {
+    let head = |raw: &[u8]| RequestHead::parse(raw).unwrap();
+    assert!(is_coalescable(&head(b"GET http://h/a HTTP/1.1\r\nHost: h\r\n\r\n")));
+    assert!(!is_coalescable(&head(b"POST http://h/a HTTP/1.1\r\nContent-Length: 0\r\n\r\n")));
+    assert!(!is_coalescable(&head(b"GET http://h/a HTTP/1.1\r\nAuthorization: Basic x\r\n\r\n")));
+    assert!(!is_coalescable(&head(b"GET http://h/a HTTP/1.1\r\nCookie: id=1\r\n\r\n")));
+    assert!(!is_coalescable(&head(b"GET http://h/a HTTP/1.1\r\nCache-Control: no-cache\r\n\r\n")));
+
+    let plain = head(b"GET http://h/a HTTP/1.1\r\n\r\n");
+    let gzip = head(b"GET http://h/a HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+    assert_ne!(request_key(&plain), request_key(&gzip));
+}
+
+#[tokio::test]
+async fn test_leader_drop_releases_followers() {
+    let coalescer = Arc::new(Coalescer::new(Duration::from_secs(5)));
+    let Role::Leader(leader) = coalescer.join("k") else { panic!("first request should lead") };
+    let Role::Follower(follower) = coalescer.join("k") else { panic!("second request should follow") };
+    assert_eq!(coalescer.in_flight(), 1);
+
+    drop(leader);
+    assert_eq!(coalescer.in_flight(), 0);
+    assert!(follower.wait().await.is_none());
+    assert!(matches!(coalescer.join("k"), Role::Leader(_)));
+}
+
+#[tokio::test]
+async fn test_concurrent_identical_gets_share_one_fetch() {
+    const CLIENTS: usize = 20;
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nshared";
+    let (origin, fetches) = start_slow_origin(RESPONSE).await;
+    let (proxy, stats) = common::start_proxy(coalescing_config()).await;
+
+    let request = format!("GET http://{}/asset.js HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let request = request.clone();
+            tokio::spawn(async move { common::send_request(proxy, request.as_bytes()).await })
+        })
+        .collect();
+    for client in clients {
+        assert_eq!(client.await.unwrap().as_bytes(), RESPONSE);
+    }
+
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    assert_eq!(stats.coalesced_requests.load(Ordering::Relaxed), CLIENTS as u64 - 1);
+}
+
+#[tokio::test]
+async fn test_private_responses_are_not_shared() {
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nSet-Cookie: session=abc\r\nContent-Length: 2\r\n\r\nme";
+    let (origin, fetches) = start_slow_origin(RESPONSE).await;
+    let (proxy, stats) = common::start_proxy(coalescing_config()).await;
+
+    let request = format!("GET http://{}/profile HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let first = tokio::spawn({
+        let request = request.clone();
+        async move { common::send_request(proxy, request.as_bytes()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let second = common::send_request(proxy, request.as_bytes()).await;
+
+    assert_eq!(first.await.unwrap().as_bytes(), RESPONSE);
+    assert_eq!(second.as_bytes(), RESPONSE);
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    assert_eq!(stats.coalesced_requests.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_download_cap_applies_to_buffered_responses() {
+    const CAP: usize = 4096;
+    let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", 64 * 1024).into_bytes();
+    response.resize(response.len() + 64 * 1024, b'x');
+    let (origin, fetches) = start_slow_origin(Box::leak(response.into_boxed_slice())).await;
+    let config = ProxyConfig { max_download_size: CAP as u64, ..coalescing_config() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    // The leader's buffered copy stops at the cap, and a cut-off response
+    // is never shared, so the waiter fetches (and is capped) on its own
+    let request = format!("GET http://{}/large.bin HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let leader = tokio::spawn({
+        let request = request.clone();
+        async move { common::send_request(proxy, request.as_bytes()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let waiter = common::send_request(proxy, request.as_bytes()).await;
+
+    for received in [leader.await.unwrap(), waiter] {
+        assert!(received.starts_with("HTTP/1.1 200 OK"), "{}", received);
+        assert!(received.len() <= CAP, "{} bytes", received.len());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    assert_eq!(stats.coalesced_requests.load(Ordering::Relaxed), 0);
+}
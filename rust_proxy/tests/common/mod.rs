@@ -0,0 +1,71 @@
+// Shared helpers for tests that drive `handle_client` in-process
+#![allow(dead_code)]
+
+use rust_proxy::{handle_client, ProxyConfig, ProxyStats};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+// Start a proxy on an ephemeral loopback port
+pub async fn start_proxy(config: ProxyConfig) -> (SocketAddr, Arc<ProxyStats>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = Arc::new(ProxyStats::new());
+    let config = Arc::new(config);
+
+    let stats_clone = stats.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let stats = stats_clone.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let _ = handle_client(socket, stats, config).await;
+            });
+        }
+    });
+
+    (addr, stats)
+}
+
+// Start an origin that reports each received request head and answers
+// with a fixed response
+pub async fn start_recording_origin(response: &'static [u8]) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut data = Vec::new();
+                let mut buffer = [0; 4096];
+                while let Ok(n) = socket.read(&mut buffer).await {
+                    if n == 0 {
+                        break;
+                    }
+                    data.extend_from_slice(&buffer[..n]);
+                    if data.windows(4).any(|w| w == b"\r\n\r\n") {
+                        break;
+                    }
+                }
+                let _ = tx.send(String::from_utf8_lossy(&data).to_string());
+                let _ = socket.write_all(response).await;
+            });
+        }
+    });
+
+    (addr, rx)
+}
+
+// Send raw bytes through the proxy and collect everything it returns
+pub async fn send_request(proxy: SocketAddr, request: &[u8]) -> String {
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let _ = stream.shutdown().await;
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+    String::from_utf8_lossy(&response).to_string()
+}
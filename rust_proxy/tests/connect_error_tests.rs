@@ -0,0 +1,81 @@
+mod common;
+
+use async_trait::async_trait;
+use rust_proxy::connect_error::ConnectFailure;
+use rust_proxy::dialer::{BoxedStream, UpstreamDialer};
+use rust_proxy::ssl_errors::SslErrorKind;
+use rust_proxy::ProxyConfig;
+use std::io;
+use std::sync::Arc;
+
+// Dialer failing each target the way its name says
+#[derive(Debug)]
+struct FailingTargets;
+
+#[async_trait]
+impl UpstreamDialer for FailingTargets {
+    async fn dial(&self, host: &str, _port: u16) -> io::Result<BoxedStream> {
+        Err(match host {
+            "refused.test" => io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"),
+            "slow.test" => io::Error::new(io::ErrorKind::TimedOut, "upstream proxy did not answer"),
+            "missing.test" => io::Error::other("failed to lookup address information: Name or service not known"),
+            "tls.test" => io::Error::other("certificate has expired"),
+            _ => io::Error::other("no route to host"),
+        })
+    }
+}
+
+#[test]
+fn test_connect_failures_are_classified() {
+    let classify = |kind, message: &str| ConnectFailure::classify(&io::Error::new(kind, message.to_string()));
+    assert_eq!(classify(io::ErrorKind::ConnectionRefused, "refused"), ConnectFailure::Refused);
+    assert_eq!(classify(io::ErrorKind::TimedOut, "timed out"), ConnectFailure::TimedOut);
+    assert_eq!(classify(io::ErrorKind::NotFound, "no such host"), ConnectFailure::Unresolved);
+    assert_eq!(classify(io::ErrorKind::Other, "failed to lookup address information"), ConnectFailure::Unresolved);
+    assert_eq!(classify(io::ErrorKind::Other, "TLS handshake failed"), ConnectFailure::Tls(SslErrorKind::HandshakeFailed));
+    assert_eq!(classify(io::ErrorKind::Other, "network is unreachable"), ConnectFailure::Failed);
+
+    assert_eq!(ConnectFailure::Blocked("port").status(), "403 Forbidden");
+    assert_eq!(ConnectFailure::TimedOut.status(), "504 Gateway Timeout");
+    assert_eq!(ConnectFailure::Refused.status(), "502 Bad Gateway");
+}
+
+#[tokio::test]
+async fn test_failed_connect_reports_status_and_reason() {
+    let config = ProxyConfig { dialer: Arc::new(FailingTargets), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let cases = [
+        ("refused.test", "HTTP/1.1 502 Bad Gateway", "Target refused the connection"),
+        ("slow.test", "HTTP/1.1 504 Gateway Timeout", "Timed out connecting to the target"),
+        ("missing.test", "HTTP/1.1 502 Bad Gateway", "Target host name could not be resolved"),
+        ("tls.test", "HTTP/1.1 502 Bad Gateway", "TLS error: Certificate has expired"),
+        ("other.test", "HTTP/1.1 502 Bad Gateway", "Could not connect to the target"),
+    ];
+    for (host, status, reason) in cases {
+        let request = format!("CONNECT {}:443 HTTP/1.1\r\nHost: {}:443\r\n\r\n", host, host);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.starts_with(status), "{}: {}", host, response);
+        assert!(response.contains("Content-Type: text/plain\r\n"), "{}: {}", host, response);
+        assert!(response.ends_with(&format!("\r\n\r\n{}\n", reason)), "{}: {}", host, response);
+    }
+}
+
+#[tokio::test]
+async fn test_refused_and_blocked_connects_against_real_targets() {
+    // A port nothing listens on any more
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed = listener.local_addr().unwrap();
+    drop(listener);
+
+    let config = ProxyConfig { connect_ports: 1024..=u16::MAX, ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+    let response = common::send_request(proxy, format!("CONNECT {} HTTP/1.1\r\n\r\n", closed).as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert!(response.ends_with("Target refused the connection\n"), "{}", response);
+
+    // Policy refusals are 403s naming the rule
+    let response = common::send_request(proxy, b"CONNECT 127.0.0.1:25 HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+    assert!(response.ends_with("Blocked by proxy policy: port not allowed for CONNECT\n"), "{}", response);
+}
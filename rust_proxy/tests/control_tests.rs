@@ -0,0 +1,111 @@
+#![cfg(unix)]
+
+mod common;
+
+use rust_proxy::control::{serve_control_socket, MAX_COMMAND_LENGTH};
+use rust_proxy::{bind_unix_listener, ProxyConfig, ProxyStats};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+#[tokio::test]
+async fn test_control_socket_commands() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("control.sock");
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let listener = bind_unix_listener(&path).unwrap();
+    tokio::spawn(serve_control_socket(listener, stats.clone(), Arc::new(AtomicBool::new(false))));
+
+    let client = UnixStream::connect(&path).await.expect("control socket should accept clients");
+    let (reader, mut writer) = client.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut command = async |line: &str| {
+        writer.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+        lines.next_line().await.unwrap().unwrap()
+    };
+
+    common::send_request(proxy, b"Invalid request\r\n\r\n").await;
+    let document: serde_json::Value = serde_json::from_str(&command("stats").await).unwrap();
+    assert_eq!(document["total_connections"], 1);
+    assert!(document["megabytes_transferred"].is_number());
+
+    assert_eq!(command("reset-stats").await, "ok stats reset");
+    let document: serde_json::Value = serde_json::from_str(&command("stats").await).unwrap();
+    assert_eq!(document["total_connections"], 0);
+
+    assert_eq!(command("set-log-level debug").await, "ok log level debug");
+    assert_eq!(log::max_level(), log::LevelFilter::Debug);
+    assert_eq!(command("set-log-level loud").await, "error unknown log level loud");
+    assert_eq!(command("reboot").await, "error unknown command reboot");
+}
+
+#[tokio::test]
+async fn test_maintenance_mode_toggled_by_control_socket() {
+    use rust_proxy::control::execute;
+
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let config = ProxyConfig::default();
+    let maintenance = config.maintenance.clone();
+    let (proxy, stats) = common::start_proxy(config).await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+
+    assert_eq!(execute("maintenance", &stats, &maintenance), "ok maintenance off");
+    assert_eq!(execute("maintenance on", &stats, &maintenance), "ok maintenance on");
+    assert_eq!(execute("maintenance", &stats, &maintenance), "ok maintenance on");
+    assert_eq!(execute("maintenance soon", &stats, &maintenance), "error expected maintenance on|off, got soon");
+
+    // Answered without reaching the origin, whether plain HTTP or CONNECT
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+    assert!(response.contains("\r\nRetry-After: 120\r\n"), "{}", response);
+    assert!(response.ends_with("down for maintenance. Please try again later.\n"), "{}", response);
+    let connect = format!("CONNECT {} HTTP/1.1\r\n\r\n", origin);
+    assert!(common::send_request(proxy, connect.as_bytes()).await.starts_with("HTTP/1.1 503"));
+    assert_eq!(stats.maintenance_rejections.load(Ordering::Relaxed), 2);
+    assert!(requests.try_recv().is_err());
+
+    assert_eq!(execute("maintenance off", &stats, &maintenance), "ok maintenance off");
+    assert!(common::send_request(proxy, request.as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(stats.maintenance_rejections.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_control_socket_drops_overlong_commands() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("control.sock");
+    let listener = bind_unix_listener(&path).unwrap();
+    tokio::spawn(serve_control_socket(listener, Arc::new(ProxyStats::new()), Arc::new(AtomicBool::new(false))));
+
+    let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b"maintenance\n").await.unwrap();
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "ok maintenance off");
+
+    // A line that never ends is cut off rather than buffered
+    let endless = vec![b'x'; MAX_COMMAND_LENGTH as usize * 4];
+    let _ = writer.write_all(&endless).await;
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "error command too long");
+    assert_eq!(lines.next_line().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_socket_path_only_replaces_stale_sockets() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // A socket left behind by an earlier run is replaced
+    let path = dir.path().join("control.sock");
+    drop(bind_unix_listener(&path).unwrap());
+    assert!(path.exists());
+    let listener = bind_unix_listener(&path).unwrap();
+    tokio::spawn(serve_control_socket(listener, Arc::new(ProxyStats::new()), Arc::new(AtomicBool::new(false))));
+    assert!(UnixStream::connect(&path).await.is_ok());
+
+    // Any other file is left alone
+    let log = dir.path().join("proxy.log");
+    std::fs::write(&log, "keep me\n").unwrap();
+    let error = bind_unix_listener(&log).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "keep me\n");
+}
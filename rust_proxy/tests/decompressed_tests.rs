@@ -0,0 +1,77 @@
+mod common;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rust_proxy::ProxyConfig;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Fetch `origin` through the proxy and return the raw response bytes
+async fn fetch(proxy: SocketAddr, origin: SocketAddr) -> Vec<u8> {
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n", origin);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+    response
+}
+
+#[tokio::test]
+async fn test_gzip_body_counted_compressed_and_decompressed() {
+    let original = "All work and no play makes Jack a dull boy. ".repeat(200);
+    let compressed = gzip(original.as_bytes());
+    let mut response = format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", compressed.len()).into_bytes();
+    response.extend_from_slice(&compressed);
+    let response: &'static [u8] = Box::leak(response.into_boxed_slice());
+
+    let (origin, _requests) = common::start_recording_origin(response).await;
+    let config = ProxyConfig { account_decompressed: Some(rust_proxy::decompressed::DEFAULT_DECOMPRESS_MAX_BYTES), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    // The client gets the compressed bytes untouched
+    assert_eq!(fetch(proxy, origin).await, response);
+    assert_eq!(stats.compressed_body_bytes.load(Ordering::Relaxed), compressed.len() as u64);
+    assert_eq!(stats.decompressed_body_bytes.load(Ordering::Relaxed), original.len() as u64);
+}
+
+#[tokio::test]
+async fn test_chunked_gzip_body_counted_up_to_limit() {
+    let original = vec![b'x'; 100_000];
+    let compressed = gzip(&original);
+    let mut response = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for chunk in compressed.chunks(7) {
+        response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        response.extend_from_slice(chunk);
+        response.extend_from_slice(b"\r\n");
+    }
+    response.extend_from_slice(b"0\r\n\r\n");
+    let response: &'static [u8] = Box::leak(response.into_boxed_slice());
+
+    let (origin, _requests) = common::start_recording_origin(response).await;
+    let config = ProxyConfig { account_decompressed: Some(10_000), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    assert_eq!(fetch(proxy, origin).await, response);
+    assert_eq!(stats.compressed_body_bytes.load(Ordering::Relaxed), compressed.len() as u64);
+    assert_eq!(stats.decompressed_body_bytes.load(Ordering::Relaxed), 10_000);
+}
+
+#[tokio::test]
+async fn test_uncompressed_body_not_counted() {
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let config = ProxyConfig { account_decompressed: Some(1024), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    assert!(fetch(proxy, origin).await.ends_with(b"hello"));
+    assert_eq!(stats.compressed_body_bytes.load(Ordering::Relaxed), 0);
+    assert_eq!(stats.decompressed_body_bytes.load(Ordering::Relaxed), 0);
+}
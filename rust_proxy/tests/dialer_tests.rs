@@ -0,0 +1,359 @@
+mod common;
+
+use async_trait::async_trait;
+use rust_proxy::dialer::{AsyncReadWrite, BoxedStream, UpstreamDialer};
+use rust_proxy::ProxyConfig;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+// In-memory origin answering one request with the request line it saw
+fn mock_origin() -> DuplexStream {
+    let (proxy_side, mut origin_side) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let mut buffer = [0; 1024];
+        let n = origin_side.read(&mut buffer).await.unwrap_or(0);
+        let request = String::from_utf8_lossy(&buffer[..n]).to_string();
+        let body = format!("mock saw: {}", request.lines().next().unwrap_or(""));
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = origin_side.write_all(response.as_bytes()).await;
+    });
+    proxy_side
+}
+
+// Dialer that serves every connection from an in-memory origin
+#[derive(Debug, Default)]
+struct MockDialer {
+    dialed: Mutex<Vec<(String, u16)>>,
+}
+
+#[async_trait]
+impl UpstreamDialer for MockDialer {
+    async fn dial(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        self.dialed.lock().unwrap().push((host.to_string(), port));
+        Ok(Box::new(mock_origin()))
+    }
+}
+
+// Upstream stream that refuses socket tuning, like a socket in a state
+// where TCP_NODELAY can no longer be set
+struct NoNodelayStream(DuplexStream);
+
+impl AsyncRead for NoNodelayStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for NoNodelayStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl AsyncReadWrite for NoNodelayStream {
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "mock nodelay failure"))
+    }
+}
+
+#[derive(Debug)]
+struct NoNodelayDialer;
+
+#[async_trait]
+impl UpstreamDialer for NoNodelayDialer {
+    async fn dial(&self, _host: &str, _port: u16) -> io::Result<BoxedStream> {
+        Ok(Box::new(NoNodelayStream(mock_origin())))
+    }
+}
+
+#[derive(Debug)]
+struct FailingDialer;
+
+#[async_trait]
+impl UpstreamDialer for FailingDialer {
+    async fn dial(&self, _host: &str, _port: u16) -> io::Result<BoxedStream> {
+        Err(io::Error::new(io::ErrorKind::ConnectionRefused, "mock refused"))
+    }
+}
+
+#[tokio::test]
+async fn test_http_request_relayed_through_mock_dialer() {
+    let dialer = Arc::new(MockDialer::default());
+    let config = ProxyConfig { dialer: dialer.clone(), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let response = common::send_request(
+        proxy,
+        b"GET http://upstream.invalid:8080/path HTTP/1.1\r\nHost: upstream.invalid:8080\r\n\r\n",
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("mock saw: GET http://upstream.invalid:8080/path HTTP/1.1"));
+    assert_eq!(*dialer.dialed.lock().unwrap(), vec![("upstream.invalid".to_string(), 8080)]);
+    assert!(stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed) > 0);
+}
+
+#[tokio::test]
+async fn test_connect_tunnel_through_mock_dialer() {
+    let dialer = Arc::new(MockDialer::default());
+    let config = ProxyConfig { dialer: dialer.clone(), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(b"CONNECT upstream.invalid:443 HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+    // Bytes after the CONNECT handshake reach the mock origin untouched
+    stream.write_all(b"PING tunnel\r\n").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.ends_with("mock saw: PING tunnel"));
+    assert_eq!(*dialer.dialed.lock().unwrap(), vec![("upstream.invalid".to_string(), 443)]);
+}
+
+#[tokio::test]
+async fn test_portless_connect_uses_default_connect_port() {
+    let dialer = Arc::new(MockDialer::default());
+    let config = ProxyConfig { dialer: dialer.clone(), default_connect_port: 8443, ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    for target in ["10.0.0.5", "10.0.0.6:22"] {
+        let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes()).await.unwrap();
+        let mut established = [0; 39];
+        stream.read_exact(&mut established).await.unwrap();
+        assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+    }
+    // Only the target without a port gets the default
+    assert_eq!(*dialer.dialed.lock().unwrap(), vec![("10.0.0.5".to_string(), 8443), ("10.0.0.6".to_string(), 22)]);
+}
+
+#[tokio::test]
+async fn test_dialer_failure_returns_bad_gateway() {
+    let config = ProxyConfig { dialer: Arc::new(FailingDialer), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let response = common::send_request(proxy, b"CONNECT upstream.invalid:443 HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"));
+    assert_eq!(stats.connection_errors.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_connect_to_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("echo.sock");
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+
+    let config = ProxyConfig { allow_unix_sockets: true, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("CONNECT unix:{} HTTP/1.1\r\n\r\n", path.display());
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+    stream.write_all(b"echo over unix").await.unwrap();
+    let mut echoed = [0; 14];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"echo over unix");
+    assert_eq!(stats.https_requests.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_connect_to_unix_socket_disabled_by_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("echo.sock");
+    let _listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+    let request = format!("CONNECT unix:{} HTTP/1.1\r\n\r\n", path.display());
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+}
+
+#[tokio::test]
+async fn test_deny_private_ranges_blocks_internal_targets() {
+    let dialer = Arc::new(MockDialer::default());
+    let config = ProxyConfig { dialer: dialer.clone(), deny_private_ranges: true, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    // A hostname is checked by what it resolves to, not by how it looks
+    let response = common::send_request(proxy, b"GET http://localhost:8080/ HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+
+    let response = common::send_request(proxy, b"GET http://[::1]:8080/ HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+
+    let response = common::send_request(proxy, b"CONNECT 10.1.2.3:443 HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+
+    assert!(dialer.dialed.lock().unwrap().is_empty());
+    assert_eq!(stats.blocked_ssrf.load(std::sync::atomic::Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn test_deny_private_ranges_allows_public_targets() {
+    let dialer = Arc::new(MockDialer::default());
+    let config = ProxyConfig { dialer: dialer.clone(), deny_private_ranges: true, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let response = common::send_request(proxy, b"GET http://93.184.215.14/ HTTP/1.1\r\nHost: 93.184.215.14\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert_eq!(*dialer.dialed.lock().unwrap(), vec![("93.184.215.14".to_string(), 80)]);
+    assert_eq!(stats.blocked_ssrf.load(std::sync::atomic::Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_connect_port_range() {
+    let dialer = Arc::new(MockDialer::default());
+    let config = ProxyConfig { dialer: dialer.clone(), connect_ports: 443..=8443, ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    for port in [22, 442, 8444] {
+        let request = format!("CONNECT upstream.invalid:{} HTTP/1.1\r\n\r\n", port);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "port {}: {}", port, response);
+    }
+    assert!(dialer.dialed.lock().unwrap().is_empty());
+
+    for port in [443, 8443] {
+        let request = format!("CONNECT upstream.invalid:{} HTTP/1.1\r\n\r\n", port);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200 Connection Established"), "port {}: {}", port, response);
+    }
+    assert_eq!(
+        *dialer.dialed.lock().unwrap(),
+        vec![("upstream.invalid".to_string(), 443), ("upstream.invalid".to_string(), 8443)]
+    );
+}
+
+#[tokio::test]
+async fn test_upstream_nodelay_failure_does_not_abort_request() {
+    let config = ProxyConfig { dialer: Arc::new(NoNodelayDialer), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let response = common::send_request(proxy, b"GET http://upstream.invalid/ HTTP/1.1\r\nHost: upstream.invalid\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("mock saw: GET http://upstream.invalid/ HTTP/1.1"));
+
+    let response = common::send_request(proxy, b"CONNECT upstream.invalid:443 HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 Connection Established"), "{}", response);
+    assert_eq!(stats.connection_errors.load(std::sync::atomic::Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_bound_dialer_connects_from_source_address() {
+    use rust_proxy::dialer::BoundDialer;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let dialer = BoundDialer::new(&["127.0.0.1".parse().unwrap()]);
+
+    let stream = dialer.dial("127.0.0.1", port).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.1");
+    assert_eq!(stream.local_addr().unwrap(), peer);
+
+    // Through the proxy, end to end
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let config = ProxyConfig { dialer: Arc::new(dialer), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    assert!(common::send_request(proxy, request.as_bytes()).await.ends_with("\r\n\r\nok"));
+}
+
+// Anywhere in 127/8 is local on Linux, so the source visibly changes
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_bound_dialer_uses_non_default_source() {
+    use rust_proxy::dialer::BoundDialer;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let dialer = BoundDialer::new(&["127.0.0.2".parse().unwrap()]);
+    let _stream = dialer.dial("127.0.0.1", port).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.2");
+}
+
+#[tokio::test]
+async fn test_bound_dialer_skips_other_families() {
+    use rust_proxy::dialer::BoundDialer;
+
+    let dialer = BoundDialer::new(&["127.0.0.1".parse().unwrap()]);
+    assert_eq!(dialer.v6, None);
+    let error = dialer.dial("::1", 9).await.err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrNotAvailable);
+}
+
+#[test]
+fn test_interleave_families_alternates_from_first_answer() {
+    use rust_proxy::dialer::interleave_families;
+    use std::net::SocketAddr;
+
+    let addrs: Vec<SocketAddr> = ["[2001:db8::1]:80", "[2001:db8::2]:80", "[2001:db8::3]:80", "192.0.2.1:80"]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+    let ordered: Vec<String> = interleave_families(addrs).iter().map(|addr| addr.to_string()).collect();
+    assert_eq!(ordered, ["[2001:db8::1]:80", "192.0.2.1:80", "[2001:db8::2]:80", "[2001:db8::3]:80"]);
+    assert!(interleave_families(Vec::new()).is_empty());
+}
+
+#[tokio::test]
+async fn test_happy_eyeballs_falls_back_when_ipv6_hangs() {
+    use rust_proxy::dialer::connect_happy_eyeballs;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let v4 = listener.local_addr().unwrap();
+    // Blackholed IPv6: the attempt never completes, as with a dropped SYN
+    let v6: SocketAddr = format!("[2001:db8::1]:{}", v4.port()).parse().unwrap();
+    let connect = |addr: SocketAddr| async move {
+        if addr.is_ipv6() {
+            std::future::pending::<()>().await;
+        }
+        tokio::net::TcpStream::connect(addr).await
+    };
+
+    let started = Instant::now();
+    let stream = connect_happy_eyeballs(vec![v6, v4], Duration::from_millis(50), connect).await.unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), v4);
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // Failures move on at once, and the last error is reported when all fail
+    drop(listener);
+    let refused = |addr: SocketAddr| async move {
+        Err::<(), _>(io::Error::new(io::ErrorKind::ConnectionRefused, addr.to_string()))
+    };
+    let started = Instant::now();
+    let error = connect_happy_eyeballs(vec![v6, v4], Duration::from_secs(10), refused).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(error.to_string(), v4.to_string());
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
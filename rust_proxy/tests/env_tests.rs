@@ -0,0 +1,21 @@
+// Kept in its own test binary: environment variables are process-wide, and
+// other tests parse `Args` in parallel.
+
+use clap::Parser;
+use rust_proxy::Args;
+
+#[test]
+fn test_auth_from_environment() {
+    std::env::set_var("PROXY_AUTH", "envuser:envpass");
+
+    let args = Args::try_parse_from(["rust_proxy"]).unwrap();
+    assert_eq!(args.auth.as_deref(), Some("envuser:envpass"));
+
+    // The command line wins over the environment
+    let args = Args::try_parse_from(["rust_proxy", "--auth", "cliuser:clipass"]).unwrap();
+    assert_eq!(args.auth.as_deref(), Some("cliuser:clipass"));
+
+    std::env::remove_var("PROXY_AUTH");
+    let args = Args::try_parse_from(["rust_proxy"]).unwrap();
+    assert_eq!(args.auth, None);
+}
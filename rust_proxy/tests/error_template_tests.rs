@@ -0,0 +1,112 @@
+mod common;
+
+use async_trait::async_trait;
+use rust_proxy::dialer::{BoxedStream, UpstreamDialer};
+use rust_proxy::error_template::{ErrorDetails, ErrorTemplate, MAX_TEMPLATE_SIZE};
+use rust_proxy::ProxyConfig;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+const TEMPLATE: &str = "Error {status} ({reason}) reaching {host}, request {request_id}. {unknown}";
+
+#[test]
+fn test_template_renders_502_with_host_and_status() {
+    let template = ErrorTemplate::new(TEMPLATE.to_string(), false);
+    let details = ErrorDetails { reason: Some("Target refused the connection"), host: Some("example.com"), request_id: Some(7) };
+    assert_eq!(
+        template.render(502, details),
+        "Error 502 (Target refused the connection) reaching example.com, request 7. {unknown}"
+    );
+
+    // Placeholders without a value stay as written
+    let rendered = template.render(502, ErrorDetails::default());
+    assert_eq!(rendered, "Error 502 ({reason}) reaching {host}, request {request_id}. {unknown}");
+}
+
+#[test]
+fn test_html_template_escapes_values() {
+    let template = ErrorTemplate::new("<style>p { color: red; }</style><p>{host}</p>".to_string(), true);
+    let details = ErrorDetails { host: Some("<script>"), ..Default::default() };
+    assert_eq!(template.render(502, details), "<style>p { color: red; }</style><p>&lt;script&gt;</p>");
+}
+
+#[test]
+fn test_apply_keeps_status_line_and_headers() {
+    let template = ErrorTemplate::new("{status} {reason}".to_string(), false);
+    let response = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\n\r\n";
+    let page = String::from_utf8(template.apply(response, ErrorDetails::default())).unwrap();
+    assert!(page.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"), "{}", page);
+    assert!(page.contains("Proxy-Authenticate: Basic realm=\"proxy\"\r\n"), "{}", page);
+    assert!(!page.contains("Content-Length: 0"), "{}", page);
+    assert!(page.ends_with("Content-Length: 33\r\n\r\n407 Proxy Authentication Required"), "{}", page);
+}
+
+#[test]
+fn test_load_validates_template() {
+    let dir = tempfile::tempdir().unwrap();
+    let empty = dir.path().join("empty.txt");
+    std::fs::write(&empty, " \n").unwrap();
+    assert!(ErrorTemplate::load(&empty).is_err());
+
+    let large = dir.path().join("large.html");
+    std::fs::write(&large, vec![b'x'; MAX_TEMPLATE_SIZE as usize + 1]).unwrap();
+    assert!(ErrorTemplate::load(&large).is_err());
+
+    assert!(ErrorTemplate::load(&dir.path().join("missing.html")).is_err());
+
+    let page = dir.path().join("error.HTML");
+    std::fs::write(&page, "<h1>{status}</h1>").unwrap();
+    let template = ErrorTemplate::load(&page).unwrap();
+    let response = String::from_utf8(template.apply(b"HTTP/1.1 502 Bad Gateway\r\n\r\n", ErrorDetails::default())).unwrap();
+    assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"), "{}", response);
+    assert!(response.ends_with("<h1>502</h1>"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_failed_connect_served_from_template() {
+    // A port nothing listens on any more
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed = listener.local_addr().unwrap();
+    drop(listener);
+
+    let template = ErrorTemplate::new(TEMPLATE.to_string(), false);
+    let config = ProxyConfig { error_template: Some(Arc::new(template)), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", closed, closed);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+    assert!(response.contains("Error 502 (Target refused the connection) reaching 127.0.0.1, request "), "{}", response);
+    assert!(response.ends_with(". {unknown}"), "{}", response);
+}
+
+// Dialer whose connects never complete
+#[derive(Debug)]
+struct HangingDialer;
+
+#[async_trait]
+impl UpstreamDialer for HangingDialer {
+    async fn dial(&self, _host: &str, _port: u16) -> io::Result<BoxedStream> {
+        std::future::pending().await
+    }
+}
+
+#[tokio::test]
+async fn test_plain_http_connect_timeout_gets_504() {
+    let template = ErrorTemplate::new(TEMPLATE.to_string(), false);
+    let config = ProxyConfig {
+        error_template: Some(Arc::new(template)),
+        dialer: Arc::new(HangingDialer),
+        adaptive_connect_timeout: true,
+        ..Default::default()
+    };
+    let (proxy, stats) = common::start_proxy(config).await;
+    // A fast connect history brings the timeout down to its floor
+    stats.host("slow.test:80").record_connect_time(Duration::from_millis(1));
+
+    let response = common::send_request(proxy, b"GET http://slow.test/ HTTP/1.1\r\nHost: slow.test\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"), "{}", response);
+    assert!(response.contains("Connection: close\r\n"), "{}", response);
+    assert!(response.contains("Error 504 (Timed out connecting to the target) reaching slow.test"), "{}", response);
+}
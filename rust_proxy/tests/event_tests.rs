@@ -0,0 +1,77 @@
+#![cfg(unix)]
+
+mod common;
+
+use rust_proxy::error::CloseReason;
+use rust_proxy::events::{serve_event_socket, EventBus, ProxyEvent};
+use rust_proxy::{bind_unix_listener, ProxyConfig};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn test_event_socket_streams_connection_events() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.sock");
+    let bus = EventBus::default();
+
+    tokio::spawn(serve_event_socket(bind_unix_listener(&path).unwrap(), bus.clone()));
+
+    let subscriber = UnixStream::connect(&path).await.expect("event socket should accept subscribers");
+    let mut lines = BufReader::new(subscriber).lines();
+    // Give the server a moment to register the subscription
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let config = ProxyConfig { events: Some(bus), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    common::send_request(proxy, request.as_bytes()).await;
+
+    let mut events = Vec::new();
+    while let Ok(Ok(Some(line))) = timeout(Duration::from_secs(2), lines.next_line()).await {
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        let done = event["event"] == "closed";
+        events.push(event);
+        if done {
+            break;
+        }
+    }
+
+    let kinds: Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap()).collect();
+    assert_eq!(kinds, vec!["opened", "established", "closed"]);
+    assert_eq!(events[1]["target"], origin.to_string());
+    assert_eq!(events[2]["bytes"], 40);
+    assert_eq!(events[2]["reason"], "eof");
+}
+
+#[test]
+fn test_event_serialization() {
+    let event = ProxyEvent::Closed {
+        client: "127.0.0.1:5000".to_string(),
+        target: Some("example.com:443".to_string()),
+        bytes: 1234,
+        duration_ms: 10,
+        reason: None,
+        compressed_bytes: None,
+        decompressed_bytes: None,
+    };
+    let json = serde_json::to_string(&event).unwrap();
+    assert_eq!(
+        json,
+        r#"{"event":"closed","client":"127.0.0.1:5000","target":"example.com:443","bytes":1234,"duration_ms":10}"#
+    );
+
+    let event = ProxyEvent::Closed {
+        client: "127.0.0.1:5000".to_string(),
+        target: Some("example.com:443".to_string()),
+        bytes: 1234,
+        duration_ms: 10,
+        reason: Some(CloseReason::IdleTimeout),
+        compressed_bytes: None,
+        decompressed_bytes: None,
+    };
+    assert!(serde_json::to_string(&event).unwrap().ends_with(r#""duration_ms":10,"reason":"idle_timeout"}"#));
+}
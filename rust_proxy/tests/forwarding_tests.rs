@@ -0,0 +1,662 @@
+mod common;
+
+use rust_proxy::forwarded::{forwarded_element, node_ip, original_client, parse_forwarded};
+use rust_proxy::headers::{RequestHead, ResponseHead};
+use rust_proxy::ProxyConfig;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_forwarded_element_generation() {
+    let element = forwarded_element(ip("192.0.2.60"), "http", Some(ip("203.0.113.43")));
+    assert_eq!(element, "for=192.0.2.60;proto=http;by=203.0.113.43");
+
+    // IPv6 nodes must be bracketed and quoted
+    let element = forwarded_element(ip("2001:db8:cafe::17"), "https", None);
+    assert_eq!(element, "for=\"[2001:db8:cafe::17]\";proto=https");
+}
+
+#[test]
+fn test_parse_forwarded_header() {
+    let elements = parse_forwarded("for=192.0.2.60;proto=http;by=203.0.113.43, for=\"[2001:db8:cafe::17]:4711\"");
+    assert_eq!(elements.len(), 2);
+    assert_eq!(elements[0].for_node.as_deref(), Some("192.0.2.60"));
+    assert_eq!(elements[0].proto.as_deref(), Some("http"));
+    assert_eq!(elements[0].by.as_deref(), Some("203.0.113.43"));
+    assert_eq!(elements[1].for_node.as_deref(), Some("[2001:db8:cafe::17]:4711"));
+
+    // Parameter names are case-insensitive
+    let elements = parse_forwarded("For=\"_hidden\";Proto=https");
+    assert_eq!(elements[0].for_node.as_deref(), Some("_hidden"));
+    assert_eq!(elements[0].proto.as_deref(), Some("https"));
+}
+
+#[test]
+fn test_node_ip() {
+    assert_eq!(node_ip("192.0.2.60"), Some(ip("192.0.2.60")));
+    assert_eq!(node_ip("192.0.2.60:8080"), Some(ip("192.0.2.60")));
+    assert_eq!(node_ip("[2001:db8::1]"), Some(ip("2001:db8::1")));
+    assert_eq!(node_ip("\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+    assert_eq!(node_ip("unknown"), None);
+    assert_eq!(node_ip("_obfuscated"), None);
+}
+
+#[test]
+fn test_original_client_from_trusted_proxy() {
+    let trusted = vec![ip("10.0.0.1"), ip("10.0.0.2")];
+    let head = RequestHead::parse(
+        b"GET http://example.com/ HTTP/1.1\r\nForwarded: for=198.51.100.7, for=10.0.0.2\r\n\r\n",
+    )
+    .unwrap();
+
+    // Trusted peer: walk back past the trusted hop to the real client
+    assert_eq!(original_client(&head, ip("10.0.0.1"), &trusted), ip("198.51.100.7"));
+
+    // Untrusted peer: headers are ignored
+    assert_eq!(original_client(&head, ip("192.0.2.1"), &trusted), ip("192.0.2.1"));
+}
+
+#[test]
+fn test_original_client_from_legacy_xff() {
+    let trusted = vec![ip("10.0.0.1")];
+    let head = RequestHead::parse(
+        b"GET http://example.com/ HTTP/1.1\r\nX-Forwarded-For: 2001:db8::7, 198.51.100.7\r\n\r\n",
+    )
+    .unwrap();
+    assert_eq!(original_client(&head, ip("10.0.0.1"), &trusted), ip("198.51.100.7"));
+}
+
+#[tokio::test]
+async fn test_forwarded_header_added_to_http_request() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let config = ProxyConfig { add_forwarded_headers: true, ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.contains("200 OK"));
+
+    let received = requests.recv().await.unwrap();
+    assert!(received.contains("Forwarded: for=127.0.0.1;proto=http;by=127.0.0.1\r\n"));
+    assert!(received.contains(&format!("Host: {}\r\n", origin)));
+}
+
+#[tokio::test]
+async fn test_proxy_authorization_not_forwarded() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let config = ProxyConfig {
+        proxy_auth: Some(rust_proxy::auth::basic_credentials("user:secret")),
+        ..Default::default()
+    };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\nAccept: */*\r\n\r\n",
+        origin, origin
+    );
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.contains("200 OK"));
+
+    let received = requests.recv().await.unwrap();
+    assert!(!received.to_ascii_lowercase().contains("proxy-authorization"));
+    assert!(received.contains("Accept: */*\r\n"));
+}
+
+#[tokio::test]
+async fn test_proxy_authorization_required() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let config = ProxyConfig {
+        proxy_auth: Some(rust_proxy::auth::basic_credentials("user:secret")),
+        ..Default::default()
+    };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    // Missing and wrong credentials are both rejected before connecting upstream
+    for credentials in ["", "Proxy-Authorization: Basic d3Jvbmc6Y3JlZHM=\r\n"] {
+        let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n{}\r\n", origin, origin, credentials);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 407"));
+        assert!(response.contains("Proxy-Authenticate: Basic"));
+    }
+    assert_eq!(stats.auth_failures.load(std::sync::atomic::Ordering::Relaxed), 2);
+    assert!(requests.try_recv().is_err());
+}
+
+#[test]
+fn test_logged_headers_redact_credentials() {
+    let head = RequestHead::parse(
+        b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nauthorization: Bearer s3cr3t\r\nPROXY-AUTHORIZATION: Basic dXNlcjpzZWNyZXQ=\r\nAccept: */*\r\n\r\n",
+    )
+    .unwrap();
+
+    let logged = head.to_redacted_string();
+    assert_eq!(
+        logged,
+        "GET http://example.com/ HTTP/1.1\nHost: example.com\nauthorization: [REDACTED]\nPROXY-AUTHORIZATION: [REDACTED]\nAccept: */*"
+    );
+    assert!(!logged.contains("s3cr3t"));
+    assert!(!logged.contains("dXNlcjpzZWNyZXQ="));
+}
+
+#[test]
+fn test_response_head_connection_close() {
+    let head = ResponseHead::parse(b"HTTP/1.1 200 OK\r\nConnection: keep-alive, Close\r\n\r\n").unwrap();
+    assert_eq!(head.status, 200);
+    assert_eq!(head.reason, "OK");
+    assert!(head.closes_connection());
+
+    let head = ResponseHead::parse(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+    assert!(!head.closes_connection());
+
+    // HTTP/1.0 closes unless keep-alive is negotiated
+    assert!(ResponseHead::parse(b"HTTP/1.0 200 OK\r\n\r\n").unwrap().closes_connection());
+    assert!(!ResponseHead::parse(b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n\r\n")
+        .unwrap()
+        .closes_connection());
+
+    // Interim responses never end the exchange
+    assert!(!ResponseHead::parse(b"HTTP/1.1 100 Continue\r\nConnection: close\r\n\r\n").unwrap().closes_connection());
+    assert!(ResponseHead::parse(b"garbage\r\n\r\n").is_none());
+}
+
+#[tokio::test]
+async fn test_upstream_connection_close_is_honored() {
+    // Origin answers with `Connection: close` and stops writing, but keeps
+    // reading to report whether the proxy closed the upstream connection or
+    // tried to send it anything more
+    let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = origin.accept().await.unwrap();
+        let mut buffer = [0; 4096];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        socket.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+        socket.shutdown().await.unwrap();
+        let after = tokio::time::timeout(Duration::from_secs(2), socket.read(&mut buffer)).await;
+        let _ = tx.send(after.map(|r| r.unwrap_or(0)));
+    });
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin_addr, origin_addr);
+    client.write_all(request.as_bytes()).await.unwrap();
+
+    // The client keeps its side open; the proxy must still finish the exchange
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(2), client.read_to_string(&mut response)).await.unwrap().unwrap();
+    assert!(response.ends_with("\r\n\r\nok"));
+
+    // A follow-up request on the same client connection never reaches the origin
+    let _ = client.write_all(request.as_bytes()).await;
+    assert_eq!(rx.await.unwrap(), Ok(0), "upstream connection should be closed, not reused");
+}
+
+#[tokio::test]
+async fn test_x_forwarded_for_injected() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let config = ProxyConfig { add_xff: true, ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    common::send_request(proxy, request.as_bytes()).await;
+    let received = requests.recv().await.unwrap();
+    assert!(received.contains("X-Forwarded-For: 127.0.0.1\r\n"));
+
+    // An existing chain is extended, not replaced
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nx-forwarded-for: 198.51.100.7, 10.0.0.2\r\n\r\n",
+        origin, origin
+    );
+    common::send_request(proxy, request.as_bytes()).await;
+    let received = requests.recv().await.unwrap();
+    assert!(received.contains("x-forwarded-for: 198.51.100.7, 10.0.0.2, 127.0.0.1\r\n"));
+    assert_eq!(received.to_ascii_lowercase().matches("x-forwarded-for").count(), 1);
+}
+
+#[tokio::test]
+async fn test_version_header_added_when_enabled() {
+    use rust_proxy::version::{LONG_VERSION, VERSION, VERSION_HEADER};
+    assert!(!VERSION.is_empty());
+    assert!(LONG_VERSION.starts_with(VERSION) && LONG_VERSION.len() > VERSION.len(), "{}", LONG_VERSION);
+
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nX-Proxy-Version: spoofed\r\n\r\n", origin, origin);
+
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+    common::send_request(proxy, request.as_bytes()).await;
+    let received = requests.recv().await.unwrap();
+    assert!(received.contains("X-Proxy-Version: spoofed\r\n"), "{}", received);
+
+    // Enabled, it replaces whatever the client sent
+    let (proxy, _stats) = common::start_proxy(ProxyConfig { version_header: true, ..Default::default() }).await;
+    common::send_request(proxy, request.as_bytes()).await;
+    let received = requests.recv().await.unwrap();
+    assert!(received.contains(&format!("{}: {}\r\n", VERSION_HEADER, LONG_VERSION)), "{}", received);
+    assert!(!received.contains("spoofed"), "{}", received);
+}
+
+#[test]
+fn test_websocket_upgrade_detection() {
+    let request = RequestHead::parse(b"GET http://h/ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: WebSocket\r\n\r\n").unwrap();
+    assert!(request.is_websocket_upgrade());
+    let request = RequestHead::parse(b"GET http://h/ HTTP/1.1\r\nUpgrade: websocket\r\n\r\n").unwrap();
+    assert!(!request.is_websocket_upgrade());
+
+    let response = ResponseHead::parse(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n").unwrap();
+    assert!(response.is_websocket_upgrade());
+    assert!(!response.closes_connection());
+    let response = ResponseHead::parse(b"HTTP/1.1 200 OK\r\nUpgrade: websocket\r\n\r\n").unwrap();
+    assert!(!response.is_websocket_upgrade());
+}
+
+#[tokio::test]
+async fn test_websocket_upgrade_tunnels_both_ways() {
+    // Origin accepts the upgrade, then echoes frames back
+    let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = origin.accept().await.unwrap();
+        let mut buffer = [0; 4096];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+            .await
+            .unwrap();
+        while let Ok(n) = socket.read(&mut buffer).await {
+            if n == 0 || socket.write_all(&buffer[..n]).await.is_err() {
+                break;
+            }
+        }
+    });
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "GET http://{}/chat HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+        origin_addr, origin_addr
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+
+    let mut head = vec![0; 4096];
+    let n = tokio::time::timeout(Duration::from_secs(2), client.read(&mut head)).await.unwrap().unwrap();
+    assert!(String::from_utf8_lossy(&head[..n]).starts_with("HTTP/1.1 101 Switching Protocols"));
+
+    for frame in [&b"\x81\x04ping"[..], &b"\x81\x04pong"[..]] {
+        client.write_all(frame).await.unwrap();
+        let mut echoed = vec![0; frame.len()];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut echoed)).await.unwrap().unwrap();
+        assert_eq!(echoed, frame);
+    }
+    assert_eq!(stats.websocket_connections.load(std::sync::atomic::Ordering::Relaxed), 1);
+    assert_eq!(stats.http_requests.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_empty_or_malformed_request_line_gets_400() {
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    // An empty header block is answered, not silently dropped
+    let response = common::send_request(proxy, b"\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{:?}", response);
+
+    let response = common::send_request(proxy, b"Invalid request\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{:?}", response);
+
+    // A connection that sends nothing is just closed
+    assert_eq!(common::send_request(proxy, b"").await, "");
+}
+
+#[tokio::test]
+async fn test_origin_form_request_explains_proxy_usage() {
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let response = common::send_request(proxy, b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains(&format!("Content-Length: {}", body.len())));
+    assert!(body.contains("forward proxy") && body.contains("absolute URI"), "{}", body);
+    assert_eq!(stats.connection_errors.load(std::sync::atomic::Ordering::Relaxed), 0);
+}
+
+// Read from `stream` until `expected` bytes have arrived
+async fn read_exactly(stream: &mut tokio::net::TcpStream, expected: usize) -> String {
+    let mut response = vec![0; expected];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await.unwrap().unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn test_message_body_framing() {
+    use rust_proxy::keep_alive::{is_relayable, request_body_length, response_body_length, BodyLength};
+
+    let request = |raw: &[u8]| RequestHead::parse(raw).unwrap();
+    assert_eq!(request_body_length(&request(b"GET http://h/ HTTP/1.1\r\n\r\n")), Some(BodyLength::Empty));
+    assert_eq!(request_body_length(&request(b"POST http://h/ HTTP/1.1\r\nContent-Length: 5\r\n\r\n")), Some(BodyLength::Fixed(5)));
+    assert_eq!(request_body_length(&request(b"POST http://h/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")), Some(BodyLength::Chunked));
+    assert_eq!(request_body_length(&request(b"POST http://h/ HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n")), None);
+    assert_eq!(request_body_length(&request(b"POST http://h/ HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n")), None);
+
+    assert!(is_relayable(&request(b"GET http://h/ HTTP/1.1\r\n\r\n")));
+    assert!(is_relayable(&request(b"PUT http://h/ HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")));
+    assert!(!is_relayable(&request(b"PUT http://h/ HTTP/1.1\r\nExpect: something-else\r\n\r\n")));
+    assert!(!is_relayable(&request(b"GET http://h/ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")));
+
+    // HTTP/1.0 closes unless keep-alive is negotiated
+    assert!(request(b"GET http://h/ HTTP/1.1\r\nConnection: close\r\n\r\n").closes_connection());
+    assert!(request(b"GET http://h/ HTTP/1.0\r\n\r\n").closes_connection());
+    assert!(!request(b"GET http://h/ HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").closes_connection());
+
+    let response = |raw: &[u8]| ResponseHead::parse(raw).unwrap();
+    assert_eq!(response_body_length("GET", &response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n")), BodyLength::Fixed(2));
+    assert_eq!(response_body_length("HEAD", &response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n")), BodyLength::Empty);
+    assert_eq!(response_body_length("GET", &response(b"HTTP/1.1 304 Not Modified\r\n\r\n")), BodyLength::Empty);
+    assert_eq!(response_body_length("GET", &response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")), BodyLength::Chunked);
+    assert_eq!(response_body_length("GET", &response(b"HTTP/1.1 200 OK\r\n\r\n")), BodyLength::UntilClose);
+}
+
+#[tokio::test]
+async fn test_sequential_requests_reuse_client_connection() {
+    const FIRST: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst";
+    const SECOND: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecond";
+    let (first_origin, mut first_rx) = common::start_recording_origin(FIRST).await;
+    let (second_origin, mut second_rx) = common::start_recording_origin(SECOND).await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("GET http://{}/a HTTP/1.1\r\nHost: {}\r\n\r\n", first_origin, first_origin);
+    client.write_all(request.as_bytes()).await.unwrap();
+    assert_eq!(read_exactly(&mut client, FIRST.len()).await.as_bytes(), FIRST);
+    assert!(first_rx.recv().await.unwrap().starts_with("GET http://"));
+
+    // Same connection, different upstream
+    let request = format!("GET http://{}/b HTTP/1.1\r\nHost: {}\r\n\r\n", second_origin, second_origin);
+    client.write_all(request.as_bytes()).await.unwrap();
+    assert_eq!(read_exactly(&mut client, SECOND.len()).await.as_bytes(), SECOND);
+    assert!(second_rx.recv().await.unwrap().contains("/b HTTP/1.1"));
+
+    assert_eq!(stats.tcp_connections_accepted.load(std::sync::atomic::Ordering::Relaxed), 1);
+    assert_eq!(stats.http_requests.load(std::sync::atomic::Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_pipelined_requests_answered_in_order() {
+    const CHUNKED: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+    let (origin, _rx) = common::start_recording_origin(CHUNKED).await;
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    // Both requests in one write, the first with a body, the second asking
+    // to close once answered
+    let requests = format!(
+        "POST http://{0}/ HTTP/1.1\r\nContent-Length: 4\r\n\r\nbodyGET http://{0}/ HTTP/1.1\r\nConnection: close\r\n\r\n",
+        origin
+    );
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    client.write_all(requests.as_bytes()).await.unwrap();
+
+    // The proxy closes after the second response without the client
+    // shutting down its side
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await.unwrap().unwrap();
+    assert_eq!(response, [CHUNKED, CHUNKED].concat());
+}
+
+// An origin for `Expect: 100-continue` uploads. It reads the request head
+// and either answers `417` straight away (`accept` false) or sends
+// `100 Continue`, reads the `Content-Length` body and answers `200`,
+// reporting the body it got.
+async fn start_continue_origin(accept: bool) -> (std::net::SocketAddr, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut byte = [0; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if socket.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                if !accept {
+                    let _ = socket.write_all(b"HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n").await;
+                    return;
+                }
+                let head = RequestHead::parse(&head).unwrap();
+                socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.unwrap();
+                let mut body = vec![0; head.content_length().unwrap() as usize];
+                socket.read_exact(&mut body).await.unwrap();
+                let _ = tx.send(String::from_utf8(body).unwrap());
+                socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone").await.unwrap();
+            });
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_expect_continue_relays_interim_response() {
+    const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+    const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone";
+    let (origin, mut bodies) = start_continue_origin(true).await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("PUT http://{}/upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n", origin);
+    for _ in 0..2 {
+        // The body only goes out once the origin has asked for it
+        client.write_all(request.as_bytes()).await.unwrap();
+        assert_eq!(read_exactly(&mut client, CONTINUE.len()).await.as_bytes(), CONTINUE);
+        client.write_all(b"hello").await.unwrap();
+        assert_eq!(read_exactly(&mut client, OK.len()).await.as_bytes(), OK);
+        assert_eq!(bodies.recv().await.unwrap(), "hello");
+    }
+
+    // Both uploads shared one client connection
+    assert_eq!(stats.tcp_connections_accepted.load(std::sync::atomic::Ordering::Relaxed), 1);
+    assert_eq!(stats.resp_1xx.load(std::sync::atomic::Ordering::Relaxed), 2);
+    assert_eq!(stats.resp_2xx.load(std::sync::atomic::Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_expect_continue_client_not_waiting_or_refused() {
+    let (origin, mut bodies) = start_continue_origin(true).await;
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    // A client that sends its body right away still gets both responses
+    let request = format!("PUT http://{}/ HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello", origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert_eq!(response, "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone");
+    assert_eq!(bodies.recv().await.unwrap(), "hello");
+
+    // An origin that answers without asking for the body ends the connection
+    let (origin, _bodies) = start_continue_origin(false).await;
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("PUT http://{}/ HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n", origin);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await.unwrap().unwrap();
+    assert_eq!(response, b"HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n");
+}
+
+#[tokio::test]
+async fn test_keep_alive_size_caps_cover_the_whole_connection() {
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+    const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+    const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone";
+    let (origin, _rx) = common::start_recording_origin(RESPONSE).await;
+    let (upload_origin, mut bodies) = start_continue_origin(true).await;
+    let (proxy, _stats) = common::start_proxy(ProxyConfig { max_download_size: 8, max_upload_size: 8, ..Default::default() }).await;
+
+    // Each response fits under the download cap, but not both
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    client.write_all(request.as_bytes()).await.unwrap();
+    assert_eq!(read_exactly(&mut client, RESPONSE.len()).await.as_bytes(), RESPONSE);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await.unwrap().unwrap();
+    assert_eq!(response, &RESPONSE[..RESPONSE.len() - 5]);
+
+    // Likewise each upload fits under the upload cap, but not both
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    let request = format!("PUT http://{}/ HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n", upload_origin);
+    client.write_all(request.as_bytes()).await.unwrap();
+    assert_eq!(read_exactly(&mut client, CONTINUE.len()).await.as_bytes(), CONTINUE);
+    client.write_all(b"hello").await.unwrap();
+    assert_eq!(read_exactly(&mut client, OK.len()).await.as_bytes(), OK);
+    assert_eq!(bodies.recv().await.unwrap(), "hello");
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await.unwrap().unwrap();
+    assert_eq!(response, CONTINUE);
+}
+
+#[tokio::test]
+async fn test_keep_alive_aborts_slow_response_body() {
+    use rust_proxy::throughput::MinThroughput;
+
+    // A byte every 20ms never trips the idle timeout but is far below 1000 B/s
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await;
+        socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n").await.unwrap();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if socket.write_all(b"x").await.is_err() {
+                break;
+            }
+        }
+    });
+    let min_throughput = Some(MinThroughput { bytes_per_sec: 1000, window: Duration::from_millis(200) });
+    let (proxy, stats) = common::start_proxy(ProxyConfig { min_throughput, ..Default::default() }).await;
+
+    let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    client.write_all(format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin).as_bytes()).await.unwrap();
+    let started = std::time::Instant::now();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await.unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert!(response.len() < 100, "{}", String::from_utf8_lossy(&response));
+    assert_eq!(stats.slow_transfer_aborted.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_tunnel_http_passes_interim_responses_before_final() {
+    use rust_proxy::{tunnel_http, ByteCounters, CopyLimits, ProxyStats};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    const RESPONSES: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok";
+    let (mut client, proxy_client) = tokio::io::duplex(1024);
+    let (proxy_upstream, mut origin) = tokio::io::duplex(1024);
+    client.write_all(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi").await.unwrap();
+    origin.write_all(RESPONSES).await.unwrap();
+    origin.shutdown().await.unwrap();
+
+    let stats = Arc::new(ProxyStats::new());
+    let tunnel = tunnel_http(1, proxy_client, proxy_upstream, stats.clone(), ByteCounters::default(), CopyLimits::default());
+    // The final response closes, so the tunnel ends without waiting for
+    // the client to shut down
+    tokio::time::timeout(Duration::from_secs(2), tunnel).await.unwrap().unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, RESPONSES);
+    assert_eq!(stats.resp_1xx.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.resp_2xx.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_xff_client_position() {
+    use rust_proxy::forwarded::{xff_client, XffPosition};
+
+    let head = RequestHead::parse(
+        b"GET http://example.com/ HTTP/1.1\r\nX-Forwarded-For: 198.51.100.7, 10.0.0.2\r\nX-Forwarded-For: 10.0.0.3\r\n\r\n",
+    )
+    .unwrap();
+    assert_eq!(xff_client(&head, XffPosition::Leftmost), Some(ip("198.51.100.7")));
+    assert_eq!(xff_client(&head, XffPosition::Rightmost), Some(ip("10.0.0.3")));
+
+    let head = RequestHead::parse(b"GET http://example.com/ HTTP/1.1\r\nX-Forwarded-For: unknown\r\n\r\n").unwrap();
+    assert_eq!(xff_client(&head, XffPosition::Rightmost), None);
+    let head = RequestHead::parse(b"GET http://example.com/ HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(xff_client(&head, XffPosition::Leftmost), None);
+}
+
+#[tokio::test]
+async fn test_rate_limit_keyed_by_xff_only_when_trusted() {
+    use rust_proxy::forwarded::XffPosition;
+    use rust_proxy::rate_limit::RateLimiter;
+    use std::sync::Arc;
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let request = |client: &str| format!("GET http://{}/ HTTP/1.1\r\nX-Forwarded-For: {}\r\n\r\n", origin, client);
+
+    // One request per client: distinct forwarded clients each get theirs
+    let limiter = Arc::new(RateLimiter::new(0.1, 16));
+    let config = ProxyConfig { rate_limiter: Some(limiter), trust_proxy_headers: Some(XffPosition::Rightmost), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+    for client in ["198.51.100.7", "198.51.100.8"] {
+        assert!(common::send_request(proxy, request(client).as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+    }
+    assert!(common::send_request(proxy, request("198.51.100.7").as_bytes()).await.starts_with("HTTP/1.1 429"));
+
+    // Untrusted, the header can't buy a spoofing client a fresh bucket
+    let limiter = Arc::new(RateLimiter::new(0.1, 16));
+    let (proxy, _stats) = common::start_proxy(ProxyConfig { rate_limiter: Some(limiter), ..Default::default() }).await;
+    assert!(common::send_request(proxy, request("198.51.100.7").as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+    assert!(common::send_request(proxy, request("198.51.100.8").as_bytes()).await.starts_with("HTTP/1.1 429"));
+}
+
+#[tokio::test]
+async fn test_via_header_appended() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let config = ProxyConfig { append_via_header: true, ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    common::send_request(proxy, request.as_bytes()).await;
+    assert!(requests.recv().await.unwrap().contains("Via: 1.1 rust_proxy\r\n"));
+
+    // Other proxies' entries are kept, with this one last
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nVia: 1.0 fred, 1.1 p.example.net (Apache/2.4)\r\n\r\n", origin, origin);
+    common::send_request(proxy, request.as_bytes()).await;
+    let received = requests.recv().await.unwrap();
+    assert!(received.contains("Via: 1.0 fred, 1.1 p.example.net (Apache/2.4), 1.1 rust_proxy\r\n"), "{}", received);
+}
+
+#[tokio::test]
+async fn test_via_loop_rejected() {
+    use rust_proxy::forwarded::via_names_self;
+    use std::sync::atomic::Ordering;
+
+    let head = RequestHead::parse(b"GET http://example.com/ HTTP/1.1\r\nVia: 1.0 fred\r\nvia: HTTP/1.1 Rust_Proxy\r\n\r\n").unwrap();
+    assert!(via_names_self(&head));
+    let head = RequestHead::parse(b"GET http://example.com/ HTTP/1.1\r\nVia: 1.1 fred (rust_proxy)\r\n\r\n").unwrap();
+    assert!(!via_names_self(&head));
+
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nVia: 1.1 fred, 1.1 rust_proxy\r\n\r\n", origin, origin);
+    let config = ProxyConfig { append_via_header: true, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert_eq!(stats.loop_detected.load(Ordering::Relaxed), 1);
+    assert!(requests.try_recv().is_err());
+
+    // Without the option the header is just passed along
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+    assert!(common::send_request(proxy, request.as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(stats.loop_detected.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use rust_proxy::histogram::{bucket_bounds, SizeHistogram, BUCKET_COUNT};
+use rust_proxy::ProxyConfig;
+use std::time::Duration;
+
+fn cumulative(histogram: &SizeHistogram, bound: u64) -> u64 {
+    let snapshot = histogram.snapshot();
+    snapshot.buckets.iter().find(|(b, _)| *b == bound).map(|(_, count)| *count).unwrap()
+}
+
+#[test]
+fn test_bucket_bounds() {
+    let bounds: Vec<u64> = bucket_bounds().collect();
+    assert_eq!(bounds.len(), BUCKET_COUNT);
+    assert_eq!(bounds.first(), Some(&1024));
+    assert_eq!(bounds.last(), Some(&(1024 * 1024 * 1024)));
+    assert!(bounds.windows(2).all(|w| w[1] == w[0] * 2));
+}
+
+#[test]
+fn test_values_land_in_their_buckets() {
+    let histogram = SizeHistogram::default();
+    for value in [0, 1024, 1025, 4096, 4097, 1 << 30, (1 << 30) + 1] {
+        histogram.record(value);
+    }
+
+    // Bounds are inclusive, and counts are cumulative
+    assert_eq!(cumulative(&histogram, 1024), 2);
+    assert_eq!(cumulative(&histogram, 2048), 3);
+    assert_eq!(cumulative(&histogram, 4096), 4);
+    assert_eq!(cumulative(&histogram, 8192), 5);
+    assert_eq!(cumulative(&histogram, 1 << 29), 5);
+    assert_eq!(cumulative(&histogram, 1 << 30), 6);
+
+    // Past the largest bound only `+Inf` (the count) includes it
+    let snapshot = histogram.snapshot();
+    assert_eq!(snapshot.count, 7);
+    assert_eq!(snapshot.sum, 1024 + 1025 + 4096 + 4097 + (1 << 30) + (1 << 30) + 1);
+
+    histogram.reset();
+    let snapshot = histogram.snapshot();
+    assert_eq!((snapshot.count, snapshot.sum), (0, 0));
+    assert!(snapshot.buckets.iter().all(|(_, count)| *count == 0));
+}
+
+#[test]
+fn test_prometheus_exposition() {
+    let histogram = SizeHistogram::default();
+    histogram.record(100);
+    histogram.record(3000);
+    let text = histogram.snapshot().to_prometheus("proxy_connection_bytes", "Bytes per connection");
+
+    assert!(text.starts_with("# HELP proxy_connection_bytes Bytes per connection\n# TYPE proxy_connection_bytes histogram\n"));
+    assert!(text.contains("proxy_connection_bytes_bucket{le=\"1024\"} 1\n"));
+    assert!(text.contains("proxy_connection_bytes_bucket{le=\"2048\"} 1\n"));
+    assert!(text.contains("proxy_connection_bytes_bucket{le=\"4096\"} 2\n"));
+    assert!(text.contains("proxy_connection_bytes_bucket{le=\"1073741824\"} 2\n"));
+    assert!(text.contains("proxy_connection_bytes_bucket{le=\"+Inf\"} 2\n"));
+    assert!(text.ends_with("proxy_connection_bytes_sum 3100\nproxy_connection_bytes_count 2\n"));
+}
+
+// A response whose whole size is `size` bytes
+fn response_of_size(size: usize) -> &'static [u8] {
+    let head = "HTTP/1.1 200 OK\r\nContent-Length: 0000000\r\n\r\n";
+    let body = "x".repeat(size - head.len());
+    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {:07}\r\n\r\n{}", body.len(), body);
+    assert_eq!(response.len(), size);
+    Box::leak(response.into_bytes().into_boxed_slice())
+}
+
+#[tokio::test]
+async fn test_connections_recorded_by_size_on_close() {
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    for size in [500, 3000, 5000] {
+        let (origin, _requests) = common::start_recording_origin(response_of_size(size)).await;
+        let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert_eq!(response.len(), size);
+    }
+
+    // Recorded as each handler finishes, which may trail the client's view
+    for _ in 0..100 {
+        if stats.connection_bytes.snapshot().count == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let histogram = &stats.connection_bytes;
+    assert_eq!(histogram.snapshot().count, 3);
+    assert_eq!(histogram.snapshot().sum, 8500);
+    assert_eq!(cumulative(histogram, 1024), 1);
+    assert_eq!(cumulative(histogram, 2048), 1);
+    assert_eq!(cumulative(histogram, 4096), 2);
+    assert_eq!(cumulative(histogram, 8192), 3);
+}
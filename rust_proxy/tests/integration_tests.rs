@@ -8,8 +8,8 @@ use tokio::time::timeout;
 #[tokio::test]
 async fn test_proxy_integration() {
     // Start proxy server in background
-    let mut child = Command::new("cargo")
-        .args(&["run", "--", "--host", "127.0.0.1", "--port", "3130", "--log-level", "error"])
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3130", "--log-level", "error"])
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
@@ -45,8 +45,8 @@ async fn test_http_proxy_request() {
     });
 
     // Start proxy
-    let mut proxy_child = Command::new("cargo")
-        .args(&["run", "--", "--host", "127.0.0.1", "--port", "3132", "--log-level", "error"])
+    let mut proxy_child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3132", "--log-level", "error"])
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
@@ -86,8 +86,8 @@ async fn test_connect_proxy_request() {
     });
 
     // Start proxy
-    let mut proxy_child = Command::new("cargo")
-        .args(&["run", "--", "--host", "127.0.0.1", "--port", "3134", "--log-level", "error"])
+    let mut proxy_child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3134", "--log-level", "error"])
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
@@ -117,8 +117,8 @@ async fn test_connect_proxy_request() {
 #[tokio::test]
 async fn test_proxy_handles_invalid_requests() {
     // Start proxy
-    let mut proxy_child = Command::new("cargo")
-        .args(&["run", "--", "--host", "127.0.0.1", "--port", "3135", "--log-level", "error"])
+    let mut proxy_child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3135", "--log-level", "error"])
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
@@ -141,4 +141,295 @@ async fn test_proxy_handles_invalid_requests() {
     // Clean up
     let _ = proxy_child.kill();
     let _ = proxy_child.wait();
-}
\ No newline at end of file
+}
+#[cfg(unix)]
+#[tokio::test]
+async fn test_json_banner_on_graceful_shutdown() {
+    use std::io::{BufRead, BufReader};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3150", "--log-level", "error", "--banner-format", "json"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    let started: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(started["event"], "started");
+    assert_eq!(started["listen"], "127.0.0.1:3150");
+    assert_eq!(started["pid"], child.id());
+
+    // One short-lived connection so the counter is non-zero
+    let mut stream = TcpStream::connect("127.0.0.1:3150").await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut buffer = Vec::new();
+    let _ = timeout(Duration::from_secs(5), stream.read_to_end(&mut buffer)).await;
+
+    let status = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    let stopped: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(stopped["event"], "stopped");
+    assert_eq!(stopped["total_connections"], 1);
+    assert!(stopped["uptime_secs"].is_u64());
+}
+
+#[test]
+fn test_run_for_shuts_down_gracefully() {
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+
+    let started_at = Instant::now();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3159", "--log-level", "error", "--banner-format", "json", "--run-for", "1s"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("\"started\""), "{}", line);
+
+    // No signal is sent: the process must stop by itself, through the same
+    // path that prints the stopped banner
+    let mut status = None;
+    while started_at.elapsed() < Duration::from_secs(10) {
+        if let Some(exited) = child.try_wait().unwrap() {
+            status = Some(exited);
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let Some(status) = status else {
+        let _ = child.kill();
+        let _ = child.wait();
+        panic!("proxy still running 10s into a 1s --run-for");
+    };
+    assert!(status.success());
+    assert!(started_at.elapsed() >= Duration::from_secs(1));
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("\"stopped\""), "{}", line);
+}
+
+#[test]
+fn test_exit_on_idle_writes_marker_and_shuts_down() {
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+
+    let marker = std::env::temp_dir().join(format!("rust_proxy_idle_marker_{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let started_at = Instant::now();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3164", "--log-level", "error", "--banner-format", "json"])
+        .args(["--drain-on-idle", "1", "--exit-on-idle", "--drain-marker"])
+        .arg(&marker)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("\"started\""), "{}", line);
+
+    let mut status = None;
+    while started_at.elapsed() < Duration::from_secs(10) {
+        if let Some(exited) = child.try_wait().unwrap() {
+            status = Some(exited);
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let Some(status) = status else {
+        let _ = child.kill();
+        let _ = child.wait();
+        panic!("proxy still running 10s into a 1s --drain-on-idle");
+    };
+    assert!(status.success());
+    assert!(started_at.elapsed() >= Duration::from_secs(1));
+    assert!(marker.exists());
+    std::fs::remove_file(&marker).unwrap();
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("\"stopped\""), "{}", line);
+}
+
+#[test]
+fn test_one_shot_serves_one_connection_then_exits() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::time::Instant;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3161", "--log-level", "error", "--banner-format", "json", "--one-shot"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("\"started\""), "{}", line);
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:3161").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    drop(stream);
+
+    // No signal is sent: serving the connection is what ends the run
+    let started_at = Instant::now();
+    let mut status = None;
+    while started_at.elapsed() < Duration::from_secs(10) {
+        if let Some(exited) = child.try_wait().unwrap() {
+            status = Some(exited);
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let Some(status) = status else {
+        let _ = child.kill();
+        let _ = child.wait();
+        panic!("proxy still running 10s after serving its --one-shot connection");
+    };
+    assert!(status.success());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    let stopped: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(stopped["event"], "stopped");
+    assert_eq!(stopped["total_connections"], 1);
+}
+
+#[tokio::test]
+async fn test_accept_rate_paces_connection_burst() {
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3160", "--log-level", "error", "--banner-format", "json", "--accept-rate", "5"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    assert!(line.contains("\"started\""), "{}", line);
+
+    // A burst of 15 connections, each answered as soon as it is accepted: the
+    // first 5 go straight through, the other 10 trickle in at 5 per second
+    let started = Instant::now();
+    let clients = (0..15).map(|_| {
+        tokio::spawn(async move {
+            let mut stream = TcpStream::connect("127.0.0.1:3160").await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+            let mut response = Vec::new();
+            let _ = timeout(Duration::from_secs(10), stream.read_to_end(&mut response)).await;
+            (response, started.elapsed())
+        })
+    });
+    let mut answered = Vec::new();
+    for client in clients.collect::<Vec<_>>() {
+        let (response, at) = client.await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400"), "{}", String::from_utf8_lossy(&response));
+        answered.push(at);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+
+    answered.sort();
+    assert!(answered[4] < Duration::from_millis(500), "{:?}", answered);
+    assert!(answered[14] >= Duration::from_millis(1800), "{:?}", answered);
+    assert!(answered[14] < Duration::from_secs(5), "{:?}", answered);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_version_flag_reports_build() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_proxy")).arg("-V").output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.trim(), format!("rust_proxy {}", rust_proxy::version::LONG_VERSION));
+    assert!(stdout.contains(env!("CARGO_PKG_VERSION")), "{}", stdout);
+    assert!(stdout.contains(", built "), "{}", stdout);
+}
+
+#[tokio::test]
+async fn test_worker_threads_flag() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3154", "--worker-threads", "0"])
+        .output()
+        .expect("Failed to run proxy");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--worker-threads must be at least 1"));
+
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3154", "--log-level", "info", "--worker-threads", "1"])
+        .env_remove("RUST_LOG")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let mut connected = false;
+    for _ in 0..50 {
+        if TcpStream::connect("127.0.0.1:3154").await.is_ok() {
+            connected = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let _ = Command::new("kill").args(["-TERM", &child.id().to_string()]).status();
+    let stderr = String::from_utf8_lossy(&child.wait_with_output().unwrap().stderr).into_owned();
+    assert!(connected, "Proxy should serve with a single worker thread");
+    assert!(stderr.contains("Runtime worker threads: 1"), "{}", stderr);
+}
+
+#[test]
+fn test_disabling_both_method_classes_is_rejected() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3157", "--disable-http", "--disable-https"])
+        .output()
+        .expect("Failed to run proxy");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--disable-http and --disable-https can't both be set"));
+}
+
+#[tokio::test]
+async fn test_malformed_block_list_fails_open_unless_fail_closed() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3163", "--block-alpn", "h2,", "--fail-closed"])
+        .output()
+        .expect("Failed to run proxy");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--block-alpn has malformed ALPN protocol IDs"), "{}", stderr);
+
+    // By default the bad entry is skipped and the proxy serves
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3163", "--log-level", "info", "--block-alpn", "h2,"])
+        .env_remove("RUST_LOG")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let mut connected = false;
+    for _ in 0..50 {
+        if TcpStream::connect("127.0.0.1:3163").await.is_ok() {
+            connected = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let _ = Command::new("kill").args(["-TERM", &child.id().to_string()]).status();
+    let stderr = String::from_utf8_lossy(&child.wait_with_output().unwrap().stderr).into_owned();
+    assert!(connected, "Proxy should start with a malformed list when failing open");
+    assert!(stderr.contains("ignoring them"), "{}", stderr);
+}
@@ -0,0 +1,419 @@
+mod common;
+
+use rust_proxy::ProxyConfig;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn test_slow_headers_get_request_timeout() {
+    let config = ProxyConfig { header_read_timeout: Duration::from_millis(500), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let started = Instant::now();
+    stream.write_all(b"GET http://127.0.0.1:1/ HTTP/1.1\r\n").await.unwrap();
+
+    // Each header line arrives well within any per-read timeout, but the
+    // block as a whole never completes before the deadline
+    let trickle = async {
+        for i in 0..20 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if stream.write_all(format!("X-Slow-{}: 1\r\n", i).as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(Duration::from_millis(700), trickle).await;
+
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await;
+    let response = String::from_utf8_lossy(&response);
+
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout"), "got: {}", response);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(stats.header_timeouts.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_headers_split_across_reads_are_accumulated() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(format!("GET http://{}/ HTTP/1.1\r\n", origin).as_bytes()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stream.write_all(format!("Host: {}\r\n\r\n", origin).as_bytes()).await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await;
+    assert!(String::from_utf8_lossy(&response).contains("200 OK"));
+
+    let received = requests.recv().await.unwrap();
+    assert!(received.contains(&format!("Host: {}\r\n\r\n", origin)));
+}
+
+#[test]
+fn test_chunked_decoder_counts_body_bytes_only() {
+    use rust_proxy::chunked::ChunkedDecoder;
+
+    let body = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n";
+    let mut decoder = ChunkedDecoder::new();
+    assert_eq!(decoder.feed(body, 100), (body.len(), 11));
+    assert!(decoder.is_done());
+
+    // Byte-at-a-time feeding decodes the same body
+    let mut decoder = ChunkedDecoder::new();
+    let decoded: u64 = body.iter().map(|b| decoder.feed(std::slice::from_ref(b), 100).1).sum();
+    assert_eq!(decoded, 11);
+    assert!(decoder.is_done());
+
+    // A budget ending mid-chunk stops right where the body would exceed it
+    let mut decoder = ChunkedDecoder::new();
+    assert_eq!(decoder.feed(body, 7), (21, 7));
+
+    let mut decoder = ChunkedDecoder::new();
+    decoder.feed(b"zz\r\n", 100);
+    assert!(decoder.is_invalid());
+}
+
+async fn relay_chunked_response(chunks: usize, max_size: u64) -> (Result<(), rust_proxy::ProxyError>, Vec<u8>) {
+    use rust_proxy::{tunnel_http, ByteCounters, CopyLimits, ProxyStats};
+    use std::sync::Arc;
+
+    let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for _ in 0..chunks {
+        response.extend_from_slice(b"a\r\n0123456789\r\n");
+    }
+    response.extend_from_slice(b"0\r\n\r\n");
+
+    let (mut client, proxy_client) = tokio::io::duplex(64 * 1024);
+    let (proxy_upstream, mut origin) = tokio::io::duplex(64 * 1024);
+    client.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+    client.shutdown().await.unwrap();
+    origin.write_all(&response).await.unwrap();
+    origin.shutdown().await.unwrap();
+
+    let limits = CopyLimits { max_download_size: max_size, ..Default::default() };
+    let stats = Arc::new(ProxyStats::new());
+    let result = tunnel_http(1, proxy_client, proxy_upstream, stats, ByteCounters::default(), limits).await;
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    (result, received)
+}
+
+#[tokio::test]
+async fn test_chunked_response_limited_by_decoded_size() {
+    use rust_proxy::error::ProxyErrorKind;
+
+    // 80 body bytes take 170 bytes on the wire: within a 100 byte limit
+    let (result, received) = relay_chunked_response(8, 100).await;
+    assert!(result.is_ok(), "{:?}", result);
+    assert!(received.ends_with(b"0\r\n\r\n"));
+
+    // 150 body bytes exceed it: the client gets the first 100 and is cut
+    // off at the first body byte past the limit
+    let (result, received) = relay_chunked_response(15, 100).await;
+    assert_eq!(ProxyErrorKind::of(&result.unwrap_err()), Some(ProxyErrorKind::SizeLimitExceeded));
+    let head_len = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".len();
+    assert_eq!(received.len(), head_len + 10 * 15 + 3);
+    assert!(received.ends_with(b"0123456789\r\na\r\n"));
+}
+
+#[tokio::test]
+async fn test_rate_per_ip_returns_429() {
+    use rust_proxy::rate_limit::RateLimiter;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let limiter = Arc::new(RateLimiter::new(2.0, 16));
+    let (proxy, stats) = common::start_proxy(ProxyConfig { rate_limiter: Some(limiter), ..Default::default() }).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    let mut statuses = Vec::new();
+    for _ in 0..6 {
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        statuses.push(response.split_whitespace().nth(1).unwrap_or("").to_string());
+    }
+    let limited = statuses.iter().filter(|s| *s == "429").count();
+    assert!(statuses[0] == "200", "{:?}", statuses);
+    assert!(limited >= 3, "{:?}", statuses);
+    assert_eq!(stats.rate_limited.load(Ordering::Relaxed), limited as u64);
+}
+
+#[test]
+fn test_rate_limiter_refills_over_time() {
+    use rust_proxy::rate_limit::RateLimiter;
+    use std::net::IpAddr;
+
+    let client: IpAddr = "192.0.2.1".parse().unwrap();
+    let other: IpAddr = "192.0.2.2".parse().unwrap();
+    let limiter = RateLimiter::new(20.0, 16);
+
+    // A full bucket allows a one-second burst, then refuses
+    assert!((0..20).all(|_| limiter.check(client)));
+    assert!(!limiter.check(client));
+    // Buckets are per client
+    assert!(limiter.check(other));
+
+    // Refill follows elapsed time: 150ms at 20/s is three more requests
+    std::thread::sleep(Duration::from_millis(150));
+    assert!((0..2).all(|_| limiter.check(client)));
+
+    // IPv4-mapped IPv6 addresses share the IPv4 bucket
+    let mapped: IpAddr = "::ffff:192.0.2.3".parse().unwrap();
+    limiter.check(mapped);
+    assert_eq!(limiter.tracked_clients(), 3);
+
+    // Once the others have refilled they are purged; the drained one is kept
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(limiter.check(client));
+    assert_eq!(limiter.purge_idle(), 1);
+}
+
+#[tokio::test]
+async fn test_accept_rate_paces_after_burst() {
+    use rust_proxy::rate_limit::AcceptRate;
+
+    let accept_rate = AcceptRate::new(20.0);
+    let started = Instant::now();
+    for _ in 0..20 {
+        accept_rate.acquire().await;
+    }
+    assert!(started.elapsed() < Duration::from_millis(100), "burst took {:?}", started.elapsed());
+
+    // Ten more at 20/s take half a second
+    for _ in 0..10 {
+        accept_rate.acquire().await;
+    }
+    assert!(started.elapsed() >= Duration::from_millis(450), "took {:?}", started.elapsed());
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+}
+
+#[test]
+fn test_scan_request_head_counts_header_lines() {
+    use rust_proxy::scan_request_head;
+
+    assert_eq!(scan_request_head(b"GET http://h/ HTTP/1.1\r\n\r\n"), (26, 0));
+    assert_eq!(scan_request_head(b"GET http://h/ HTTP/1.1\r\nHost: h\r\nA: b\r\n\r\nbody"), (41, 2));
+    assert_eq!(scan_request_head(b"\r\n\r\n"), (4, 0));
+    // Incomplete block: only finished header lines count
+    assert_eq!(scan_request_head(b"GET http://h/ HTTP/1.1\r\nA: b\r\nB"), (31, 1));
+}
+
+#[test]
+fn test_head_scan_across_reads_matches_one_pass() {
+    use rust_proxy::{find_request_end, scan_request_head, HeadScan};
+
+    let head = b"GET http://h/ HTTP/1.1\r\nHost: h\r\nA: b\r\r\nB: c\n\r\n\r\nbody";
+    let (end, lines) = scan_request_head(head);
+    assert_eq!(end, find_request_end(head));
+    // Every split point, including ones between the \r and \n of a line end
+    for split in 0..head.len() {
+        let mut scan = HeadScan::default();
+        let first = scan.advance(&head[..split]);
+        assert_eq!(scan.advance(head), Some(end), "split at {}", split);
+        assert!(first.is_none() || first == Some(end));
+        assert_eq!(scan.header_lines(), lines, "split at {}", split);
+    }
+}
+
+#[tokio::test]
+async fn test_too_many_headers_get_431() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let mut request = format!("GET http://{}/ HTTP/1.1\r\n", origin);
+    for i in 0..200 {
+        request.push_str(&format!("X{}: a\r\n", i));
+    }
+    request.push_str("\r\n");
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+    assert_eq!(stats.header_limit_exceeded.load(std::sync::atomic::Ordering::Relaxed), 1);
+    assert!(requests.try_recv().is_err());
+
+    // Exactly at the limit is fine
+    let mut request = format!("GET http://{}/ HTTP/1.1\r\n", origin);
+    for i in 0..rust_proxy::DEFAULT_MAX_HEADER_COUNT {
+        request.push_str(&format!("X{}: a\r\n", i));
+    }
+    request.push_str("\r\n");
+    assert!(common::send_request(proxy, request.as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+}
+
+#[tokio::test]
+async fn test_busy_tunnel_cut_at_max_duration() {
+    use std::sync::atomic::Ordering;
+
+    // Echo origin
+    let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = origin.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let config = ProxyConfig { max_tunnel_duration: Some(Duration::from_millis(400)), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", origin_addr).as_bytes()).await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    let opened = Instant::now();
+
+    // Traffic flows every 20ms, far inside the idle timeout, until the cap
+    let mut echoed = 0;
+    let closed = loop {
+        if stream.write_all(b"ping").await.is_err() {
+            break opened.elapsed();
+        }
+        let mut pong = [0; 4];
+        match tokio::time::timeout(Duration::from_secs(2), stream.read_exact(&mut pong)).await {
+            Ok(Ok(_)) => echoed += 1,
+            Ok(Err(_)) => break opened.elapsed(),
+            Err(_) => panic!("tunnel neither echoed nor closed"),
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+
+    assert!(echoed > 5, "tunnel should carry traffic until the cap ({} echoes)", echoed);
+    assert!(closed >= Duration::from_millis(400), "closed after {:?}", closed);
+    assert!(closed < Duration::from_secs(2), "closed after {:?}", closed);
+    assert_eq!(stats.tunnel_duration_exceeded.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_tunnels_over_max_per_destination_are_shed() {
+    use rust_proxy::dest_limit::DestinationLimiter;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    // Origin holding each tunnel open until the client sends something
+    let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = origin.accept().await {
+            tokio::spawn(async move {
+                let _ = socket.read(&mut [0; 16]).await;
+            });
+        }
+    });
+
+    let limiter = Arc::new(DestinationLimiter::new(2, Duration::from_millis(100), 16));
+    let config = ProxyConfig { destination_limiter: Some(limiter.clone()), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let mut tunnels = Vec::new();
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", origin_addr).as_bytes()).await.unwrap();
+        let mut established = [0; 39];
+        stream.read_exact(&mut established).await.unwrap();
+        assert!(established.starts_with(b"HTTP/1.1 200"));
+        tunnels.push(stream);
+    }
+
+    let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", origin_addr);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "{}", response);
+    assert_eq!(stats.dest_overload.load(Ordering::Relaxed), 1);
+
+    // Closing a tunnel frees its slot, and an idle destination is purged
+    let mut closing = tunnels.pop().unwrap();
+    closing.write_all(b"bye").await.unwrap();
+    drop(closing);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert!(established.starts_with(b"HTTP/1.1 200"));
+    assert_eq!(limiter.purge_idle(), 1);
+    for mut tunnel in tunnels.into_iter().chain([stream]) {
+        tunnel.write_all(b"bye").await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(limiter.purge_idle(), 0);
+}
+
+#[tokio::test]
+async fn test_slow_response_cut_at_request_timeout() {
+    use std::sync::atomic::Ordering;
+
+    // Origin that keeps `/slow` waiting before the head, and sends `/trickle`
+    // its head at once but the body a byte at a time
+    let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = origin.accept().await {
+            tokio::spawn(async move {
+                let mut request = [0; 1024];
+                let n = socket.read(&mut request).await.unwrap_or(0);
+                if String::from_utf8_lossy(&request[..n]).contains("/slow ") {
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                    let _ = socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+                    return;
+                }
+                let _ = socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n").await;
+                for _ in 0..100 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    if socket.write_all(b"x").await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    let config = ProxyConfig { request_timeout: Some(Duration::from_millis(500)), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    // Nothing sent yet: the client is told the upstream timed out
+    let started = Instant::now();
+    let request = format!("GET http://{}/slow HTTP/1.1\r\nHost: {}\r\n\r\n", origin_addr, origin_addr);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout"), "{}", response);
+    assert!(started.elapsed() < Duration::from_secs(2), "answered after {:?}", started.elapsed());
+
+    // Response under way, though never idle: the connection is just closed
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!("GET http://{}/trickle HTTP/1.1\r\nHost: {}\r\n\r\n", origin_addr, origin_addr);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await.unwrap().unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(!response.contains("504"), "{}", response);
+    assert!(response.len() < 100, "{}", response);
+    assert_eq!(stats.request_timeout.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_blocked_request_waits_out_tarpit() {
+    let tarpit = rust_proxy::tarpit::Tarpit::new(Duration::from_millis(400), 1);
+    let config = ProxyConfig { block_tarpit: Some(std::sync::Arc::new(tarpit)), connect_ports: 1024..=u16::MAX, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let started = Instant::now();
+    let response = common::send_request(proxy, b"CONNECT 127.0.0.1:25 HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+    assert!(started.elapsed() >= Duration::from_millis(400), "answered after {:?}", started.elapsed());
+    assert_eq!(stats.tarpitted.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+    // With the one tarpit slot taken, another block is answered straight away
+    let held = tokio::spawn(common::send_request(proxy, b"CONNECT 127.0.0.1:25 HTTP/1.1\r\n\r\n"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let started = Instant::now();
+    let response = common::send_request(proxy, b"CONNECT 127.0.0.1:25 HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+    assert!(started.elapsed() < Duration::from_millis(200), "answered after {:?}", started.elapsed());
+    assert!(held.await.unwrap().starts_with("HTTP/1.1 403 Forbidden"));
+    assert_eq!(stats.tarpitted.load(std::sync::atomic::Ordering::Relaxed), 2);
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use rust_proxy::log_file::RotatingFile;
+use std::fs;
+use std::io::Write;
+
+#[test]
+fn test_rotates_once_size_limit_would_be_passed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.log");
+    let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+
+    file.write_all(b"first record\n").unwrap();
+    assert!(!RotatingFile::rotated_path(&path, 1).exists());
+    file.write_all(b"second record\n").unwrap();
+    file.flush().unwrap();
+
+    // A record is never split across files
+    assert_eq!(fs::read_to_string(RotatingFile::rotated_path(&path, 1)).unwrap(), "first record\n");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "second record\n");
+}
+
+#[test]
+fn test_keeps_only_the_newest_rotated_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.log");
+    let mut file = RotatingFile::open(&path, 4, 2).unwrap();
+    for record in ["a\n", "bb\n", "ccc\n", "dddd\n"] {
+        file.write_all(record.as_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "dddd\n");
+    assert_eq!(fs::read_to_string(RotatingFile::rotated_path(&path, 1)).unwrap(), "ccc\n");
+    assert_eq!(fs::read_to_string(RotatingFile::rotated_path(&path, 2)).unwrap(), "bb\n");
+    assert!(!RotatingFile::rotated_path(&path, 3).exists());
+}
+
+#[test]
+fn test_reopening_appends_and_counts_existing_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.log");
+    fs::write(&path, "from last run\n").unwrap();
+
+    let mut file = RotatingFile::open(&path, 20, 0).unwrap();
+    file.write_all(b"new\n").unwrap();
+    file.flush().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "from last run\nnew\n");
+
+    // With nothing kept, rotation truncates in place
+    file.write_all(b"over the limit\n").unwrap();
+    file.flush().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "over the limit\n");
+    assert!(!RotatingFile::rotated_path(&path, 1).exists());
+}
+
+#[test]
+fn test_write_failure_backs_off_then_reopens() {
+    let dir = tempfile::tempdir().unwrap();
+    let logs = dir.path().join("logs");
+    fs::create_dir(&logs).unwrap();
+    let path = logs.join("proxy.log");
+    let mut file = RotatingFile::open(&path, 10, 1).unwrap();
+    file.write_all(b"before\n").unwrap();
+
+    // With the directory gone the rotation fails, which the logger never sees
+    fs::remove_dir_all(&logs).unwrap();
+    file.write_all(b"lost record\n").unwrap();
+    file.flush().unwrap();
+
+    // Records are dropped until the backoff (at most 1s at first) is over
+    fs::create_dir(&logs).unwrap();
+    file.write_all(b"dropped\n").unwrap();
+    assert!(!path.exists());
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    file.write_all(b"after\n").unwrap();
+    file.flush().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_proxy_keeps_serving_while_log_file_fails() {
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let dir = tempfile::tempdir().unwrap();
+    let logs = dir.path().join("logs");
+    fs::create_dir(&logs).unwrap();
+    let path = logs.join("proxy.log");
+
+    // Every record rotates the file, so each one touches the directory
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3167", "--log-level", "debug", "--log-max-size", "1"])
+        .arg("--log-file")
+        .arg(&path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let proxy: std::net::SocketAddr = "127.0.0.1:3167".parse().unwrap();
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(proxy).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    let before = common::send_request(proxy, request.as_bytes()).await;
+    let logged = path.exists();
+
+    // The log's directory goes away underneath the running proxy
+    fs::remove_dir_all(&logs).unwrap();
+    let mut during = Vec::new();
+    for _ in 0..3 {
+        during.push(common::send_request(proxy, request.as_bytes()).await);
+    }
+
+    Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(before.starts_with("HTTP/1.1 200 OK"), "{}", before);
+    assert!(logged);
+    for response in &during {
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+    assert!(stderr.contains("write failed"), "{}", stderr);
+    assert!(output.status.success(), "a failing log file must not stop the proxy");
+}
@@ -9,8 +9,8 @@ fn test_logging_output_to_file() {
     let _log_file = NamedTempFile::new().unwrap();
 
     // Start proxy with debug logging redirected to file
-    let mut child = Command::new("cargo")
-        .args(&["run", "--", "--host", "127.0.0.1", "--port", "3140", "--log-level", "debug"])
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3140", "--log-level", "debug"])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .env("RUST_LOG", "debug")
@@ -43,8 +43,9 @@ fn test_logging_levels() {
     
     for level in log_levels {
         // Start proxy with specific log level
-        let mut child = Command::new("cargo")
-            .args(&["run", "--", "--host", "127.0.0.1", "--port", "3141", "--log-level", level])
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+            .args(["--host", "127.0.0.1", "--port", "3141", "--log-level", level])
+            .env_remove("RUST_LOG")
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
@@ -55,24 +56,20 @@ fn test_logging_levels() {
         let _ = child.kill();
         let output = child.wait_with_output().unwrap();
 
-        // Should have some log output
-        assert!(!output.stderr.is_empty(), "Should have log output for level: {}", level);
-        
         let stderr_output = String::from_utf8_lossy(&output.stderr);
-        
-        // Should contain the log level we specified (case insensitive)
-        assert!(stderr_output.to_uppercase().contains(&level.to_uppercase()) || 
-                stderr_output.contains("INFO") || 
-                stderr_output.contains("Proxy server"),
-                "Should contain appropriate log level messages for: {}", level);
+
+        // The startup messages are logged at info, so only info and debug show them
+        let shows_info = level == "info" || level == "debug";
+        assert_eq!(stderr_output.contains("Proxy server starting"), shows_info,
+                   "Unexpected startup log messages for level {}: {}", level, stderr_output);
     }
 }
 
 #[test]
 fn test_invalid_log_level_handling() {
     // Test with invalid log level - should default to info
-    let mut child = Command::new("cargo")
-        .args(&["run", "--", "--host", "127.0.0.1", "--port", "3142", "--log-level", "invalid"])
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3142", "--log-level", "invalid"])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -88,4 +85,171 @@ fn test_invalid_log_level_handling() {
     // Should contain warning about invalid log level
     assert!(stderr_output.contains("Invalid log level") || stderr_output.contains("INFO"),
             "Should handle invalid log level gracefully");
-}
\ No newline at end of file
+}
+#[cfg(unix)]
+#[test]
+fn test_quiet_hosts_log_requests_at_debug() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3151", "--log-level", "info", "--quiet-hosts", "quiet.invalid,*.quiet.invalid"])
+        .env_remove("RUST_LOG")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy server");
+
+    let connect = || {
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect("127.0.0.1:3151") {
+                return stream;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("proxy did not start listening");
+    };
+    for url in ["http://quiet.invalid/health", "http://api.quiet.invalid/poll", "http://loud.invalid/"] {
+        let mut stream = connect();
+        stream.set_read_timeout(Some(Duration::from_secs(15))).unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\n\r\n", url).as_bytes()).unwrap();
+        let _ = stream.read_to_end(&mut Vec::new());
+    }
+
+    let status = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(stderr.contains("request to http://loud.invalid:80"), "{}", stderr);
+    assert!(!stderr.contains("request to http://quiet.invalid"), "{}", stderr);
+    assert!(!stderr.contains("request to http://api.quiet.invalid"), "{}", stderr);
+    // Quiet requests are still counted
+    assert!(stderr.contains("HTTP Requests: 3"), "{}", stderr);
+}
+
+// Runs the proxy at `--log-level warn` until SIGTERM, returning its stderr
+#[cfg(unix)]
+fn run_at_warn_until_shutdown(port: u16, extra_args: &[&str]) -> String {
+    let port = port.to_string();
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", &port, "--log-level", "warn"])
+        .args(extra_args)
+        .env_remove("RUST_LOG")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy server");
+
+    for _ in 0..50 {
+        if std::net::TcpStream::connect(format!("127.0.0.1:{}", port)).is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let status = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    String::from_utf8_lossy(&child.wait_with_output().unwrap().stderr).into_owned()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stats_log_level_independent_of_log_level() {
+    let stderr = run_at_warn_until_shutdown(3152, &["--stats-log-level", "warn"]);
+    assert!(stderr.contains("Proxy Statistics"), "{}", stderr);
+    assert!(stderr.contains("TCP Connections Accepted: 1"), "{}", stderr);
+    // Other info-level output stays filtered
+    assert!(!stderr.contains("Proxy server starting"), "{}", stderr);
+
+    let stderr = run_at_warn_until_shutdown(3153, &[]);
+    assert!(!stderr.contains("Proxy Statistics"), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_log_file_rotates_by_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let log_path = dir.path().join("proxy.log");
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3158", "--log-level", "info", "--log-keep", "1", "--log-max-size", "512"])
+        .arg("--log-file")
+        .arg(&log_path)
+        .env_remove("RUST_LOG")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy server");
+
+    for _ in 0..50 {
+        if std::net::TcpStream::connect("127.0.0.1:3158").is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    // The statistics block logged on shutdown is well past 512 bytes
+    let status = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("Proxy server starting"), "{}", stderr);
+
+    let current = std::fs::read_to_string(&log_path).unwrap();
+    let rotated = std::fs::read_to_string(dir.path().join("proxy.log.1")).unwrap();
+    for contents in [&current, &rotated] {
+        assert!(!contents.is_empty() && contents.len() <= 512, "{}", contents);
+        assert!(contents.ends_with('\n'), "{}", contents);
+    }
+    assert!(!dir.path().join("proxy.log.2").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_proxy_keeps_serving_when_log_file_fails() {
+    use std::io::{Read, Write};
+
+    let origin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        for mut stream in origin.incoming().flatten() {
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+        }
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let logs = dir.path().join("logs");
+    std::fs::create_dir(&logs).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3162", "--log-level", "info", "--log-keep", "1", "--log-max-size", "512"])
+        .arg("--log-file")
+        .arg(logs.join("proxy.log"))
+        .env_remove("RUST_LOG")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy server");
+
+    for _ in 0..50 {
+        if std::net::TcpStream::connect("127.0.0.1:3162").is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    // The log's directory disappears, so the next rotation fails
+    std::fs::remove_dir_all(&logs).unwrap();
+
+    for _ in 0..5 {
+        let mut stream = std::net::TcpStream::connect("127.0.0.1:3162").unwrap();
+        let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", origin_addr);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("ok"), "{}", response);
+    }
+
+    let _ = child.kill();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("write failed"), "{}", stderr);
+}
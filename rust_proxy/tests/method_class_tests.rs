@@ -0,0 +1,52 @@
+mod common;
+
+use rust_proxy::ProxyConfig;
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+#[tokio::test]
+async fn test_disable_https_refuses_connect_but_serves_http() {
+    let (origin, mut requests) = common::start_recording_origin(OK).await;
+    let config = ProxyConfig { disable_https: true, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+    assert_eq!(stats.method_class_disabled.load(Ordering::Relaxed), 1);
+    assert!(requests.try_recv().is_err(), "a refused CONNECT must not reach the origin");
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert_eq!(response.as_bytes(), OK);
+    assert!(requests.recv().await.unwrap().starts_with("GET http://"));
+    assert_eq!(stats.method_class_disabled.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_disable_http_refuses_plain_http_but_tunnels_connect() {
+    let (origin, mut requests) = common::start_recording_origin(OK).await;
+    let config = ProxyConfig { disable_http: true, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    for method in ["GET", "POST", "OPTIONS"] {
+        let request = format!("{} http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", method, origin, origin);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}: {}", method, response);
+    }
+    assert_eq!(stats.method_class_disabled.load(Ordering::Relaxed), 3);
+    assert!(requests.try_recv().is_err());
+
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", origin).as_bytes()).await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut response = vec![0; OK.len()];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, OK);
+    assert_eq!(requests.recv().await.unwrap(), "GET / HTTP/1.1\r\n\r\n");
+}
@@ -0,0 +1,362 @@
+mod common;
+
+use rust_proxy::auth::basic_credentials;
+use rust_proxy::profiles::parse_profiles;
+use rust_proxy::ProxyConfig;
+
+const PROFILES: &str = r#"{
+    "listeners": [
+        { "listen": "127.0.0.1:0" },
+        { "listen": "127.0.0.1:0", "auth": "user:secret", "methods": ["GET", "CONNECT"] }
+    ]
+}"#;
+
+#[test]
+fn test_parse_profiles() {
+    let profiles = parse_profiles(PROFILES).unwrap();
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(profiles[1].auth.as_deref(), Some("user:secret"));
+
+    // Typos must not silently drop a policy
+    assert!(parse_profiles(r#"{"listeners": [{"listen": "127.0.0.1:0", "auht": "x:y"}]}"#).is_err());
+    assert!(parse_profiles(r#"{"listeners": [{"listen": "not an address"}]}"#).is_err());
+
+    // Values are held to the same rules as the flags they override
+    for bad in [
+        r#""rate_per_ip": 0"#,
+        r#""rate_per_ip": -2.5"#,
+        r#""header_read_timeout": 0"#,
+        r#""idle_timeout": 0"#,
+        r#""connect_port_min": 8443, "connect_port_max": 443"#,
+    ] {
+        let json = format!(r#"{{"listeners": [{{"listen": "127.0.0.1:8001"}}, {{"listen": "127.0.0.1:8002", {}}}]}}"#, bad);
+        let error = parse_profiles(&json).unwrap_err().to_string();
+        assert!(error.starts_with("listener 127.0.0.1:8002: "), "{}: {}", bad, error);
+    }
+    assert!(parse_profiles(r#"{"listeners": [{"listen": "127.0.0.1:0", "rate_per_ip": 0.5, "idle_timeout": 1}]}"#).is_ok());
+}
+
+#[test]
+fn test_profile_overrides_base_config() {
+    let base = ProxyConfig { proxy_auth: Some(basic_credentials("base:pw")), deny_private_ranges: true, ..Default::default() };
+    let profiles = parse_profiles(r#"{"listeners": [{"listen": "127.0.0.1:0", "connect_port_min": 443}]}"#).unwrap();
+    let config = profiles[0].apply(&base);
+
+    assert_eq!(config.proxy_auth, None);
+    assert!(config.deny_private_ranges);
+    assert_eq!(config.connect_ports, 443..=u16::MAX);
+    assert_eq!(config.allowed_methods, None);
+}
+
+#[tokio::test]
+async fn test_policy_applied_per_listener() {
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let profiles = parse_profiles(PROFILES).unwrap();
+    let base = ProxyConfig::default();
+    let (open, _) = common::start_proxy(profiles[0].apply(&base)).await;
+    let (guarded, _) = common::start_proxy(profiles[1].apply(&base)).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    assert!(common::send_request(open, request.as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+    assert!(common::send_request(guarded, request.as_bytes()).await.starts_with("HTTP/1.1 407"));
+
+    let authorized = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: {}\r\n\r\n",
+        origin,
+        origin,
+        basic_credentials("user:secret")
+    );
+    assert!(common::send_request(guarded, authorized.as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+
+    // Method restrictions apply only where configured
+    let post = format!(
+        "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nProxy-Authorization: {}\r\n\r\n",
+        origin,
+        origin,
+        basic_credentials("user:secret")
+    );
+    assert!(common::send_request(guarded, post.as_bytes()).await.starts_with("HTTP/1.1 405 Method Not Allowed"));
+    let post = format!("POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\n\r\n", origin, origin);
+    assert!(common::send_request(open, post.as_bytes()).await.starts_with("HTTP/1.1 200 OK"));
+}
+
+#[tokio::test]
+async fn test_listener_connection_limit_is_per_listener() {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let limited = |n| ProxyConfig { connection_limit: Some(Arc::new(Semaphore::new(n))), ..Default::default() };
+    let (busy, busy_stats) = common::start_proxy(limited(1)).await;
+    let (other, _) = common::start_proxy(limited(1)).await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+
+    // An idle client holds the busy listener's only slot
+    let held = tokio::net::TcpStream::connect(busy).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    let response = common::send_request(busy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    assert_eq!(busy_stats.listener_limit_rejections.load(Ordering::Relaxed), 1);
+
+    // The other listener is unaffected
+    let response = common::send_request(other, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // Closing the idle client frees the slot
+    drop(held);
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    let response = common::send_request(busy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+#[test]
+fn test_listen_max_connections_parsing() {
+    use clap::Parser;
+    use rust_proxy::Args;
+
+    let args = Args::try_parse_from(["rust_proxy", "--listen-max-connections", "127.0.0.1:3128=50"]).unwrap();
+    assert_eq!(args.listen_max_connections, vec![("127.0.0.1:3128".parse().unwrap(), 50)]);
+    for bad in ["127.0.0.1:3128", "127.0.0.1:3128=0", "localhost:3128=5", "127.0.0.1:3128=many"] {
+        assert!(Args::try_parse_from(["rust_proxy", "--listen-max-connections", bad]).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_reload_swaps_profile_configs() {
+    use rust_proxy::reload::{apply_profiles, LiveConfig, ProfileListener};
+    use std::sync::Arc;
+
+    let base = ProxyConfig::default();
+    let limit = Arc::new(tokio::sync::Semaphore::new(5));
+    let listener = ProfileListener {
+        listen: "127.0.0.1:8001".parse().unwrap(),
+        config: Arc::new(LiveConfig::new(Arc::new(ProxyConfig { connection_limit: Some(limit.clone()), ..Default::default() }))),
+        main: false,
+    };
+    let before = listener.config.current();
+
+    let profiles = parse_profiles(r#"{ "listeners": [
+        { "listen": "127.0.0.1:8001", "deny_private_ranges": true, "methods": ["GET"] },
+        { "listen": "127.0.0.1:8002" }
+    ] }"#)
+    .unwrap();
+    assert_eq!(apply_profiles(&base, std::slice::from_ref(&listener), &profiles), 1);
+
+    let after = listener.config.current();
+    assert!(after.deny_private_ranges);
+    assert_eq!(after.allowed_methods, Some(vec!["GET".to_string()]));
+    // The listener's cap survives; connections holding the old config keep it
+    assert!(Arc::ptr_eq(after.connection_limit.as_ref().unwrap(), &limit));
+    assert!(!before.deny_private_ranges);
+
+    // Timeouts and the rate limit reload too; a new rate gets a new limiter
+    let profiles = parse_profiles(r#"{ "listeners": [
+        { "listen": "127.0.0.1:8001", "idle_timeout": 30, "request_timeout": 10, "rate_per_ip": 2 }
+    ] }"#)
+    .unwrap();
+    assert_eq!(apply_profiles(&base, std::slice::from_ref(&listener), &profiles), 1);
+    let reloaded = listener.config.current();
+    assert_eq!(reloaded.idle_timeout, std::time::Duration::from_secs(30));
+    assert_eq!(reloaded.request_timeout, Some(std::time::Duration::from_secs(10)));
+    assert_eq!(reloaded.write_timeout, base.write_timeout);
+    let limiter = reloaded.rate_limiter.clone().unwrap();
+    let client = "192.0.2.1".parse().unwrap();
+    assert!(limiter.check(client) && limiter.check(client) && !limiter.check(client));
+    assert_eq!(after.idle_timeout, base.idle_timeout);
+    assert!(after.rate_limiter.is_none());
+    apply_profiles(&base, std::slice::from_ref(&listener), &profiles);
+    assert!(listener.config.current().rate_limiter.as_ref().unwrap().check(client));
+    let profiles = parse_profiles(r#"{ "listeners": [
+        { "listen": "127.0.0.1:8001", "deny_private_ranges": true, "methods": ["GET"] }
+    ] }"#)
+    .unwrap();
+    apply_profiles(&base, std::slice::from_ref(&listener), &profiles);
+    assert_eq!(listener.config.current().idle_timeout, base.idle_timeout);
+    assert!(listener.config.current().rate_limiter.is_none());
+
+    // A listener missing from the file keeps what it has
+    let profiles = parse_profiles(r#"{ "listeners": [] }"#).unwrap();
+    assert_eq!(apply_profiles(&base, std::slice::from_ref(&listener), &profiles), 0);
+    assert!(listener.config.current().deny_private_ranges);
+
+    // The main listener keeps --auth under a profile without its own, and
+    // goes back to the command-line config once its profile is removed
+    let base = ProxyConfig { proxy_auth: Some(basic_credentials("admin:pw")), ..Default::default() };
+    let main = ProfileListener { listen: "127.0.0.1:8000".parse().unwrap(), config: Arc::new(LiveConfig::new(Arc::new(base.clone()))), main: true };
+    let profiles = parse_profiles(r#"{ "listeners": [ { "listen": "127.0.0.1:8000", "deny_private_ranges": true } ] }"#).unwrap();
+    assert_eq!(apply_profiles(&base, std::slice::from_ref(&main), &profiles), 1);
+    assert!(main.config.current().deny_private_ranges);
+    assert_eq!(main.config.current().proxy_auth, base.proxy_auth);
+    let profiles = parse_profiles(r#"{ "listeners": [] }"#).unwrap();
+    assert_eq!(apply_profiles(&base, std::slice::from_ref(&main), &profiles), 1);
+    assert!(!main.config.current().deny_private_ranges);
+}
+
+#[test]
+fn test_reload_with_invalid_value_keeps_previous_policy() {
+    use rust_proxy::reload::{reload_profiles, LiveConfig, ProfileListener};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let base = ProxyConfig::default();
+    let listener = ProfileListener {
+        listen: "127.0.0.1:8001".parse().unwrap(),
+        config: Arc::new(LiveConfig::new(Arc::new(base.clone()))),
+        main: false,
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("listeners.json");
+    std::fs::write(&path, r#"{ "listeners": [ { "listen": "127.0.0.1:8001", "idle_timeout": 30, "rate_per_ip": 2 } ] }"#).unwrap();
+    assert_eq!(reload_profiles(&path, &base, std::slice::from_ref(&listener)).unwrap(), 1);
+    let good = listener.config.current();
+
+    // One bad value refuses the whole file, and the listener keeps its policy
+    for bad in [r#""idle_timeout": 0"#, r#""rate_per_ip": 0"#] {
+        let json = format!(r#"{{ "listeners": [ {{ "listen": "127.0.0.1:8001", "deny_private_ranges": true, {} }} ] }}"#, bad);
+        std::fs::write(&path, json).unwrap();
+        let error = reload_profiles(&path, &base, std::slice::from_ref(&listener)).unwrap_err();
+        assert!(error.to_string().contains("listener 127.0.0.1:8001"), "{}", error);
+        let current = listener.config.current();
+        assert!(Arc::ptr_eq(&current, &good));
+        assert!(!current.deny_private_ranges);
+        assert_eq!(current.idle_timeout, Duration::from_secs(30));
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sighup_reloads_listener_config() {
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("listeners.json");
+    let profile = |deny: bool| format!(r#"{{ "listeners": [ {{ "listen": "127.0.0.1:3156", "deny_private_ranges": {} }} ] }}"#, deny);
+    std::fs::write(&path, profile(false)).unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3155", "--log-level", "error"])
+        .arg("--listener-config")
+        .arg(&path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let proxy: std::net::SocketAddr = "127.0.0.1:3156".parse().unwrap();
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(proxy).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    let before = common::send_request(proxy, request.as_bytes()).await;
+
+    // Block private destinations on the running listener
+    std::fs::write(&path, profile(true)).unwrap();
+    Command::new("kill").args(["-HUP", &child.id().to_string()]).status().unwrap();
+    let mut after = String::new();
+    for _ in 0..50 {
+        after = common::send_request(proxy, request.as_bytes()).await;
+        if after.starts_with("HTTP/1.1 403") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    let status = child.wait_with_output().unwrap().status;
+    assert!(before.starts_with("HTTP/1.1 200 OK"), "{}", before);
+    assert!(after.starts_with("HTTP/1.1 403 Forbidden"), "{}", after);
+    assert!(status.success(), "SIGHUP must not stop the proxy");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sighup_reloads_main_listener() {
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("listeners.json");
+    // A profile for the --host/--port address configures the main listener
+    let profile = |deny: bool| format!(r#"{{ "listeners": [ {{ "listen": "127.0.0.1:3165", "deny_private_ranges": {} }} ] }}"#, deny);
+    std::fs::write(&path, profile(false)).unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3165", "--log-level", "error"])
+        .arg("--listener-config")
+        .arg(&path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let proxy: std::net::SocketAddr = "127.0.0.1:3165".parse().unwrap();
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(proxy).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    let before = common::send_request(proxy, request.as_bytes()).await;
+
+    // Block private destinations, then lift the block again by dropping the
+    // profile, which leaves the command-line config
+    let mut responses = Vec::new();
+    for (contents, expected) in [(profile(true), "HTTP/1.1 403"), (r#"{ "listeners": [] }"#.to_string(), "HTTP/1.1 200")] {
+        std::fs::write(&path, contents).unwrap();
+        Command::new("kill").args(["-HUP", &child.id().to_string()]).status().unwrap();
+        let mut response = String::new();
+        for _ in 0..50 {
+            response = common::send_request(proxy, request.as_bytes()).await;
+            if response.starts_with(expected) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        responses.push(response);
+    }
+
+    Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    let status = child.wait_with_output().unwrap().status;
+    assert!(before.starts_with("HTTP/1.1 200 OK"), "{}", before);
+    assert!(responses[0].starts_with("HTTP/1.1 403 Forbidden"), "{}", responses[0]);
+    assert!(responses[1].starts_with("HTTP/1.1 200 OK"), "{}", responses[1]);
+    assert!(status.success(), "SIGHUP must not stop the proxy");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sighup_without_listener_config_is_harmless() {
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_proxy"))
+        .args(["--host", "127.0.0.1", "--port", "3166", "--log-level", "error"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy server");
+    let proxy: std::net::SocketAddr = "127.0.0.1:3166".parse().unwrap();
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(proxy).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    Command::new("kill").args(["-HUP", &child.id().to_string()]).status().unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+
+    Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    let status = child.wait_with_output().unwrap().status;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(status.success(), "SIGHUP must not stop the proxy");
+}
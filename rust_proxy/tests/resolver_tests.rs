@@ -0,0 +1,91 @@
+mod common;
+
+use clap::Parser;
+use rust_proxy::resolver::{parse_resolver, Resolver};
+use rust_proxy::{Args, ProxyConfig};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+// A stub DNS server answering every A query with 127.0.0.1 (and every other
+// type with no records), reporting the names asked for
+async fn start_stub_dns() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut query = [0; 512];
+        while let Ok((_, peer)) = socket.recv_from(&mut query).await {
+            // The question follows the 12-byte header: labels, then type and class
+            let mut end = 12;
+            let mut labels = Vec::new();
+            while query[end] != 0 {
+                let label_len = query[end] as usize;
+                labels.push(String::from_utf8_lossy(&query[end + 1..end + 1 + label_len]).into_owned());
+                end += 1 + label_len;
+            }
+            let question_end = end + 5;
+            let is_a = query[end + 1..end + 3] == [0, 1];
+            if is_a {
+                let _ = tx.send(labels.join("."));
+            }
+
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+            response.extend_from_slice(&query[12..question_end]);
+            if is_a {
+                response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                response.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+            }
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+
+    (addr, rx)
+}
+
+#[test]
+fn test_parse_resolver() {
+    assert_eq!(parse_resolver("8.8.8.8:5353").unwrap(), "8.8.8.8:5353".parse().unwrap());
+    assert_eq!(parse_resolver("8.8.8.8").unwrap(), "8.8.8.8:53".parse().unwrap());
+    assert_eq!(parse_resolver("[2001:4860:4860::8888]").unwrap(), "[2001:4860:4860::8888]:53".parse().unwrap());
+    assert!(parse_resolver("dns.google").is_err());
+}
+
+#[tokio::test]
+async fn test_resolver_answers_lookups() {
+    let (dns, mut names) = start_stub_dns().await;
+    let resolver = Resolver::dns(dns);
+
+    let addrs = resolver.lookup("anything.test", 8080).await.unwrap();
+    assert_eq!(addrs, vec!["127.0.0.1:8080".parse().unwrap()]);
+    assert_eq!(names.recv().await.unwrap(), "anything.test");
+
+    // Literals never reach the server
+    let addrs = resolver.lookup("[::1]", 443).await.unwrap();
+    assert_eq!(addrs, vec!["[::1]:443".parse().unwrap()]);
+    assert!(names.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_upstream_names_resolve_through_configured_server() {
+    let (dns, mut names) = start_stub_dns().await;
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let args = Args::parse_from(["rust_proxy", "--resolver", &dns.to_string()]);
+    let (proxy, _stats) = common::start_proxy(ProxyConfig::from_args(&args)).await;
+
+    // `origin.test` only exists in the stub's answers
+    let request = format!("GET http://origin.test:{}/ HTTP/1.1\r\nHost: origin.test\r\n\r\n", origin.port());
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert_eq!(names.recv().await.unwrap(), "origin.test");
+    assert!(requests.recv().await.unwrap().contains("Host: origin.test\r\n"));
+
+    // The SSRF guard checks the same answer the dial would use
+    let args = Args::parse_from(["rust_proxy", "--resolver", &dns.to_string(), "--deny-private-ranges"]);
+    let (proxy, stats) = common::start_proxy(ProxyConfig::from_args(&args)).await;
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    assert_eq!(stats.blocked_ssrf.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use rust_proxy::{ProxyConfig, ProxyStats};
+use std::sync::atomic::Ordering;
+
+#[test]
+fn test_statuses_counted_by_class() {
+    let stats = ProxyStats::new();
+    for status in [101, 200, 204, 301, 304, 404, 429, 500, 503] {
+        stats.record_response_status(Some(status));
+    }
+    stats.record_response_status(None);
+    stats.record_response_status(Some(99));
+    stats.record_response_status(Some(600));
+
+    let snapshot = stats.snapshot();
+    assert_eq!(
+        [snapshot.resp_1xx, snapshot.resp_2xx, snapshot.resp_3xx, snapshot.resp_4xx, snapshot.resp_5xx, snapshot.resp_invalid],
+        [1, 2, 2, 2, 2, 3]
+    );
+}
+
+#[tokio::test]
+async fn test_upstream_404_counted_as_4xx() {
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let request = format!("GET http://{}/missing HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    assert_eq!(stats.resp_4xx.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.resp_2xx.load(Ordering::Relaxed), 0);
+    assert_eq!(stats.resp_invalid.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_response_without_status_line_counted_as_invalid() {
+    let (origin, _requests) = common::start_recording_origin(b"garbage without a status line\r\n\r\n").await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    // Relayed as-is; the proxy only counts it
+    assert_eq!(response, "garbage without a status line\r\n\r\n");
+    assert_eq!(stats.resp_invalid.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_status_counted_when_tunneled() {
+    // `Expect` keeps the exchange off the keep-alive relay, so the response
+    // goes through `tunnel_http`
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let request = format!("POST http://{}/ HTTP/1.1\r\nHost: {}\r\nExpect: 100-continue\r\nContent-Length: 0\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert_eq!(stats.resp_5xx.load(Ordering::Relaxed), 1);
+}
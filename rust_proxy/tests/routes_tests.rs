@@ -0,0 +1,100 @@
+mod common;
+
+use rust_proxy::routes::{parse_route, RouteMap};
+use rust_proxy::ProxyConfig;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+fn route_to(from: &str, to: std::net::SocketAddr) -> RouteMap {
+    RouteMap::new([parse_route(&format!("{}={}", from, to)).unwrap()])
+}
+
+#[test]
+fn test_parse_route() {
+    let route = parse_route("API.Example.com.:443=10.0.0.5:8443").unwrap();
+    assert_eq!(route.from, ("api.example.com".to_string(), 443));
+    assert_eq!(route.to, ("10.0.0.5".to_string(), 8443));
+
+    let route = parse_route("[::1]:80=[fd00::5]:8080").unwrap();
+    assert_eq!(route.from, ("::1".to_string(), 80));
+    assert_eq!(route.to, ("fd00::5".to_string(), 8080));
+
+    assert!(parse_route("api.example.com:443").is_err());
+    assert!(parse_route("api.example.com=10.0.0.5:8443").is_err());
+    assert!(parse_route("api.example.com:443=10.0.0.5:99999").is_err());
+    assert!(parse_route(":443=10.0.0.5:8443").is_err());
+}
+
+#[test]
+fn test_route_lookup() {
+    let routes = RouteMap::new([
+        parse_route("api.example.com:443=10.0.0.5:8443").unwrap(),
+        parse_route("api.example.com:443=10.0.0.6:8443").unwrap(),
+    ]);
+    assert_eq!(routes.lookup("API.example.com", 443), Some(("10.0.0.6".to_string(), 8443)));
+    assert_eq!(routes.lookup("api.example.com.", 443), Some(("10.0.0.6".to_string(), 8443)));
+    assert_eq!(routes.lookup("api.example.com", 80), None);
+    assert_eq!(routes.lookup("www.example.com", 443), None);
+    assert!(RouteMap::default().is_empty());
+}
+
+#[tokio::test]
+async fn test_routed_http_request_reaches_mapped_target() {
+    let (origin, mut requests) = common::start_recording_origin(OK).await;
+    let config = ProxyConfig { routes: route_to("api.example.invalid:80", origin), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let response = common::send_request(
+        proxy,
+        b"GET http://api.example.invalid/v1 HTTP/1.1\r\nHost: api.example.invalid\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(response.as_bytes(), OK);
+
+    // The origin sees the request exactly as the client addressed it
+    let request = requests.recv().await.unwrap();
+    assert!(request.starts_with("GET http://api.example.invalid/v1 HTTP/1.1\r\n"), "{}", request);
+    assert!(request.contains("Host: api.example.invalid\r\n"), "{}", request);
+}
+
+#[tokio::test]
+async fn test_routed_connect_tunnels_to_mapped_target() {
+    let (origin, mut requests) = common::start_recording_origin(OK).await;
+    let config = ProxyConfig { routes: route_to("tunnel.example.invalid:443", origin), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(b"CONNECT tunnel.example.invalid:443 HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: tunnel.example.invalid\r\n\r\n").await.unwrap();
+    let mut response = vec![0; OK.len()];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, OK);
+    assert!(requests.recv().await.unwrap().starts_with("GET / HTTP/1.1\r\n"));
+}
+
+#[tokio::test]
+async fn test_unrouted_destinations_pass_through() {
+    let (origin, mut requests) = common::start_recording_origin(OK).await;
+    let config = ProxyConfig { routes: route_to("api.example.invalid:80", origin), ..Default::default() };
+    let (proxy, _stats) = common::start_proxy(config).await;
+
+    // Other destinations are dialed as requested
+    let request = format!("GET http://{}/direct HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert_eq!(response.as_bytes(), OK);
+    assert!(requests.recv().await.unwrap().contains("/direct"));
+
+    // A route is for one port only
+    let response = common::send_request(
+        proxy,
+        b"GET http://api.example.invalid:81/ HTTP/1.1\r\nHost: api.example.invalid:81\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert!(requests.try_recv().is_err());
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use rust_proxy::headers::RequestHead;
+use rust_proxy::ProxyConfig;
+use std::sync::atomic::Ordering;
+
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+fn conflict(headers: &str) -> Option<&'static str> {
+    let raw = format!("POST http://h/ HTTP/1.1\r\nHost: h\r\n{}\r\n", headers);
+    RequestHead::parse(raw.as_bytes()).unwrap().framing_conflict()
+}
+
+#[test]
+fn test_framing_conflicts() {
+    assert_eq!(conflict("Content-Length: 4\r\nTransfer-Encoding: chunked\r\n"), Some("both Content-Length and Transfer-Encoding"));
+    assert_eq!(conflict("Transfer-Encoding: chunked\r\nContent-Length: 4\r\n"), Some("both Content-Length and Transfer-Encoding"));
+    assert_eq!(conflict("Content-Length: 4\r\nContent-Length: 5\r\n"), Some("duplicate Content-Length"));
+    assert_eq!(conflict("Content-Length: 4\r\nContent-Length: 4\r\n"), Some("duplicate Content-Length"));
+    assert_eq!(conflict("Content-Length: 4, 4\r\n"), Some("duplicate Content-Length"));
+    assert_eq!(conflict("Content-Length: +4\r\n"), Some("invalid Content-Length"));
+    assert_eq!(conflict("Content-Length: four\r\n"), Some("invalid Content-Length"));
+    assert_eq!(conflict("Transfer-Encoding: chunked, gzip\r\n"), Some("Transfer-Encoding not ending in chunked"));
+
+    assert_eq!(conflict(""), None);
+    assert_eq!(conflict("Content-Length: 4\r\n"), None);
+    assert_eq!(conflict("Transfer-Encoding: gzip, chunked\r\n"), None);
+}
+
+#[tokio::test]
+async fn test_smuggling_vectors_rejected_before_forwarding() {
+    let (origin, mut requests) = common::start_recording_origin(OK).await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig::default()).await;
+
+    let vectors = [
+        "Content-Length: 4\r\nTransfer-Encoding: chunked\r\n",
+        "Content-Length: 4\r\nContent-Length: 40\r\n",
+        "Content-Length: 4\r\nContent-Length: 4\r\n",
+        "Content-Length: -4\r\n",
+        "Transfer-Encoding: identity\r\n",
+    ];
+    for headers in vectors {
+        let request = format!("POST http://{}/ HTTP/1.1\r\nHost: {}\r\n{}\r\nbody", origin, origin, headers);
+        let response = common::send_request(proxy, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", headers, response);
+    }
+    assert_eq!(stats.smuggling_blocked.load(Ordering::Relaxed), vectors.len() as u64);
+    assert!(requests.try_recv().is_err(), "no rejected request should reach the origin");
+
+    // A well-formed request with a body still goes through
+    let request = format!("POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: 4\r\n\r\nbody", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert_eq!(response.as_bytes(), OK);
+    assert!(requests.recv().await.unwrap().starts_with("POST http://"));
+    assert_eq!(stats.smuggling_blocked.load(Ordering::Relaxed), vectors.len() as u64);
+}
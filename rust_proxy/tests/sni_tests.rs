@@ -0,0 +1,240 @@
+mod common;
+
+use rust_proxy::alpn::{AlpnPolicy, NO_APPLICATION_PROTOCOL_ALERT};
+use rust_proxy::routes::{parse_route, RouteMap};
+use rust_proxy::sni::{is_mismatch, offered_alpn, parse_client_hello, ClientHelloSni};
+use rust_proxy::ProxyConfig;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+// The ClientHello a rustls client sends when connecting to `server_name`
+fn client_hello(server_name: &str) -> Vec<u8> {
+    client_hello_with_alpn(server_name, &[])
+}
+
+// Same, offering the `alpn` protocols
+fn client_hello_with_alpn(server_name: &str, alpn: &[&str]) -> Vec<u8> {
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    let name = ServerName::try_from(server_name.to_string()).unwrap();
+    let mut connection = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut hello = Vec::new();
+    while connection.wants_write() {
+        connection.write_tls(&mut hello).unwrap();
+    }
+    hello
+}
+
+// The ClientHello in `hello` split into two handshake records, the first
+// carrying `at` bytes of the message
+fn fragment(hello: &[u8], at: usize) -> Vec<u8> {
+    let message = &hello[5..];
+    let mut split = Vec::new();
+    for part in [&message[..at], &message[at..]] {
+        split.extend_from_slice(&[0x16, 0x03, 0x01]);
+        split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+        split.extend_from_slice(part);
+    }
+    split
+}
+
+// `hello` (offering h2 and http/1.1) with the h2 entry's length overrunning
+// the ALPN list
+fn malformed_alpn(hello: &[u8]) -> Vec<u8> {
+    let at = hello.windows(6).position(|w| w == b"\x02h2\x08ht").unwrap();
+    let mut malformed = hello.to_vec();
+    malformed[at] = 0x7f;
+    malformed
+}
+
+// An origin that reports the first `len` bytes each connection sends
+async fn start_capturing_origin(len: usize) -> (SocketAddr, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut received = vec![0; len];
+            if socket.read_exact(&mut received).await.is_ok() {
+                let _ = tx.send(received);
+            }
+        }
+    });
+    (addr, rx)
+}
+
+// Open a tunnel to `target` and send `hello` through it
+async fn send_through_tunnel(proxy: SocketAddr, target: &str, hello: &[u8]) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes()).await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+    stream.write_all(hello).await.unwrap();
+    stream
+}
+
+#[test]
+fn test_sni_extracted_from_client_hello() {
+    let hello = client_hello("Www.Example.com");
+    assert_eq!(parse_client_hello(&hello), ClientHelloSni::Found("www.example.com".to_string()));
+    assert_eq!(parse_client_hello(&hello[..hello.len() - 1]), ClientHelloSni::Incomplete);
+    assert_eq!(parse_client_hello(&hello[..3]), ClientHelloSni::Incomplete);
+    assert_eq!(parse_client_hello(&[]), ClientHelloSni::Incomplete);
+
+    // rustls leaves SNI out when connecting to an IP address
+    assert_eq!(parse_client_hello(&client_hello("192.0.2.1")), ClientHelloSni::Absent);
+
+    assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n"), ClientHelloSni::NotTls);
+    assert_eq!(parse_client_hello(b"SSH-2.0-OpenSSH_9.6\r\n"), ClientHelloSni::NotTls);
+}
+
+#[test]
+fn test_sni_mismatch() {
+    assert!(!is_mismatch("example.com", "example.com"));
+    assert!(!is_mismatch("Example.COM.", "example.com"));
+    assert!(is_mismatch("allowed.example.com", "blocked.example.net"));
+    assert!(!is_mismatch("192.0.2.1", "example.com"));
+    assert!(!is_mismatch("::1", "example.com"));
+}
+
+#[tokio::test]
+async fn test_client_hello_forwarded_unchanged_and_counted() {
+    let hello = client_hello("front.example.com");
+    let (origin, mut received) = start_capturing_origin(hello.len()).await;
+    let routes = RouteMap::new([
+        parse_route(&format!("front.example.com:443={}", origin)).unwrap(),
+        parse_route(&format!("cdn.example.net:443={}", origin)).unwrap(),
+    ]);
+    let config = ProxyConfig { inspect_sni: true, routes, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let _matching = send_through_tunnel(proxy, "front.example.com:443", &hello).await;
+    assert_eq!(received.recv().await.unwrap(), hello);
+    assert_eq!(stats.sni_seen.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.sni_mismatches.load(Ordering::Relaxed), 0);
+
+    let _fronted = send_through_tunnel(proxy, "cdn.example.net:443", &hello).await;
+    assert_eq!(received.recv().await.unwrap(), hello);
+    assert_eq!(stats.sni_seen.load(Ordering::Relaxed), 2);
+    assert_eq!(stats.sni_mismatches.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_non_tls_tunnel_still_relayed() {
+    let (origin, mut received) = start_capturing_origin(4).await;
+    let config = ProxyConfig { inspect_sni: true, ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let _stream = send_through_tunnel(proxy, &origin.to_string(), b"PING").await;
+    assert_eq!(received.recv().await.unwrap(), b"PING");
+    assert_eq!(stats.sni_seen.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_alpn_extracted_from_client_hello() {
+    let hello = client_hello_with_alpn("example.com", &["h2", "http/1.1"]);
+    assert_eq!(offered_alpn(&hello), Some(vec!["h2".to_string(), "http/1.1".to_string()]));
+    assert_eq!(parse_client_hello(&hello), ClientHelloSni::Found("example.com".to_string()));
+    assert_eq!(offered_alpn(&client_hello("example.com")), Some(Vec::new()));
+    assert_eq!(offered_alpn(b"GET / HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(offered_alpn(&hello[..hello.len() - 1]), None);
+
+    // A ClientHello split over two records reads the same as one
+    let split = fragment(&hello, 40);
+    assert_eq!(parse_client_hello(&split), ClientHelloSni::Found("example.com".to_string()));
+    assert_eq!(offered_alpn(&split), offered_alpn(&hello));
+    assert_eq!(parse_client_hello(&split[..split.len() - 1]), ClientHelloSni::Incomplete);
+
+    // A malformed ALPN list is unknown, not empty
+    assert_eq!(offered_alpn(&malformed_alpn(&hello)), None);
+}
+
+#[test]
+fn test_alpn_policy() {
+    let strings = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    assert_eq!(AlpnPolicy::new(&[], &[]), None);
+
+    let block_h2 = AlpnPolicy::new(&[], &strings(&["h2"])).unwrap();
+    assert_eq!(block_h2.refused(&strings(&["h2", "http/1.1"])), Some("h2"));
+    assert_eq!(block_h2.refused(&strings(&["http/1.1"])), None);
+    assert_eq!(block_h2.refused(&[]), None);
+
+    let only_http1 = AlpnPolicy::new(&strings(&["http/1.1"]), &[]).unwrap();
+    assert_eq!(only_http1.refused(&strings(&["h2", "http/1.1"])), Some("h2"));
+    assert_eq!(only_http1.refused(&strings(&["http/1.1"])), None);
+    assert_eq!(only_http1.refused(&strings(&["HTTP/1.1"])), Some("HTTP/1.1"));
+}
+
+#[tokio::test]
+async fn test_tunnel_offering_blocked_alpn_refused() {
+    let http1 = client_hello_with_alpn("example.com", &["http/1.1"]);
+    let (origin, mut received) = start_capturing_origin(http1.len()).await;
+    let config = ProxyConfig { alpn_policy: AlpnPolicy::new(&[], &["h2".to_string()]), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    // Offering h2 gets the TLS alert and a closed tunnel; nothing goes upstream
+    let mut refused = send_through_tunnel(proxy, &origin.to_string(), &client_hello_with_alpn("example.com", &["h2", "http/1.1"])).await;
+    let mut reply = Vec::new();
+    refused.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, NO_APPLICATION_PROTOCOL_ALERT);
+    assert_eq!(stats.alpn_blocked.load(Ordering::Relaxed), 1);
+
+    // HTTP/1.1 alone is forwarded untouched
+    let _allowed = send_through_tunnel(proxy, &origin.to_string(), &http1).await;
+    assert_eq!(received.recv().await.unwrap(), http1);
+    assert_eq!(stats.alpn_blocked.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_fragmented_or_malformed_client_hello_checked_for_alpn() {
+    let hello = client_hello_with_alpn("example.com", &["h2", "http/1.1"]);
+    let (origin, _received) = start_capturing_origin(hello.len()).await;
+    let config = ProxyConfig { alpn_policy: AlpnPolicy::new(&[], &["h2".to_string()]), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    // Neither gets through without --fail-closed
+    for (i, hello) in [fragment(&hello, 40), malformed_alpn(&hello)].iter().enumerate() {
+        let mut refused = send_through_tunnel(proxy, &origin.to_string(), hello).await;
+        let mut reply = Vec::new();
+        refused.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, NO_APPLICATION_PROTOCOL_ALERT);
+        assert_eq!(stats.alpn_blocked.load(Ordering::Relaxed), i as u64 + 1);
+    }
+
+    // Nor does a ClientHello that stops partway
+    let mut refused = send_through_tunnel(proxy, &origin.to_string(), &hello[..hello.len() / 2]).await;
+    let mut reply = Vec::new();
+    refused.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, NO_APPLICATION_PROTOCOL_ALERT);
+    assert_eq!(stats.alpn_blocked.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn test_unparseable_client_hello_refused_only_when_failing_closed() {
+    let (origin, mut received) = start_capturing_origin(4).await;
+    let alpn_policy = AlpnPolicy::new(&[], &["h2".to_string()]);
+
+    let (proxy, stats) = common::start_proxy(ProxyConfig { alpn_policy: alpn_policy.clone(), ..Default::default() }).await;
+    let _open = send_through_tunnel(proxy, &origin.to_string(), b"PING").await;
+    assert_eq!(received.recv().await.unwrap(), b"PING");
+    assert_eq!(stats.fail_closed_denied.load(Ordering::Relaxed), 0);
+
+    let (proxy, stats) = common::start_proxy(ProxyConfig { alpn_policy, fail_closed: true, ..Default::default() }).await;
+    let mut closed = send_through_tunnel(proxy, &origin.to_string(), b"PING").await;
+    let mut reply = Vec::new();
+    closed.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, NO_APPLICATION_PROTOCOL_ALERT);
+    assert_eq!(stats.fail_closed_denied.load(Ordering::Relaxed), 1);
+}
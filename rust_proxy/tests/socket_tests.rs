@@ -0,0 +1,105 @@
+mod common;
+
+use async_trait::async_trait;
+use rust_proxy::dialer::{BoxedStream, UpstreamDialer};
+use rust_proxy::{handle_client, ProxyConfig, ProxyStats};
+use socket2::SockRef;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// TCP dialer that keeps a handle on each socket it opens so the test can
+// inspect the options the proxy applied
+#[derive(Debug, Default)]
+struct InspectingDialer {
+    sockets: Mutex<Vec<std::net::TcpStream>>,
+}
+
+#[async_trait]
+impl UpstreamDialer for InspectingDialer {
+    async fn dial(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        let stream = TcpStream::connect((host, port)).await?.into_std()?;
+        self.sockets.lock().unwrap().push(stream.try_clone()?);
+        Ok(Box::new(TcpStream::from_std(stream)?))
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_keepalive_applied_to_client_and_upstream() {
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let dialer = Arc::new(InspectingDialer::default());
+    let config = Arc::new(ProxyConfig {
+        dialer: dialer.clone(),
+        tcp_keepalive: Some(Duration::from_secs(7)),
+        ..Default::default()
+    });
+
+    // Drive handle_client on an accepted socket we also hold a handle to
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    let accepted = accepted.into_std().unwrap();
+    let proxy_side = accepted.try_clone().unwrap();
+    let proxy = tokio::spawn(handle_client(TcpStream::from_std(accepted).unwrap(), Arc::new(ProxyStats::new()), config));
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = vec![0; 256];
+    let n = tokio::time::timeout(Duration::from_secs(5), client.read(&mut response)).await.unwrap().unwrap();
+    assert!(String::from_utf8_lossy(&response[..n]).starts_with("HTTP/1.1 200 OK"));
+
+    let upstream = dialer.sockets.lock().unwrap().pop().expect("proxy should have dialed the origin");
+    for socket in [SockRef::from(&proxy_side), SockRef::from(&upstream)] {
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(7));
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.tcp_keepalive_interval().unwrap(), Duration::from_secs(7));
+    }
+
+    drop(client);
+    let _ = proxy.await;
+}
+
+#[tokio::test]
+async fn test_tcp_keepalive_left_to_os_when_unset() {
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let dialer = Arc::new(InspectingDialer::default());
+    let (proxy, _stats) = common::start_proxy(ProxyConfig { dialer: dialer.clone(), ..Default::default() }).await;
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    common::send_request(proxy, request.as_bytes()).await;
+    let upstream = dialer.sockets.lock().unwrap().pop().unwrap();
+    assert!(!SockRef::from(&upstream).keepalive().unwrap());
+}
+
+#[tokio::test]
+async fn test_nodelay_follows_nagle_flag() {
+    let (origin, _requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+
+    for nagle in [false, true] {
+        let dialer = Arc::new(InspectingDialer::default());
+        let config = Arc::new(ProxyConfig { dialer: dialer.clone(), nagle, ..Default::default() });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let accepted = accepted.into_std().unwrap();
+        let proxy_side = accepted.try_clone().unwrap();
+        let proxy = tokio::spawn(handle_client(TcpStream::from_std(accepted).unwrap(), Arc::new(ProxyStats::new()), config));
+
+        let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![0; 256];
+        let n = tokio::time::timeout(Duration::from_secs(5), client.read(&mut response)).await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&response[..n]).starts_with("HTTP/1.1 200 OK"));
+
+        // TCP_NODELAY is set on both sides unless Nagle was asked for
+        let upstream = dialer.sockets.lock().unwrap().pop().unwrap();
+        assert_eq!(SockRef::from(&proxy_side).tcp_nodelay().unwrap(), !nagle);
+        assert_eq!(SockRef::from(&upstream).tcp_nodelay().unwrap(), !nagle);
+
+        drop(client);
+        let _ = proxy.await;
+    }
+}
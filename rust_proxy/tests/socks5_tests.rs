@@ -0,0 +1,146 @@
+mod common;
+
+use rust_proxy::dialer::TcpDialer;
+use rust_proxy::socks5::{parse_socks5_auth, parse_socks5_proxy, Socks5Credentials, Socks5Dialer};
+use rust_proxy::ProxyConfig;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+// A stub SOCKS5 proxy. It requires `password` (username "user") when given,
+// reports each CONNECT target as `host:port`, answers it with success and
+// then plays the origin itself, replying `OK` to whatever arrives.
+async fn start_socks5_proxy(password: Option<&'static str>) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0; 2];
+                socket.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0; greeting[1] as usize];
+                socket.read_exact(&mut methods).await.unwrap();
+                let Some(password) = password else {
+                    socket.write_all(&[5, 0]).await.unwrap();
+                    return serve_connect(socket, tx).await;
+                };
+                if !methods.contains(&2) {
+                    let _ = socket.write_all(&[5, 0xFF]).await;
+                    return;
+                }
+                socket.write_all(&[5, 2]).await.unwrap();
+                let mut header = [0; 2];
+                socket.read_exact(&mut header).await.unwrap();
+                let mut username = vec![0; header[1] as usize];
+                socket.read_exact(&mut username).await.unwrap();
+                let mut password_len = [0; 1];
+                socket.read_exact(&mut password_len).await.unwrap();
+                let mut given = vec![0; password_len[0] as usize];
+                socket.read_exact(&mut given).await.unwrap();
+                if username != b"user" || given != password.as_bytes() {
+                    let _ = socket.write_all(&[1, 1]).await;
+                    return;
+                }
+                socket.write_all(&[1, 0]).await.unwrap();
+                serve_connect(socket, tx).await;
+            });
+        }
+    });
+
+    (addr, rx)
+}
+
+async fn serve_connect(mut socket: TcpStream, tx: mpsc::UnboundedSender<String>) {
+    let mut request = [0; 4];
+    socket.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request[..3], &[5, 1, 0]);
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0; 4];
+            socket.read_exact(&mut ip).await.unwrap();
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let len = socket.read_u8().await.unwrap();
+            let mut name = vec![0; len as usize];
+            socket.read_exact(&mut name).await.unwrap();
+            String::from_utf8(name).unwrap()
+        }
+        atyp => panic!("unexpected address type {}", atyp),
+    };
+    let port = socket.read_u16().await.unwrap();
+    let _ = tx.send(format!("{}:{}", host, port));
+    socket.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x1F, 0x90]).await.unwrap();
+
+    let mut buffer = [0; 1024];
+    if socket.read(&mut buffer).await.unwrap_or(0) > 0 {
+        let _ = socket.write_all(OK).await;
+    }
+}
+
+fn socks5_config(proxy: SocketAddr, credentials: Option<Socks5Credentials>) -> ProxyConfig {
+    let proxy = parse_socks5_proxy(&format!("socks5://{}", proxy)).unwrap();
+    ProxyConfig { dialer: Arc::new(Socks5Dialer::new(proxy, credentials, Arc::new(TcpDialer))), ..Default::default() }
+}
+
+#[test]
+fn test_parse_socks5_options() {
+    let proxy = parse_socks5_proxy("socks5://proxy.internal:1080").unwrap();
+    assert_eq!((proxy.host.as_str(), proxy.port), ("proxy.internal", 1080));
+    assert!(parse_socks5_proxy("proxy.internal").is_err());
+
+    let credentials = parse_socks5_auth("user:pa:ss").unwrap();
+    assert_eq!((credentials.username.as_str(), credentials.password.as_str()), ("user", "pa:ss"));
+    assert!(parse_socks5_auth("user").is_err());
+    assert!(parse_socks5_auth(":pass").is_err());
+    assert!(parse_socks5_auth(&format!("user:{}", "x".repeat(256))).is_err());
+}
+
+#[tokio::test]
+async fn test_requests_reach_destination_through_socks5_proxy() {
+    let (socks, mut targets) = start_socks5_proxy(None).await;
+    let (proxy, _stats) = common::start_proxy(socks5_config(socks, None)).await;
+
+    // The hostname goes to the SOCKS5 proxy unresolved
+    let response = common::send_request(proxy, b"GET http://origin.invalid:8080/ HTTP/1.1\r\nHost: origin.invalid:8080\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert_eq!(targets.recv().await.unwrap(), "origin.invalid:8080");
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(b"CONNECT 192.0.2.7:443 HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert!(established.starts_with(b"HTTP/1.1 200"));
+    assert_eq!(targets.recv().await.unwrap(), "192.0.2.7:443");
+    stream.write_all(b"hello").await.unwrap();
+    let mut tunneled = vec![0; OK.len()];
+    stream.read_exact(&mut tunneled).await.unwrap();
+    assert_eq!(tunneled, OK);
+}
+
+#[tokio::test]
+async fn test_socks5_username_password_auth() {
+    let (socks, mut targets) = start_socks5_proxy(Some("secret")).await;
+    let request = b"GET http://origin.invalid/ HTTP/1.1\r\nHost: origin.invalid\r\n\r\n";
+
+    let (proxy, _stats) = common::start_proxy(socks5_config(socks, parse_socks5_auth("user:secret").ok())).await;
+    let response = common::send_request(proxy, request).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert_eq!(targets.recv().await.unwrap(), "origin.invalid:80");
+
+    // Without credentials, or with the wrong ones, the client gets a 502
+    for credentials in [None, parse_socks5_auth("user:wrong").ok()] {
+        let (proxy, stats) = common::start_proxy(socks5_config(socks, credentials)).await;
+        let response = common::send_request(proxy, request).await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+        assert_eq!(stats.connection_errors.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+    assert!(targets.try_recv().is_err());
+}
@@ -0,0 +1,71 @@
+use rust_proxy::ssl_errors::{analyze_ssl_error, SslErrorKind, SSL_ERROR_INDICATORS};
+use rust_proxy::ProxyStats;
+
+#[test]
+fn test_each_indicator_maps_to_expected_kind() {
+    let expected = [
+        ("certificate", SslErrorKind::Unknown),
+        ("cert", SslErrorKind::Unknown),
+        ("tls", SslErrorKind::Unknown),
+        ("ssl", SslErrorKind::Unknown),
+        ("handshake", SslErrorKind::HandshakeFailed),
+        ("verification", SslErrorKind::Unknown),
+        ("expired", SslErrorKind::Expired),
+        ("self-signed", SslErrorKind::SelfSigned),
+        ("untrusted", SslErrorKind::SelfSigned),
+        ("certificate chain", SslErrorKind::Unknown),
+        ("certificate verify", SslErrorKind::VerifyFailed),
+        ("certificate has expired", SslErrorKind::Expired),
+        ("certificate not yet valid", SslErrorKind::Unknown),
+        ("certificate revoked", SslErrorKind::Revoked),
+        ("certificate signature", SslErrorKind::Unknown),
+        ("certificate authority", SslErrorKind::Unknown),
+        ("ca", SslErrorKind::Unknown),
+        ("unknown ca", SslErrorKind::Unknown),
+        ("unable to get local issuer", SslErrorKind::Unknown),
+        ("issuer certificate", SslErrorKind::Unknown),
+        ("root certificate", SslErrorKind::Unknown),
+    ];
+    assert_eq!(expected.len(), SSL_ERROR_INDICATORS.len());
+    for (indicator, kind) in expected {
+        assert!(SSL_ERROR_INDICATORS.contains(&indicator), "{}", indicator);
+        assert_eq!(SslErrorKind::classify(indicator), kind, "{}", indicator);
+    }
+}
+
+#[test]
+fn test_classification_precedence_and_case() {
+    // Expiry wins over everything else in the message
+    assert_eq!(SslErrorKind::classify("Handshake failed: certificate has EXPIRED"), SslErrorKind::Expired);
+    assert_eq!(SslErrorKind::classify("TLS handshake: self-signed certificate"), SslErrorKind::SelfSigned);
+    assert_eq!(SslErrorKind::classify("Connection refused (os error 111)"), SslErrorKind::NotSslRelated);
+    assert_eq!(SslErrorKind::classify("Connection reset by peer"), SslErrorKind::NotSslRelated);
+}
+
+#[test]
+fn test_analyze_ssl_error_returns_kind() {
+    let error = std::io::Error::other("invalid peer certificate: certificate revoked");
+    assert_eq!(analyze_ssl_error("example.com", 443, &error), SslErrorKind::Revoked);
+    let error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+    assert_eq!(analyze_ssl_error("example.com", 443, &error), SslErrorKind::NotSslRelated);
+}
+
+#[test]
+fn test_ssl_error_stats_by_kind() {
+    let stats = ProxyStats::new();
+    stats.ssl_errors.record(SslErrorKind::Expired);
+    stats.ssl_errors.record(SslErrorKind::Expired);
+    stats.ssl_errors.record(SslErrorKind::HandshakeFailed);
+    stats.ssl_errors.record(SslErrorKind::NotSslRelated);
+
+    let counts = stats.snapshot().ssl_errors;
+    assert_eq!(counts.expired, 2);
+    assert_eq!(counts.handshake_failed, 1);
+    assert_eq!(counts.total(), 3);
+
+    let document = serde_json::to_value(stats.snapshot()).unwrap();
+    assert_eq!(document["ssl_errors"]["expired"], 2);
+
+    stats.reset();
+    assert_eq!(stats.snapshot().ssl_errors.total(), 0);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use rust_proxy::stale::{with_stale_warning, StaleStore};
+use rust_proxy::ProxyConfig;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const FRESH: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfresh";
+
+// Origin answering each connection with the next response in turn
+async fn start_sequence_origin(responses: Vec<&'static [u8]>) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        for response in responses {
+            let Ok((mut socket, _)) = listener.accept().await else { break };
+            let mut buffer = [0; 4096];
+            let _ = socket.read(&mut buffer).await;
+            let _ = socket.write_all(response).await;
+        }
+        // Dropping the listener makes later fetches fail to connect
+    });
+    addr
+}
+
+fn stale_config() -> ProxyConfig {
+    ProxyConfig { stale_store: Some(Arc::new(StaleStore::new(16))), ..Default::default() }
+}
+
+#[test]
+fn test_stale_warning_inserted_after_status_line() {
+    let marked = with_stale_warning(FRESH);
+    assert_eq!(
+        marked,
+        b"HTTP/1.1 200 OK\r\nWarning: 110 - \"Response is Stale\"\r\nContent-Length: 5\r\n\r\nfresh".to_vec()
+    );
+    // Anything without a complete head is left alone
+    assert_eq!(with_stale_warning(b"HTTP/1.1 200 OK\r\n"), b"HTTP/1.1 200 OK\r\n".to_vec());
+}
+
+#[tokio::test]
+async fn test_stale_served_on_upstream_5xx() {
+    let origin = start_sequence_origin(vec![FRESH, b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"]).await;
+    let (proxy, stats) = common::start_proxy(stale_config()).await;
+    let request = format!("GET http://{}/page HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert_eq!(response.as_bytes(), FRESH);
+
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("\r\nWarning: 110 - \"Response is Stale\"\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nfresh"));
+    assert_eq!(stats.stale_responses.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_stale_served_on_connect_failure() {
+    let origin = start_sequence_origin(vec![FRESH]).await;
+    let (proxy, stats) = common::start_proxy(stale_config()).await;
+    let request = format!("GET http://{}/page HTTP/1.1\r\n\r\n", origin);
+
+    assert_eq!(common::send_request(proxy, request.as_bytes()).await.as_bytes(), FRESH);
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.contains("Warning: 110"), "{}", response);
+    assert!(response.ends_with("fresh"));
+
+    // Requests that were never eligible still see the failure
+    let other = format!("GET http://{}/other HTTP/1.1\r\n\r\n", origin);
+    assert!(common::send_request(proxy, other.as_bytes()).await.starts_with("HTTP/1.1 502"));
+    let personal = format!("GET http://{}/page HTTP/1.1\r\nCookie: a=b\r\n\r\n", origin);
+    assert!(common::send_request(proxy, personal.as_bytes()).await.starts_with("HTTP/1.1 502"));
+    assert_eq!(stats.stale_responses.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_error_responses_not_remembered() {
+    let origin = start_sequence_origin(vec![
+        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+    ])
+    .await;
+    let (proxy, _stats) = common::start_proxy(stale_config()).await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+
+    assert!(common::send_request(proxy, request.as_bytes()).await.starts_with("HTTP/1.1 404"));
+    // Nothing to fall back on, so the 500 is relayed as is
+    assert!(common::send_request(proxy, request.as_bytes()).await.starts_with("HTTP/1.1 500"));
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use rust_proxy::events::EventBus;
+use rust_proxy::syslog::{forward_access_log, format_message, Facility, SyslogTarget, SEVERITY_INFO};
+use rust_proxy::ProxyConfig;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::net::{TcpListener, UdpSocket};
+
+#[test]
+fn test_rfc5424_message_format() {
+    // 2024-02-29T12:34:56.789Z
+    let timestamp = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+    let message = format_message(Facility::Local0, SEVERITY_INFO, timestamp, "access", "{}");
+    let expected = format!("<134>1 2024-02-29T12:34:56.789Z - rust_proxy {} access - {{}}", std::process::id());
+    assert_eq!(message, expected);
+
+    let message = format_message(Facility::Daemon, 3, UNIX_EPOCH, "access", "x");
+    assert!(message.starts_with("<27>1 1970-01-01T00:00:00.000Z "), "{}", message);
+}
+
+#[test]
+fn test_syslog_target_parsing() {
+    assert_eq!("127.0.0.1:514".parse(), Ok(SyslogTarget::Udp("127.0.0.1:514".to_string())));
+    assert_eq!("udp://logs:514".parse(), Ok(SyslogTarget::Udp("logs:514".to_string())));
+    assert_eq!("tcp://[::1]:601".parse(), Ok(SyslogTarget::Tcp("[::1]:601".to_string())));
+    assert!("http://logs:514".parse::<SyslogTarget>().is_err());
+    assert!("logs".parse::<SyslogTarget>().is_err());
+}
+
+#[tokio::test]
+async fn test_proxied_request_sent_to_udp_syslog() {
+    let collector = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = SyslogTarget::Udp(collector.local_addr().unwrap().to_string());
+    let bus = EventBus::default();
+    tokio::spawn(forward_access_log(target, Facility::Local3, bus.subscribe()));
+
+    let (origin, _rx) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let (proxy, _stats) = common::start_proxy(ProxyConfig { events: Some(bus), ..Default::default() }).await;
+    let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin);
+    common::send_request(proxy, request.as_bytes()).await;
+
+    let mut datagram = [0; 2048];
+    let n = tokio::time::timeout(Duration::from_secs(5), collector.recv(&mut datagram)).await.unwrap().unwrap();
+    let message = String::from_utf8_lossy(&datagram[..n]).to_string();
+    // local3 (19) * 8 + informational (6)
+    assert!(message.starts_with("<158>1 "), "{}", message);
+    let (header, msg) = message.split_once(" - {").unwrap();
+    assert!(header.ends_with(" access"), "{}", message);
+    let event: serde_json::Value = serde_json::from_str(&format!("{{{}", msg)).unwrap();
+    assert_eq!(event["event"], "closed");
+    assert_eq!(event["target"], origin.to_string());
+}
+
+#[tokio::test]
+async fn test_tcp_syslog_uses_octet_counting() {
+    use tokio::io::AsyncReadExt;
+
+    let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = SyslogTarget::Tcp(collector.local_addr().unwrap().to_string());
+    let bus = EventBus::default();
+    tokio::spawn(forward_access_log(target, Facility::Local0, bus.subscribe()));
+
+    let (proxy, _stats) = common::start_proxy(ProxyConfig { events: Some(bus), ..Default::default() }).await;
+    common::send_request(proxy, b"Invalid request\r\n\r\n").await;
+
+    let (mut stream, _) = tokio::time::timeout(Duration::from_secs(5), collector.accept()).await.unwrap().unwrap();
+    let mut received = vec![0; 2048];
+    let n = stream.read(&mut received).await.unwrap();
+    let received = String::from_utf8_lossy(&received[..n]).to_string();
+    let (length, message) = received.split_once(' ').unwrap();
+    assert_eq!(length.parse::<usize>().unwrap(), message.len());
+    assert!(message.starts_with("<134>1 "), "{}", message);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use rust_proxy::tls::{handle_tls_client, load_acceptor};
+use rust_proxy::{ProxyConfig, ProxyStats};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+// Start a TLS-terminating proxy with a freshly generated self-signed cert,
+// returning its address and the certificate clients should trust
+async fn start_tls_proxy() -> (SocketAddr, Arc<ProxyStats>, CertificateDer<'static>, tempfile::TempDir) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_path = dir.path().join("cert.pem");
+    let key_path = dir.path().join("key.pem");
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+    let acceptor = load_acceptor(&cert_path, &key_path).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = Arc::new(ProxyStats::new());
+    let config = Arc::new(ProxyConfig { header_read_timeout: Duration::from_secs(2), ..Default::default() });
+
+    let server_stats = stats.clone();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let stats = server_stats.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let _ = handle_tls_client(socket, acceptor, stats, config).await;
+            });
+        }
+    });
+    (addr, stats, certified.cert.der().clone(), dir)
+}
+
+#[tokio::test]
+async fn test_http_request_over_tls_proxy_endpoint() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let (proxy, stats, cert, _dir) = start_tls_proxy().await;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let client_config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(client_config));
+
+    let socket = TcpStream::connect(proxy).await.unwrap();
+    let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), socket).await.unwrap();
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = vec![0; 64];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut response)).await.unwrap().unwrap();
+    let response = String::from_utf8_lossy(&response[..n]);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("ok"));
+
+    let received = requests.recv().await.unwrap();
+    assert!(received.starts_with("GET http://"));
+    assert_eq!(stats.http_requests.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.tls_handshake_errors.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_plaintext_client_counts_handshake_error() {
+    let (proxy, stats, _cert, _dir) = start_tls_proxy().await;
+
+    // A plain HTTP request is not a TLS ClientHello; the proxy must close the
+    // connection without ever treating it as a proxy request
+    let response = common::send_request(proxy, b"GET http://example.com/ HTTP/1.1\r\n\r\n").await;
+    assert!(!response.contains("HTTP/1.1"));
+    assert_eq!(stats.tls_handshake_errors.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.tcp_connections_accepted.load(Ordering::Relaxed), 0);
+}
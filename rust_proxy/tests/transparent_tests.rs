@@ -0,0 +1,223 @@
+// `--transparent` needs an iptables REDIRECT rule (and so root) to see a
+// redirected connection end to end; to try it by hand, run the proxy with
+// `--transparent --port 3128` and add
+//
+//     iptables -t nat -A OUTPUT -p tcp --dport 80 -m owner ! --uid-owner <proxy user> \
+//         -j REDIRECT --to-ports 3128
+//
+// then `curl http://example.com/` as another user. These tests cover what
+// runs without that: reading the option's answer, connections that were
+// not redirected, and the policy checks on a redirected connection, fed to
+// `tunnel_transparent` directly with an in-memory target.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use async_trait::async_trait;
+use rust_proxy::circuit_breaker::CircuitBreaker;
+use rust_proxy::dest_limit::DestinationLimiter;
+use rust_proxy::dialer::{AsyncReadWrite, BoxedStream, UpstreamDialer};
+use rust_proxy::events::ConnectionEvents;
+use rust_proxy::rate_limit::RateLimiter;
+use rust_proxy::transparent::{original_dst, redirected_target};
+use rust_proxy::{handle_client, tunnel_transparent, ProxyConfig, ProxyStats};
+use socket2::SockAddr;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+// The redirected destination: echoes the first read back and closes, except
+// on port 81, where it refuses connections
+#[derive(Debug, Default)]
+struct FakeTarget {
+    dialed: Mutex<Vec<u16>>,
+}
+
+#[async_trait]
+impl UpstreamDialer for FakeTarget {
+    async fn dial(&self, _host: &str, port: u16) -> io::Result<BoxedStream> {
+        self.dialed.lock().unwrap().push(port);
+        if port == 81 {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "fake target refused"));
+        }
+        let (proxy_side, mut target_side) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+            let n = target_side.read(&mut buffer).await.unwrap_or(0);
+            let _ = target_side.write_all(&buffer[..n]).await;
+        });
+        Ok(Box::new(proxy_side))
+    }
+}
+
+// Send `hello` over a connection redirected to `target` and return what
+// came back: the echo if it was tunneled, nothing if it was refused
+async fn redirect(config: &ProxyConfig, stats: &Arc<ProxyStats>, target: &str) -> Vec<u8> {
+    let (mut client, proxy_side) = tokio::io::duplex(1024);
+    let client_addr: SocketAddr = "192.0.2.10:50000".parse().unwrap();
+    let mut events = ConnectionEvents::new(None, client_addr.to_string());
+    let tunnel = tunnel_transparent(1, proxy_side, client_addr, target.parse().unwrap(), config, stats, &mut events);
+    let exchange = async {
+        // A refused connection may already be gone
+        let _ = client.write_all(b"hello").await;
+        let _ = client.shutdown().await;
+        let mut reply = Vec::new();
+        let _ = client.read_to_end(&mut reply).await;
+        reply
+    };
+    let (result, reply) = tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(tunnel, exchange) }).await.unwrap();
+    result.unwrap();
+    reply
+}
+
+#[test]
+fn test_redirected_target_from_original_dst() {
+    let local: SocketAddr = "127.0.0.1:3128".parse().unwrap();
+    let original: SocketAddr = "93.184.216.34:80".parse().unwrap();
+    assert_eq!(redirected_target(&SockAddr::from(original), local), Some(original));
+
+    // Our own address means nothing was translated
+    assert_eq!(redirected_target(&SockAddr::from(local), local), None);
+    let mapped: SocketAddr = "[::ffff:127.0.0.1]:3128".parse().unwrap();
+    assert_eq!(redirected_target(&SockAddr::from(mapped), local), None);
+
+    let original_v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    assert_eq!(redirected_target(&SockAddr::from(original_v6), "[::1]:3128".parse().unwrap()), Some(original_v6));
+    assert_eq!(redirected_target(&SockAddr::unix("/tmp/proxy.sock").unwrap(), local), None);
+}
+
+#[tokio::test]
+async fn test_direct_connection_not_redirected() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    // Without conntrack the option fails outright; either way there is no target
+    assert!(!matches!(original_dst(&accepted), Ok(Some(_))));
+}
+
+#[tokio::test]
+async fn test_transparent_listener_still_serves_proxy_requests() {
+    let (origin, mut requests) = common::start_recording_origin(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let (proxy, stats) = common::start_proxy(ProxyConfig { transparent: true, ..Default::default() }).await;
+
+    let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n", origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("ok"), "{}", response);
+    assert!(requests.recv().await.unwrap().contains(&format!("Host: {}\r\n", origin)));
+    assert_eq!(stats.snapshot().transparent_connections, 0);
+}
+
+#[tokio::test]
+async fn test_redirected_connection_tunneled_to_target() {
+    let target = Arc::new(FakeTarget::default());
+    let config = ProxyConfig { transparent: true, dialer: target.clone(), ..Default::default() };
+    let stats = Arc::new(ProxyStats::new());
+
+    assert_eq!(redirect(&config, &stats, "198.51.100.1:80").await, b"hello");
+    assert_eq!(*target.dialed.lock().unwrap(), [80]);
+    assert_eq!(stats.transparent_connections.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_redirected_connection_refused_by_policy() {
+    let target = Arc::new(FakeTarget::default());
+    let base = ProxyConfig { transparent: true, dialer: target.clone(), ..Default::default() };
+
+    // Maintenance mode
+    let config = ProxyConfig { maintenance: Arc::new(AtomicBool::new(true)), ..base.clone() };
+    let stats = Arc::new(ProxyStats::new());
+    assert!(redirect(&config, &stats, "198.51.100.1:80").await.is_empty());
+    assert_eq!(stats.maintenance_rejections.load(Ordering::Relaxed), 1);
+
+    // --rate-per-ip: the second connection from the client is over it
+    let config = ProxyConfig { rate_limiter: Some(Arc::new(RateLimiter::new(1.0, 16))), ..base.clone() };
+    let stats = Arc::new(ProxyStats::new());
+    assert_eq!(redirect(&config, &stats, "198.51.100.1:80").await, b"hello");
+    assert!(redirect(&config, &stats, "198.51.100.1:80").await.is_empty());
+    assert_eq!(stats.rate_limited.load(Ordering::Relaxed), 1);
+
+    // A port outside --connect-port-min/--connect-port-max
+    let config = ProxyConfig { connect_ports: 443..=443, ..base.clone() };
+    let stats = Arc::new(ProxyStats::new());
+    assert!(redirect(&config, &stats, "198.51.100.1:80").await.is_empty());
+
+    // --max-per-destination, with the only slot already taken
+    let limiter = Arc::new(DestinationLimiter::new(1, Duration::from_millis(50), 16));
+    let _held = limiter.acquire("198.51.100.1:80").await.unwrap();
+    let config = ProxyConfig { destination_limiter: Some(limiter), ..base.clone() };
+    let stats = Arc::new(ProxyStats::new());
+    assert!(redirect(&config, &stats, "198.51.100.1:80").await.is_empty());
+    assert_eq!(stats.dest_overload.load(Ordering::Relaxed), 1);
+
+    // None of those reached the target
+    assert_eq!(*target.dialed.lock().unwrap(), [80]);
+
+    // The circuit breaker opens on a failed connect and then refuses
+    // without dialing
+    let breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_secs(60), 16));
+    let config = ProxyConfig { circuit_breaker: Some(breaker), ..base.clone() };
+    let stats = Arc::new(ProxyStats::new());
+    assert!(redirect(&config, &stats, "198.51.100.1:81").await.is_empty());
+    assert!(redirect(&config, &stats, "198.51.100.1:81").await.is_empty());
+    assert_eq!(stats.circuit_open_rejections.load(Ordering::Relaxed), 1);
+    assert_eq!(*target.dialed.lock().unwrap(), [80, 81]);
+}
+
+// A client connection whose original destination can't be read, as on a
+// host where the conntrack lookup itself fails
+struct UnknownOriginalDst(DuplexStream);
+
+impl AsyncRead for UnknownOriginalDst {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnknownOriginalDst {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl AsyncReadWrite for UnknownOriginalDst {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok("192.0.2.10:50000".parse().unwrap())
+    }
+
+    fn original_dst(&self) -> io::Result<Option<SocketAddr>> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "fake conntrack failure"))
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_original_destination_fails_closed() {
+    for fail_closed in [false, true] {
+        let config = Arc::new(ProxyConfig { transparent: true, fail_closed, ..Default::default() });
+        let stats = Arc::new(ProxyStats::new());
+        let (mut client, proxy_side) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(handle_client(UnknownOriginalDst(proxy_side), stats.clone(), config));
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_string(&mut response)).await.unwrap().unwrap();
+        handler.await.unwrap().unwrap();
+
+        // Failing open serves it as an ordinary proxy client, which answers
+        // this origin-form request with a 400
+        assert_eq!(response.is_empty(), fail_closed, "{}", response);
+        assert_eq!(stats.fail_closed_denied.load(Ordering::Relaxed), u64::from(fail_closed));
+    }
+}
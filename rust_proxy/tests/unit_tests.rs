@@ -1,7 +1,9 @@
-use rust_proxy::{find_request_end, parse_host_port, bounded_copy, ProxyStats, ProxyError, Args};
+use rust_proxy::{find_request_end, parse_host_port, normalize_peer_addr, bounded_copy, Direction, ProxyStats, ProxyError, Args};
+use rust_proxy::banner::EffectiveConfig;
+use rust_proxy::error::CloseReason;
 use std::sync::Arc;
 use std::time::Duration;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use tokio::io::AsyncWriteExt;
 
 #[test]
@@ -25,6 +27,18 @@ fn test_find_request_end() {
     let data = b"GET / HTTP/1.1\r\nHost: example.com\n\r\n";
     let result = find_request_end(data);
     assert_eq!(result, data.len());
+
+    // Large header block with lone `\r`s and `\r\n\r` near misses along the way
+    let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+    for i in 0..500 {
+        data.extend_from_slice(format!("X-Header-{}: a\rb\r\n", i).as_bytes());
+    }
+    data.extend_from_slice(b"X-Last: \r\n\rx\r\n\r\n");
+    let end = data.len();
+    data.extend_from_slice(b"body\r\n\r\n");
+    assert_eq!(find_request_end(&data), end);
+    assert_eq!(find_request_end(&data[..end - 1]), end - 1);
+    assert_eq!(find_request_end(&data[..end - 4]), end - 4);
 }
 
 #[test]
@@ -50,6 +64,27 @@ fn test_parse_host_port() {
     assert_eq!(port, 80);
 }
 
+#[test]
+fn test_normalize_peer_addr() {
+    use std::net::{SocketAddr, SocketAddrV6};
+
+    let normalized = |addr: SocketAddr| normalize_peer_addr(addr).to_string();
+
+    // IPv4-mapped IPv6 unwraps to plain IPv4
+    assert_eq!(normalized("[::ffff:127.0.0.1]:5000".parse().unwrap()), "127.0.0.1:5000");
+    assert!(normalize_peer_addr("[::ffff:10.1.2.3]:80".parse().unwrap()).is_ipv4());
+
+    // Plain IPv6 and IPv4 are unchanged
+    assert_eq!(normalized("[2001:db8::1]:443".parse().unwrap()), "[2001:db8::1]:443");
+    assert_eq!(normalized("[::1]:8080".parse().unwrap()), "[::1]:8080");
+    assert_eq!(normalized("192.168.0.10:3128".parse().unwrap()), "192.168.0.10:3128");
+
+    // Scoped addresses keep their scope; the flow label is dropped
+    let scoped = SocketAddrV6::new("fe80::1".parse().unwrap(), 3128, 0x1234, 2);
+    assert_eq!(normalized(scoped.into()), "[fe80::1%2]:3128");
+    assert_eq!(normalize_peer_addr(scoped.into()), SocketAddrV6::new("fe80::1".parse().unwrap(), 3128, 0, 2).into());
+}
+
 #[tokio::test]
 async fn test_bounded_copy_basic() {
     // Create a pipe to test bounded_copy
@@ -133,13 +168,13 @@ async fn test_connect_request_parsing() {
 #[test]
 fn test_args_parsing() {
     // Test default arguments
-    let args = Args::try_parse_from(&["rust_proxy"]).unwrap();
+    let args = Args::try_parse_from(["rust_proxy"]).unwrap();
     assert_eq!(args.host, "0.0.0.0");
     assert_eq!(args.port, 3129);
     assert_eq!(args.log_level, "info");
 
     // Test custom arguments
-    let args = Args::try_parse_from(&[
+    let args = Args::try_parse_from([
         "rust_proxy",
         "--host", "127.0.0.1",
         "--port", "8080",
@@ -150,7 +185,7 @@ fn test_args_parsing() {
     assert_eq!(args.log_level, "debug");
 
     // Test long arguments only for host (no short for host due to conflict with help)
-    let args = Args::try_parse_from(&[
+    let args = Args::try_parse_from([
         "rust_proxy",
         "--host", "192.168.1.1",
         "-p", "9000",
@@ -161,11 +196,33 @@ fn test_args_parsing() {
     assert_eq!(args.log_level, "warn");
 }
 
+#[test]
+fn test_effective_config_banner() {
+    let matches = Args::command()
+        .try_get_matches_from(["rust_proxy", "--port", "3999", "--log-level", "debug", "--auth", "user:secret", "--inspect-sni"])
+        .unwrap();
+    let effective = EffectiveConfig::from_matches(&Args::command(), &matches);
+    let banner = effective.render();
+
+    assert!(banner.contains("--port 3999 [command line]"), "{}", banner);
+    assert!(banner.contains("--log-level debug [command line]"), "{}", banner);
+    assert!(banner.contains("--host 0.0.0.0 [default]"), "{}", banner);
+    assert!(banner.contains("--auth <redacted> [command line]") && !banner.contains("secret"), "{}", banner);
+    assert!(banner.contains("Enabled features: --inspect-sni"), "{}", banner);
+    // Explicit settings are listed ahead of defaults
+    assert!(banner.find("--port").unwrap() < banner.find("--host").unwrap());
+
+    let json = effective.to_json();
+    assert_eq!(json["--port"]["value"], "3999");
+    assert_eq!(json["--log-level"]["source"], "command line");
+    assert_eq!(json["--auth"]["value"], "<redacted>");
+}
+
 #[test]
 fn test_log_level_parsing() {
     // Test valid log levels
     for level in ["debug", "info", "warn", "error"] {
-        let args = Args::try_parse_from(&[
+        let args = Args::try_parse_from([
             "rust_proxy",
             "--log-level", level
         ]).unwrap();
@@ -173,7 +230,7 @@ fn test_log_level_parsing() {
     }
 
     // Test custom host with default log level
-    let args = Args::try_parse_from(&[
+    let args = Args::try_parse_from([
         "rust_proxy",
         "--host", "localhost"
     ]).unwrap();
@@ -181,7 +238,7 @@ fn test_log_level_parsing() {
     assert_eq!(args.log_level, "info");
 
     // Test custom port with default log level
-    let args = Args::try_parse_from(&[
+    let args = Args::try_parse_from([
         "rust_proxy",
         "--port", "1234"
     ]).unwrap();
@@ -196,7 +253,7 @@ fn test_proxy_stats_creation() {
     let stats = ProxyStats::new();
     
     // Test initial values
-    assert_eq!(stats.total_connections.load(std::sync::atomic::Ordering::Relaxed), 0);
+    assert_eq!(stats.tcp_connections_accepted.load(std::sync::atomic::Ordering::Relaxed), 0);
     assert_eq!(stats.active_connections.load(std::sync::atomic::Ordering::Relaxed), 0);
     assert_eq!(stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed), 0);
     assert_eq!(stats.http_requests.load(std::sync::atomic::Ordering::Relaxed), 0);
@@ -213,10 +270,10 @@ fn test_proxy_stats_counters() {
     let stats = ProxyStats::new();
     
     // Test connection counters
-    stats.total_connections.fetch_add(5, std::sync::atomic::Ordering::Relaxed);
+    stats.tcp_connections_accepted.fetch_add(5, std::sync::atomic::Ordering::Relaxed);
     stats.active_connections.fetch_add(2, std::sync::atomic::Ordering::Relaxed);
     
-    assert_eq!(stats.total_connections.load(std::sync::atomic::Ordering::Relaxed), 5);
+    assert_eq!(stats.tcp_connections_accepted.load(std::sync::atomic::Ordering::Relaxed), 5);
     assert_eq!(stats.active_connections.load(std::sync::atomic::Ordering::Relaxed), 2);
     
     // Test request counters
@@ -240,7 +297,7 @@ fn test_proxy_stats_log_format() {
     let stats = ProxyStats::new();
     
     // Add some test data
-    stats.total_connections.store(100, std::sync::atomic::Ordering::Relaxed);
+    stats.tcp_connections_accepted.store(100, std::sync::atomic::Ordering::Relaxed);
     stats.active_connections.store(5, std::sync::atomic::Ordering::Relaxed);
     stats.bytes_transferred.store(1048576, std::sync::atomic::Ordering::Relaxed); // 1MB
     stats.http_requests.store(60, std::sync::atomic::Ordering::Relaxed);
@@ -252,11 +309,70 @@ fn test_proxy_stats_log_format() {
     stats.log_stats();
     
     // Verify the data is still correct after logging
-    assert_eq!(stats.total_connections.load(std::sync::atomic::Ordering::Relaxed), 100);
+    assert_eq!(stats.tcp_connections_accepted.load(std::sync::atomic::Ordering::Relaxed), 100);
     assert_eq!(stats.active_connections.load(std::sync::atomic::Ordering::Relaxed), 5);
     assert_eq!(stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed), 1048576);
 }
 
+#[test]
+fn test_proxy_stats_snapshot_immediately_after_creation() {
+    let stats = ProxyStats::new();
+    let snapshot = stats.snapshot();
+
+    assert_eq!(snapshot.total_connections, 0);
+    assert_eq!(snapshot.bytes_transferred, 0);
+
+    // Derived values must be finite even with ~zero uptime and no connections
+    for value in [
+        snapshot.megabytes_transferred(),
+        snapshot.bytes_per_second(),
+        snapshot.avg_bytes_per_connection(),
+        snapshot.error_rate(),
+    ] {
+        assert!(value.is_finite());
+        assert_eq!(value, 0.0);
+    }
+
+    // Same guarantee for a zero-duration snapshot that has seen traffic
+    let mut snapshot = snapshot;
+    snapshot.bytes_transferred = 4096;
+    snapshot.uptime = Duration::ZERO;
+    snapshot.period = Duration::ZERO;
+    assert_eq!(snapshot.bytes_per_second(), 0.0);
+    assert!(snapshot.avg_bytes_per_connection().is_finite());
+}
+
+#[test]
+fn test_proxy_stats_snapshot_values() {
+    let stats = ProxyStats::new();
+    stats.tcp_connections_accepted.store(4, std::sync::atomic::Ordering::Relaxed);
+    stats.bytes_transferred.store(2048, std::sync::atomic::Ordering::Relaxed);
+    stats.connection_errors.store(1, std::sync::atomic::Ordering::Relaxed);
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.total_connections, 4);
+    assert_eq!(snapshot.avg_bytes_per_connection(), 512.0);
+    assert_eq!(snapshot.error_rate(), 0.25);
+}
+
+#[test]
+fn test_throughput_since_previous_snapshot() {
+    let stats = ProxyStats::new();
+    stats.bytes_transferred.store(1_000_000, std::sync::atomic::Ordering::Relaxed);
+    let mut previous = stats.snapshot();
+    previous.uptime = Duration::from_secs(60);
+
+    // 3 MB more over the next 30 seconds, whatever came before
+    let mut current = previous;
+    current.bytes_transferred += 3_000_000;
+    current.uptime = Duration::from_secs(90);
+    assert_eq!(current.throughput_since(&previous), 100_000.0);
+
+    // No time elapsed, or counters that went backwards (a reset), stay finite
+    assert_eq!(previous.throughput_since(&previous), 0.0);
+    assert_eq!(previous.throughput_since(&current), 0.0);
+}
+
 #[tokio::test]
 async fn test_bounded_copy_with_stats() {
     use rust_proxy::bounded_copy_with_stats;
@@ -274,7 +390,7 @@ async fn test_bounded_copy_with_stats() {
     
     // Read back using bounded_copy_with_stats
     let mut output = Vec::new();
-    let result: Result<(), ProxyError> = bounded_copy_with_stats(
+    let outcome = bounded_copy_with_stats(
         &mut reader, 
         &mut output, 
         1024, 
@@ -282,11 +398,13 @@ async fn test_bounded_copy_with_stats() {
         Some("src"),
         Some("dst"),
         "test",
+        Direction::ServerToClient,
         stats.clone()
     ).await;
     
     // Verify success
-    assert!(result.is_ok());
+    assert_eq!(outcome.reason, CloseReason::Eof);
+    assert_eq!(outcome.bytes, test_data.len() as u64);
     assert_eq!(output, test_data);
     
     // Verify statistics were updated
@@ -298,7 +416,7 @@ async fn test_bounded_copy_with_stats() {
 async fn test_bounded_copy_with_stats_size_limit() {
     use rust_proxy::bounded_copy_with_stats;
     
-    let (mut reader, mut writer) = tokio::io::duplex(64);
+    let (mut reader, mut writer) = tokio::io::duplex(128);
     
     // Write data that exceeds limit
     let test_data = b"This is a very long string that exceeds the size limit for testing purposes";
@@ -309,7 +427,7 @@ async fn test_bounded_copy_with_stats_size_limit() {
     
     // Read with small limit
     let mut output = Vec::new();
-    let result: Result<(), ProxyError> = bounded_copy_with_stats(
+    let outcome = bounded_copy_with_stats(
         &mut reader, 
         &mut output, 
         10, 
@@ -317,12 +435,13 @@ async fn test_bounded_copy_with_stats_size_limit() {
         None,
         None,
         "test",
+        Direction::ServerToClient,
         stats.clone()
     ).await;
     
-    // Should fail due to size limit
-    assert!(result.is_err());
-    assert!(result.unwrap_err().to_string().contains("size limit exceeded"));
+    // Should stop at the size limit
+    assert_eq!(outcome.reason, CloseReason::SizeLimit);
+    assert_eq!(outcome.bytes, 10);
     
     // Some bytes should have been tracked before hitting limit
     let bytes_transferred = stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed);
@@ -342,7 +461,7 @@ async fn test_bounded_copy_with_stats_timeout() {
     // Don't write anything to simulate timeout scenario
     drop(writer);
     
-    let result: Result<(), ProxyError> = bounded_copy_with_stats(
+    let outcome = bounded_copy_with_stats(
         reader, 
         &mut output, 
         1024, 
@@ -350,11 +469,12 @@ async fn test_bounded_copy_with_stats_timeout() {
         None,
         None,
         "timeout_test",
+        Direction::ServerToClient,
         stats.clone()
     ).await;
     
-    // Should not fail due to timeout since we dropped the writer (EOF)
-    assert!(result.is_ok());
+    // Should not time out since we dropped the writer (EOF)
+    assert_eq!(outcome.reason, CloseReason::Eof);
     
     // No bytes should have been transferred
     let bytes_transferred = stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed);
@@ -373,7 +493,7 @@ fn test_stats_concurrent_access() {
         let stats_clone = stats.clone();
         let handle = thread::spawn(move || {
             for j in 0..100 {
-                stats_clone.total_connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                stats_clone.tcp_connections_accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 stats_clone.bytes_transferred.fetch_add((i * 100 + j) as u64, std::sync::atomic::Ordering::Relaxed);
                 stats_clone.http_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 stats_clone.https_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -388,11 +508,389 @@ fn test_stats_concurrent_access() {
     }
     
     // Verify final counts
-    assert_eq!(stats.total_connections.load(std::sync::atomic::Ordering::Relaxed), 1000);
+    assert_eq!(stats.tcp_connections_accepted.load(std::sync::atomic::Ordering::Relaxed), 1000);
     assert_eq!(stats.http_requests.load(std::sync::atomic::Ordering::Relaxed), 1000);
     assert_eq!(stats.https_requests.load(std::sync::atomic::Ordering::Relaxed), 1000);
     
     // Bytes should be sum of all additions
     let expected_bytes: u64 = (0..10).flat_map(|i| (0..100).map(move |j| (i * 100 + j) as u64)).sum();
     assert_eq!(stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed), expected_bytes);
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_tunnel_fast_between_duplex_pipes() {
+    use rust_proxy::{tunnel_fast, ByteCounters, HostStats};
+    use tokio::io::AsyncReadExt;
+
+    let (mut client, proxy_client_side) = tokio::io::duplex(64);
+    let (proxy_server_side, mut server) = tokio::io::duplex(64);
+    let stats = Arc::new(ProxyStats::new());
+
+    let tunnel_stats = stats.clone();
+    let tunnel = tokio::spawn(async move {
+        let host = HostStats::default();
+        let counters = ByteCounters { host: Some(&host), ..Default::default() };
+        let outcome = tunnel_fast(1, proxy_client_side, proxy_server_side, None, None, tunnel_stats, counters, Default::default()).await;
+        (outcome, host.bytes.load(std::sync::atomic::Ordering::Relaxed))
+    });
+
+    let mut buffer = [0; 4];
+    client.write_all(b"ping").await.unwrap();
+    server.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+    server.write_all(b"pong").await.unwrap();
+    client.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"pong");
+
+    // Both ends closing finishes both directions of the tunnel
+    drop(client);
+    drop(server);
+    let (outcome, host_bytes) = tokio::time::timeout(Duration::from_secs(1), tunnel).await.unwrap().unwrap();
+    assert_eq!(outcome.reason, CloseReason::Eof);
+    assert_eq!(host_bytes, 8);
+    assert_eq!(stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed), 8);
+}
+
+#[tokio::test]
+async fn test_tunnel_fast_returns_bytes_per_direction() {
+    use rust_proxy::{tunnel_fast, ByteCounters};
+    use tokio::io::AsyncReadExt;
+
+    let (mut client, proxy_client_side) = tokio::io::duplex(64);
+    let (proxy_server_side, mut server) = tokio::io::duplex(64);
+    let stats = Arc::new(ProxyStats::new());
+    let tunnel = tokio::spawn(tunnel_fast(1, proxy_client_side, proxy_server_side, None, None, stats.clone(), ByteCounters::default(), Default::default()));
+
+    let request = b"GET / HTTP/1.1\r\n\r\n";
+    let response = b"HTTP/1.1 204 No Content\r\n\r\n";
+    client.write_all(request).await.unwrap();
+    let mut received = vec![0; request.len()];
+    server.read_exact(&mut received).await.unwrap();
+    server.write_all(response).await.unwrap();
+    let mut received = vec![0; response.len()];
+    client.read_exact(&mut received).await.unwrap();
+
+    drop(client);
+    drop(server);
+    let outcome = tokio::time::timeout(Duration::from_secs(1), tunnel).await.unwrap().unwrap();
+    assert_eq!((outcome.sent, outcome.received), (request.len() as u64, response.len() as u64));
+    // The global counter still sees both directions
+    assert_eq!(stats.bytes_transferred.load(std::sync::atomic::Ordering::Relaxed), outcome.sent + outcome.received);
+}
+
+#[tokio::test]
+async fn test_upload_and_download_caps_are_independent() {
+    use rust_proxy::{tunnel_fast, ByteCounters, CopyLimits, ProxyConfig};
+    use tokio::io::AsyncReadExt;
+
+    let args = Args::parse_from(["rust_proxy", "--max-download-size", "4096", "--max-upload-size", "0"]);
+    let limits = ProxyConfig::from_args(&args).copy_limits();
+    assert_eq!((limits.max_download_size, limits.max_upload_size), (4096, u64::MAX));
+    let limits = CopyLimits { idle_timeout: Duration::from_secs(1), ..limits };
+
+    let (mut client, proxy_client_side) = tokio::io::duplex(64 * 1024);
+    let (proxy_server_side, mut server) = tokio::io::duplex(64 * 1024);
+    let stats = Arc::new(ProxyStats::new());
+    let tunnel = tokio::spawn(tunnel_fast(1, proxy_client_side, proxy_server_side, None, None, stats, ByteCounters::default(), limits));
+
+    // A large upload goes through whole
+    let upload = vec![1u8; 32 * 1024];
+    client.write_all(&upload).await.unwrap();
+    let mut received = vec![0; upload.len()];
+    server.read_exact(&mut received).await.unwrap();
+
+    // A large download is cut at the cap
+    server.write_all(&[2u8; 8192]).await.unwrap();
+    let outcome = tokio::time::timeout(Duration::from_secs(1), tunnel).await.unwrap().unwrap();
+    assert_eq!(outcome.reason, CloseReason::SizeLimit);
+    let mut downloaded = Vec::new();
+    client.read_to_end(&mut downloaded).await.unwrap();
+    assert_eq!(downloaded.len(), 4096);
+}
+
+#[tokio::test]
+async fn test_byte_counters_split_by_direction() {
+    use rust_proxy::{tunnel_fast, ByteCounters};
+    use std::sync::atomic::Ordering;
+    use tokio::io::AsyncReadExt;
+
+    // A small upload answered by a much larger download
+    let (mut client, proxy_client_side) = tokio::io::duplex(1024);
+    let (proxy_server_side, mut server) = tokio::io::duplex(1024);
+    let stats = Arc::new(ProxyStats::new());
+    let tunnel = tokio::spawn(tunnel_fast(1, proxy_client_side, proxy_server_side, None, None, stats.clone(), ByteCounters::default(), Default::default()));
+
+    let upload = vec![1u8; 100];
+    let download = vec![2u8; 10_000];
+    client.write_all(&upload).await.unwrap();
+    let mut received = vec![0; upload.len()];
+    server.read_exact(&mut received).await.unwrap();
+    let writer = tokio::spawn(async move {
+        server.write_all(&download).await.unwrap();
+        server
+    });
+    let mut received = vec![0; 10_000];
+    client.read_exact(&mut received).await.unwrap();
+
+    drop(client);
+    drop(writer.await.unwrap());
+    tokio::time::timeout(Duration::from_secs(1), tunnel).await.unwrap().unwrap();
+    assert_eq!(stats.bytes_client_to_server.load(Ordering::Relaxed), 100);
+    assert_eq!(stats.bytes_server_to_client.load(Ordering::Relaxed), 10_000);
+    assert_eq!(stats.bytes_transferred.load(Ordering::Relaxed), 10_100);
+    assert_eq!(stats.snapshot().download_upload_ratio(), 100.0);
+}
+
+#[tokio::test]
+async fn test_write_timeout_allows_slow_but_progressing_reader() {
+    use rust_proxy::{bounded_copy_with_counters, ByteCounters, CopyLimits};
+    use tokio::io::AsyncReadExt;
+
+    let data = vec![7u8; 16 * 1024];
+    let limits = CopyLimits {
+        max_download_size: 1024 * 1024,
+        idle_timeout: Duration::from_secs(5),
+        write_timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+
+    // Drains 1KB every 20ms: far slower overall than the write timeout, but
+    // never stalls for longer than it
+    let (writer, mut slow_reader) = tokio::io::duplex(1024);
+    let drain = tokio::spawn(async move {
+        let mut received = 0;
+        let mut buffer = [0; 1024];
+        loop {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            match slow_reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break received,
+                Ok(n) => received += n,
+            }
+        }
+    });
+    let stats = Arc::new(ProxyStats::new());
+    let result = bounded_copy_with_counters(
+        &data[..], writer, limits, "slow", Direction::ServerToClient, stats.clone(), ByteCounters::default()
+    ).await;
+    assert!(result.is_ok());
+    assert_eq!(drain.await.unwrap(), data.len());
+
+    // A reader that never drains stalls the write and times out
+    let (writer, _stalled_reader) = tokio::io::duplex(1024);
+    let started = std::time::Instant::now();
+    let result = bounded_copy_with_counters(
+        &data[..], writer, limits, "stalled", Direction::ServerToClient, stats, ByteCounters::default()
+    ).await;
+    assert_eq!(result.unwrap_err().to_string(), "Write timeout");
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_bounded_copy_reports_close_reasons() {
+    use rust_proxy::bounded_copy_with_stats;
+    use rust_proxy::error::ProxyErrorKind;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    struct FailingReader;
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    let stats = Arc::new(ProxyStats::new());
+    let copy = |reader: Box<dyn AsyncRead + Unpin>, writer: Box<dyn AsyncWrite + Unpin>, max_size, idle_ms| {
+        bounded_copy_with_stats(reader, writer, max_size, Duration::from_millis(idle_ms), None, None, "test", Direction::ServerToClient, stats.clone())
+    };
+
+    // Normal end of stream
+    let data = [1u8; 32];
+    let outcome = copy(Box::new(&data[..]), Box::new(tokio::io::sink()), 1024, 1000).await;
+    assert_eq!((outcome.bytes, outcome.reason), (32, CloseReason::Eof));
+
+    // Size limit
+    let outcome = copy(Box::new(&data[..]), Box::new(tokio::io::sink()), 10, 1000).await;
+    assert_eq!((outcome.bytes, outcome.reason), (10, CloseReason::SizeLimit));
+
+    // Idle timeout: the writer side stays open but never sends anything
+    let (reader, _writer) = tokio::io::duplex(64);
+    let outcome = copy(Box::new(reader), Box::new(tokio::io::sink()), 1024, 20).await;
+    assert_eq!(outcome.reason, CloseReason::IdleTimeout);
+
+    // Write timeout: the destination never drains
+    let (writer, _stalled) = tokio::io::duplex(16);
+    let outcome = copy(Box::new(&data[..]), Box::new(writer), 1024, 20).await;
+    assert_eq!(outcome.reason, CloseReason::WriteTimeout);
+
+    // Write error: the destination is gone
+    let (writer, gone) = tokio::io::duplex(16);
+    drop(gone);
+    let outcome = copy(Box::new(&data[..]), Box::new(writer), 1024, 1000).await;
+    assert_eq!(outcome.reason, CloseReason::WriteError);
+
+    // Read error: the source fails
+    let outcome = copy(Box::new(FailingReader), Box::new(tokio::io::sink()), 1024, 1000).await;
+    assert_eq!(outcome.reason, CloseReason::ReadError);
+
+    // Every terminating kind has its reason; anything else is a plain error
+    let reason = |kind: ProxyErrorKind| CloseReason::of(&Err::<(), ProxyError>(kind.into()));
+    assert_eq!(reason(ProxyErrorKind::SlowTransfer), CloseReason::SlowTransfer);
+    assert_eq!(reason(ProxyErrorKind::WriteFailed), CloseReason::WriteError);
+    assert_eq!(reason(ProxyErrorKind::ConnectFailed), CloseReason::Error);
+    let io_error: ProxyError = std::io::Error::other("boom").into();
+    assert_eq!(ProxyErrorKind::of(&io_error), None);
+    assert_eq!(CloseReason::of(&Err::<(), ProxyError>(io_error)), CloseReason::Error);
+    assert_eq!(serde_json::to_string(&CloseReason::IdleTimeout).unwrap(), r#""idle_timeout""#);
+}
+
+#[test]
+fn test_ssrf_internal_ranges() {
+    use rust_proxy::ssrf::is_internal;
+
+    for ip in ["127.0.0.1", "10.0.0.1", "172.16.5.4", "192.168.1.1", "169.254.169.254", "0.0.0.0", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+        assert!(is_internal(ip.parse().unwrap()), "{} should be internal", ip);
+    }
+    for ip in ["8.8.8.8", "172.32.0.1", "2606:4700::1111", "::ffff:1.1.1.1"] {
+        assert!(!is_internal(ip.parse().unwrap()), "{} should be public", ip);
+    }
+}
+
+#[tokio::test]
+async fn test_bind_listener_with_backlog() {
+    let listener = rust_proxy::bind_listener("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (connected, accepted) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+    assert!(connected.is_ok());
+    assert!(accepted.is_ok());
+}
+
+#[test]
+fn test_listen_backlog_validation() {
+    let args = Args::try_parse_from(["rust_proxy"]).unwrap();
+    assert_eq!(args.listen_backlog, rust_proxy::DEFAULT_LISTEN_BACKLOG);
+    let args = Args::try_parse_from(["rust_proxy", "--listen-backlog", "4096"]).unwrap();
+    assert_eq!(args.listen_backlog, 4096);
+    assert!(Args::try_parse_from(["rust_proxy", "--listen-backlog", "0"]).is_err());
+    assert!(Args::try_parse_from(["rust_proxy", "--listen-backlog", "4294967295"]).is_err());
+}
+
+#[test]
+fn test_byte_counter_saturates() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let counter = AtomicU64::new(u64::MAX - 10);
+    rust_proxy::add_saturating(&counter, 5);
+    assert_eq!(counter.load(Ordering::Relaxed), u64::MAX - 5);
+    rust_proxy::add_saturating(&counter, 100);
+    assert_eq!(counter.load(Ordering::Relaxed), u64::MAX);
+    rust_proxy::add_saturating(&counter, 1);
+    assert_eq!(counter.load(Ordering::Relaxed), u64::MAX);
+}
+
+#[tokio::test]
+async fn test_copy_near_counter_limit_does_not_wrap() {
+    use std::sync::atomic::Ordering;
+
+    // Stats already at the edge; a copy with an effectively unlimited size
+    // cap must clamp rather than wrap (or panic with debug assertions)
+    let stats = Arc::new(ProxyStats::new());
+    stats.bytes_transferred.store(u64::MAX - 10, Ordering::Relaxed);
+    let (reader, mut writer) = tokio::io::duplex(1024);
+    writer.write_all(&[0; 100]).await.unwrap();
+    drop(writer);
+
+    let outcome = rust_proxy::bounded_copy_with_stats(
+        reader, tokio::io::sink(), u64::MAX, Duration::from_secs(1), None, None, "test", Direction::ServerToClient, stats.clone(),
+    ).await;
+    assert_eq!(outcome.reason, CloseReason::Eof);
+    assert_eq!(stats.bytes_transferred.load(Ordering::Relaxed), u64::MAX);
+
+    let result = bounded_copy(&[0u8; 100][..], tokio::io::sink(), u64::MAX, Duration::from_secs(1)).await;
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_host_matcher_patterns() {
+    use rust_proxy::host_match::HostMatcher;
+
+    let matcher = HostMatcher::new(["health.internal", "*.poll.example.com", "10.0.0.5", " "]);
+    assert!(matcher.matches("health.internal"));
+    assert!(matcher.matches("Health.Internal."));
+    assert!(!matcher.matches("api.health.internal"));
+    assert!(matcher.matches("a.poll.example.com"));
+    assert!(matcher.matches("a.b.poll.example.com"));
+    assert!(!matcher.matches("poll.example.com"));
+    assert!(!matcher.matches("xpoll.example.com"));
+    assert!(matcher.matches("10.0.0.5"));
+
+    let matcher = HostMatcher::new(["::1"]);
+    assert!(matcher.matches("[::1]"));
+    assert!(HostMatcher::new(Vec::<String>::new()).is_empty());
+}
+
+#[tokio::test]
+async fn test_min_throughput_aborts_trickling_transfer() {
+    use rust_proxy::error::ProxyErrorKind;
+    use rust_proxy::throughput::MinThroughput;
+    use rust_proxy::{bounded_copy_with_counters, ByteCounters, CopyLimits};
+
+    let limits = CopyLimits {
+        idle_timeout: Duration::from_secs(5),
+        min_throughput: Some(MinThroughput { bytes_per_sec: 1000, window: Duration::from_millis(200) }),
+        ..Default::default()
+    };
+    let stats = Arc::new(ProxyStats::new());
+
+    // A byte every 20ms never trips the idle timeout but is far below 1000 B/s
+    let (reader, mut trickle) = tokio::io::duplex(64);
+    tokio::spawn(async move {
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if trickle.write_all(b"x").await.is_err() {
+                break;
+            }
+        }
+    });
+    let started = std::time::Instant::now();
+    let error = bounded_copy_with_counters(
+        reader, tokio::io::sink(), limits, "trickle", Direction::ServerToClient, stats.clone(), ByteCounters::default()
+    ).await.unwrap_err();
+    assert_eq!(ProxyErrorKind::of(&error), Some(ProxyErrorKind::SlowTransfer));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(stats.slow_transfer_aborted.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+    // A pause longer than the window is idleness, not slowness: the
+    // burst after it is measured afresh
+    let (reader, mut bursty) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        bursty.write_all(&[1; 400]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        bursty.write_all(&[2; 400]).await.unwrap();
+    });
+    let copied = bounded_copy_with_counters(
+        reader, tokio::io::sink(), limits, "bursty", Direction::ServerToClient, stats.clone(), ByteCounters::default()
+    ).await.unwrap();
+    assert_eq!(copied, 800);
+    assert_eq!(stats.slow_transfer_aborted.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_parse_duration() {
+    use rust_proxy::parse_duration;
+
+    assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("45s").unwrap(), Duration::from_secs(45));
+    assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30 * 60));
+    assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(2 * 3600));
+    assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86_400));
+    assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+    for invalid in ["", "0", "0m", "h", "10x", "1.5h", "30 m", "-1s", "99999999999999999999d"] {
+        assert!(parse_duration(invalid).is_err(), "{:?} should be rejected", invalid);
+    }
+
+    let args = Args::parse_from(["rust_proxy", "--run-for", "30m"]);
+    assert_eq!(args.run_for, Some(Duration::from_secs(1800)));
+    assert_eq!(Args::parse_from(["rust_proxy"]).run_for, None);
+}
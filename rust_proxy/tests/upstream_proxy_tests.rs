@@ -0,0 +1,223 @@
+mod common;
+
+use rust_proxy::upstream_proxy::{open_tunnel, parse_upstream_proxy, UpstreamProxies, UpstreamProxy};
+use rust_proxy::{ProxyConfig, ProxyStats};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+// A stub upstream proxy that answers each CONNECT with `answer` and, when
+// that opens the tunnel, relays it to the requested target. Reports each
+// CONNECT request line.
+async fn start_upstream_proxy(answer: &'static [u8]) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buffer = [0; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => head.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head).to_string();
+                let request_line = head.lines().next().unwrap_or_default().to_string();
+                let _ = tx.send(request_line.clone());
+                let _ = socket.write_all(answer).await;
+                if !answer.starts_with(b"HTTP/1.1 200") {
+                    return;
+                }
+                let target = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+                if let Ok(mut upstream) = TcpStream::connect(target).await {
+                    let _ = tokio::io::copy_bidirectional(&mut socket, &mut upstream).await;
+                }
+            });
+        }
+    });
+
+    (addr, rx)
+}
+
+// An address nothing is listening on
+async fn refusing_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn proxies(addrs: &[SocketAddr]) -> Option<Arc<UpstreamProxies>> {
+    let proxies = addrs.iter().map(|addr| parse_upstream_proxy(&addr.to_string()).unwrap()).collect();
+    Some(Arc::new(UpstreamProxies::new(proxies)))
+}
+
+#[test]
+fn test_parse_upstream_proxy() {
+    let proxy = parse_upstream_proxy("proxy.internal:3128").unwrap();
+    assert_eq!(proxy, UpstreamProxy { host: "proxy.internal".to_string(), port: 3128 });
+    assert_eq!(parse_upstream_proxy("http://proxy.internal:3128/").unwrap(), proxy);
+
+    let proxy = parse_upstream_proxy("[fd00::1]:8080").unwrap();
+    assert_eq!(proxy.host, "fd00::1");
+    assert_eq!(proxy.to_string(), "[fd00::1]:8080");
+
+    assert!(parse_upstream_proxy("proxy.internal").is_err());
+    assert!(parse_upstream_proxy("proxy.internal:http").is_err());
+    assert!(parse_upstream_proxy(":3128").is_err());
+}
+
+#[test]
+fn test_rotation_is_round_robin() {
+    let pool = UpstreamProxies::new(["a:1", "b:1", "c:1"].iter().map(|p| parse_upstream_proxy(p).unwrap()).collect());
+    let order = |pool: &UpstreamProxies| pool.rotation().map(|p| p.host.clone()).collect::<Vec<_>>();
+    assert_eq!(order(&pool), ["a", "b", "c"]);
+    assert_eq!(order(&pool), ["b", "c", "a"]);
+    assert_eq!(order(&pool), ["c", "a", "b"]);
+    assert_eq!(order(&pool), ["a", "b", "c"]);
+    assert_eq!(UpstreamProxies::new(Vec::new()).rotation().count(), 0);
+}
+
+#[tokio::test]
+async fn test_open_tunnel_rejects_non_2xx() {
+    let (proxy, _requests) = start_upstream_proxy(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let error = open_tunnel(&mut stream, "example.com", 443).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+    assert!(error.to_string().contains("403"), "{}", error);
+}
+
+#[tokio::test]
+async fn test_fails_over_to_next_upstream_proxy() {
+    let (origin, mut requests) = common::start_recording_origin(OK).await;
+    let (good, mut connects) = start_upstream_proxy(b"HTTP/1.1 200 Connection Established\r\n\r\n").await;
+    let refused = refusing_addr().await;
+    let config = ProxyConfig { upstream_proxies: proxies(&[refused, good]), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let request = format!("GET http://{}/via-upstream HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", origin, origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert_eq!(response.as_bytes(), OK);
+    assert_eq!(connects.recv().await.unwrap(), format!("CONNECT {} HTTP/1.1", origin));
+    assert!(requests.recv().await.unwrap().starts_with("GET http://"));
+
+    let refused_stats = stats.upstream_proxy(&refused.to_string());
+    let good_stats = stats.upstream_proxy(&good.to_string());
+    assert_eq!(refused_stats.failures.load(Ordering::Relaxed), 1);
+    assert_eq!(refused_stats.successes.load(Ordering::Relaxed), 0);
+    assert_eq!(good_stats.successes.load(Ordering::Relaxed), 1);
+
+    // The next request starts with the good proxy's turn and needs no failover
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", origin).as_bytes()).await.unwrap();
+    let mut established = [0; 39];
+    stream.read_exact(&mut established).await.unwrap();
+    assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+    stream.write_all(b"GET /tunneled HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut response = vec![0; OK.len()];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, OK);
+    assert_eq!(refused_stats.failures.load(Ordering::Relaxed), 1);
+    assert_eq!(good_stats.successes.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_refused_tunnel_fails_over_and_exhaustion_returns_502() {
+    let (origin, _requests) = common::start_recording_origin(OK).await;
+    let (forbidding, _) = start_upstream_proxy(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await;
+    let refused = refusing_addr().await;
+    let config = ProxyConfig { upstream_proxies: proxies(&[forbidding, refused]), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", origin);
+    let response = common::send_request(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert_eq!(stats.upstream_proxy(&forbidding.to_string()).failures.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.upstream_proxy(&refused.to_string()).failures.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.connection_errors.load(Ordering::Relaxed), 1);
+}
+
+// Read a CONNECT request head, then answer it with each of `parts` in its
+// own write, pausing in between so they arrive as separate reads
+async fn answer_in_parts<S>(mut socket: S, parts: &[&[u8]])
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buffer).await.unwrap();
+        assert!(n > 0, "closed before sending CONNECT");
+        head.extend_from_slice(&buffer[..n]);
+    }
+    for part in parts {
+        socket.write_all(part).await.unwrap();
+        socket.flush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    // Hold the tunnel open until the other side is done with it
+    let _ = socket.read(&mut buffer).await;
+}
+
+#[tokio::test]
+async fn test_open_tunnel_reads_split_response() {
+    let (mut stream, upstream) = tokio::io::duplex(1024);
+    tokio::spawn(async move {
+        answer_in_parts(upstream, &[b"HTTP/1.1 200 Connection", b" Established\r\n\r\n"]).await;
+    });
+    assert!(open_tunnel(&mut stream, "example.com", 443).await.unwrap().is_empty());
+
+    // Bytes past the head are the tunnel's, not an error
+    let (mut stream, upstream) = tokio::io::duplex(1024);
+    tokio::spawn(async move {
+        answer_in_parts(upstream, &[b"HTTP/1.1 200 OK\r", b"\n\r\nSSH-2.0-stub\r\n"]).await;
+    });
+    assert_eq!(open_tunnel(&mut stream, "example.com", 22).await.unwrap(), b"SSH-2.0-stub\r\n");
+}
+
+#[tokio::test]
+async fn test_bytes_after_split_connect_response_reach_client() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        answer_in_parts(socket, &[b"HTTP/1.1 200 Connection Est", b"ablished\r\n\r\nSSH-2.0-stub\r\n"]).await;
+    });
+    let config = ProxyConfig { upstream_proxies: proxies(&[upstream]), ..Default::default() };
+    let (proxy, stats) = common::start_proxy(config).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(b"CONNECT 192.0.2.1:22 HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut received = vec![0; 39 + 14];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, b"HTTP/1.1 200 Connection Established\r\n\r\nSSH-2.0-stub\r\n");
+    assert_eq!(stats.upstream_proxy(&upstream.to_string()).successes.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_probe_upstream_reachable_and_unreachable() {
+    let (origin, _requests) = common::start_recording_origin(OK).await;
+    let rtt = rust_proxy::probe_upstream(&ProxyConfig::default(), &ProxyStats::new(), &origin.to_string()).await.unwrap();
+    assert!(rtt < std::time::Duration::from_secs(1), "{:?}", rtt);
+
+    let refused = refusing_addr().await;
+    let error = rust_proxy::probe_upstream(&ProxyConfig::default(), &ProxyStats::new(), &refused.to_string()).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+
+    // Through an upstream proxy, the probe checks the whole path
+    let (good, mut connects) = start_upstream_proxy(b"HTTP/1.1 200 Connection Established\r\n\r\n").await;
+    let config = ProxyConfig { upstream_proxies: proxies(&[good]), ..Default::default() };
+    rust_proxy::probe_upstream(&config, &ProxyStats::new(), &origin.to_string()).await.unwrap();
+    assert_eq!(connects.recv().await.unwrap(), format!("CONNECT {} HTTP/1.1", origin));
+
+    let config = ProxyConfig { upstream_proxies: proxies(&[refused]), ..Default::default() };
+    assert!(rust_proxy::probe_upstream(&config, &ProxyStats::new(), &origin.to_string()).await.is_err());
+}